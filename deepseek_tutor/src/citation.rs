@@ -0,0 +1,130 @@
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::sync::LazyLock;
+use utoipa::ToSchema;
+
+use crate::retrieval::{Passage, Position};
+use crate::tools::ToolUse;
+
+/// Longest snippet kept with a citation, in characters.
+const SNIPPET_CHARS: usize = 280;
+
+/// `[1]`, `[2, 3]` or `[2,3]`.
+static MARKER: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\[(\d{1,3}(?:\s*,\s*\d{1,3})*)\]").unwrap());
+
+/// Inline code spans, which are left out when looking for markers.
+static CODE_SPAN: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"`[^`]*`").unwrap());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum CitationKind {
+    Document,
+    Tool,
+}
+
+/// A source that contributed to a tutor reply.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Citation {
+    /// Number of the `[n]` marker in the reply that points here. Tool
+    /// results are not marked in the text, so they have none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub marker: Option<usize>,
+    pub kind: CitationKind,
+    /// The cited course document; absent for tool results.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub document_id: Option<String>,
+    /// Document title, or the name of the tool.
+    pub title: String,
+    /// Start of the passage, or the tool's output.
+    pub snippet: String,
+    /// Where the passage sits in its document.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub position: Option<Position>,
+}
+
+/// The sources of a reply: the passages it cites by marker, in marker order,
+/// then every tool call that ran. Markers with no matching passage are
+/// ignored.
+pub fn collect(reply: &str, passages: &[Passage], tools: &[ToolUse]) -> Vec<Citation> {
+    let documents = markers(reply).into_iter().filter_map(|marker| {
+        let passage = passages.get(marker.checked_sub(1)?)?;
+        Some(Citation {
+            marker: Some(marker),
+            kind: CitationKind::Document,
+            document_id: Some(passage.document_id.clone()),
+            title: passage.title.clone(),
+            snippet: snippet(&passage.text),
+            position: Some(passage.position.clone()),
+        })
+    });
+    let tools = tools.iter().map(|tool| Citation {
+        marker: None,
+        kind: CitationKind::Tool,
+        document_id: None,
+        title: tool.name.clone(),
+        snippet: snippet(&tool.output),
+        position: None,
+    });
+    documents.chain(tools).collect()
+}
+
+/// Numbers cited in `reply` outside code. A bracketed number right after a
+/// name or bracket is an index such as `v[1]`, not a marker, unless the
+/// bracket closes another marker as in `[1][2]`.
+fn markers(reply: &str) -> BTreeSet<usize> {
+    let mut found = BTreeSet::new();
+    let mut fenced = false;
+    for line in reply.lines() {
+        if line.trim_start().starts_with("```") {
+            fenced = !fenced;
+            continue;
+        }
+        if fenced {
+            continue;
+        }
+        let line = CODE_SPAN.replace_all(line, "``");
+        let mut previous_end = None;
+        for capture in MARKER.captures_iter(&line) {
+            let whole = capture.get(0).unwrap();
+            let indexing = line[..whole.start()]
+                .chars()
+                .next_back()
+                .is_some_and(|c| c.is_alphanumeric() || matches!(c, '_' | ']' | ')'));
+            if indexing && previous_end != Some(whole.start()) {
+                continue;
+            }
+            previous_end = Some(whole.end());
+            found.extend(
+                capture[1]
+                    .split(',')
+                    .filter_map(|number| number.trim().parse::<usize>().ok()),
+            );
+        }
+    }
+    found
+}
+
+fn snippet(text: &str) -> String {
+    let text = text.trim();
+    match text.char_indices().nth(SNIPPET_CHARS) {
+        Some((end, _)) => format!("{}…", text[..end].trim_end()),
+        None => text.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_markers_outside_code_and_indexing() {
+        let reply = "Meiosis halves the count [1]. Both divide [2, 3].\n\
+                     Use `v[4]` or v[5] to index.\n\
+                     ```\nlet x = [6];\n```\n\
+                     Crossing over [7][8], unlike m[1][9].";
+        let found: Vec<usize> = markers(reply).into_iter().collect();
+        assert_eq!(found, [1, 2, 3, 7, 8]);
+    }
+}
@@ -0,0 +1,1284 @@
+use clap::Parser;
+use serde::Deserialize;
+use tutor_config::{Common, CommonArgs, CommonFile, FileModel, FileServer, FileUpstream};
+
+use crate::moderation::{Action, Category};
+use std::{collections::HashMap, path::PathBuf, time::Duration};
+
+pub use tutor_config::{ConfigError, ModelConfig, ServerConfig, UpstreamConfig};
+use tutor_config::{positive, require_dir, require_file};
+
+/// Command line flags. Every flag can also be set through the environment
+/// variable named next to it; flags win over the environment, which wins
+/// over the config file.
+#[derive(Debug, Default, Parser)]
+#[command(name = "deepseek_tutor", about = "Personal tutor web server")]
+pub struct Cli {
+    #[command(flatten)]
+    pub common: CommonArgs,
+
+    /// Directory served under /static
+    #[arg(long, env = "TUTOR_STATIC_DIR")]
+    pub static_dir: Option<PathBuf>,
+
+    /// Longest message accepted from an imported transcript, in characters
+    #[arg(long, env = "TUTOR_MAX_MESSAGE_CHARS")]
+    pub max_message_chars: Option<usize>,
+
+    /// Most messages accepted in one imported transcript
+    #[arg(long, env = "TUTOR_MAX_IMPORT_MESSAGES")]
+    pub max_import_messages: Option<usize>,
+}
+
+/// Raw config file contents. Every field is optional so that a partial file
+/// only overrides what it mentions.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    server: FileServer,
+    paths: FilePaths,
+    upstream: FileUpstream,
+    model: FileModel,
+    limits: FileLimits,
+    sessions: FileSessions,
+    integrity: FileIntegrity,
+    moderation: FileModeration,
+    privacy: FilePrivacy,
+    tools: FileTools,
+    retrieval: FileRetrieval,
+    quiz: FileQuiz,
+    flashcards: FileFlashcards,
+    progress: FileProgress,
+    profile: FileProfile,
+    problems: FileProblems,
+    dashboard: FileDashboard,
+    organizations: Vec<FileOrganization>,
+    personas: Vec<FilePersona>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FilePaths {
+    data_dir: Option<PathBuf>,
+    static_dir: Option<PathBuf>,
+    system_prompt: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileLimits {
+    max_query_chars: Option<usize>,
+    max_message_chars: Option<usize>,
+    max_import_messages: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileSessions {
+    idle_ttl_secs: Option<u64>,
+    max_per_student: Option<usize>,
+    max_in_memory: Option<usize>,
+    sweep_interval_secs: Option<u64>,
+    spill: Option<bool>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileIntegrity {
+    enabled: Option<bool>,
+    model_classifier: Option<bool>,
+    log_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileModeration {
+    enabled: Option<bool>,
+    model: Option<String>,
+    word_list: Option<PathBuf>,
+    audit_log: Option<PathBuf>,
+    actions: HashMap<Category, Action>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FilePrivacy {
+    redact_upstream: Option<bool>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileTools {
+    enabled: Option<bool>,
+    max_iterations: Option<usize>,
+    rust: FileRustRunner,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileRustRunner {
+    enabled: Option<bool>,
+    rustc: Option<PathBuf>,
+    edition: Option<String>,
+    compile_timeout_secs: Option<u64>,
+    run_timeout_secs: Option<u64>,
+    compile_memory_mb: Option<u64>,
+    run_memory_mb: Option<u64>,
+    max_output_bytes: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileRetrieval {
+    enabled: Option<bool>,
+    dir: Option<PathBuf>,
+    top_k: Option<usize>,
+    min_score: Option<f32>,
+    chunk_size: Option<usize>,
+    chunk_overlap: Option<usize>,
+    max_upload_mb: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileQuiz {
+    enabled: Option<bool>,
+    dir: Option<PathBuf>,
+    default_questions: Option<usize>,
+    max_questions: Option<usize>,
+    max_attempts: Option<usize>,
+    max_tokens: Option<u32>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileFlashcards {
+    enabled: Option<bool>,
+    dir: Option<PathBuf>,
+    max_candidates: Option<usize>,
+    max_attempts: Option<usize>,
+    max_tokens: Option<u32>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileProgress {
+    enabled: Option<bool>,
+    dir: Option<PathBuf>,
+    idle_minutes: Option<u64>,
+    summary_in_prompt: Option<bool>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileProfile {
+    enabled: Option<bool>,
+    dir: Option<PathBuf>,
+    max_attempts: Option<usize>,
+    max_tokens: Option<u32>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileProblems {
+    enabled: Option<bool>,
+    dir: Option<PathBuf>,
+    hint_levels: Option<usize>,
+    max_hints: Option<usize>,
+    reveal_after_attempts: Option<usize>,
+    max_attempts: Option<usize>,
+    max_tokens: Option<u32>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileDashboard {
+    enabled: Option<bool>,
+    recent_sessions: Option<usize>,
+    recent_events: Option<usize>,
+    teachers: Vec<FileTeacher>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileTeacher {
+    id: String,
+    name: Option<String>,
+    /// Environment variable holding the teacher's sign-in token.
+    token_env: String,
+    organization: Option<String>,
+    #[serde(default)]
+    classrooms: Vec<String>,
+    #[serde(default)]
+    students: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileOrganization {
+    id: String,
+    name: Option<String>,
+    /// Relative to data_dir, like the global prompt.
+    system_prompt: Option<PathBuf>,
+    #[serde(default)]
+    model: FileModel,
+    #[serde(default)]
+    quotas: FileQuotas,
+    #[serde(default)]
+    classrooms: Vec<FileClassroom>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileQuotas {
+    max_sessions_per_student: Option<usize>,
+    daily_tokens: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FilePersona {
+    id: String,
+    name: Option<String>,
+    /// Relative to data_dir.
+    prompt: PathBuf,
+    #[serde(default)]
+    model: FileModel,
+    /// Names of the tools the persona may call; all of them when unset.
+    tools: Option<Vec<String>>,
+    #[serde(default)]
+    keywords: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileClassroom {
+    id: String,
+    name: Option<String>,
+    #[serde(default)]
+    students: Vec<String>,
+}
+
+/// Validated application configuration.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub paths: PathsConfig,
+    pub upstream: UpstreamConfig,
+    pub model: ModelConfig,
+    pub limits: LimitsConfig,
+    pub sessions: SessionsConfig,
+    pub integrity: IntegrityConfig,
+    pub moderation: ModerationConfig,
+    pub privacy: PrivacyConfig,
+    pub tools: ToolsConfig,
+    pub retrieval: RetrievalConfig,
+    pub quiz: QuizConfig,
+    pub flashcards: FlashcardsConfig,
+    pub progress: ProgressConfig,
+    pub profile: ProfileConfig,
+    pub problems: ProblemsConfig,
+    pub dashboard: DashboardConfig,
+    /// Schools served by this instance. Students not enrolled in any of
+    /// their classrooms belong to the default organization, which uses the
+    /// global settings.
+    pub organizations: Vec<OrganizationConfig>,
+    /// Subject specialists a session can be routed to.
+    pub personas: Vec<PersonaConfig>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PathsConfig {
+    pub static_dir: PathBuf,
+    pub system_prompt: PathBuf,
+}
+
+#[derive(Debug, Clone)]
+pub struct LimitsConfig {
+    /// Longest question a student may send.
+    pub max_query_chars: usize,
+    /// Longest single message accepted from an imported transcript.
+    pub max_message_chars: usize,
+    /// Most messages accepted in one imported transcript.
+    pub max_import_messages: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct SessionsConfig {
+    /// Sessions unused for this long are removed and answer 410 Gone.
+    pub idle_ttl: Duration,
+    /// Most sessions one student may hold; the least recently used one is
+    /// removed when a new one would exceed this.
+    pub max_per_student: usize,
+    /// Most sessions kept in memory across all students. Beyond this the
+    /// least recently used session is spilled to disk, or removed when
+    /// spilling is off.
+    pub max_in_memory: usize,
+    pub sweep_interval: Duration,
+    /// Where evicted sessions are written, `<data_dir>/sessions` when
+    /// spilling is on.
+    pub spill_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone)]
+pub struct IntegrityConfig {
+    /// Screen questions for graded-assessment answer requests.
+    pub enabled: bool,
+    /// Also ask the model when the rules do not match. Costs one short
+    /// completion per question.
+    pub model_classifier: bool,
+    /// JSON Lines file receiving flagged events.
+    pub log_file: PathBuf,
+}
+
+#[derive(Debug, Clone)]
+pub struct ModerationConfig {
+    /// Check student messages and tutor replies.
+    pub enabled: bool,
+    /// Moderation model to call through the upstream `/moderations`
+    /// endpoint, in addition to the local checkers.
+    pub model: Option<String>,
+    /// Extra `category: pattern` lines for the keyword checker.
+    pub word_list: Option<PathBuf>,
+    /// JSON Lines audit trail of moderated messages.
+    pub audit_log: PathBuf,
+    /// Overrides of the default action per category.
+    pub actions: HashMap<Category, Action>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PrivacyConfig {
+    /// Replace names, contact details and addresses with placeholders in
+    /// requests to the upstream provider.
+    pub redact_upstream: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct ToolsConfig {
+    /// Offer the built-in tools (calculator, unit converter) to the model.
+    pub enabled: bool,
+    /// Most rounds of tool calls in one turn; after that the model must
+    /// answer with what it has.
+    pub max_iterations: usize,
+    pub rust: RustRunnerConfig,
+}
+
+#[derive(Debug, Clone)]
+pub struct RustRunnerConfig {
+    /// Offer the `run_rust` tool. It is skipped at startup if `rustc` does
+    /// not run.
+    pub enabled: bool,
+    pub rustc: PathBuf,
+    pub edition: String,
+    pub compile_timeout: Duration,
+    pub run_timeout: Duration,
+    /// Address-space limits for the compiler and for the program.
+    pub compile_memory_bytes: u64,
+    pub run_memory_bytes: u64,
+    /// Output kept from each of stdout and stderr.
+    pub max_output_bytes: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct RetrievalConfig {
+    /// Accept course materials and ground answers in them.
+    pub enabled: bool,
+    /// Where uploaded documents and their vectors are kept.
+    pub dir: PathBuf,
+    /// Most passages added to one turn.
+    pub top_k: usize,
+    /// Passages scoring below this cosine similarity are left out.
+    pub min_score: f32,
+    /// Target passage length and the overlap between neighbours, in bytes.
+    pub chunk_size: usize,
+    pub chunk_overlap: usize,
+    pub max_upload_bytes: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct QuizConfig {
+    /// Let students generate quizzes from their sessions.
+    pub enabled: bool,
+    /// Where quizzes and their submissions are kept.
+    pub dir: PathBuf,
+    /// Questions in a quiz when the request does not say, and the most it
+    /// may ask for.
+    pub default_questions: usize,
+    pub max_questions: usize,
+    /// Completions tried before giving up on malformed quiz JSON.
+    pub max_attempts: usize,
+    /// Completion budget for one quiz; questions with explanations are
+    /// longer than a tutoring reply.
+    pub max_tokens: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct FlashcardsConfig {
+    /// Let students draft flashcards from tutor replies and review them.
+    pub enabled: bool,
+    /// Where cards and their review schedules are kept.
+    pub dir: PathBuf,
+    /// Most cards drafted from one reply.
+    pub max_candidates: usize,
+    /// Completions tried before giving up on malformed card JSON.
+    pub max_attempts: usize,
+    /// Completion budget for drafting cards from one reply.
+    pub max_tokens: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct ProgressConfig {
+    /// Track topics, quiz scores and study time per student.
+    pub enabled: bool,
+    /// Where each student's progress is kept.
+    pub dir: PathBuf,
+    /// Gaps between turns longer than this do not count as study time.
+    pub idle_minutes: u64,
+    /// Tell the tutor about the student's progress when a session starts.
+    pub summary_in_prompt: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct ProblemsConfig {
+    /// Let sessions work through practice problems with graded hints.
+    pub enabled: bool,
+    /// Where problems, their reference solutions and attempts are kept.
+    pub dir: PathBuf,
+    /// Hints the model writes when a problem is registered without them,
+    /// and the most a problem may be registered with.
+    pub hint_levels: usize,
+    pub max_hints: usize,
+    /// Wrong attempts after which the worked solution is shown.
+    pub reveal_after_attempts: usize,
+    /// Completions tried before giving up on malformed hints or verdicts.
+    pub max_attempts: usize,
+    /// Completion budget for writing hints or checking an answer.
+    pub max_tokens: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct ProfileConfig {
+    /// Remember each student's grade, goals, style and misconceptions and
+    /// add them to the system prompt of new sessions.
+    pub enabled: bool,
+    /// Where student profiles are kept.
+    pub dir: PathBuf,
+    /// Completions tried before giving up on malformed profile notes.
+    pub max_attempts: usize,
+    /// Completion budget for the end-of-session extraction.
+    pub max_tokens: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct DashboardConfig {
+    /// Serve the teacher dashboard under /teacher.
+    pub enabled: bool,
+    /// Sessions listed per student, newest first.
+    pub recent_sessions: usize,
+    /// Integrity and moderation events listed per student, newest first.
+    pub recent_events: usize,
+    pub teachers: Vec<TeacherConfig>,
+}
+
+/// A teacher who may sign in to the dashboard and the students they see.
+#[derive(Clone)]
+pub struct TeacherConfig {
+    pub id: String,
+    pub name: String,
+    /// Password for HTTP Basic sign-in, read from the environment.
+    pub token: String,
+    /// Organization whose students the teacher sees; `None` for the
+    /// default organization.
+    pub organization: Option<String>,
+    /// Students listed directly and those of the teacher's classrooms.
+    pub students: Vec<String>,
+}
+
+impl std::fmt::Debug for TeacherConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TeacherConfig")
+            .field("id", &self.id)
+            .field("name", &self.name)
+            .field("token", &"<redacted>")
+            .field("organization", &self.organization)
+            .field("students", &self.students)
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct OrganizationConfig {
+    pub id: String,
+    pub name: String,
+    /// The organization's own system prompt, or the global one.
+    pub system_prompt: PathBuf,
+    /// Global model settings with the organization's overrides.
+    pub model: ModelConfig,
+    /// Global session settings with the organization's session quota and
+    /// its own spill directory.
+    pub sessions: SessionsConfig,
+    /// Tokens the organization's students may use per UTC day, over
+    /// tutoring turns and every other upstream call; `None` for no limit.
+    pub daily_tokens: Option<u64>,
+    /// Where the day's token count is kept so that a restart does not reset
+    /// it, `<data_dir>/organizations/<id>/usage.json` when `daily_tokens`
+    /// is set.
+    pub usage_file: Option<PathBuf>,
+    pub classrooms: Vec<ClassroomConfig>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ClassroomConfig {
+    /// Unique across organizations; also the `course_id` of the
+    /// classroom's course materials.
+    pub id: String,
+    pub name: String,
+    pub students: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PersonaConfig {
+    pub id: String,
+    pub name: String,
+    /// Instructions added after the organization's system prompt.
+    pub prompt: PathBuf,
+    /// Replace the organization's model settings where set.
+    pub model_name: Option<String>,
+    pub temperature: Option<f32>,
+    pub max_tokens: Option<u32>,
+    /// Tools offered to the model; `None` for all of them.
+    pub tools: Option<Vec<String>>,
+    /// Words in a first question that route the session here.
+    pub keywords: Vec<String>,
+}
+
+/// Id of the organization holding every student not enrolled elsewhere.
+pub const DEFAULT_ORGANIZATION: &str = "default";
+
+impl Config {
+    /// Load configuration from the command line, the environment and the
+    /// config file, in that order of precedence.
+    pub fn load() -> Result<Self, ConfigError> {
+        Self::from_cli(Cli::parse(), |name| std::env::var(name).ok())
+    }
+
+    /// Resolve `cli` over the config file, reading the settings that have
+    /// no flag through `env`.
+    pub fn from_cli(cli: Cli, env: impl Fn(&str) -> Option<String>) -> Result<Self, ConfigError> {
+        let (mut file, base_dir): (FileConfig, _) =
+            tutor_config::read_file(cli.common.config.as_deref())?;
+        // Paths from the file are relative to the file, not the working directory.
+        let static_dir = cli
+            .static_dir
+            .or(file.paths.static_dir.take().map(|p| base_dir.join(p)))
+            .unwrap_or_else(|| PathBuf::from("static"));
+        require_dir("paths.static_dir", &static_dir)?;
+
+        let common = Common::resolve(
+            cli.common,
+            CommonFile {
+                server: std::mem::take(&mut file.server),
+                data_dir: file.paths.data_dir.take(),
+                system_prompt: file.paths.system_prompt.take(),
+                upstream: std::mem::take(&mut file.upstream),
+                model: std::mem::take(&mut file.model),
+                max_query_chars: file.limits.max_query_chars,
+            },
+            &base_dir,
+            "0.0.0.0:3000",
+            &env,
+        )?;
+        let data_dir = common.data_dir;
+
+        let limits = LimitsConfig {
+            max_query_chars: common.max_query_chars,
+            max_message_chars: positive(
+                "limits.max_message_chars",
+                cli.max_message_chars
+                    .or(file.limits.max_message_chars)
+                    .unwrap_or(20_000),
+            )?,
+            max_import_messages: positive(
+                "limits.max_import_messages",
+                cli.max_import_messages
+                    .or(file.limits.max_import_messages)
+                    .unwrap_or(500),
+            )?,
+        };
+
+        let sessions = SessionsConfig {
+            idle_ttl: Duration::from_secs(positive(
+                "sessions.idle_ttl_secs",
+                file.sessions.idle_ttl_secs.unwrap_or(2 * 60 * 60),
+            )?),
+            max_per_student: positive(
+                "sessions.max_per_student",
+                file.sessions.max_per_student.unwrap_or(20),
+            )?,
+            max_in_memory: positive(
+                "sessions.max_in_memory",
+                file.sessions.max_in_memory.unwrap_or(10_000),
+            )?,
+            sweep_interval: Duration::from_secs(positive(
+                "sessions.sweep_interval_secs",
+                file.sessions.sweep_interval_secs.unwrap_or(60),
+            )?),
+            spill_dir: file
+                .sessions
+                .spill
+                .unwrap_or(false)
+                .then(|| data_dir.join("sessions")),
+        };
+
+        let integrity = IntegrityConfig {
+            enabled: file.integrity.enabled.unwrap_or(true),
+            model_classifier: file.integrity.model_classifier.unwrap_or(false),
+            // Relative to data_dir, like the system prompt.
+            log_file: data_dir.join(
+                file.integrity
+                    .log_file
+                    .unwrap_or_else(|| PathBuf::from("integrity_events.jsonl")),
+            ),
+        };
+
+        let word_list = file.moderation.word_list.map(|p| data_dir.join(p));
+        if let Some(path) = &word_list {
+            require_file("moderation.word_list", path)?;
+        }
+        let moderation = ModerationConfig {
+            enabled: file.moderation.enabled.unwrap_or(true),
+            model: file.moderation.model.filter(|m| !m.trim().is_empty()),
+            word_list,
+            audit_log: data_dir.join(
+                file.moderation
+                    .audit_log
+                    .unwrap_or_else(|| PathBuf::from("moderation_audit.jsonl")),
+            ),
+            actions: file.moderation.actions,
+        };
+
+        let rust = file.tools.rust;
+        let edition = rust.edition.unwrap_or_else(|| "2021".to_string());
+        if !["2015", "2018", "2021", "2024"].contains(&edition.as_str()) {
+            return Err(ConfigError::Invalid {
+                field: "tools.rust.edition",
+                reason: format!("unknown edition `{}`", edition),
+            });
+        }
+        let megabytes =
+            |field: &'static str, value: Option<u64>, default: u64| -> Result<u64, ConfigError> {
+                Ok(positive(field, value.unwrap_or(default))? * 1024 * 1024)
+            };
+        let tools = ToolsConfig {
+            enabled: file.tools.enabled.unwrap_or(true),
+            max_iterations: positive(
+                "tools.max_iterations",
+                file.tools.max_iterations.unwrap_or(4),
+            )?,
+            rust: RustRunnerConfig {
+                enabled: rust.enabled.unwrap_or(true),
+                rustc: rust.rustc.unwrap_or_else(|| PathBuf::from("rustc")),
+                edition,
+                compile_timeout: Duration::from_secs(positive(
+                    "tools.rust.compile_timeout_secs",
+                    rust.compile_timeout_secs.unwrap_or(30),
+                )?),
+                run_timeout: Duration::from_secs(positive(
+                    "tools.rust.run_timeout_secs",
+                    rust.run_timeout_secs.unwrap_or(5),
+                )?),
+                compile_memory_bytes: megabytes(
+                    "tools.rust.compile_memory_mb",
+                    rust.compile_memory_mb,
+                    2048,
+                )?,
+                run_memory_bytes: megabytes("tools.rust.run_memory_mb", rust.run_memory_mb, 256)?,
+                max_output_bytes: positive(
+                    "tools.rust.max_output_bytes",
+                    rust.max_output_bytes.unwrap_or(16 * 1024),
+                )?,
+            },
+        };
+
+        let chunk_size = positive(
+            "retrieval.chunk_size",
+            file.retrieval.chunk_size.unwrap_or(1200),
+        )?;
+        let chunk_overlap = file.retrieval.chunk_overlap.unwrap_or(200);
+        if chunk_overlap >= chunk_size {
+            return Err(ConfigError::Invalid {
+                field: "retrieval.chunk_overlap",
+                reason: "must be smaller than retrieval.chunk_size".to_string(),
+            });
+        }
+        let min_score = file.retrieval.min_score.unwrap_or(0.15);
+        if !(0.0..1.0).contains(&min_score) {
+            return Err(ConfigError::Invalid {
+                field: "retrieval.min_score",
+                reason: "must be at least 0 and below 1".to_string(),
+            });
+        }
+        let retrieval = RetrievalConfig {
+            enabled: file.retrieval.enabled.unwrap_or(true),
+            dir: data_dir.join(
+                file.retrieval
+                    .dir
+                    .unwrap_or_else(|| PathBuf::from("documents")),
+            ),
+            top_k: positive("retrieval.top_k", file.retrieval.top_k.unwrap_or(4))?,
+            min_score,
+            chunk_size,
+            chunk_overlap,
+            max_upload_bytes: megabytes(
+                "retrieval.max_upload_mb",
+                file.retrieval.max_upload_mb,
+                20,
+            )? as usize,
+        };
+
+        let max_questions = positive("quiz.max_questions", file.quiz.max_questions.unwrap_or(10))?;
+        let default_questions = positive(
+            "quiz.default_questions",
+            file.quiz.default_questions.unwrap_or(5),
+        )?;
+        if default_questions > max_questions {
+            return Err(ConfigError::Invalid {
+                field: "quiz.default_questions",
+                reason: "must not exceed quiz.max_questions".to_string(),
+            });
+        }
+        let quiz = QuizConfig {
+            enabled: file.quiz.enabled.unwrap_or(true),
+            dir: data_dir.join(file.quiz.dir.unwrap_or_else(|| PathBuf::from("quizzes"))),
+            default_questions,
+            max_questions,
+            max_attempts: positive("quiz.max_attempts", file.quiz.max_attempts.unwrap_or(3))?,
+            max_tokens: positive("quiz.max_tokens", file.quiz.max_tokens.unwrap_or(2000))?,
+        };
+
+        let flashcards = FlashcardsConfig {
+            enabled: file.flashcards.enabled.unwrap_or(true),
+            dir: data_dir.join(
+                file.flashcards
+                    .dir
+                    .unwrap_or_else(|| PathBuf::from("flashcards")),
+            ),
+            max_candidates: positive(
+                "flashcards.max_candidates",
+                file.flashcards.max_candidates.unwrap_or(8),
+            )?,
+            max_attempts: positive(
+                "flashcards.max_attempts",
+                file.flashcards.max_attempts.unwrap_or(3),
+            )?,
+            max_tokens: positive(
+                "flashcards.max_tokens",
+                file.flashcards.max_tokens.unwrap_or(1000),
+            )?,
+        };
+
+        let progress = ProgressConfig {
+            enabled: file.progress.enabled.unwrap_or(true),
+            dir: data_dir.join(
+                file.progress
+                    .dir
+                    .unwrap_or_else(|| PathBuf::from("progress")),
+            ),
+            idle_minutes: positive(
+                "progress.idle_minutes",
+                file.progress.idle_minutes.unwrap_or(30),
+            )?,
+            summary_in_prompt: file.progress.summary_in_prompt.unwrap_or(true),
+        };
+
+        let profile = ProfileConfig {
+            enabled: file.profile.enabled.unwrap_or(true),
+            dir: data_dir.join(
+                file.profile
+                    .dir
+                    .unwrap_or_else(|| PathBuf::from("profiles")),
+            ),
+            max_attempts: positive(
+                "profile.max_attempts",
+                file.profile.max_attempts.unwrap_or(3),
+            )?,
+            max_tokens: positive("profile.max_tokens", file.profile.max_tokens.unwrap_or(800))?,
+        };
+
+        let hint_levels = positive(
+            "problems.hint_levels",
+            file.problems.hint_levels.unwrap_or(3),
+        )?;
+        let max_hints = positive("problems.max_hints", file.problems.max_hints.unwrap_or(6))?;
+        if hint_levels > max_hints {
+            return Err(ConfigError::Invalid {
+                field: "problems.hint_levels",
+                reason: format!("{hint_levels} exceeds problems.max_hints ({max_hints})"),
+            });
+        }
+        let problems = ProblemsConfig {
+            enabled: file.problems.enabled.unwrap_or(true),
+            dir: data_dir.join(
+                file.problems
+                    .dir
+                    .unwrap_or_else(|| PathBuf::from("problems")),
+            ),
+            hint_levels,
+            max_hints,
+            reveal_after_attempts: positive(
+                "problems.reveal_after_attempts",
+                file.problems.reveal_after_attempts.unwrap_or(3),
+            )?,
+            max_attempts: positive(
+                "problems.max_attempts",
+                file.problems.max_attempts.unwrap_or(3),
+            )?,
+            max_tokens: positive(
+                "problems.max_tokens",
+                file.problems.max_tokens.unwrap_or(1000),
+            )?,
+        };
+
+        let mut organizations: Vec<OrganizationConfig> = Vec::new();
+        // Organization of each enrolled student and each classroom.
+        let mut enrolled: HashMap<String, String> = HashMap::new();
+        let mut classroom_orgs: HashMap<String, (String, Vec<String>)> = HashMap::new();
+        for org in file.organizations {
+            check_id("organizations.id", &org.id)?;
+            if org.id == DEFAULT_ORGANIZATION || organizations.iter().any(|o| o.id == org.id) {
+                return Err(ConfigError::Invalid {
+                    field: "organizations.id",
+                    reason: format!("{:?} is reserved or used twice", org.id),
+                });
+            }
+            let system_prompt = match org.system_prompt {
+                Some(path) => {
+                    let path = data_dir.join(path);
+                    require_file("organizations.system_prompt", &path)?;
+                    path
+                }
+                None => common.system_prompt.clone(),
+            };
+            let org_temperature = org.model.temperature.unwrap_or(common.model.temperature);
+            if !(0.0..=2.0).contains(&org_temperature) {
+                return Err(ConfigError::Invalid {
+                    field: "organizations.model.temperature",
+                    reason: format!("{org_temperature} is outside 0.0..=2.0"),
+                });
+            }
+            let model = ModelConfig {
+                name: org.model.name.unwrap_or_else(|| common.model.name.clone()),
+                temperature: org_temperature,
+                max_tokens: positive(
+                    "organizations.model.max_tokens",
+                    org.model.max_tokens.unwrap_or(common.model.max_tokens),
+                )?,
+            };
+            let org_sessions = SessionsConfig {
+                max_per_student: positive(
+                    "organizations.quotas.max_sessions_per_student",
+                    org.quotas
+                        .max_sessions_per_student
+                        .unwrap_or(sessions.max_per_student),
+                )?,
+                // Each organization spills to its own directory.
+                spill_dir: sessions.spill_dir.as_ref().map(|_| {
+                    data_dir
+                        .join("organizations")
+                        .join(&org.id)
+                        .join("sessions")
+                }),
+                ..sessions.clone()
+            };
+            let daily_tokens = org
+                .quotas
+                .daily_tokens
+                .map(|tokens| positive("organizations.quotas.daily_tokens", tokens))
+                .transpose()?;
+            let usage_file = daily_tokens.map(|_| {
+                data_dir
+                    .join("organizations")
+                    .join(&org.id)
+                    .join("usage.json")
+            });
+
+            let mut classrooms = Vec::new();
+            for classroom in org.classrooms {
+                check_id("organizations.classrooms.id", &classroom.id)?;
+                if classroom_orgs.contains_key(&classroom.id) {
+                    return Err(ConfigError::Invalid {
+                        field: "organizations.classrooms.id",
+                        reason: format!("{:?} is used twice", classroom.id),
+                    });
+                }
+                for student in &classroom.students {
+                    let other = enrolled.insert(student.clone(), org.id.clone());
+                    if other.is_some_and(|other| other != org.id) {
+                        return Err(ConfigError::Invalid {
+                            field: "organizations.classrooms.students",
+                            reason: format!("student {student:?} is enrolled in two organizations"),
+                        });
+                    }
+                }
+                classroom_orgs.insert(
+                    classroom.id.clone(),
+                    (org.id.clone(), classroom.students.clone()),
+                );
+                classrooms.push(ClassroomConfig {
+                    name: classroom.name.unwrap_or_else(|| classroom.id.clone()),
+                    id: classroom.id,
+                    students: classroom.students,
+                });
+            }
+            organizations.push(OrganizationConfig {
+                name: org.name.unwrap_or_else(|| org.id.clone()),
+                id: org.id,
+                system_prompt,
+                model,
+                sessions: org_sessions,
+                daily_tokens,
+                usage_file,
+                classrooms,
+            });
+        }
+
+        let mut personas: Vec<PersonaConfig> = Vec::new();
+        for persona in file.personas {
+            check_id("personas.id", &persona.id)?;
+            if personas.iter().any(|p| p.id == persona.id) {
+                return Err(ConfigError::Invalid {
+                    field: "personas.id",
+                    reason: format!("{:?} is used twice", persona.id),
+                });
+            }
+            let prompt = data_dir.join(persona.prompt);
+            require_file("personas.prompt", &prompt)?;
+            if let Some(temperature) = persona.model.temperature
+                && !(0.0..=2.0).contains(&temperature)
+            {
+                return Err(ConfigError::Invalid {
+                    field: "personas.model.temperature",
+                    reason: format!("{temperature} is outside 0.0..=2.0"),
+                });
+            }
+            let max_tokens = persona
+                .model
+                .max_tokens
+                .map(|tokens| positive("personas.model.max_tokens", tokens))
+                .transpose()?;
+            if persona
+                .keywords
+                .iter()
+                .any(|keyword| keyword.trim().is_empty())
+            {
+                return Err(ConfigError::Invalid {
+                    field: "personas.keywords",
+                    reason: format!("persona {} has an empty keyword", persona.id),
+                });
+            }
+            personas.push(PersonaConfig {
+                name: persona.name.unwrap_or_else(|| persona.id.clone()),
+                id: persona.id,
+                prompt,
+                model_name: persona.model.name,
+                temperature: persona.model.temperature,
+                max_tokens,
+                tools: persona.tools,
+                keywords: persona.keywords,
+            });
+        }
+
+        let mut teachers = Vec::new();
+        for teacher in file.dashboard.teachers {
+            if teacher.id.is_empty() || teachers.iter().any(|t: &TeacherConfig| t.id == teacher.id)
+            {
+                return Err(ConfigError::Invalid {
+                    field: "dashboard.teachers",
+                    reason: format!(
+                        "teacher ids must be unique and not empty, got {:?}",
+                        teacher.id
+                    ),
+                });
+            }
+            let token = env(&teacher.token_env)
+                .filter(|token| !token.trim().is_empty())
+                .ok_or_else(|| ConfigError::Invalid {
+                    field: "dashboard.teachers",
+                    reason: format!(
+                        "{} is not set; it holds the token for teacher {}",
+                        teacher.token_env, teacher.id
+                    ),
+                })?;
+            if let Some(org) = &teacher.organization
+                && !organizations.iter().any(|o| &o.id == org)
+            {
+                return Err(ConfigError::Invalid {
+                    field: "dashboard.teachers.organization",
+                    reason: format!("unknown organization {org:?} for teacher {}", teacher.id),
+                });
+            }
+            // Teachers only ever see students of their own organization.
+            let mut students = teacher.students;
+            for classroom in &teacher.classrooms {
+                match classroom_orgs.get(classroom) {
+                    Some((org, members)) if Some(org) == teacher.organization.as_ref() => {
+                        students.extend(members.iter().cloned());
+                    }
+                    _ => {
+                        return Err(ConfigError::Invalid {
+                            field: "dashboard.teachers.classrooms",
+                            reason: format!(
+                                "classroom {classroom:?} is not in the organization of teacher {}",
+                                teacher.id
+                            ),
+                        });
+                    }
+                }
+            }
+            if let Some(student) = students
+                .iter()
+                .find(|student| enrolled.get(*student) != teacher.organization.as_ref())
+            {
+                return Err(ConfigError::Invalid {
+                    field: "dashboard.teachers.students",
+                    reason: format!(
+                        "student {student:?} is not in the organization of teacher {}",
+                        teacher.id
+                    ),
+                });
+            }
+            students.sort();
+            students.dedup();
+            teachers.push(TeacherConfig {
+                name: teacher.name.unwrap_or_else(|| teacher.id.clone()),
+                id: teacher.id,
+                token,
+                organization: teacher.organization,
+                students,
+            });
+        }
+        let dashboard = DashboardConfig {
+            enabled: file.dashboard.enabled.unwrap_or(true),
+            recent_sessions: positive(
+                "dashboard.recent_sessions",
+                file.dashboard.recent_sessions.unwrap_or(10),
+            )?,
+            recent_events: positive(
+                "dashboard.recent_events",
+                file.dashboard.recent_events.unwrap_or(20),
+            )?,
+            teachers,
+        };
+
+        Ok(Config {
+            server: common.server,
+            paths: PathsConfig {
+                static_dir,
+                system_prompt: common.system_prompt,
+            },
+            upstream: common.upstream,
+            model: common.model,
+            limits,
+            sessions,
+            integrity,
+            moderation,
+            privacy: PrivacyConfig {
+                redact_upstream: file.privacy.redact_upstream.unwrap_or(true),
+            },
+            tools,
+            retrieval,
+            quiz,
+            flashcards,
+            progress,
+            profile,
+            problems,
+            dashboard,
+            organizations,
+            personas,
+        })
+    }
+}
+
+/// Organization and classroom ids end up in paths and URLs.
+fn check_id(field: &'static str, id: &str) -> Result<(), ConfigError> {
+    let valid = !id.is_empty()
+        && id.len() <= 64
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(ConfigError::Invalid {
+            field,
+            reason: format!("{id:?} must be 1 to 64 letters, digits, '-' or '_'"),
+        })
+    }
+}
+
+#[cfg(test)]
+impl Config {
+    /// Configuration pointing at the checked-in data files, for handler tests
+    /// that never reach the upstream API. The logs and stores it writes go
+    /// to a directory of its own, which nothing creates until they are
+    /// opened; tests that open them use `for_tests_in` with a `TempDir`.
+    pub fn for_tests() -> Self {
+        Self::for_tests_in(
+            &std::env::temp_dir().join(format!("tutor-test-{}", uuid::Uuid::new_v4())),
+        )
+    }
+
+    /// `for_tests` with the logs and stores under `scratch`.
+    pub fn for_tests_in(scratch: &std::path::Path) -> Self {
+        Config {
+            server: ServerConfig {
+                bind: "127.0.0.1:0".parse().unwrap(),
+                tls: None,
+            },
+            paths: PathsConfig {
+                static_dir: PathBuf::from("static"),
+                system_prompt: PathBuf::from("data/system_prompt.txt"),
+            },
+            upstream: UpstreamConfig {
+                base_url: "http://127.0.0.1:9/v1".to_string(),
+                api_key: "test-key".to_string(),
+            },
+            model: ModelConfig {
+                name: "test-model".to_string(),
+                temperature: 0.0,
+                max_tokens: 16,
+            },
+            limits: LimitsConfig {
+                max_query_chars: 4000,
+                max_message_chars: 20_000,
+                max_import_messages: 500,
+            },
+            sessions: SessionsConfig {
+                idle_ttl: Duration::from_secs(3600),
+                max_per_student: 20,
+                max_in_memory: 1000,
+                sweep_interval: Duration::from_secs(60),
+                spill_dir: None,
+            },
+            integrity: IntegrityConfig {
+                enabled: true,
+                model_classifier: false,
+                log_file: scratch.join("integrity_events.jsonl"),
+            },
+            moderation: ModerationConfig {
+                enabled: true,
+                model: None,
+                word_list: None,
+                audit_log: scratch.join("moderation_audit.jsonl"),
+                actions: HashMap::new(),
+            },
+            privacy: PrivacyConfig {
+                redact_upstream: true,
+            },
+            tools: ToolsConfig {
+                enabled: true,
+                max_iterations: 4,
+                rust: RustRunnerConfig {
+                    enabled: false,
+                    rustc: PathBuf::from("rustc"),
+                    edition: "2021".to_string(),
+                    compile_timeout: Duration::from_secs(30),
+                    run_timeout: Duration::from_secs(5),
+                    compile_memory_bytes: 2048 * 1024 * 1024,
+                    run_memory_bytes: 256 * 1024 * 1024,
+                    max_output_bytes: 16 * 1024,
+                },
+            },
+            retrieval: RetrievalConfig {
+                enabled: true,
+                dir: scratch.join("documents"),
+                top_k: 4,
+                min_score: 0.15,
+                chunk_size: 1200,
+                chunk_overlap: 200,
+                max_upload_bytes: 20 * 1024 * 1024,
+            },
+            quiz: QuizConfig {
+                enabled: true,
+                dir: scratch.join("quizzes"),
+                default_questions: 5,
+                max_questions: 10,
+                max_attempts: 3,
+                max_tokens: 2000,
+            },
+            flashcards: FlashcardsConfig {
+                enabled: true,
+                dir: scratch.join("flashcards"),
+                max_candidates: 8,
+                max_attempts: 3,
+                max_tokens: 1000,
+            },
+            progress: ProgressConfig {
+                enabled: true,
+                dir: scratch.join("progress"),
+                idle_minutes: 30,
+                summary_in_prompt: true,
+            },
+            profile: ProfileConfig {
+                enabled: true,
+                dir: scratch.join("profiles"),
+                max_attempts: 3,
+                max_tokens: 800,
+            },
+            problems: ProblemsConfig {
+                enabled: true,
+                dir: scratch.join("problems"),
+                hint_levels: 3,
+                max_hints: 6,
+                reveal_after_attempts: 3,
+                max_attempts: 3,
+                max_tokens: 1000,
+            },
+            dashboard: DashboardConfig {
+                enabled: true,
+                recent_sessions: 10,
+                recent_events: 20,
+                teachers: vec![TeacherConfig {
+                    id: "teacher".to_string(),
+                    name: "Test Teacher".to_string(),
+                    token: "teacher-token".to_string(),
+                    organization: None,
+                    students: vec!["student".to_string()],
+                }],
+            },
+            organizations: Vec::new(),
+            personas: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checked_in_config_loads_with_overrides() {
+        let config = Config::from_cli(
+            Cli {
+                common: CommonArgs {
+                    config: Some(PathBuf::from("tutor.toml")),
+                    temperature: Some(1.5),
+                    max_query_chars: Some(123),
+                    ..CommonArgs::default()
+                },
+                max_import_messages: Some(7),
+                ..Cli::default()
+            },
+            // The file leaves the key to the environment.
+            |name| (name == "OPENAI_API_KEY").then(|| "test-key".to_string()),
+        )
+        .unwrap();
+        assert_eq!(config.model.temperature, 1.5);
+        assert_eq!(config.model.max_tokens, 500);
+        assert_eq!(config.limits.max_query_chars, 123);
+        assert_eq!(config.limits.max_message_chars, 20_000);
+        assert_eq!(config.limits.max_import_messages, 7);
+        assert_eq!(config.upstream.base_url, "https://api.deepseek.com/v1");
+        assert_eq!(config.personas.len(), 3);
+    }
+}
@@ -1,50 +1,1368 @@
-use crate::models::AppError;
+use crate::config::{
+    Config, DashboardConfig, FlashcardsConfig, LimitsConfig, ProblemsConfig, ProfileConfig,
+    QuizConfig, RetrievalConfig, TeacherConfig,
+};
+use crate::dashboard::{self, StudentOverview};
+use crate::error::ServiceError;
+use crate::export::SessionExport;
+use crate::flashcards::{self, Card, CardCandidates, CardDraft, CardSource, PendingCards};
+use crate::import;
+use crate::integrity::FlaggedEvent;
+use crate::models::{AppError, DocumentForm};
+use crate::moderation::{ModerationEvent, Review};
+use crate::persona::PersonaSummary;
+use crate::problem::{self, PendingCheck, PendingProblem, ProblemDraft, ProblemView};
+use crate::profile::{self, PendingProfile, ProfileUpdate, StudentProfile};
+use crate::progress::ProgressReport;
+use crate::quiz::{self, PendingQuiz, QuizAnswer, QuizView, Submission};
+use crate::retrieval::{Document, DocumentSummary, Scope, Upload};
 use crate::service::TutorService;
+use crate::session::{ActiveMessage, BranchSummary, NodeId, SweepStats, TokenUsage, message_text};
+use crate::turn::{PendingTurn, TurnReply};
 use anyhow::Result;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+const MAX_TURN_ID_LEN: usize = 128;
+const MAX_SCOPE_ID_LEN: usize = 128;
+const MAX_QUIZ_TOPIC_CHARS: usize = 200;
+const DEFAULT_EVENT_LIMIT: usize = 100;
+const MAX_EVENT_LIMIT: usize = 1000;
+const DEFAULT_CARD_LIMIT: usize = 20;
+const MAX_CARD_LIMIT: usize = 500;
 
 pub struct TutorController {
     service: TutorService,
+    limits: LimitsConfig,
+    dashboard: DashboardConfig,
 }
 
 impl TutorController {
-    pub fn new() -> Self {
-        Self {
-            service: TutorService::new(),
-        }
+    pub fn new(config: &Config) -> Result<Self> {
+        Ok(Self {
+            service: TutorService::new(config)?,
+            limits: config.limits.clone(),
+            dashboard: config.dashboard.clone(),
+        })
     }
 
-    pub fn create_session(&mut self, student_id: String) -> Result<String, AppError> {
+    pub fn create_session(
+        &mut self,
+        student_id: String,
+        course_id: Option<String>,
+        organization_id: Option<String>,
+        persona: Option<String>,
+    ) -> Result<String, AppError> {
         if student_id.is_empty() {
-            return Err(AppError::BadRequest("student_id cannot be empty".to_string()));
+            return Err(AppError::BadRequest(
+                "student_id cannot be empty".to_string(),
+            ));
+        }
+        self.check_persona(persona.as_deref())?;
+        let scopes = [
+            course_id.map(Scope::Course),
+            organization_id.map(Scope::Organization),
+        ];
+        let scopes: Vec<Scope> = scopes.into_iter().flatten().collect();
+        for scope in &scopes {
+            validate_scope(scope)?;
+            self.service
+                .check_scope(&student_id, scope)
+                .map_err(AppError::BadRequest)?;
         }
         // Create the session
-        let session_id = self.service.create_session(&student_id);
+        let session_id = self.service.create_session(&student_id, scopes, persona);
         Ok(session_id)
     }
 
-    pub async fn send_query(
+    /// Run a question for a session through moderation. The review may
+    /// call the model, so everything that would refuse the question without
+    /// it is checked first: the ids, the length, the session, a turn in
+    /// progress and the organization's quota. Only those checks and the
+    /// token count hold the lock.
+    pub async fn moderate(
+        controller: &Arc<Mutex<Self>>,
+        student_id: &str,
+        session_id: &str,
+        text: String,
+        turn_id: Option<&str>,
+    ) -> Result<Review, AppError> {
+        let moderator = {
+            let mut controller_guard = controller.lock().await;
+            controller_guard.validate_query(student_id, session_id, &text)?;
+            validate_turn_id(turn_id)?;
+            controller_guard
+                .service
+                .check_turn(student_id, session_id)
+                .map_err(service_error)?;
+            controller_guard.service.moderator()
+        };
+        let review = match moderator {
+            Some(moderator) => moderator.review(text).await,
+            None => return Ok(Review::unchanged(text)),
+        };
+        controller
+            .lock()
+            .await
+            .service
+            .record_tokens(student_id, &review.usage);
+        Ok(review)
+    }
+
+    /// Validate a moderated question and add it to the session. The
+    /// returned turn is run with `run_turn` once the controller lock has
+    /// been released.
+    pub fn start_query(
+        &mut self,
+        student_id: String,
+        session_id: String,
+        query: Review,
+        turn_id: Option<String>,
+    ) -> Result<PendingTurn, AppError> {
+        self.validate_query(&student_id, &session_id, &query.text)?;
+        validate_turn_id(turn_id.as_deref())?;
+
+        self.service
+            .begin_query(&student_id, &session_id, query, turn_id)
+            .map_err(service_error)
+    }
+
+    pub fn start_edit(
+        &mut self,
+        student_id: String,
+        session_id: String,
+        message_id: NodeId,
+        query: Review,
+        turn_id: Option<String>,
+    ) -> Result<PendingTurn, AppError> {
+        self.validate_query(&student_id, &session_id, &query.text)?;
+        validate_turn_id(turn_id.as_deref())?;
+
+        self.service
+            .begin_edit(&student_id, &session_id, message_id, query, turn_id)
+            .map_err(service_error)
+    }
+
+    pub fn start_regenerate(
+        &mut self,
+        student_id: String,
+        session_id: String,
+        message_id: NodeId,
+        turn_id: Option<String>,
+    ) -> Result<PendingTurn, AppError> {
+        if student_id.is_empty() || session_id.is_empty() {
+            return Err(AppError::BadRequest(
+                "Missing student_id or session_id".to_string(),
+            ));
+        }
+        validate_turn_id(turn_id.as_deref())?;
+
+        self.service
+            .begin_regenerate(&student_id, &session_id, message_id, turn_id)
+            .map_err(service_error)
+    }
+
+    /// Wait for the model without holding the controller lock, then record
+    /// the outcome. The wait runs on its own task so that the turn is still
+    /// recorded if the client disconnects.
+    pub async fn run_turn(
+        controller: &Arc<Mutex<Self>>,
+        turn: PendingTurn,
+    ) -> Result<TurnReply, AppError> {
+        let controller = Arc::clone(controller);
+        tokio::spawn(async move {
+            let (ticket, outcome) = turn.run().await;
+            let mut controller_guard = controller.lock().await;
+            controller_guard
+                .service
+                .finish_turn(ticket, outcome)
+                .map_err(service_error)
+        })
+        .await
+        .map_err(|err| AppError::Internal(err.to_string()))?
+    }
+
+    /// Stop a running turn. Returns the id of the session it belongs to.
+    pub fn cancel_turn(&mut self, student_id: String, turn_id: String) -> Result<String, AppError> {
+        if student_id.is_empty() || turn_id.is_empty() {
+            return Err(AppError::BadRequest(
+                "Missing student_id or turn_id".to_string(),
+            ));
+        }
+
+        self.service
+            .cancel_turn(&student_id, &turn_id)
+            .map_err(service_error)
+    }
+
+    pub fn active_messages(
+        &mut self,
+        student_id: String,
+        session_id: String,
+    ) -> Result<Vec<ActiveMessage>, AppError> {
+        if student_id.is_empty() || session_id.is_empty() {
+            return Err(AppError::BadRequest(
+                "Missing student_id or session_id".to_string(),
+            ));
+        }
+
+        self.service
+            .active_messages(&student_id, &session_id)
+            .map_err(service_error)
+    }
+
+    pub fn branches(
+        &mut self,
+        student_id: String,
+        session_id: String,
+    ) -> Result<Vec<BranchSummary>, AppError> {
+        if student_id.is_empty() || session_id.is_empty() {
+            return Err(AppError::BadRequest(
+                "Missing student_id or session_id".to_string(),
+            ));
+        }
+
+        self.service
+            .branches(&student_id, &session_id)
+            .map_err(service_error)
+    }
+
+    pub fn activate_branch(
+        &mut self,
+        student_id: String,
+        session_id: String,
+        message_id: NodeId,
+    ) -> Result<Vec<ActiveMessage>, AppError> {
+        if student_id.is_empty() || session_id.is_empty() {
+            return Err(AppError::BadRequest(
+                "Missing student_id or session_id".to_string(),
+            ));
+        }
+
+        self.service
+            .activate_branch(&student_id, &session_id, message_id)
+            .map_err(service_error)
+    }
+
+    pub fn personas(&self) -> Vec<PersonaSummary> {
+        self.service.personas()
+    }
+
+    /// Switch the subject persona of a session.
+    pub fn set_persona(
+        &mut self,
+        student_id: String,
+        session_id: String,
+        persona: Option<String>,
+    ) -> Result<(), AppError> {
+        if student_id.is_empty() || session_id.is_empty() {
+            return Err(AppError::BadRequest(
+                "Missing student_id or session_id".to_string(),
+            ));
+        }
+        self.check_persona(persona.as_deref())?;
+
+        self.service
+            .set_persona(&student_id, &session_id, persona)
+            .map_err(service_error)
+    }
+
+    fn check_persona(&self, persona: Option<&str>) -> Result<(), AppError> {
+        match persona {
+            Some(id) if !self.service.has_persona(id) => {
+                Err(AppError::BadRequest(format!("Unknown persona: {}", id)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Create a session from an exported or OpenAI-format transcript.
+    /// Every message goes through moderation, without the lock, as a live
+    /// one would, and is charged to the organization's quota as it goes:
+    /// an import stops with 429 once the quota is used up. Returns the new
+    /// session id and the number of imported messages.
+    pub async fn import_session(
+        controller: &Arc<Mutex<Self>>,
+        student_id: String,
+        transcript: serde_json::Value,
+    ) -> Result<(String, usize), AppError> {
+        if student_id.is_empty() {
+            return Err(AppError::BadRequest(
+                "student_id cannot be empty".to_string(),
+            ));
+        }
+
+        let (transcript, moderator) = {
+            let controller_guard = controller.lock().await;
+            let transcript = import::parse_transcript(transcript, &controller_guard.limits)
+                .map_err(|err| AppError::BadRequest(err.to_string()))?;
+            (transcript, controller_guard.service.moderator())
+        };
+        let mut reviews = Vec::with_capacity(transcript.messages.len());
+        for stored in &transcript.messages {
+            let (_, text) = message_text(&stored.message);
+            let Some(moderator) = &moderator else {
+                reviews.push(Review::unchanged(text));
+                continue;
+            };
+            controller
+                .lock()
+                .await
+                .service
+                .check_quota(&student_id)
+                .map_err(service_error)?;
+            let review = moderator.review(text).await;
+            controller
+                .lock()
+                .await
+                .service
+                .record_tokens(&student_id, &review.usage);
+            reviews.push(review);
+        }
+
+        let count = transcript.messages.len();
+        let mut controller_guard = controller.lock().await;
+        let session_id = controller_guard
+            .service
+            .import_session(&student_id, transcript, reviews)
+            .map_err(service_error)?;
+        Ok((session_id, count))
+    }
+
+    pub fn clone_session(
         &mut self,
         student_id: String,
         session_id: String,
-        query: String,
     ) -> Result<String, AppError> {
+        if student_id.is_empty() || session_id.is_empty() {
+            return Err(AppError::BadRequest(
+                "Missing student_id or session_id".to_string(),
+            ));
+        }
+
+        self.service
+            .clone_session(&student_id, &session_id)
+            .map_err(service_error)
+    }
+
+    pub fn export_session(
+        &mut self,
+        student_id: String,
+        session_id: String,
+    ) -> Result<SessionExport, AppError> {
+        if student_id.is_empty() || session_id.is_empty() {
+            return Err(AppError::BadRequest(
+                "Missing student_id or session_id".to_string(),
+            ));
+        }
+
+        self.service
+            .export_session(&student_id, &session_id)
+            .map_err(service_error)
+    }
+
+    pub fn export_student(&self, student_id: String) -> Result<Vec<SessionExport>, AppError> {
         if student_id.is_empty() {
-            return Err(AppError::BadRequest("student_id cannot be empty".to_string()));
+            return Err(AppError::BadRequest(
+                "student_id cannot be empty".to_string(),
+            ));
         }
 
-        if session_id.is_empty() || query.is_empty() {
-            return Err(AppError::BadRequest("Missing session_id or query".to_string()));
+        let sessions = self.service.export_student(&student_id);
+        if sessions.is_empty() {
+            return Err(AppError::NotFound(format!(
+                "No sessions for student {}",
+                student_id
+            )));
+        }
+        Ok(sessions)
+    }
+
+    /// Flagged turns of the teacher's students, or of one of them.
+    pub fn integrity_events(
+        &self,
+        teacher: &TeacherConfig,
+        student_id: Option<String>,
+        limit: Option<usize>,
+    ) -> Result<Vec<FlaggedEvent>, AppError> {
+        let limit = event_limit(limit)?;
+        let students = assigned_students(teacher, student_id)?;
+        self.service
+            .integrity_events(&students, limit)
+            .map_err(service_error)
+    }
+
+    /// Moderation decisions about the teacher's students, or one of them.
+    pub fn moderation_events(
+        &self,
+        teacher: &TeacherConfig,
+        student_id: Option<String>,
+        limit: Option<usize>,
+    ) -> Result<Vec<ModerationEvent>, AppError> {
+        let limit = event_limit(limit)?;
+        let students = assigned_students(teacher, student_id)?;
+        self.service
+            .moderation_events(&students, limit)
+            .map_err(service_error)
+    }
+
+    /// Largest file accepted by `upload_document`.
+    pub fn max_upload_bytes(&self) -> Result<usize, AppError> {
+        Ok(self.retrieval_config()?.max_upload_bytes)
+    }
+
+    /// Add a file to the materials of a course or organization of the
+    /// teacher's. Text extraction and embedding run on a blocking thread
+    /// without the lock.
+    pub async fn upload_document(
+        controller: &Arc<Mutex<Self>>,
+        teacher: &TeacherConfig,
+        form: DocumentForm,
+    ) -> Result<DocumentSummary, AppError> {
+        let scope = document_scope(form.course_id, form.organization_id)?.ok_or_else(|| {
+            AppError::BadRequest("course_id or organization_id is required".to_string())
+        })?;
+        let (Some(file_name), Some(bytes)) = (form.file_name, form.file) else {
+            return Err(AppError::BadRequest("file is required".to_string()));
+        };
+        if bytes.is_empty() {
+            return Err(AppError::BadRequest("file is empty".to_string()));
+        }
+        let config = {
+            let controller = controller.lock().await;
+            let config = controller.retrieval_config()?.clone();
+            controller
+                .service
+                .check_organization_scope(teacher.organization.as_deref(), &scope)
+                .map_err(AppError::BadRequest)?;
+            config
+        };
+
+        let upload = Upload {
+            file_name,
+            content_type: form.content_type,
+            title: form.title,
+            scope,
+            bytes,
+        };
+        let document = tokio::task::spawn_blocking(move || Document::prepare(upload, &config))
+            .await
+            .map_err(|err| AppError::Internal(err.to_string()))?
+            .map_err(|err| AppError::BadRequest(format!("{:#}", err)))?;
+
+        controller
+            .lock()
+            .await
+            .service
+            .add_document(document)
+            .map_err(service_error)
+    }
+
+    /// Documents shared within the teacher's organization, narrowed to one
+    /// course or organization when given.
+    pub fn documents(
+        &self,
+        teacher: &TeacherConfig,
+        course_id: Option<String>,
+        organization_id: Option<String>,
+    ) -> Result<Vec<DocumentSummary>, AppError> {
+        self.retrieval_config()?;
+        let organization = teacher.organization.as_deref();
+        let scope = document_scope(course_id, organization_id)?;
+        if let Some(scope) = &scope {
+            self.service
+                .check_organization_scope(organization, scope)
+                .map_err(AppError::BadRequest)?;
+        }
+        let mut documents = self.service.documents(scope.as_ref());
+        documents.retain(|document| {
+            self.service
+                .check_organization_scope(organization, &document.scope)
+                .is_ok()
+        });
+        Ok(documents)
+    }
+
+    /// Remove a document shared within the teacher's organization. Those of
+    /// other organizations are reported as not found.
+    pub fn delete_document(
+        &mut self,
+        teacher: &TeacherConfig,
+        document_id: String,
+    ) -> Result<(), AppError> {
+        self.retrieval_config()?;
+        let owned = self.service.document(&document_id).is_some_and(|document| {
+            self.service
+                .check_organization_scope(teacher.organization.as_deref(), &document.scope)
+                .is_ok()
+        });
+        if !owned {
+            return Err(AppError::NotFound(format!(
+                "Document {} not found",
+                document_id
+            )));
+        }
+        self.service
+            .remove_document(&document_id)
+            .map_err(service_error)
+    }
+
+    fn retrieval_config(&self) -> Result<&RetrievalConfig, AppError> {
+        self.service
+            .retrieval_config()
+            .ok_or_else(|| AppError::NotFound("Course materials are disabled".to_string()))
+    }
+
+    /// Validate a quiz request and prepare it from the session. The
+    /// returned quiz is generated with `generate_quiz` once the lock has
+    /// been released.
+    pub fn start_quiz(
+        &mut self,
+        student_id: String,
+        session_id: String,
+        questions: Option<usize>,
+        topic: Option<String>,
+    ) -> Result<PendingQuiz, AppError> {
+        if student_id.is_empty() || session_id.is_empty() {
+            return Err(AppError::BadRequest(
+                "Missing student_id or session_id".to_string(),
+            ));
+        }
+        let config = self.quiz_config()?;
+        let questions = questions.unwrap_or(config.default_questions);
+        if questions == 0 || questions > config.max_questions {
+            return Err(AppError::BadRequest(format!(
+                "questions must be 1 to {}",
+                config.max_questions
+            )));
+        }
+        let topic = topic
+            .map(|topic| topic.trim().to_string())
+            .filter(|topic| !topic.is_empty());
+        if topic
+            .as_ref()
+            .is_some_and(|topic| topic.chars().count() > MAX_QUIZ_TOPIC_CHARS)
+        {
+            return Err(AppError::BadRequest(format!(
+                "topic exceeds {} characters",
+                MAX_QUIZ_TOPIC_CHARS
+            )));
+        }
+
+        self.service
+            .begin_quiz(&student_id, &session_id, questions, topic)
+            .map_err(service_error)?
+            .ok_or_else(|| {
+                AppError::BadRequest("The session has no tutor replies to quiz on yet".to_string())
+            })
+    }
+
+    /// Ask the model for the quiz without holding the lock, then store it.
+    pub async fn generate_quiz(
+        controller: &Arc<Mutex<Self>>,
+        pending: PendingQuiz,
+    ) -> Result<QuizView, AppError> {
+        let student_id = pending.owner.student_id.clone();
+        let mut usage = TokenUsage::default();
+        let quiz = pending.run(&mut usage).await;
+        let mut controller_guard = controller.lock().await;
+        controller_guard.service.record_tokens(&student_id, &usage);
+        let quiz = quiz.map_err(|err| AppError::Internal(format!("{:#}", err)))?;
+        controller_guard
+            .service
+            .add_quiz(quiz)
+            .map_err(service_error)
+    }
+
+    pub fn quiz(&self, student_id: String, quiz_id: String) -> Result<QuizView, AppError> {
+        if student_id.is_empty() {
+            return Err(AppError::BadRequest(
+                "student_id cannot be empty".to_string(),
+            ));
+        }
+        self.quiz_config()?;
+        self.service
+            .quiz(&student_id, &quiz_id)
+            .map_err(service_error)
+    }
+
+    /// Grade a submission. Answers are checked against the questions first
+    /// so that a malformed submission is not recorded.
+    pub fn submit_quiz(
+        &mut self,
+        student_id: String,
+        quiz_id: String,
+        answers: Vec<QuizAnswer>,
+    ) -> Result<Submission, AppError> {
+        let view = self.quiz(student_id.clone(), quiz_id.clone())?;
+        quiz::check_answers(&view.questions, &answers).map_err(AppError::BadRequest)?;
+        self.service
+            .submit_quiz(&student_id, &quiz_id, &answers)
+            .map_err(service_error)
+    }
+
+    /// Validate a practice problem and prepare it for the session. Its
+    /// hints are written with `register_problem` once the lock has been
+    /// released.
+    pub fn start_problem(
+        &mut self,
+        student_id: String,
+        session_id: String,
+        draft: ProblemDraft,
+    ) -> Result<PendingProblem, AppError> {
+        if student_id.is_empty() || session_id.is_empty() {
+            return Err(AppError::BadRequest(
+                "Missing student_id or session_id".to_string(),
+            ));
+        }
+        let config = self.problems_config()?;
+        problem::check_draft(&draft, config.max_hints).map_err(AppError::BadRequest)?;
+
+        self.service
+            .begin_problem(&student_id, &session_id, draft)
+            .map_err(service_error)
+    }
+
+    /// Ask the model for hints if needed without holding the lock, then
+    /// store the problem.
+    pub async fn register_problem(
+        controller: &Arc<Mutex<Self>>,
+        pending: PendingProblem,
+    ) -> Result<ProblemView, AppError> {
+        let student_id = pending.owner.student_id.clone();
+        let mut usage = TokenUsage::default();
+        let problem = pending.run(&mut usage).await;
+        let mut controller_guard = controller.lock().await;
+        controller_guard.service.record_tokens(&student_id, &usage);
+        let problem = problem.map_err(|err| AppError::Internal(format!("{:#}", err)))?;
+        controller_guard
+            .service
+            .add_problem(problem)
+            .map_err(service_error)
+    }
+
+    pub fn problem(&self, student_id: String, problem_id: String) -> Result<ProblemView, AppError> {
+        if student_id.is_empty() {
+            return Err(AppError::BadRequest(
+                "student_id cannot be empty".to_string(),
+            ));
+        }
+        self.problems_config()?;
+        self.service
+            .problem(&student_id, &problem_id)
+            .map_err(service_error)
+    }
+
+    /// Show the next hint of a problem.
+    pub fn reveal_hint(
+        &mut self,
+        student_id: String,
+        problem_id: String,
+    ) -> Result<ProblemView, AppError> {
+        let view = self.problem(student_id.clone(), problem_id.clone())?;
+        if view.hints.len() == view.hints_total {
+            return Err(AppError::BadRequest(
+                "Every hint is already shown".to_string(),
+            ));
+        }
+        self.service
+            .reveal_hint(&student_id, &problem_id)
+            .map_err(service_error)
+    }
+
+    /// Validate an attempt at an open problem. It is checked with
+    /// `check_attempt` once the lock has been released.
+    pub fn start_attempt(
+        &mut self,
+        student_id: String,
+        problem_id: String,
+        answer: String,
+    ) -> Result<PendingCheck, AppError> {
+        let view = self.problem(student_id.clone(), problem_id.clone())?;
+        if view.solved {
+            return Err(AppError::BadRequest(
+                "The problem is already solved".to_string(),
+            ));
+        }
+        if view.solution.is_some() {
+            return Err(AppError::BadRequest(
+                "The solution has already been shown".to_string(),
+            ));
+        }
+        let answer = answer.trim().to_string();
+        if answer.is_empty() {
+            return Err(AppError::BadRequest("answer cannot be empty".to_string()));
+        }
+        if answer.chars().count() > problem::MAX_ANSWER_CHARS {
+            return Err(AppError::BadRequest(format!(
+                "answer exceeds {} characters",
+                problem::MAX_ANSWER_CHARS
+            )));
         }
 
         self.service
-            .process_query(&student_id, &session_id, &query)
+            .begin_attempt(&student_id, &problem_id, answer)
+            .map_err(service_error)
+    }
+
+    /// Check the attempt without holding the lock, then record it.
+    pub async fn check_attempt(
+        controller: &Arc<Mutex<Self>>,
+        pending: PendingCheck,
+    ) -> Result<ProblemView, AppError> {
+        let student_id = pending.owner.student_id.clone();
+        let mut usage = TokenUsage::default();
+        let checked = pending.run(&mut usage).await;
+        let mut controller_guard = controller.lock().await;
+        controller_guard.service.record_tokens(&student_id, &usage);
+        let checked = checked.map_err(|err| AppError::Internal(format!("{:#}", err)))?;
+        controller_guard
+            .service
+            .finish_attempt(checked)
+            .map_err(service_error)
+    }
+
+    fn problems_config(&self) -> Result<&ProblemsConfig, AppError> {
+        self.service
+            .problems_config()
+            .ok_or_else(|| AppError::NotFound("Practice problems are disabled".to_string()))
+    }
+
+    fn quiz_config(&self) -> Result<&QuizConfig, AppError> {
+        self.service
+            .quiz_config()
+            .ok_or_else(|| AppError::NotFound("Quizzes are disabled".to_string()))
+    }
+
+    /// Prepare card drafts from a tutor reply. The drafts are written with
+    /// `draft_cards` once the lock has been released.
+    pub fn start_cards(
+        &mut self,
+        student_id: String,
+        session_id: String,
+        message_id: Option<NodeId>,
+    ) -> Result<PendingCards, AppError> {
+        if student_id.is_empty() || session_id.is_empty() {
+            return Err(AppError::BadRequest(
+                "Missing student_id or session_id".to_string(),
+            ));
+        }
+        self.flashcards_config()?;
+        self.service
+            .begin_cards(&student_id, &session_id, message_id)
+            .map_err(service_error)?
+            .ok_or_else(|| {
+                AppError::BadRequest(
+                    "The session has no tutor replies to draft cards from yet".to_string(),
+                )
+            })
+    }
+
+    /// Ask the model for drafts without holding the lock. Nothing is stored
+    /// until the student keeps a draft with `add_card`; only the tokens
+    /// spent are counted.
+    pub async fn draft_cards(
+        controller: &Arc<Mutex<Self>>,
+        pending: PendingCards,
+    ) -> Result<CardCandidates, AppError> {
+        let student_id = pending.student_id.clone();
+        let mut usage = TokenUsage::default();
+        let candidates = pending.run(&mut usage).await;
+        controller
+            .lock()
             .await
-            .map_err(|err| {
-                if err.to_string().contains("Session not found") {
-                    AppError::NotFound(err.to_string())
-                } else {
-                    AppError::Internal(err.to_string())
+            .service
+            .record_tokens(&student_id, &usage);
+        candidates.map_err(|err| AppError::Internal(format!("{:#}", err)))
+    }
+
+    /// Save a card, written by the student or kept from a draft. A kept
+    /// draft names the session and message it came from.
+    pub fn add_card(
+        &mut self,
+        student_id: String,
+        draft: CardDraft,
+        session_id: Option<String>,
+        message_id: Option<NodeId>,
+    ) -> Result<Card, AppError> {
+        if student_id.is_empty() {
+            return Err(AppError::BadRequest(
+                "student_id cannot be empty".to_string(),
+            ));
+        }
+        let source = match (session_id, message_id) {
+            (Some(session_id), Some(message_id)) => Some(CardSource {
+                session_id,
+                message_id,
+            }),
+            (None, None) => None,
+            _ => {
+                return Err(AppError::BadRequest(
+                    "session_id and message_id must be given together".to_string(),
+                ));
+            }
+        };
+        self.flashcards_config()?;
+        flashcards::check_side(&draft.front)
+            .and_then(|_| flashcards::check_side(&draft.back))
+            .map_err(AppError::BadRequest)?;
+        self.service
+            .add_card(&student_id, draft, source)
+            .map_err(service_error)
+    }
+
+    pub fn edit_card(
+        &mut self,
+        student_id: String,
+        card_id: String,
+        front: Option<String>,
+        back: Option<String>,
+    ) -> Result<Card, AppError> {
+        if student_id.is_empty() {
+            return Err(AppError::BadRequest(
+                "student_id cannot be empty".to_string(),
+            ));
+        }
+        if front.is_none() && back.is_none() {
+            return Err(AppError::BadRequest(
+                "Nothing to change: give front or back".to_string(),
+            ));
+        }
+        self.flashcards_config()?;
+        for side in front.iter().chain(back.iter()) {
+            flashcards::check_side(side).map_err(AppError::BadRequest)?;
+        }
+        self.service
+            .edit_card(&student_id, &card_id, front, back)
+            .map_err(service_error)
+    }
+
+    /// Record how well the student recalled a card and reschedule it.
+    pub fn review_card(
+        &mut self,
+        student_id: String,
+        card_id: String,
+        grade: u8,
+    ) -> Result<Card, AppError> {
+        if student_id.is_empty() {
+            return Err(AppError::BadRequest(
+                "student_id cannot be empty".to_string(),
+            ));
+        }
+        if grade > 5 {
+            return Err(AppError::BadRequest("grade must be 0 to 5".to_string()));
+        }
+        self.flashcards_config()?;
+        self.service
+            .review_card(&student_id, &card_id, grade)
+            .map_err(service_error)
+    }
+
+    pub fn remove_card(&mut self, student_id: String, card_id: String) -> Result<(), AppError> {
+        if student_id.is_empty() {
+            return Err(AppError::BadRequest(
+                "student_id cannot be empty".to_string(),
+            ));
+        }
+        self.flashcards_config()?;
+        self.service
+            .remove_card(&student_id, &card_id)
+            .map_err(service_error)
+    }
+
+    pub fn cards(
+        &self,
+        student_id: String,
+        due: bool,
+        limit: Option<usize>,
+    ) -> Result<Vec<Card>, AppError> {
+        if student_id.is_empty() {
+            return Err(AppError::BadRequest(
+                "student_id cannot be empty".to_string(),
+            ));
+        }
+        let limit = limit.unwrap_or(DEFAULT_CARD_LIMIT);
+        if limit == 0 || limit > MAX_CARD_LIMIT {
+            return Err(AppError::BadRequest(format!(
+                "limit must be 1 to {}",
+                MAX_CARD_LIMIT
+            )));
+        }
+        self.flashcards_config()?;
+        self.service
+            .cards(&student_id, due, limit)
+            .map_err(service_error)
+    }
+
+    fn flashcards_config(&self) -> Result<&FlashcardsConfig, AppError> {
+        self.service
+            .flashcards_config()
+            .ok_or_else(|| AppError::NotFound("Flashcards are disabled".to_string()))
+    }
+
+    /// Topics, quiz scores and study time across a student's sessions.
+    pub fn progress(&self, student_id: String) -> Result<ProgressReport, AppError> {
+        if student_id.is_empty() {
+            return Err(AppError::BadRequest(
+                "student_id cannot be empty".to_string(),
+            ));
+        }
+        self.service
+            .progress(&student_id)
+            .ok_or_else(|| AppError::NotFound("Progress tracking is disabled".to_string()))
+    }
+
+    pub fn profile(&self, student_id: String) -> Result<StudentProfile, AppError> {
+        if student_id.is_empty() {
+            return Err(AppError::BadRequest(
+                "student_id cannot be empty".to_string(),
+            ));
+        }
+        self.profile_config()?;
+        self.service.profile(&student_id).map_err(service_error)
+    }
+
+    pub fn update_profile(
+        &mut self,
+        student_id: String,
+        update: ProfileUpdate,
+    ) -> Result<StudentProfile, AppError> {
+        if student_id.is_empty() {
+            return Err(AppError::BadRequest(
+                "student_id cannot be empty".to_string(),
+            ));
+        }
+        self.profile_config()?;
+        profile::check_update(&update).map_err(AppError::BadRequest)?;
+        self.service
+            .update_profile(&student_id, update)
+            .map_err(service_error)
+    }
+
+    pub fn remove_profile(&mut self, student_id: String) -> Result<(), AppError> {
+        if student_id.is_empty() {
+            return Err(AppError::BadRequest(
+                "student_id cannot be empty".to_string(),
+            ));
+        }
+        self.profile_config()?;
+        self.service
+            .remove_profile(&student_id)
+            .map_err(service_error)
+    }
+
+    /// Prepare end-of-session notes for the student's profile. The notes
+    /// are taken with `extract_profile` once the lock has been released.
+    pub fn start_profile(
+        &mut self,
+        student_id: String,
+        session_id: String,
+    ) -> Result<PendingProfile, AppError> {
+        if student_id.is_empty() || session_id.is_empty() {
+            return Err(AppError::BadRequest(
+                "Missing student_id or session_id".to_string(),
+            ));
+        }
+        self.profile_config()?;
+        self.service
+            .begin_profile(&student_id, &session_id)
+            .map_err(service_error)?
+            .ok_or_else(|| {
+                AppError::BadRequest(
+                    "The session has no tutor replies to learn from yet".to_string(),
+                )
+            })
+    }
+
+    /// Ask the model for notes without holding the lock, then merge them
+    /// into the profile.
+    pub async fn extract_profile(
+        controller: &Arc<Mutex<Self>>,
+        pending: PendingProfile,
+    ) -> Result<StudentProfile, AppError> {
+        let student_id = pending.owner.student_id.clone();
+        let mut usage = TokenUsage::default();
+        let notes = pending.run(&mut usage).await;
+        let mut controller_guard = controller.lock().await;
+        controller_guard.service.record_tokens(&student_id, &usage);
+        let (owner, notes) = notes.map_err(|err| AppError::Internal(format!("{:#}", err)))?;
+        controller_guard
+            .service
+            .merge_profile(&owner, notes)
+            .map_err(service_error)
+    }
+
+    fn profile_config(&self) -> Result<&ProfileConfig, AppError> {
+        self.service
+            .profile_config()
+            .ok_or_else(|| AppError::NotFound("Student profiles are disabled".to_string()))
+    }
+
+    /// The teacher signing in with an HTTP `Authorization` header.
+    pub fn teacher(&self, authorization: Option<&str>) -> Result<TeacherConfig, AppError> {
+        if !self.dashboard.enabled {
+            return Err(AppError::NotFound(
+                "The teacher dashboard is disabled".to_string(),
+            ));
+        }
+        authorization
+            .and_then(|header| dashboard::sign_in(&self.dashboard.teachers, header))
+            .cloned()
+            .ok_or_else(|| {
+                AppError::Unauthorized("Sign in with a teacher id and token".to_string())
+            })
+    }
+
+    /// Name of the teacher's organization; empty for the default one.
+    pub fn organization_name(&self, teacher: &TeacherConfig) -> String {
+        teacher
+            .organization
+            .as_deref()
+            .and_then(|id| self.service.organization_name(id))
+            .unwrap_or_default()
+    }
+
+    /// Sessions, flagged events and progress of each of a teacher's students.
+    pub fn class_overview(
+        &self,
+        teacher: &TeacherConfig,
+    ) -> Result<Vec<StudentOverview>, AppError> {
+        let (flagged, moderated) = self
+            .service
+            .student_events(&teacher.students)
+            .map_err(service_error)?;
+        Ok(teacher
+            .students
+            .iter()
+            .map(|student_id| {
+                let overview = StudentOverview::new(
+                    student_id,
+                    self.service.progress(student_id),
+                    self.service.export_student(student_id),
+                    flagged
+                        .iter()
+                        .filter(|event| &event.student_id == student_id)
+                        .cloned()
+                        .collect(),
+                    moderated
+                        .iter()
+                        .filter(|event| &event.student_id == student_id)
+                        .cloned()
+                        .collect(),
+                    self.dashboard.recent_sessions,
+                    self.dashboard.recent_events,
+                );
+                StudentOverview {
+                    classroom: self.service.classroom_name(student_id).unwrap_or_default(),
+                    ..overview
                 }
             })
+            .collect())
+    }
+
+    /// A session of one of the teacher's students, for reading. Viewing it
+    /// does not keep it from expiring.
+    pub fn teacher_transcript(
+        &self,
+        teacher: &TeacherConfig,
+        student_id: String,
+        session_id: String,
+    ) -> Result<SessionExport, AppError> {
+        if !teacher.students.contains(&student_id) {
+            return Err(AppError::NotFound(format!(
+                "Student {} is not assigned to you",
+                student_id
+            )));
+        }
+        self.service
+            .export_student(&student_id)
+            .into_iter()
+            .find(|session| session.session_id == session_id)
+            .ok_or_else(|| AppError::NotFound(format!("Session {} not found", session_id)))
+    }
+
+    pub fn sweep_sessions(&mut self) -> SweepStats {
+        self.service.sweep_sessions()
+    }
+
+    fn validate_query(
+        &self,
+        student_id: &str,
+        session_id: &str,
+        query: &str,
+    ) -> Result<(), AppError> {
+        if student_id.is_empty() {
+            return Err(AppError::BadRequest(
+                "student_id cannot be empty".to_string(),
+            ));
+        }
+
+        if session_id.is_empty() || query.is_empty() {
+            return Err(AppError::BadRequest(
+                "Missing session_id or query".to_string(),
+            ));
+        }
+
+        if query.chars().count() > self.limits.max_query_chars {
+            return Err(AppError::BadRequest(format!(
+                "query exceeds {} characters",
+                self.limits.max_query_chars
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// The teacher's students, or just `student_id` if it is one of them.
+fn assigned_students(
+    teacher: &TeacherConfig,
+    student_id: Option<String>,
+) -> Result<Vec<String>, AppError> {
+    match student_id {
+        Some(id) if !teacher.students.contains(&id) => Err(AppError::NotFound(format!(
+            "Student {} is not assigned to you",
+            id
+        ))),
+        Some(id) => Ok(vec![id]),
+        None => Ok(teacher.students.clone()),
+    }
+}
+
+/// The `limit` of an event listing, checked against `MAX_EVENT_LIMIT`.
+fn event_limit(limit: Option<usize>) -> Result<usize, AppError> {
+    let limit = limit.unwrap_or(DEFAULT_EVENT_LIMIT);
+    if limit == 0 || limit > MAX_EVENT_LIMIT {
+        return Err(AppError::BadRequest(format!(
+            "limit must be 1 to {}",
+            MAX_EVENT_LIMIT
+        )));
+    }
+    Ok(limit)
+}
+
+/// The scope named by exactly one of the two ids, or `None` for neither.
+fn document_scope(
+    course_id: Option<String>,
+    organization_id: Option<String>,
+) -> Result<Option<Scope>, AppError> {
+    let scope = match (course_id, organization_id) {
+        (Some(_), Some(_)) => {
+            return Err(AppError::BadRequest(
+                "give course_id or organization_id, not both".to_string(),
+            ));
+        }
+        (Some(id), None) => Scope::Course(id),
+        (None, Some(id)) => Scope::Organization(id),
+        (None, None) => return Ok(None),
+    };
+    validate_scope(&scope)?;
+    Ok(Some(scope))
+}
+
+fn validate_scope(scope: &Scope) -> Result<(), AppError> {
+    let (Scope::Course(id) | Scope::Organization(id)) = scope;
+    if id.is_empty() || id.len() > MAX_SCOPE_ID_LEN {
+        return Err(AppError::BadRequest(format!(
+            "course_id and organization_id must be 1 to {} characters",
+            MAX_SCOPE_ID_LEN
+        )));
+    }
+    Ok(())
+}
+
+/// Client-chosen turn ids end up in URLs and logs; keep them short.
+fn validate_turn_id(turn_id: Option<&str>) -> Result<(), AppError> {
+    match turn_id {
+        Some(id) if id.is_empty() || id.len() > MAX_TURN_ID_LEN => Err(AppError::BadRequest(
+            format!("turn_id must be 1 to {} characters", MAX_TURN_ID_LEN),
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn service_error(err: anyhow::Error) -> AppError {
+    let message = err.to_string();
+    match err.downcast_ref::<ServiceError>() {
+        Some(ServiceError::NotFound(_)) => AppError::NotFound(message),
+        Some(ServiceError::Expired(_)) => AppError::Gone(message),
+        Some(ServiceError::Busy(_)) => AppError::Conflict(message),
+        Some(ServiceError::OverQuota(_)) => AppError::TooManyRequests(message),
+        Some(ServiceError::Rejected(_)) => AppError::BadRequest(message),
+        None => AppError::Internal(message),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{FakeUpstream, TempDir, unflagged_moderation};
+    use axum::response::IntoResponse;
+
+    #[test]
+    fn maps_service_errors_by_type() {
+        let status = |err: anyhow::Error| service_error(err).into_response().status();
+        let typed = [
+            (ServiceError::NotFound("Quiz q not found".into()), 404),
+            (ServiceError::Expired("Session expired".into()), 410),
+            (
+                ServiceError::Busy("Turn t is already in progress".into()),
+                409,
+            ),
+            (
+                ServiceError::OverQuota("Organization o has used its quota".into()),
+                429,
+            ),
+            (
+                ServiceError::Rejected("Message blocked by moderation".into()),
+                400,
+            ),
+        ];
+        for (err, code) in typed {
+            assert_eq!(status(err.into()), code);
+        }
+        let wrapped = anyhow::Error::from(ServiceError::NotFound("Session not found".into()))
+            .context("failed to load the session");
+        assert_eq!(status(wrapped), 404);
+
+        // Upstream errors are ours to fix, whatever they say.
+        for message in [
+            "You exceeded your current quota",
+            "The model `x` was not found",
+        ] {
+            assert_eq!(status(anyhow::anyhow!(message)), 500);
+        }
+    }
+
+    /// Enroll "ana" at Northside, which may use `daily_tokens` a day.
+    fn northside(config: &mut Config, daily_tokens: u64, dir: &TempDir) {
+        use crate::config::{ClassroomConfig, OrganizationConfig};
+
+        config.organizations = vec![OrganizationConfig {
+            id: "northside".to_string(),
+            name: "Northside High".to_string(),
+            system_prompt: config.paths.system_prompt.clone(),
+            model: config.model.clone(),
+            sessions: config.sessions.clone(),
+            daily_tokens: Some(daily_tokens),
+            usage_file: Some(dir.path().join("usage.json")),
+            classrooms: vec![ClassroomConfig {
+                id: "9a".to_string(),
+                name: "Year 9A".to_string(),
+                students: vec!["ana".to_string()],
+            }],
+        }];
+    }
+
+    #[tokio::test]
+    async fn refuses_upstream_calls_once_the_quota_is_used() {
+        let dir = TempDir::new("quota");
+        let mut config = Config::for_tests_in(dir.path());
+        northside(&mut config, 10, &dir);
+        let mut controller = TutorController::new(&config).unwrap();
+        let ana = || "ana".to_string();
+        let session = controller.create_session(ana(), None, None, None).unwrap();
+        // A problem with hints and an answer needs the model for neither.
+        let draft = |hints: Vec<String>| ProblemDraft {
+            statement: "Solve 2x = 8.".to_string(),
+            solution: "x = 4".to_string(),
+            answer: Some("4".to_string()),
+            hints,
+        };
+        let pending = controller
+            .start_problem(ana(), session.clone(), draft(vec!["Halve it.".to_string()]))
+            .unwrap();
+        let problem = pending.run(&mut TokenUsage::default()).await.unwrap();
+        let problem_id = controller.service.add_problem(problem).unwrap().problem_id;
+        let used_up = TokenUsage {
+            total_tokens: 10,
+            ..TokenUsage::default()
+        };
+        controller.service.record_tokens("ana", &used_up);
+
+        let over_quota = |result: Result<(), AppError>| {
+            assert!(
+                matches!(result, Err(AppError::TooManyRequests(_))),
+                "{result:?}"
+            );
+        };
+        let query = || Review::unchanged("What is 2 + 2?".to_string());
+        over_quota(
+            controller
+                .start_query(ana(), session.clone(), query(), None)
+                .map(drop),
+        );
+        over_quota(
+            controller
+                .start_quiz(ana(), session.clone(), None, None)
+                .map(drop),
+        );
+        over_quota(
+            controller
+                .start_problem(ana(), session.clone(), draft(Vec::new()))
+                .map(drop),
+        );
+        over_quota(
+            controller
+                .start_attempt(ana(), problem_id.clone(), "5".to_string())
+                .map(drop),
+        );
+        over_quota(
+            controller
+                .start_cards(ana(), session.clone(), None)
+                .map(drop),
+        );
+        over_quota(controller.start_profile(ana(), session.clone()).map(drop));
+        // Matching the answer needs no model call.
+        assert!(
+            controller
+                .start_attempt(ana(), problem_id, "4".to_string())
+                .is_ok()
+        );
+
+        let bo_session = controller
+            .create_session("bo".to_string(), None, None, None)
+            .unwrap();
+        let controller = Arc::new(Mutex::new(controller));
+        over_quota(
+            TutorController::moderate(&controller, "ana", &session, "hi".to_string(), None)
+                .await
+                .map(drop),
+        );
+        let transcript = serde_json::json!({"messages": [{"role": "user", "content": "hi"}]});
+        over_quota(
+            TutorController::import_session(&controller, ana(), transcript)
+                .await
+                .map(drop),
+        );
+        // Other organizations are not affected.
+        assert!(
+            TutorController::moderate(&controller, "bo", &bo_session, "hi".to_string(), None)
+                .await
+                .is_ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn charges_an_import_message_by_message() {
+        let upstream = FakeUpstream::start(|_| (200, unflagged_moderation()));
+        let dir = TempDir::new("import-quota");
+        let mut config = Config::for_tests_in(dir.path());
+        config.upstream.base_url = upstream.base_url().to_string();
+        config.moderation.model = Some("omni-moderation-latest".to_string());
+        // Each message is 40 bytes, counted as 10 tokens.
+        northside(&mut config, 10, &dir);
+        let controller = Arc::new(Mutex::new(TutorController::new(&config).unwrap()));
+
+        let message = |role: &str| serde_json::json!({"role": role, "content": "x".repeat(40)});
+        let transcript = serde_json::json!({"messages": [message("user"), message("assistant"), message("user")]});
+        let result = TutorController::import_session(&controller, "ana".to_string(), transcript)
+            .await
+            .map(drop);
+        assert!(
+            matches!(result, Err(AppError::TooManyRequests(_))),
+            "{result:?}"
+        );
+        assert_eq!(upstream.requests().len(), 1);
     }
 }
@@ -0,0 +1,45 @@
+use axum::{
+    extract::Request,
+    http::{HeaderValue, header::CONTENT_SECURITY_POLICY},
+    middleware::Next,
+    response::Response,
+};
+use std::sync::LazyLock;
+use uuid::Uuid;
+
+/// Everything but scripts and styles: resources only from this server, no
+/// plugins or `<base>`, and the page may not be framed.
+const BASE: &str = "default-src 'self'; img-src 'self' data:; object-src 'none'; \
+                    base-uri 'none'; form-action 'self'; frame-ancestors 'none'";
+
+static POLICY: LazyLock<HeaderValue> = LazyLock::new(|| policy("'self'"));
+
+/// Middleware that adds a Content-Security-Policy to every response that
+/// does not set its own. Scripts and styles must come from files on this
+/// server: inline `<script>`, event handler attributes and `style`
+/// attributes are refused, so markup slipped into a tutor reply cannot run.
+pub async fn apply(req: Request, next: Next) -> Response {
+    let mut res = next.run(req).await;
+    res.headers_mut()
+        .entry(CONTENT_SECURITY_POLICY)
+        .or_insert_with(|| POLICY.clone());
+    res
+}
+
+/// A fresh nonce for a page with inline `<script>` or `<style>` elements.
+pub fn nonce() -> String {
+    Uuid::new_v4().simple().to_string()
+}
+
+/// The policy for a page whose scripts and styles, inline or loaded from
+/// this server, all carry `nonce`.
+pub fn with_nonce(nonce: &str) -> HeaderValue {
+    policy(&format!("'nonce-{nonce}'"))
+}
+
+fn policy(sources: &str) -> HeaderValue {
+    HeaderValue::from_str(&format!(
+        "{BASE}; script-src {sources}; style-src {sources}"
+    ))
+    .expect("policy is a valid header value")
+}
@@ -0,0 +1,294 @@
+use askama::Template;
+use base64::{Engine, engine::general_purpose::STANDARD};
+use std::cmp::Reverse;
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
+
+use crate::config::TeacherConfig;
+use crate::export::SessionExport;
+use crate::integrity::FlaggedEvent;
+use crate::moderation::{Action, Direction, ModerationEvent};
+use crate::progress::ProgressReport;
+use crate::session::message_text;
+
+/// Topics named per student on the dashboard and in the CSV.
+const TOP_TOPICS: usize = 3;
+
+/// The teacher whose HTTP Basic credentials match, if any. The user name
+/// is the teacher id and the password their token.
+pub fn sign_in<'a>(
+    teachers: &'a [TeacherConfig],
+    authorization: &str,
+) -> Option<&'a TeacherConfig> {
+    let encoded = authorization.strip_prefix("Basic ")?.trim();
+    let decoded = String::from_utf8(STANDARD.decode(encoded).ok()?).ok()?;
+    let (id, token) = decoded.split_once(':')?;
+    teachers
+        .iter()
+        .find(|teacher| teacher.id == id && same_secret(&teacher.token, token))
+}
+
+/// Compare without stopping at the first difference, so that response
+/// times do not reveal how much of a token was right.
+fn same_secret(expected: &str, given: &str) -> bool {
+    expected.len() == given.len()
+        && expected
+            .bytes()
+            .zip(given.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// One row of the session list.
+pub struct SessionRow {
+    pub session_id: String,
+    pub started: String,
+    pub last_activity: String,
+    /// Student questions and tutor replies on the active branch.
+    pub messages: usize,
+    pub tokens: u32,
+}
+
+/// One flagged integrity or moderation event.
+pub struct EventRow {
+    at: OffsetDateTime,
+    pub time: String,
+    pub session_id: String,
+    pub kind: &'static str,
+    pub detail: String,
+}
+
+/// Everything the dashboard shows about one assigned student.
+pub struct StudentOverview {
+    pub student_id: String,
+    /// Name of the student's classroom; empty outside organizations.
+    pub classroom: String,
+    pub progress: Option<ProgressReport>,
+    /// Newest first.
+    pub sessions: Vec<SessionRow>,
+    /// Newest first.
+    pub events: Vec<EventRow>,
+    pub integrity_flags: usize,
+    pub moderation_events: usize,
+}
+
+impl StudentOverview {
+    pub fn new(
+        student_id: &str,
+        progress: Option<ProgressReport>,
+        mut sessions: Vec<SessionExport>,
+        flagged: Vec<FlaggedEvent>,
+        moderated: Vec<ModerationEvent>,
+        recent_sessions: usize,
+        recent_events: usize,
+    ) -> Self {
+        sessions.sort_by_key(|session| Reverse(session.updated_at));
+        let integrity_flags = flagged.len();
+        let moderation_events = moderated.len();
+        let mut events: Vec<EventRow> = flagged
+            .into_iter()
+            .map(|event| EventRow {
+                at: event.at,
+                time: timestamp(event.at),
+                session_id: event.session_id,
+                kind: "integrity",
+                detail: if event.flag.reasons.is_empty() {
+                    "flagged by the classifier".to_string()
+                } else {
+                    event.flag.reasons.join(", ")
+                },
+            })
+            .chain(moderated.into_iter().map(|event| {
+                let categories: Vec<&str> = event.categories.iter().map(|c| c.as_str()).collect();
+                EventRow {
+                    at: event.at,
+                    time: timestamp(event.at),
+                    session_id: event.session_id,
+                    kind: "moderation",
+                    detail: format!(
+                        "{} {}: {}",
+                        direction_label(event.direction),
+                        action_label(event.action),
+                        categories.join(", ")
+                    ),
+                }
+            }))
+            .collect();
+        events.sort_by_key(|event| Reverse(event.at));
+        events.truncate(recent_events);
+
+        Self {
+            student_id: student_id.to_string(),
+            classroom: String::new(),
+            progress,
+            sessions: sessions
+                .iter()
+                .take(recent_sessions)
+                .map(|session| SessionRow {
+                    session_id: session.session_id.clone(),
+                    started: timestamp(session.created_at),
+                    last_activity: timestamp(session.updated_at),
+                    messages: session
+                        .messages
+                        .iter()
+                        .filter(|stored| {
+                            matches!(message_text(&stored.message).0, "user" | "assistant")
+                        })
+                        .count(),
+                    tokens: session.usage.total_tokens,
+                })
+                .collect(),
+            events,
+            integrity_flags,
+            moderation_events,
+        }
+    }
+
+    pub fn minutes(&self) -> i64 {
+        self.progress
+            .as_ref()
+            .map_or(0, |progress| progress.time_spent_seconds / 60)
+    }
+
+    pub fn top_topics(&self) -> String {
+        self.progress
+            .as_ref()
+            .map(|progress| {
+                let topics: Vec<&str> = progress
+                    .topics
+                    .iter()
+                    .take(TOP_TOPICS)
+                    .map(|topic| topic.topic.as_str())
+                    .collect();
+                topics.join(", ")
+            })
+            .unwrap_or_default()
+    }
+
+    /// Share of quiz questions answered correctly, as a percentage.
+    pub fn quiz_accuracy(&self) -> String {
+        self.progress
+            .as_ref()
+            .and_then(|progress| progress.quiz_accuracy)
+            .map(|accuracy| format!("{:.0}%", accuracy * 100.0))
+            .unwrap_or_default()
+    }
+
+    pub fn last_active(&self) -> String {
+        self.progress
+            .as_ref()
+            .and_then(|progress| progress.last_active_at)
+            .map(timestamp)
+            .unwrap_or_default()
+    }
+}
+
+fn direction_label(direction: Direction) -> &'static str {
+    match direction {
+        Direction::Input => "student message",
+        Direction::Output => "tutor reply",
+    }
+}
+
+fn action_label(action: Action) -> &'static str {
+    match action {
+        Action::Allow => "allowed",
+        Action::Annotate => "annotated",
+        Action::Redact => "redacted",
+        Action::Block => "blocked",
+    }
+}
+
+fn timestamp(at: OffsetDateTime) -> String {
+    at.format(&Rfc3339).unwrap_or_else(|_| at.to_string())
+}
+
+#[derive(Template)]
+#[template(path = "teacher.html")]
+pub struct DashboardTemplate<'a> {
+    pub teacher_name: &'a str,
+    /// Empty for teachers of the default organization.
+    pub organization: &'a str,
+    pub students: &'a [StudentOverview],
+}
+
+/// One row per student: the roster with its progress and flag counts.
+pub fn roster_csv(students: &[StudentOverview]) -> String {
+    let mut csv = String::from(
+        "student_id,classroom,sessions,tutor_replies,minutes_studied,top_topics,quizzes,quiz_accuracy,\
+         integrity_flags,moderation_events,last_active_at\r\n",
+    );
+    for student in students {
+        let (sessions, turns, quizzes) = student
+            .progress
+            .as_ref()
+            .map_or((0, 0, 0), |p| (p.sessions, p.turns, p.quizzes.len()));
+        let row = [
+            student.student_id.clone(),
+            student.classroom.clone(),
+            sessions.to_string(),
+            turns.to_string(),
+            student.minutes().to_string(),
+            student.top_topics(),
+            quizzes.to_string(),
+            student.quiz_accuracy(),
+            student.integrity_flags.to_string(),
+            student.moderation_events.to_string(),
+            student.last_active(),
+        ];
+        let fields: Vec<String> = row.iter().map(|field| csv_field(field)).collect();
+        csv.push_str(&fields.join(","));
+        csv.push_str("\r\n");
+    }
+    csv
+}
+
+/// Quote a field when it needs it, and defuse text a spreadsheet would
+/// read as a formula.
+fn csv_field(text: &str) -> String {
+    let text = if text.starts_with(['=', '+', '-', '@']) {
+        format!("'{}", text)
+    } else {
+        text.to_string()
+    };
+    if text.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn teachers() -> Vec<TeacherConfig> {
+        vec![TeacherConfig {
+            id: "rivera".to_string(),
+            name: "Ms Rivera".to_string(),
+            token: "s3cret".to_string(),
+            organization: None,
+            students: vec!["s1".to_string()],
+        }]
+    }
+
+    #[test]
+    fn signs_in_with_basic_credentials() {
+        let teachers = teachers();
+        let header = |credentials: &str| format!("Basic {}", STANDARD.encode(credentials));
+        assert_eq!(
+            sign_in(&teachers, &header("rivera:s3cret")).map(|t| t.id.as_str()),
+            Some("rivera")
+        );
+        assert!(sign_in(&teachers, &header("rivera:s3cre")).is_none());
+        assert!(sign_in(&teachers, &header("someone:s3cret")).is_none());
+        assert!(sign_in(&teachers, "Bearer s3cret").is_none());
+    }
+
+    #[test]
+    fn quotes_and_defuses_csv_fields() {
+        assert_eq!(csv_field("algebra"), "algebra");
+        assert_eq!(csv_field("algebra, geometry"), "\"algebra, geometry\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("=HYPERLINK(\"x\")"), "\"'=HYPERLINK(\"\"x\"\")\"");
+    }
+}
@@ -0,0 +1,25 @@
+/// Failures of the service layer that clients can act on. The controller
+/// maps each to its HTTP status; anything else, upstream errors included, is
+/// reported as an internal error whatever its message says.
+#[derive(Debug, thiserror::Error)]
+pub enum ServiceError {
+    /// A session, message, turn or stored item that does not exist.
+    #[error("{0}")]
+    NotFound(String),
+
+    /// A session removed for inactivity or over a limit.
+    #[error("{0}")]
+    Expired(String),
+
+    /// The session already has a turn waiting for the model.
+    #[error("{0}")]
+    Busy(String),
+
+    /// The organization has used its token quota.
+    #[error("{0}")]
+    OverQuota(String),
+
+    /// A request the service refuses, such as a blocked message.
+    #[error("{0}")]
+    Rejected(String),
+}
@@ -0,0 +1,50 @@
+use anyhow::Result;
+use serde::{Serialize, de::DeserializeOwned};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::marker::PhantomData;
+use std::path::PathBuf;
+
+/// Append-only JSON Lines file of review events.
+pub struct EventLog<T> {
+    path: PathBuf,
+    _event: PhantomData<fn() -> T>,
+}
+
+impl<T: Serialize + DeserializeOwned> EventLog<T> {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            _event: PhantomData,
+        }
+    }
+
+    pub fn append(&self, event: &T) -> Result<()> {
+        let mut line = serde_json::to_vec(event)?;
+        line.push(b'\n');
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?
+            .write_all(&line)?;
+        Ok(())
+    }
+
+    /// The newest `limit` events accepted by `keep`, oldest first. Lines
+    /// that no longer parse are skipped.
+    pub fn read(&self, keep: impl Fn(&T) -> bool, limit: usize) -> Result<Vec<T>> {
+        let text = match fs::read_to_string(&self.path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+        let mut events: Vec<T> = text
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .filter(|event| keep(event))
+            .collect();
+        let skip = events.len().saturating_sub(limit);
+        events.drain(..skip);
+        Ok(events)
+    }
+}
@@ -0,0 +1,356 @@
+use anyhow::Result;
+use askama::Template;
+use serde::{Deserialize, Serialize};
+use std::io::{Cursor, Write};
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
+use utoipa::ToSchema;
+use zip::{ZipWriter, write::SimpleFileOptions};
+
+use crate::markdown;
+use crate::math;
+use crate::retrieval::Scope;
+use crate::session::{MessageNode, NodeId, SessionData, StoredMessage, TokenUsage, message_text};
+
+/// Bumped whenever the JSON export layout changes. Version 2 added `nodes`
+/// and `head`, version 3 `scopes`, version 4 `citations` on messages.
+pub const EXPORT_VERSION: u32 = 4;
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Markdown,
+    #[default]
+    Json,
+    Html,
+}
+
+impl ExportFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Markdown => "md",
+            Self::Json => "json",
+            Self::Html => "html",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Markdown => "text/markdown; charset=utf-8",
+            Self::Json => "application/json",
+            Self::Html => "text/html; charset=utf-8",
+        }
+    }
+}
+
+/// Lossless snapshot of one session. This is also the import format.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionExport {
+    pub format_version: u32,
+    pub student_id: String,
+    pub session_id: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    pub updated_at: OffsetDateTime,
+    pub system_prompt: String,
+    pub usage: TokenUsage,
+    /// The active branch: OpenAI chat messages with `created_at` and
+    /// optional `usage` added.
+    pub messages: Vec<StoredMessage>,
+    /// Every message including edited and regenerated alternatives.
+    #[serde(default)]
+    pub nodes: Vec<MessageNode>,
+    /// Last message of the active branch.
+    #[serde(default)]
+    pub head: Option<NodeId>,
+    /// Course and organization materials the session searches.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub scopes: Vec<Scope>,
+    /// Subject persona answering in the session.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub persona: Option<String>,
+}
+
+impl SessionExport {
+    pub fn new(student_id: &str, session_id: &str, session: &SessionData) -> Self {
+        Self {
+            format_version: EXPORT_VERSION,
+            student_id: student_id.to_string(),
+            session_id: session_id.to_string(),
+            created_at: session.created_at,
+            updated_at: session.updated_at,
+            system_prompt: session.system_prompt.clone(),
+            usage: session.usage,
+            messages: session.messages().into_iter().cloned().collect(),
+            nodes: session.nodes().to_vec(),
+            head: session.head(),
+            scopes: session.scopes.clone(),
+            persona: session.persona.clone(),
+        }
+    }
+
+    pub fn render(&self, format: ExportFormat) -> Result<String> {
+        match format {
+            ExportFormat::Markdown => Ok(self.to_markdown()),
+            ExportFormat::Json => Ok(serde_json::to_string_pretty(self)?),
+            ExportFormat::Html => Ok(self.to_html(MathSource::Inline, None)?),
+        }
+    }
+
+    /// The HTML transcript for serving as a page whose Content-Security-Policy
+    /// allows scripts and styles with this nonce. KaTeX is loaded from the
+    /// server's static files.
+    pub fn html_page(&self, nonce: &str) -> Result<String> {
+        Ok(self.to_html(MathSource::Linked("/static/"), Some(nonce))?)
+    }
+
+    pub fn file_name(&self, format: ExportFormat) -> String {
+        format!("session-{}.{}", self.session_id, format.extension())
+    }
+
+    fn to_markdown(&self) -> String {
+        let mut out = format!("# Tutoring session {}\n\n", self.session_id);
+        out.push_str(&format!("- Student: {}\n", self.student_id));
+        out.push_str(&format!("- Started: {}\n", timestamp(self.created_at)));
+        out.push_str(&format!(
+            "- Last activity: {}\n",
+            timestamp(self.updated_at)
+        ));
+        out.push_str(&format!("- Tokens used: {}\n", self.usage.total_tokens));
+
+        for entry in self.transcript() {
+            out.push_str(&format!(
+                "\n---\n\n### {} · {}\n\n{}\n",
+                entry.speaker,
+                entry.time,
+                math::normalize(&entry.text)
+            ));
+        }
+        out
+    }
+
+    fn to_html(&self, math: MathSource, nonce: Option<&str>) -> askama::Result<String> {
+        let entries = self.transcript();
+        let math_scripts = if has_math(&entries) {
+            math.scripts()
+        } else {
+            Vec::new()
+        };
+        TranscriptTemplate {
+            session_id: &self.session_id,
+            student_id: &self.student_id,
+            started: timestamp(self.created_at),
+            updated: timestamp(self.updated_at),
+            total_tokens: self.usage.total_tokens,
+            entries,
+            math_scripts,
+            nonce,
+        }
+        .render()
+    }
+
+    /// Visible turns only: system and tool plumbing is left to the JSON export.
+    fn transcript(&self) -> Vec<TranscriptEntry> {
+        self.messages
+            .iter()
+            .filter_map(|stored| {
+                let (role, text) = message_text(&stored.message);
+                let speaker = match role {
+                    "user" => "Student",
+                    "assistant" if !text.is_empty() => "Tutor",
+                    _ => return None,
+                };
+                Some(TranscriptEntry {
+                    role,
+                    speaker,
+                    time: timestamp(stored.created_at),
+                    html: markdown::to_html(&text),
+                    text,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Where an HTML transcript with math gets KaTeX from.
+#[derive(Debug, Clone, Copy)]
+enum MathSource {
+    /// Inlined, so that a single downloaded file renders math offline.
+    Inline,
+    /// Loaded from the paths of `math::SCRIPTS` under this prefix.
+    Linked(&'static str),
+}
+
+impl MathSource {
+    fn scripts(self) -> Vec<Script> {
+        math::SCRIPTS
+            .iter()
+            .map(|(path, code)| match self {
+                Self::Inline => Script::Inline(code),
+                Self::Linked(prefix) => Script::Src(format!("{prefix}{path}")),
+            })
+            .collect()
+    }
+}
+
+enum Script {
+    Inline(&'static str),
+    Src(String),
+}
+
+struct TranscriptEntry {
+    role: &'static str,
+    speaker: &'static str,
+    time: String,
+    text: String,
+    /// `text` rendered from Markdown and sanitized.
+    html: String,
+}
+
+#[derive(Template)]
+#[template(path = "transcript.html")]
+struct TranscriptTemplate<'a> {
+    session_id: &'a str,
+    student_id: &'a str,
+    started: String,
+    updated: String,
+    total_tokens: u32,
+    entries: Vec<TranscriptEntry>,
+    /// Empty unless some message has math.
+    math_scripts: Vec<Script>,
+    /// Set on scripts and styles when the page is served.
+    nonce: Option<&'a str>,
+}
+
+fn has_math(entries: &[TranscriptEntry]) -> bool {
+    entries.iter().any(|entry| math::has_math(&entry.text))
+}
+
+fn timestamp(at: OffsetDateTime) -> String {
+    at.format(&Rfc3339).unwrap_or_else(|_| at.to_string())
+}
+
+/// Zip archive holding one file per session in the given format. HTML
+/// transcripts with math share one copy of KaTeX, stored next to them.
+pub fn zip_sessions(sessions: &[SessionExport], format: ExportFormat) -> Result<Vec<u8>> {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let options = SimpleFileOptions::default();
+    let mut any_math = false;
+    for session in sessions {
+        let file = match format {
+            ExportFormat::Html => {
+                any_math |= has_math(&session.transcript());
+                session.to_html(MathSource::Linked(""), None)?
+            }
+            _ => session.render(format)?,
+        };
+        zip.start_file(session.file_name(format), options)?;
+        zip.write_all(file.as_bytes())?;
+    }
+    if any_math {
+        for (path, contents) in math::SCRIPTS.into_iter().chain([math::KATEX_LICENSE]) {
+            zip.start_file(path, options)?;
+            zip.write_all(contents.as_bytes())?;
+        }
+    }
+    Ok(zip.finish()?.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::session::SessionManager;
+
+    /// A session whose second reply was regenerated, so it has two branches.
+    fn branched_session() -> SessionExport {
+        let mut sessions = SessionManager::new(Config::for_tests().sessions);
+        sessions.create_session("ana", "s1", "Be brief.", Vec::new(), None);
+        sessions
+            .add_message("ana", "s1", "user", "What is $x^2$ at 3?")
+            .unwrap();
+        let first = sessions.add_message("ana", "s1", "assistant", "9").unwrap();
+        let usage = TokenUsage {
+            prompt_tokens: 10,
+            completion_tokens: 2,
+            total_tokens: 12,
+        };
+        sessions.record_usage("ana", "s1", usage).unwrap();
+        let session = sessions.get_session_mut("ana", "s1").unwrap();
+        session.rewind_before(first, "assistant").unwrap();
+        sessions
+            .add_message("ana", "s1", "assistant", "Nine <b>exactly</b>")
+            .unwrap();
+        SessionExport::new("ana", "s1", sessions.get_session("ana", "s1").unwrap())
+    }
+
+    #[test]
+    fn json_export_round_trips_every_branch() {
+        let export = branched_session();
+        assert_eq!(export.nodes.len(), 3);
+        assert_eq!(export.messages.len(), 2);
+
+        let json = export.render(ExportFormat::Json).unwrap();
+        let restored =
+            SessionData::try_from(serde_json::from_str::<SessionExport>(&json).unwrap()).unwrap();
+        assert_eq!(restored.branches().len(), 2);
+        assert_eq!(restored.usage.total_tokens, 12);
+        let again = SessionExport::new("ana", "s1", &restored);
+        assert_eq!(again.render(ExportFormat::Json).unwrap(), json);
+    }
+
+    #[test]
+    fn transcripts_show_the_active_branch() {
+        let export = branched_session();
+
+        let markdown = export.render(ExportFormat::Markdown).unwrap();
+        assert!(markdown.contains("### Student"), "{markdown}");
+        assert!(markdown.contains("Nine <b>exactly</b>"), "{markdown}");
+        assert!(!markdown.contains("\n9\n"), "{markdown}");
+        assert!(!markdown.contains("Be brief."), "{markdown}");
+
+        let html = export.render(ExportFormat::Html).unwrap();
+        assert!(html.contains("Nine exactly"), "{html}");
+        assert!(!html.contains("<b>"), "{html}");
+        assert!(
+            html.contains("<span class=\"math math-inline\">x^2</span>"),
+            "{html}"
+        );
+        assert!(html.contains("katex"), "a single file inlines KaTeX");
+        assert!(!html.contains("<script src="), "{html}");
+    }
+
+    #[test]
+    fn archives_store_katex_once() {
+        let mut second = branched_session();
+        second.session_id = "s2".to_string();
+        let sessions = [branched_session(), second];
+        let archive = zip_sessions(&sessions, ExportFormat::Html).unwrap();
+        let mut zip = zip::ZipArchive::new(Cursor::new(archive)).unwrap();
+        let names: Vec<String> = zip.file_names().map(str::to_string).collect();
+        assert_eq!(
+            names
+                .iter()
+                .filter(|name| name.ends_with("katex.min.js"))
+                .count(),
+            1
+        );
+        assert!(
+            names.iter().any(|name| name == "katex/LICENSE"),
+            "{names:?}"
+        );
+
+        let mut html = String::new();
+        std::io::Read::read_to_string(&mut zip.by_name("session-s1.html").unwrap(), &mut html)
+            .unwrap();
+        assert!(
+            html.contains("<script src=\"katex/katex.min.js\">"),
+            "{html}"
+        );
+        assert!(html.len() < 20_000, "KaTeX inlined: {} bytes", html.len());
+
+        let page = sessions[0].html_page("n0nce").unwrap();
+        assert!(page.contains("<script src=\"/static/katex/katex.min.js\" nonce=\"n0nce\">"));
+        assert!(page.len() < 20_000, "KaTeX inlined: {} bytes", page.len());
+    }
+}
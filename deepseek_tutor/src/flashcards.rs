@@ -0,0 +1,431 @@
+use anyhow::{Context, Result, anyhow, bail};
+use async_openai::{
+    Client,
+    config::OpenAIConfig,
+    types::{
+        ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestUserMessageArgs,
+        CreateChatCompletionRequest, CreateChatCompletionRequestArgs, ResponseFormat,
+    },
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use time::{Duration, OffsetDateTime};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::config::FlashcardsConfig;
+use crate::error::ServiceError;
+use crate::pii::Redactor;
+use crate::session::{NodeId, TokenUsage};
+use crate::store::{self, Owned, atomic_write_json};
+use crate::structured;
+use crate::tenancy::{Owner, default_organization};
+
+/// Longest front or back of a card, in characters.
+pub const MAX_CARD_CHARS: usize = 1000;
+
+const EXTRACTION_PROMPT: &str = "You turn a tutor's explanation into flashcards for spaced \
+repetition. Each card tests one fact, definition or step the explanation teaches. The front is a \
+question or cue that makes sense on its own, without the explanation; the back is a short, \
+complete answer. Skip greetings, encouragement and anything the explanation does not teach. \
+Reply with a single JSON object and nothing else, in this form:\n\
+{\"cards\": [{\"front\": \"...\", \"back\": \"...\"}]}";
+
+/// SM-2 starting ease, and the floor it never drops below.
+const INITIAL_EASE: f32 = 2.5;
+const MIN_EASE: f32 = 1.3;
+
+/// A proposed card, for the student to accept, edit or drop.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct CardDraft {
+    pub front: String,
+    pub back: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ExtractedCards {
+    cards: Vec<CardDraft>,
+}
+
+/// Drafts proposed from one tutor reply.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CardCandidates {
+    pub session_id: String,
+    /// The tutor message the drafts came from; pass it back when saving a
+    /// card to keep the link.
+    pub message_id: NodeId,
+    pub cards: Vec<CardDraft>,
+}
+
+/// Where a card came from.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct CardSource {
+    pub session_id: String,
+    pub message_id: NodeId,
+}
+
+/// When a card is next due and what SM-2 needs to push it further out.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Schedule {
+    /// Reviews in a row graded 3 or better.
+    pub repetitions: u32,
+    /// Days between the last review and `due_at`.
+    pub interval_days: u32,
+    /// Growth factor for the interval; 2.5 for a new card.
+    pub ease: f32,
+    #[serde(with = "time::serde::rfc3339")]
+    #[schema(value_type = String, format = DateTime)]
+    pub due_at: OffsetDateTime,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    #[schema(value_type = Option<String>, format = DateTime)]
+    pub last_reviewed_at: Option<OffsetDateTime>,
+}
+
+/// A saved flashcard. Kept as `<card_id>.json` in the flashcard directory.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Card {
+    pub card_id: String,
+    #[serde(default = "default_organization")]
+    pub organization: String,
+    pub student_id: String,
+    pub front: String,
+    pub back: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<CardSource>,
+    #[serde(with = "time::serde::rfc3339")]
+    #[schema(value_type = String, format = DateTime)]
+    pub created_at: OffsetDateTime,
+    pub schedule: Schedule,
+    /// Every review so far.
+    pub reviews: u32,
+}
+
+impl Owned for Card {
+    fn organization(&self) -> &str {
+        &self.organization
+    }
+
+    fn student_id(&self) -> &str {
+        &self.student_id
+    }
+}
+
+impl Schedule {
+    /// A new card is due straight away.
+    fn new(now: OffsetDateTime) -> Self {
+        Self {
+            repetitions: 0,
+            interval_days: 0,
+            ease: INITIAL_EASE,
+            due_at: now,
+            last_reviewed_at: None,
+        }
+    }
+
+    /// Apply a review graded 0 (no recall) to 5 (perfect recall), as in
+    /// SM-2: a lapse below 3 starts the card over at one day, otherwise the
+    /// interval goes 1, 6, then grows by the ease. The ease moves with
+    /// every grade.
+    fn review(&mut self, grade: u8, now: OffsetDateTime) {
+        let grade = grade.min(5);
+        if grade < 3 {
+            self.repetitions = 0;
+            self.interval_days = 1;
+        } else {
+            self.interval_days = match self.repetitions {
+                0 => 1,
+                1 => 6,
+                _ => (self.interval_days as f32 * self.ease).round() as u32,
+            };
+            self.repetitions += 1;
+        }
+        let miss = f32::from(5 - grade);
+        self.ease = (self.ease + 0.1 - miss * (0.08 + miss * 0.02)).max(MIN_EASE);
+        self.due_at = now + Duration::days(i64::from(self.interval_days));
+        self.last_reviewed_at = Some(now);
+    }
+}
+
+/// An extraction request prepared under the controller lock and sent
+/// without it.
+pub struct PendingCards {
+    pub client: Client<OpenAIConfig>,
+    pub request: CreateChatCompletionRequest,
+    /// Placeholders used in `request`, to be swapped back in the drafts.
+    pub redactor: Option<Redactor>,
+    pub max_cards: usize,
+    pub max_attempts: usize,
+    pub student_id: String,
+    pub session_id: String,
+    pub message_id: NodeId,
+}
+
+impl PendingCards {
+    pub fn request(
+        model: &str,
+        max_tokens: u32,
+        reply: &str,
+        max_cards: usize,
+    ) -> Result<CreateChatCompletionRequest> {
+        let ask = format!("Write at most {max_cards} flashcards from this explanation:\n\n{reply}");
+        Ok(CreateChatCompletionRequestArgs::default()
+            .model(model)
+            .messages([
+                ChatCompletionRequestSystemMessageArgs::default()
+                    .content(EXTRACTION_PROMPT)
+                    .build()?
+                    .into(),
+                ChatCompletionRequestUserMessageArgs::default()
+                    .content(ask)
+                    .build()?
+                    .into(),
+            ])
+            .response_format(ResponseFormat::JsonObject)
+            .temperature(0.2)
+            .max_tokens(max_tokens)
+            .build()?)
+    }
+
+    /// Ask for the drafts, adding the tokens spent to `usage`.
+    pub async fn run(self, usage: &mut TokenUsage) -> Result<CardCandidates> {
+        let max_cards = self.max_cards;
+        let mut cards = structured::complete(
+            &self.client,
+            self.request,
+            self.max_attempts,
+            usage,
+            |reply| parse(reply, max_cards),
+        )
+        .await?;
+        if let Some(redactor) = &self.redactor {
+            for card in &mut cards {
+                card.front = redactor.restore(&card.front);
+                card.back = redactor.restore(&card.back);
+            }
+        }
+        Ok(CardCandidates {
+            session_id: self.session_id,
+            message_id: self.message_id,
+            cards,
+        })
+    }
+}
+
+/// The drafts in a model reply, checked against the card format.
+fn parse(reply: &str, max_cards: usize) -> Result<Vec<CardDraft>> {
+    let extracted: ExtractedCards =
+        serde_json::from_str(reply).context("reply is not flashcard JSON")?;
+    if extracted.cards.is_empty() || extracted.cards.len() > max_cards {
+        bail!(
+            "asked for 1 to {} cards but got {}",
+            max_cards,
+            extracted.cards.len()
+        );
+    }
+    for (number, card) in extracted.cards.iter().enumerate() {
+        check_side(&card.front)
+            .and_then(|_| check_side(&card.back))
+            .map_err(|problem| anyhow!("card {}: {}", number + 1, problem))?;
+        if extracted.cards[..number]
+            .iter()
+            .any(|other| other.front.trim() == card.front.trim())
+        {
+            bail!("card {} repeats the front of an earlier card", number + 1);
+        }
+    }
+    Ok(extracted.cards)
+}
+
+/// Why a card side cannot be saved, if it cannot.
+pub fn check_side(text: &str) -> Result<(), String> {
+    if text.trim().is_empty() {
+        return Err("front and back must not be empty".to_string());
+    }
+    if text.chars().count() > MAX_CARD_CHARS {
+        return Err(format!(
+            "front and back must be at most {} characters",
+            MAX_CARD_CHARS
+        ));
+    }
+    Ok(())
+}
+
+/// Every flashcard, kept in memory and written through to one file each.
+pub struct CardStore {
+    dir: PathBuf,
+    cards: HashMap<String, Card>,
+}
+
+impl CardStore {
+    pub fn open(config: &FlashcardsConfig) -> Result<Self> {
+        fs::create_dir_all(&config.dir)
+            .with_context(|| format!("failed to create {}", config.dir.display()))?;
+        let mut cards = HashMap::new();
+        for entry in fs::read_dir(&config.dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            match read_card(&path) {
+                Ok(card) => {
+                    cards.insert(card.card_id.clone(), card);
+                }
+                Err(err) => eprintln!("Skipping flashcard {}: {:#}", path.display(), err),
+            }
+        }
+        Ok(Self {
+            dir: config.dir.clone(),
+            cards,
+        })
+    }
+
+    pub fn add(
+        &mut self,
+        owner: &Owner,
+        draft: CardDraft,
+        source: Option<CardSource>,
+    ) -> Result<Card> {
+        let now = OffsetDateTime::now_utc();
+        let card = Card {
+            card_id: Uuid::new_v4().to_string(),
+            organization: owner.organization.clone(),
+            student_id: owner.student_id.clone(),
+            front: draft.front.trim().to_string(),
+            back: draft.back.trim().to_string(),
+            source,
+            created_at: now,
+            schedule: Schedule::new(now),
+            reviews: 0,
+        };
+        self.write(&card)?;
+        self.cards.insert(card.card_id.clone(), card.clone());
+        Ok(card)
+    }
+
+    /// Change the text of a card; its schedule is kept.
+    pub fn edit(
+        &mut self,
+        owner: &Owner,
+        card_id: &str,
+        front: Option<String>,
+        back: Option<String>,
+    ) -> Result<Card> {
+        self.update(owner, card_id, |card| {
+            if let Some(front) = front {
+                card.front = front.trim().to_string();
+            }
+            if let Some(back) = back {
+                card.back = back.trim().to_string();
+            }
+        })
+    }
+
+    pub fn review(&mut self, owner: &Owner, card_id: &str, grade: u8) -> Result<Card> {
+        self.update(owner, card_id, |card| {
+            card.schedule.review(grade, OffsetDateTime::now_utc());
+            card.reviews += 1;
+        })
+    }
+
+    pub fn remove(&mut self, owner: &Owner, card_id: &str) -> Result<()> {
+        self.get(owner, card_id)?;
+        self.cards.remove(card_id);
+        fs::remove_file(self.path(card_id))?;
+        Ok(())
+    }
+
+    /// A student's cards, soonest due first; only those due by `due_by`
+    /// when given.
+    pub fn list(&self, owner: &Owner, due_by: Option<OffsetDateTime>, limit: usize) -> Vec<Card> {
+        let mut cards: Vec<&Card> = store::owned_by(&self.cards, owner)
+            .filter(|card| due_by.is_none_or(|at| card.schedule.due_at <= at))
+            .collect();
+        cards.sort_by_key(|card| (card.schedule.due_at, card.created_at));
+        cards.into_iter().take(limit).cloned().collect()
+    }
+
+    /// A student's own card; other students' cards are not found.
+    fn get(&self, owner: &Owner, card_id: &str) -> Result<&Card> {
+        store::get_owned(&self.cards, owner, card_id)
+            .ok_or_else(|| ServiceError::NotFound(format!("Card {} not found", card_id)).into())
+    }
+
+    fn update(
+        &mut self,
+        owner: &Owner,
+        card_id: &str,
+        change: impl FnOnce(&mut Card),
+    ) -> Result<Card> {
+        let mut card = self.get(owner, card_id)?.clone();
+        change(&mut card);
+        self.write(&card)?;
+        self.cards.insert(card.card_id.clone(), card.clone());
+        Ok(card)
+    }
+
+    fn path(&self, card_id: &str) -> PathBuf {
+        self.dir.join(format!("{card_id}.json"))
+    }
+
+    fn write(&self, card: &Card) -> Result<()> {
+        let path = self.path(&card.card_id);
+        atomic_write_json(&path, card)
+    }
+}
+
+fn read_card(path: &Path) -> Result<Card> {
+    Ok(serde_json::from_slice(&fs::read(path)?)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TempDir};
+
+    #[test]
+    fn schedules_reviews_like_sm2() {
+        let start = OffsetDateTime::UNIX_EPOCH;
+        let mut schedule = Schedule::new(start);
+        let mut intervals = Vec::new();
+        for grade in [5, 4, 4, 3] {
+            schedule.review(grade, start);
+            intervals.push(schedule.interval_days);
+        }
+        assert_eq!(intervals, [1, 6, 16, 42]);
+        assert!((schedule.ease - 2.46).abs() < 1e-4, "{}", schedule.ease);
+        assert_eq!(schedule.due_at, start + Duration::days(42));
+
+        schedule.review(1, start);
+        assert_eq!((schedule.repetitions, schedule.interval_days), (0, 1));
+        assert!((schedule.ease - 1.92).abs() < 1e-4, "{}", schedule.ease);
+        for _ in 0..10 {
+            schedule.review(0, start);
+        }
+        assert_eq!(schedule.ease, MIN_EASE);
+    }
+
+    #[test]
+    fn cards_stay_in_their_organization() {
+        let dir = TempDir::new("cards");
+        let mut config = crate::config::Config::for_tests().flashcards;
+        config.dir = dir.path().to_path_buf();
+        let mut store = CardStore::open(&config).unwrap();
+        let owner = |organization| testing::owner(organization, "ana");
+        let draft = CardDraft {
+            front: "Mitosis makes".to_string(),
+            back: "Two identical cells".to_string(),
+        };
+        let card = store.add(&owner("northside"), draft, None).unwrap();
+
+        let other = owner("default");
+        assert_eq!(store.list(&owner("northside"), None, 10).len(), 1);
+        assert!(store.list(&other, None, 10).is_empty());
+        assert!(store.review(&other, &card.card_id, 5).is_err());
+        assert!(store.edit(&other, &card.card_id, None, None).is_err());
+        assert!(store.remove(&other, &card.card_id).is_err());
+        let store = CardStore::open(&config).unwrap();
+        assert!(store.list(&other, None, 10).is_empty());
+    }
+}
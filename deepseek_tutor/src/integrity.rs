@@ -0,0 +1,317 @@
+use anyhow::Result;
+use async_openai::types::{
+    ChatCompletionRequestMessage, ChatCompletionRequestSystemMessageArgs,
+    ChatCompletionRequestUserMessageArgs, CreateChatCompletionRequest,
+    CreateChatCompletionRequestArgs,
+};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use utoipa::ToSchema;
+
+use crate::tenancy::default_organization;
+
+/// Added after the student's question when a turn runs in hint-only mode.
+/// It is sent upstream but never stored in the session.
+const HINT_ONLY_INSTRUCTION: &str = "\
+The student's last message looks like a question from a graded exam, quiz or \
+homework assignment. Do not give the final answer, a complete solution, or \
+text the student could hand in. Instead, identify the concept being tested, \
+give one hint or a guiding question for the next step, and invite the student \
+to attempt it and share their reasoning.";
+
+const CLASSIFIER_PROMPT: &str = "\
+You screen messages sent to a tutoring assistant. Answer YES if the message \
+asks for the answer to, or a complete solution of, a graded exam, quiz, test \
+or homework question, and NO otherwise. Reply with YES or NO only.";
+
+/// Why a turn was switched to hint-only mode.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum FlagSource {
+    Rules,
+    Model,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct IntegrityFlag {
+    pub source: FlagSource,
+    /// Names of the rules that matched; empty for the model classifier.
+    pub reasons: Vec<String>,
+}
+
+struct Rule {
+    name: &'static str,
+    pattern: Regex,
+}
+
+impl Rule {
+    fn new(name: &'static str, pattern: &str) -> Self {
+        Self {
+            name,
+            pattern: Regex::new(&format!("(?i){pattern}")).expect("integrity rule compiles"),
+        }
+    }
+}
+
+/// Rule-based screen for requests to answer graded work. A message is
+/// flagged when it matches a decisive rule, or when it both mentions graded
+/// work and asks for an answer rather than help.
+pub struct IntegrityChecker {
+    decisive: Vec<Rule>,
+    context: Vec<Rule>,
+    demand: Vec<Rule>,
+}
+
+impl IntegrityChecker {
+    pub fn new() -> Self {
+        Self {
+            decisive: vec![
+                Rule::new("answer key", r"\banswer key\b"),
+                Rule::new(
+                    "exam in progress",
+                    r"\b(during|in the middle of) (an?|the|my) (exam|test|quiz)\b",
+                ),
+                Rule::new("take-home exam", r"\btake[- ]home (exam|test|quiz)\b"),
+                Rule::new(
+                    "write my work",
+                    r"\b(write|do|finish) my (essay|homework|assignment|project|lab report)\b",
+                ),
+            ],
+            context: vec![
+                Rule::new("exam", r"\b(exams?|midterms?|finals|tests?|quiz(zes)?)\b"),
+                Rule::new(
+                    "homework",
+                    r"\b(homework|assignment|problem set|worksheet|coursework)\b",
+                ),
+                Rule::new(
+                    "graded",
+                    r"\b(graded|for (a )?grade|for marks|worth \d+ ?(points|marks|%))\b",
+                ),
+                Rule::new("deadline", r"\bdue (today|tonight|tomorrow|in \d+)\b"),
+                Rule::new(
+                    "numbered question",
+                    r"\b(question|q|problem|exercise) ?#?\d+[a-z]?\b",
+                ),
+            ],
+            demand: vec![
+                Rule::new(
+                    "answer only",
+                    r"\b(just|only) (give|tell|send|show) me the (final )?answers?\b",
+                ),
+                Rule::new("answer only", r"\b(only|just) the (final )?answers?\b"),
+                Rule::new(
+                    "no explanation",
+                    r"\b(no|without( any)?|skip the) (explanation|working|steps)\b",
+                ),
+                Rule::new(
+                    "solve for me",
+                    r"\b(solve|answer|complete|do) (this|these|it|them)( \w+)? for me\b",
+                ),
+                Rule::new(
+                    "which option",
+                    r"\bwhich (option|choice|one) is (correct|right)\b",
+                ),
+                Rule::new(
+                    "what is the answer",
+                    r"\bwhat('s| is| are) the (correct |right )?answers?\b",
+                ),
+            ],
+        }
+    }
+
+    pub fn check(&self, message: &str) -> Option<IntegrityFlag> {
+        let matches = |rules: &[Rule]| -> Vec<String> {
+            let mut names: Vec<String> = rules
+                .iter()
+                .filter(|rule| rule.pattern.is_match(message))
+                .map(|rule| rule.name.to_string())
+                .collect();
+            names.dedup();
+            names
+        };
+
+        let decisive = matches(&self.decisive);
+        if !decisive.is_empty() {
+            return Some(IntegrityFlag {
+                source: FlagSource::Rules,
+                reasons: decisive,
+            });
+        }
+        let context = matches(&self.context);
+        let demand = matches(&self.demand);
+        if context.is_empty() || demand.is_empty() {
+            return None;
+        }
+        Some(IntegrityFlag {
+            source: FlagSource::Rules,
+            reasons: context.into_iter().chain(demand).collect(),
+        })
+    }
+}
+
+/// Append the hint-only instruction to a conversation about to be sent.
+pub fn restrict_to_hints(messages: &mut Vec<ChatCompletionRequestMessage>) {
+    messages.push(
+        ChatCompletionRequestSystemMessageArgs::default()
+            .content(HINT_ONLY_INSTRUCTION)
+            .build()
+            .expect("text system message always builds")
+            .into(),
+    );
+}
+
+/// A short completion asking the model whether `message` requests graded
+/// work. Sent through the same backend as the tutoring turn.
+pub fn classifier_request(model: &str, message: &str) -> Result<CreateChatCompletionRequest> {
+    Ok(CreateChatCompletionRequestArgs::default()
+        .model(model)
+        .messages([
+            ChatCompletionRequestSystemMessageArgs::default()
+                .content(CLASSIFIER_PROMPT)
+                .build()?
+                .into(),
+            ChatCompletionRequestUserMessageArgs::default()
+                .content(message)
+                .build()?
+                .into(),
+        ])
+        .temperature(0.0)
+        .max_tokens(3u32)
+        .build()?)
+}
+
+pub fn classifier_flagged(reply: &str) -> bool {
+    reply.trim_start().to_ascii_uppercase().starts_with("YES")
+}
+
+/// One flagged turn, kept for teacher review.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct FlaggedEvent {
+    #[serde(with = "time::serde::rfc3339")]
+    #[schema(value_type = String, format = DateTime)]
+    pub at: OffsetDateTime,
+    /// Organization the student belongs to.
+    #[serde(default = "default_organization")]
+    pub organization: String,
+    pub student_id: String,
+    pub session_id: String,
+    pub turn_id: String,
+    /// The student's message as sent.
+    pub query: String,
+    #[serde(flatten)]
+    pub flag: IntegrityFlag,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reasons(message: &str) -> Option<Vec<String>> {
+        IntegrityChecker::new()
+            .check(message)
+            .map(|flag| flag.reasons)
+    }
+
+    #[test]
+    fn decisive_rules_flag_on_their_own() {
+        assert_eq!(
+            reasons("Where can I find the ANSWER KEY for unit 4?").unwrap(),
+            ["answer key"]
+        );
+        assert_eq!(
+            reasons("I'm in the middle of the test, quick").unwrap(),
+            ["exam in progress"]
+        );
+        assert_eq!(
+            reasons("Help with my take-home exam").unwrap(),
+            ["take-home exam"]
+        );
+        assert_eq!(
+            reasons("Can you write my essay on Macbeth?").unwrap(),
+            ["write my work"]
+        );
+
+        assert_eq!(reasons("What is the key idea of this answer?"), None);
+        assert_eq!(reasons("How do I take notes at home?"), None);
+        assert_eq!(reasons("Can you check my essay on Macbeth?"), None);
+    }
+
+    #[test]
+    fn context_alone_is_not_flagged() {
+        assert_eq!(reasons("Can you help me revise for my exam?"), None);
+        assert_eq!(
+            reasons("My homework is due tomorrow, where do I start?"),
+            None
+        );
+        assert_eq!(reasons("I got question 3b wrong, why?"), None);
+        assert_eq!(reasons("Is this worth 10 points?"), None);
+    }
+
+    #[test]
+    fn demand_alone_is_not_flagged() {
+        assert_eq!(reasons("What is the answer to 2 + 2?"), None);
+        assert_eq!(reasons("Which option is correct, and why?"), None);
+        assert_eq!(reasons("Just give me the answer, I'm curious"), None);
+    }
+
+    #[test]
+    fn context_with_a_demand_is_flagged() {
+        let flag = IntegrityChecker::new()
+            .check("Question 5 of my homework: just give me the answer, no explanation")
+            .unwrap();
+        assert_eq!(flag.source, FlagSource::Rules);
+        assert_eq!(
+            flag.reasons,
+            [
+                "homework",
+                "numbered question",
+                "answer only",
+                "no explanation"
+            ]
+        );
+
+        assert_eq!(
+            reasons("Solve this for me, it's due tonight").unwrap(),
+            ["deadline", "solve for me"]
+        );
+        assert_eq!(
+            reasons("Quiz: which choice is right? A or B").unwrap(),
+            ["exam", "which option"]
+        );
+    }
+
+    #[test]
+    fn a_rule_matched_twice_is_reported_once() {
+        assert_eq!(
+            reasons("For my worksheet: only the answers, just tell me the answers").unwrap(),
+            ["homework", "answer only"]
+        );
+    }
+
+    #[test]
+    fn reads_the_classifier_verdict() {
+        assert!(classifier_flagged("YES"));
+        assert!(classifier_flagged("  yes."));
+        assert!(!classifier_flagged("NO"));
+        assert!(!classifier_flagged("I think yes"));
+        assert!(!classifier_flagged(""));
+    }
+
+    #[test]
+    fn hint_instruction_is_appended_last() {
+        let mut messages = vec![
+            ChatCompletionRequestUserMessageArgs::default()
+                .content("What is 6 x 7?")
+                .build()
+                .unwrap()
+                .into(),
+        ];
+        restrict_to_hints(&mut messages);
+        assert_eq!(messages.len(), 2);
+        assert!(matches!(
+            messages.last(),
+            Some(ChatCompletionRequestMessage::System(_))
+        ));
+    }
+}
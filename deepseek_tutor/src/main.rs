@@ -1,36 +1,129 @@
+mod citation;
+mod config;
 mod controller;
+mod csp;
+mod dashboard;
+mod error;
+mod event_log;
+mod export;
+mod flashcards;
+mod import;
+mod integrity;
+mod markdown;
+mod math;
 mod models;
+mod moderation;
+mod openapi;
+mod persona;
+mod pii;
+mod problem;
+mod profile;
+mod progress;
+mod quiz;
+mod request_id;
+mod retrieval;
 mod routes;
 mod service;
 mod session;
+mod store;
+mod structured;
+mod tenancy;
+#[cfg(test)]
+mod testing;
+mod tools;
+mod turn;
 
-use axum::{
-    routing::{get, post},
-    Router,
-    extract::Extension,
-};
+use anyhow::{Context, Result};
+use axum::{Router, extract::Extension, middleware, routing::get};
+use axum_server::tls_rustls::RustlsConfig;
+use config::Config;
+use controller::TutorController;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::Mutex;
-use controller::TutorController;
 use tower_http::services::ServeDir;
+use utoipa_swagger_ui::SwaggerUi;
 
 #[tokio::main]
 async fn main() {
+    // Load .env first so its variables take part in configuration.
+    dotenv::dotenv().ok();
+
+    if let Err(err) = run().await {
+        eprintln!("Error: {:#}", err);
+        std::process::exit(1);
+    }
+}
 
-    let controller = Arc::new(Mutex::new(TutorController::new()));
+async fn run() -> Result<()> {
+    let config = Config::load().context("invalid configuration")?;
 
-    // Define application routes and middleware
-    let app = Router::new()
-        .route("/", get(routes::root))
-        .route("/api/create_session", post(routes::create_session))
-        .route("/api/send_query", post(routes::send_query))
-        .nest_service("/static", ServeDir::new("static"))
-        .layer(Extension(controller));
+    let controller = Arc::new(Mutex::new(TutorController::new(&config)?));
+    spawn_session_sweeper(Arc::clone(&controller), config.sessions.sweep_interval);
+    let app = app(controller, &config);
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000")
-        .await
-        .expect("failed to bind");
-    println!("Listening on http://0.0.0.0:3000");
+    let addr = config.server.bind;
+    match &config.server.tls {
+        Some(tls) => {
+            let _ = rustls::crypto::ring::default_provider().install_default();
+            let tls_config = RustlsConfig::from_pem_file(&tls.cert, &tls.key)
+                .await
+                .context("failed to load TLS certificate or key")?;
+            println!("Listening on https://{}", addr);
+            axum_server::bind_rustls(addr, tls_config)
+                .serve(app.into_make_service())
+                .await
+                .context("server error")?;
+        }
+        None => {
+            let listener = tokio::net::TcpListener::bind(addr)
+                .await
+                .with_context(|| format!("failed to bind {}", addr))?;
+            println!("Listening on http://{}", addr);
+            axum::serve(listener, app).await.context("server error")?;
+        }
+    }
 
-    axum::serve(listener, app).await.unwrap();
+    Ok(())
+}
+
+/// Periodically remove sessions that have been idle for longer than the TTL.
+fn spawn_session_sweeper(controller: Arc<Mutex<TutorController>>, every: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(every);
+        loop {
+            ticker.tick().await;
+            let stats = controller.lock().await.sweep_sessions();
+            if stats.expired + stats.expired_on_disk > 0 {
+                println!(
+                    "Expired {} idle sessions ({} from disk)",
+                    stats.expired + stats.expired_on_disk,
+                    stats.expired_on_disk
+                );
+            }
+        }
+    });
+}
+
+/// Define application routes and middleware
+fn app(controller: Arc<Mutex<TutorController>>, config: &Config) -> Router {
+    let (api, openapi) = routes::api_routes().split_for_parts();
+
+    Router::new()
+        .route("/", get(routes::root))
+        .route("/review", get(routes::review))
+        .route("/teacher", get(routes::teacher_dashboard))
+        .route("/teacher/students.csv", get(routes::teacher_roster_csv))
+        .route(
+            "/teacher/students/{student_id}/sessions/{session_id}",
+            get(routes::teacher_transcript),
+        )
+        .merge(api)
+        .merge(SwaggerUi::new(openapi::DOCS_PATH).url(openapi::SPEC_PATH, openapi))
+        .nest_service("/static", ServeDir::new(&config.paths.static_dir))
+        .fallback(routes::not_found)
+        .method_not_allowed_fallback(routes::method_not_allowed)
+        .layer(Extension(controller))
+        .layer(middleware::from_fn(csp::apply))
+        .layer(middleware::from_fn(request_id::assign))
 }
@@ -0,0 +1,111 @@
+use ammonia::Builder;
+use pulldown_cmark::{Options, Parser, html};
+use std::collections::{HashMap, HashSet};
+use std::sync::LazyLock;
+
+use crate::math;
+
+/// Everything a rendered reply may contain. Raw HTML in the Markdown goes
+/// through the same list, so scripts, event handlers, styles, forms, frames
+/// and images are dropped, and links may only be http, https or mailto.
+static SANITIZER: LazyLock<Builder<'static>> = LazyLock::new(|| {
+    let mut builder = Builder::empty();
+    builder
+        .tags(HashSet::from([
+            "p",
+            "br",
+            "hr",
+            "h1",
+            "h2",
+            "h3",
+            "h4",
+            "h5",
+            "h6",
+            "strong",
+            "em",
+            "del",
+            "code",
+            "pre",
+            "blockquote",
+            "ul",
+            "ol",
+            "li",
+            "a",
+            "table",
+            "thead",
+            "tbody",
+            "tr",
+            "th",
+            "td",
+            "span",
+            "sup",
+        ]))
+        .clean_content_tags(HashSet::from(["script", "style"]))
+        .tag_attributes(HashMap::from([
+            ("a", HashSet::from(["href", "title"])),
+            ("ol", HashSet::from(["start"])),
+        ]))
+        .allowed_classes(HashMap::from([(
+            "span",
+            HashSet::from(["math", "math-inline", "math-display"]),
+        )]))
+        .url_schemes(HashSet::from(["http", "https", "mailto"]))
+        .link_rel(Some("noopener noreferrer nofollow"));
+    builder
+});
+
+/// Tutor Markdown as HTML that is safe to insert into a page. Math becomes
+/// `<span class="math math-inline">` or `math-display` holding the TeX,
+/// for the page to render.
+pub fn to_html(markdown: &str) -> String {
+    let options = Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH | Options::ENABLE_MATH;
+    let markdown = math::normalize(markdown);
+    let mut unsafe_html = String::with_capacity(markdown.len() * 3 / 2);
+    html::push_html(&mut unsafe_html, Parser::new_ext(&markdown, options));
+    SANITIZER.clean(&unsafe_html).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_markdown_and_math() {
+        assert_eq!(
+            to_html("**Area** is $a^2$, see [notes](https://example.com)."),
+            "<p><strong>Area</strong> is <span class=\"math math-inline\">a^2</span>, see \
+             <a href=\"https://example.com\" rel=\"noopener noreferrer nofollow\">notes</a>.</p>\n"
+        );
+        assert_eq!(
+            to_html(r"\[x_1 * x_2\]"),
+            "<p><span class=\"math math-display\">x_1 * x_2</span></p>\n"
+        );
+        assert_eq!(
+            to_html("It costs $5 and $10."),
+            "<p>It costs $5 and $10.</p>\n"
+        );
+    }
+
+    #[test]
+    fn strips_scripts_handlers_and_unsafe_links() {
+        let html = to_html(
+            "Hi<script>alert(1)</script> <img src=x onerror=alert(2)> \
+             <a href=\"javascript:alert(3)\" onclick=\"alert(4)\">x</a> \
+             <span class=\"evil math\" style=\"color:red\">y</span>\n\n\
+             [link](javascript:alert(5))",
+        );
+        for banned in [
+            "script",
+            "alert",
+            "img",
+            "onerror",
+            "onclick",
+            "javascript",
+            "style",
+            "evil",
+        ] {
+            assert!(!html.contains(banned), "{banned} left in {html}");
+        }
+        assert!(html.contains("<span class=\"math\">y</span>"), "{html}");
+    }
+}
@@ -0,0 +1,96 @@
+use regex::{Captures, Regex};
+use std::borrow::Cow;
+use std::sync::LazyLock;
+
+/// KaTeX and the page's math helpers by their path under `static/`, for
+/// HTML exports that have math: inlined into a single file so that it
+/// renders offline, or stored once next to the files of an archive.
+pub const SCRIPTS: [(&str, &str); 2] = [
+    (
+        "katex/katex.min.js",
+        include_str!("../static/katex/katex.min.js"),
+    ),
+    ("math.js", include_str!("../static/math.js")),
+];
+
+/// KaTeX's license, stored with it in archives.
+pub const KATEX_LICENSE: (&str, &str) = ("katex/LICENSE", include_str!("../static/katex/LICENSE"));
+
+/// Fenced code blocks and inline code spans, which hold no math.
+static CODE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?s)```.*?(?:```|\z)|`[^`\n]*`").unwrap());
+static BRACKETS: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?s)\\\[(.+?)\\\]|\\\((.+?)\\\)").unwrap());
+/// `$$...$$`, or `$...$` with no space just inside the dollars and no digit
+/// right after, so amounts such as "$5 and $10" are not math.
+static DOLLARS: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?s)\$\$.+?\$\$|\$(?:[^\s$]|[^\s$][^$\n]*?[^\s$\\])\$(?:[^\d]|\z)").unwrap()
+});
+
+/// Rewrite `\[...\]` and `\(...\)` as `$$...$$` and `$...$`, the
+/// delimiters most Markdown viewers render. Code is left as written.
+pub fn normalize(text: &str) -> Cow<'_, str> {
+    if !text.contains("\\[") && !text.contains("\\(") {
+        return Cow::Borrowed(text);
+    }
+    let mut out = String::with_capacity(text.len());
+    let mut prose_start = 0;
+    for code in CODE.find_iter(text) {
+        out.push_str(&normalize_prose(&text[prose_start..code.start()]));
+        out.push_str(code.as_str());
+        prose_start = code.end();
+    }
+    out.push_str(&normalize_prose(&text[prose_start..]));
+    Cow::Owned(out)
+}
+
+fn normalize_prose(prose: &str) -> Cow<'_, str> {
+    BRACKETS.replace_all(prose, |caps: &Captures| match (caps.get(1), caps.get(2)) {
+        (Some(display), _) => format!("$${}$$", display.as_str()),
+        (_, Some(inline)) => format!("${}$", inline.as_str().trim()),
+        _ => unreachable!("one alternative always matches"),
+    })
+}
+
+/// Whether the text has math outside code.
+pub fn has_math(text: &str) -> bool {
+    let text = normalize(text);
+    let prose = CODE.replace_all(&text, " ");
+    DOLLARS.is_match(&prose)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_the_bundled_katex_version() {
+        let recorded = include_str!("../static/katex/VERSION");
+        let version = recorded.lines().next().unwrap();
+        let bundled = format!("version:\"{version}\"");
+        assert!(
+            SCRIPTS[0].1.contains(&bundled),
+            "static/katex/VERSION says {version}"
+        );
+        assert!(recorded.contains(&format!("/v{version}/")));
+    }
+
+    #[test]
+    fn normalizes_brackets_outside_code() {
+        assert_eq!(
+            normalize(r"So \( a^2 + b^2 = c^2 \) and \[\frac{d}{dx} x^2 = 2x\]"),
+            r"So $a^2 + b^2 = c^2$ and $$\frac{d}{dx} x^2 = 2x$$"
+        );
+        let code = "Run `printf '\\(x\\)'` or\n```\necho \"\\[1\\]\"\n```\n";
+        assert_eq!(normalize(code), code);
+    }
+
+    #[test]
+    fn finds_math_but_not_prices() {
+        assert!(has_math("The derivative of $x^2$ is $2x$."));
+        assert!(has_math("$$\\int_0^1 x\\,dx = \\tfrac12$$"));
+        assert!(has_math(r"Here \(c = 5\)."));
+        assert!(!has_math("It costs $5 and $10 with tax."));
+        assert!(!has_math("Use `$x$` in the shell."));
+    }
+}
@@ -1,32 +1,345 @@
 use axum::{
     Json,
-    http::StatusCode,
+    extract::{
+        FromRequest, FromRequestParts, Path, Query, Request,
+        rejection::{JsonRejection, PathRejection, QueryRejection},
+    },
+    http::{HeaderValue, StatusCode, header, request::Parts},
     response::{IntoResponse, Response},
 };
 use serde::{Deserialize, Serialize};
-use serde_json::json;
+use utoipa::{IntoParams, ToSchema};
 
-#[derive(Deserialize)]
+use crate::citation::Citation;
+use crate::markdown;
+use crate::quiz::QuizAnswer;
+use crate::request_id;
+use crate::session::NodeId;
+use crate::tools::ToolUse;
+use crate::turn::TurnReply;
+
+#[derive(Deserialize, ToSchema)]
 pub struct CreateSessionRequest {
+    /// Identifier chosen by the client for the student.
     pub student_id: String,
+    /// Course whose uploaded materials ground the tutor's answers.
+    #[serde(default)]
+    pub course_id: Option<String>,
+    /// Organization whose uploaded materials ground the tutor's answers.
+    #[serde(default)]
+    pub organization_id: Option<String>,
+    /// Subject persona to answer as, from `GET /api/v1/personas`. When
+    /// left out, the first question picks one.
+    #[serde(default)]
+    pub persona: Option<String>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, ToSchema)]
 pub struct CreateSessionResponse {
     pub session_id: String,
     pub message: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 pub struct SendQueryRequest {
     pub student_id: String,
     pub session_id: String,
+    /// The student's question.
     pub query: String,
 }
 
-#[derive(Serialize)]
+/// Body of `POST /api/v1/sessions/{session_id}/queries`; the session id
+/// comes from the path.
+#[derive(Deserialize, ToSchema)]
+pub struct QueryRequest {
+    pub student_id: String,
+    /// The student's question.
+    pub query: String,
+    /// Id for this turn, chosen by the client so that it can cancel the
+    /// turn before the reply arrives. Generated when omitted.
+    #[serde(default)]
+    pub turn_id: Option<String>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct ImportSessionRequest {
+    pub student_id: String,
+    /// A JSON export from `GET /api/v1/sessions/{session_id}/export`, an
+    /// OpenAI `messages` array, or an object with a `messages` array.
+    /// System messages are dropped; the server's prompt is used instead.
+    #[schema(value_type = Object)]
+    pub transcript: serde_json::Value,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ImportSessionResponse {
+    pub session_id: String,
+    /// Number of user and assistant messages imported.
+    pub message_count: usize,
+}
+
+/// Body of session operations that only need to know whose session it is.
+#[derive(Deserialize, ToSchema)]
+pub struct StudentRequest {
+    pub student_id: String,
+}
+
+/// Body of `PUT /api/v1/sessions/{session_id}/persona`.
+#[derive(Deserialize, ToSchema)]
+pub struct SetPersonaRequest {
+    pub student_id: String,
+    /// Persona id from `GET /api/v1/personas`; `null` for the general
+    /// tutor, or to let the first question pick one.
+    pub persona: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct SessionPersonaResponse {
+    pub session_id: String,
+    pub persona: Option<String>,
+}
+
+/// Body of `POST .../regenerate`.
+#[derive(Deserialize, ToSchema)]
+pub struct RegenerateRequest {
+    pub student_id: String,
+    /// Id for this turn; see `QueryRequest::turn_id`.
+    #[serde(default)]
+    pub turn_id: Option<String>,
+}
+
+/// Body of `POST /api/v1/sessions/{session_id}/quiz`.
+#[derive(Deserialize, ToSchema)]
+pub struct CreateQuizRequest {
+    pub student_id: String,
+    /// Number of questions; defaults to `quiz.default_questions`.
+    #[serde(default)]
+    pub questions: Option<usize>,
+    /// What to focus on, e.g. "the phases of mitosis"; the whole session
+    /// by default.
+    #[serde(default)]
+    pub topic: Option<String>,
+}
+
+/// Body of `POST /api/v1/quizzes/{quiz_id}/submissions`.
+#[derive(Deserialize, ToSchema)]
+pub struct SubmitQuizRequest {
+    pub student_id: String,
+    /// One answer per question, in order: a choice index for
+    /// multiple-choice questions and text for short answers.
+    pub answers: Vec<QuizAnswer>,
+}
+
+/// Body of `POST /api/v1/sessions/{session_id}/problems`.
+#[derive(Deserialize, ToSchema)]
+pub struct CreateProblemRequest {
+    pub student_id: String,
+    pub statement: String,
+    /// Worked solution, kept from the student until the problem is solved
+    /// or `problems.reveal_after_attempts` wrong answers have been given.
+    pub solution: String,
+    /// Final answer; attempts equal to it are accepted without asking the
+    /// model.
+    #[serde(default)]
+    pub answer: Option<String>,
+    /// Hints from least to most help; written by the model when left out.
+    #[serde(default)]
+    pub hints: Vec<String>,
+}
+
+/// Body of `POST /api/v1/problems/{problem_id}/attempts`.
+#[derive(Deserialize, ToSchema)]
+pub struct ProblemAttemptRequest {
+    pub student_id: String,
+    pub answer: String,
+}
+
+/// Body of `POST /api/v1/sessions/{session_id}/cards/candidates`.
+#[derive(Deserialize, ToSchema)]
+pub struct CardCandidatesRequest {
+    pub student_id: String,
+    /// Tutor message to draft cards from; the latest reply on the active
+    /// branch by default.
+    #[serde(default)]
+    pub message_id: Option<NodeId>,
+}
+
+/// Body of `POST /api/v1/cards`.
+#[derive(Deserialize, ToSchema)]
+pub struct CreateCardRequest {
+    pub student_id: String,
+    pub front: String,
+    pub back: String,
+    /// Session and tutor message a kept draft came from; give both or
+    /// neither.
+    #[serde(default)]
+    pub session_id: Option<String>,
+    #[serde(default)]
+    pub message_id: Option<NodeId>,
+}
+
+/// Body of `PATCH /api/v1/cards/{card_id}`; omitted sides are kept.
+#[derive(Deserialize, ToSchema)]
+pub struct UpdateCardRequest {
+    pub student_id: String,
+    #[serde(default)]
+    pub front: Option<String>,
+    #[serde(default)]
+    pub back: Option<String>,
+}
+
+/// Body of `POST /api/v1/cards/{card_id}/reviews`.
+#[derive(Deserialize, ToSchema)]
+pub struct ReviewCardRequest {
+    pub student_id: String,
+    /// How well the student recalled the back: 0 (not at all) to 5
+    /// (perfectly). Below 3 the card starts over.
+    pub grade: u8,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct CardsQuery {
+    /// Most cards returned, soonest due first; defaults to 20.
+    pub limit: Option<usize>,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct EventsQuery {
+    /// Only events for this student, who must be assigned to the teacher.
+    pub student_id: Option<String>,
+    /// Most events returned, newest kept; defaults to 100.
+    pub limit: Option<usize>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct CancelTurnResponse {
+    pub turn_id: String,
+    /// Session the cancelled turn belongs to.
+    pub session_id: String,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct StudentQuery {
+    /// Owner of the session.
+    pub student_id: String,
+}
+
+/// Form fields of `POST /api/v1/documents`. Give exactly one of
+/// `course_id` and `organization_id`.
+#[derive(ToSchema)]
+#[allow(dead_code)]
+pub struct UploadDocumentForm {
+    /// A Markdown (`.md`), plain text (`.txt`) or PDF file.
+    #[schema(value_type = String, format = Binary)]
+    pub file: Vec<u8>,
+    pub course_id: Option<String>,
+    pub organization_id: Option<String>,
+    /// Shown in citations; defaults to the file name.
+    pub title: Option<String>,
+}
+
+/// `UploadDocumentForm` as received, before validation.
+#[derive(Default)]
+pub struct DocumentForm {
+    pub file_name: Option<String>,
+    pub content_type: Option<String>,
+    pub file: Option<Vec<u8>>,
+    pub course_id: Option<String>,
+    pub organization_id: Option<String>,
+    pub title: Option<String>,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DocumentsQuery {
+    /// Only documents shared with this course.
+    pub course_id: Option<String>,
+    /// Only documents shared with this organization.
+    pub organization_id: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
 pub struct SendQueryResponse {
+    /// The tutor's reply, formatted as Markdown. Empty when cancelled.
     pub message: String,
+    /// `message` rendered to sanitized HTML, safe to insert into a page.
+    /// Math is left as `<span class="math math-inline">` and `math-display`
+    /// elements holding the TeX source.
+    pub html: String,
+    pub turn_id: String,
+    /// True when the turn was stopped with `POST /api/v1/turns/{turn_id}/cancel`.
+    pub cancelled: bool,
+    /// True when the question looked like graded work and the tutor was
+    /// told to give hints rather than the answer.
+    pub hint_only: bool,
+    /// Tools the tutor called while answering, in order.
+    pub tools: Vec<ToolUse>,
+    /// Course passages cited in `message` by their `[n]` marker, then the
+    /// tools whose results the answer used.
+    pub citations: Vec<Citation>,
+    /// Subject persona that answered; `null` for the general tutor.
+    pub persona: Option<String>,
+}
+
+impl From<TurnReply> for SendQueryResponse {
+    fn from(reply: TurnReply) -> Self {
+        Self {
+            html: markdown::to_html(&reply.message),
+            message: reply.message,
+            turn_id: reply.turn_id,
+            cancelled: reply.cancelled,
+            hint_only: reply.hint_only,
+            tools: reply.tools,
+            citations: reply.citations,
+            persona: reply.persona,
+        }
+    }
+}
+
+/// Success envelope used by every `/api/v1` route.
+#[derive(Serialize, ToSchema)]
+pub struct ApiResponse<T> {
+    /// Always `"success"`.
+    pub status: String,
+    pub data: T,
+    /// Echo of the `x-request-id` header.
+    pub request_id: Option<String>,
+}
+
+impl<T> ApiResponse<T> {
+    pub fn new(data: T) -> Self {
+        Self {
+            status: "success".to_string(),
+            data,
+            request_id: request_id::current(),
+        }
+    }
+}
+
+impl<T: Serialize> IntoResponse for ApiResponse<T> {
+    fn into_response(self) -> Response {
+        Json(self).into_response()
+    }
+}
+
+/// Body returned with every error status.
+#[derive(Serialize, ToSchema)]
+pub struct ErrorResponse {
+    /// Always `"error"`.
+    pub status: String,
+    pub error: ErrorDetail,
+    /// Echo of the `x-request-id` header.
+    pub request_id: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ErrorDetail {
+    pub message: String,
+    /// HTTP status code, repeated for clients that only see the body.
+    pub code: u16,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -37,6 +350,30 @@ pub enum AppError {
     #[error("Bad request: {0}")]
     BadRequest(String),
 
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+
+    #[error("Gone: {0}")]
+    Gone(String),
+
+    #[error("Conflict: {0}")]
+    Conflict(String),
+
+    #[error("Method not allowed: {0}")]
+    MethodNotAllowed(String),
+
+    #[error("Payload too large: {0}")]
+    PayloadTooLarge(String),
+
+    #[error("Unsupported media type: {0}")]
+    UnsupportedMediaType(String),
+
+    #[error("Too many requests: {0}")]
+    TooManyRequests(String),
+
+    #[error("Invalid request body: {0}")]
+    InvalidBody(String),
+
     #[error("Internal error: {0}")]
     Internal(String),
 }
@@ -46,17 +383,102 @@ impl IntoResponse for AppError {
         let (status, error_message) = match self {
             Self::NotFound(msg) => (StatusCode::NOT_FOUND, msg),
             Self::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg),
+            Self::Unauthorized(msg) => (StatusCode::UNAUTHORIZED, msg),
+            Self::Conflict(msg) => (StatusCode::CONFLICT, msg),
+            Self::Gone(msg) => (StatusCode::GONE, msg),
+            Self::MethodNotAllowed(msg) => (StatusCode::METHOD_NOT_ALLOWED, msg),
+            Self::PayloadTooLarge(msg) => (StatusCode::PAYLOAD_TOO_LARGE, msg),
+            Self::UnsupportedMediaType(msg) => (StatusCode::UNSUPPORTED_MEDIA_TYPE, msg),
+            Self::TooManyRequests(msg) => (StatusCode::TOO_MANY_REQUESTS, msg),
+            Self::InvalidBody(msg) => (StatusCode::UNPROCESSABLE_ENTITY, msg),
             Self::Internal(msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg),
         };
 
-        let body = Json(json!({
-            "status": "error",
-            "error": {
-                "message": error_message,
-                "code": status.as_u16()
-            }
-        }));
+        let body = Json(ErrorResponse {
+            status: "error".to_string(),
+            error: ErrorDetail {
+                message: error_message,
+                code: status.as_u16(),
+            },
+            request_id: request_id::current(),
+        });
+
+        let mut response = (status, body).into_response();
+        if status == StatusCode::UNAUTHORIZED {
+            // Let the browser ask for the teacher's credentials.
+            response.headers_mut().insert(
+                header::WWW_AUTHENTICATE,
+                HeaderValue::from_static("Basic realm=\"Teacher dashboard\", charset=\"UTF-8\""),
+            );
+        }
+        response
+    }
+}
+
+/// `Json` extractor whose rejections are reported with the error envelope:
+/// 415 without a JSON content type, 413 over the body limit and 422 for a
+/// body that does not parse.
+pub struct ApiJson<T>(pub T);
+
+impl<S, T> FromRequest<S> for ApiJson<T>
+where
+    Json<T>: FromRequest<S, Rejection = JsonRejection>,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        Json::<T>::from_request(req, state)
+            .await
+            .map(|Json(value)| ApiJson(value))
+            .map_err(|rejection| match rejection.status() {
+                StatusCode::UNSUPPORTED_MEDIA_TYPE => {
+                    AppError::UnsupportedMediaType(rejection.body_text())
+                }
+                StatusCode::PAYLOAD_TOO_LARGE => AppError::PayloadTooLarge(rejection.body_text()),
+                _ => AppError::InvalidBody(rejection.body_text()),
+            })
+    }
+}
+
+/// `Query` extractor whose rejections are reported with the error envelope.
+pub struct ApiQuery<T>(pub T);
+
+impl<S, T> FromRequestParts<S> for ApiQuery<T>
+where
+    Query<T>: FromRequestParts<S, Rejection = QueryRejection>,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        Query::<T>::from_request_parts(parts, state)
+            .await
+            .map(|Query(value)| ApiQuery(value))
+            .map_err(|rejection| AppError::BadRequest(rejection.body_text()))
+    }
+}
+
+/// `Path` extractor whose rejections, such as a message id that is not a
+/// number, are reported with the error envelope.
+pub struct ApiPath<T>(pub T);
+
+impl<S, T> FromRequestParts<S> for ApiPath<T>
+where
+    Path<T>: FromRequestParts<S, Rejection = PathRejection>,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
 
-        (status, body).into_response()
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        Path::<T>::from_request_parts(parts, state)
+            .await
+            .map(|Path(value)| ApiPath(value))
+            .map_err(|rejection| match rejection {
+                PathRejection::FailedToDeserializePathParams(e) => {
+                    AppError::BadRequest(e.body_text())
+                }
+                other => AppError::Internal(other.body_text()),
+            })
     }
 }
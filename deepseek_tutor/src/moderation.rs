@@ -0,0 +1,623 @@
+use anyhow::{Context, Result};
+use async_openai::{Client, config::OpenAIConfig, types::CreateModerationRequestArgs};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::ops::Range;
+use std::path::Path;
+use time::OffsetDateTime;
+use utoipa::ToSchema;
+
+use crate::config::ModerationConfig;
+use crate::pii::{self, PiiKind, Redactor};
+use crate::session::TokenUsage;
+use crate::tenancy::{Owner, default_organization};
+
+/// Sent to the student in place of a tutor reply that was blocked.
+pub const BLOCKED_REPLY: &str = "I can't help with that. If something is worrying you, \
+please talk to a teacher, a parent or another adult you trust.";
+
+const REDACTED: &str = "[redacted]";
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize, ToSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Category {
+    Profanity,
+    Harassment,
+    Hate,
+    SelfHarm,
+    Sexual,
+    Violence,
+    Illicit,
+    Email,
+    Phone,
+}
+
+impl Category {
+    fn parse(name: &str) -> Option<Self> {
+        serde_json::from_value(serde_json::Value::String(name.to_string())).ok()
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Category::Profanity => "profanity",
+            Category::Harassment => "harassment",
+            Category::Hate => "hate",
+            Category::SelfHarm => "self_harm",
+            Category::Sexual => "sexual",
+            Category::Violence => "violence",
+            Category::Illicit => "illicit",
+            Category::Email => "email",
+            Category::Phone => "phone",
+        }
+    }
+}
+
+/// What to do with a message in a category, mildest first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Allow,
+    /// Keep the message and record the categories on it.
+    Annotate,
+    /// Replace the matched text. Findings without a span (from the
+    /// moderation model) fall back to `Annotate`.
+    Redact,
+    /// Refuse a student message; replace a tutor reply with `BLOCKED_REPLY`.
+    Block,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    /// A student message on its way into the session.
+    Input,
+    /// A tutor reply on its way to the student.
+    Output,
+}
+
+#[derive(Debug, Clone)]
+pub struct Finding {
+    pub category: Category,
+    pub checker: &'static str,
+    /// Matched bytes, when the checker can point at them.
+    pub span: Option<Range<usize>>,
+}
+
+/// A synchronous content check. Checkers only report; the pipeline decides
+/// what happens from the configured actions.
+pub trait Checker: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn check(&self, text: &str) -> Vec<Finding>;
+}
+
+/// Case-insensitive patterns per category: the built-in list plus any from
+/// the configured word list.
+pub struct KeywordChecker {
+    rules: Vec<(Category, Regex)>,
+}
+
+impl KeywordChecker {
+    pub fn new(extra: Option<&Path>) -> Result<Self> {
+        let mut rules = Vec::new();
+        for (category, pattern) in BUILTIN_RULES {
+            rules.push((*category, Regex::new(&format!(r"(?i)\b(?:{pattern})\b"))?));
+        }
+        if let Some(path) = extra {
+            let text = std::fs::read_to_string(path)
+                .with_context(|| format!("failed to read word list {}", path.display()))?;
+            rules.extend(parse_word_list(&text).with_context(|| path.display().to_string())?);
+        }
+        Ok(Self { rules })
+    }
+}
+
+impl Checker for KeywordChecker {
+    fn name(&self) -> &'static str {
+        "keywords"
+    }
+
+    fn check(&self, text: &str) -> Vec<Finding> {
+        self.rules
+            .iter()
+            .flat_map(|(category, pattern)| {
+                pattern.find_iter(text).map(|m| Finding {
+                    category: *category,
+                    checker: self.name(),
+                    span: Some(m.range()),
+                })
+            })
+            .collect()
+    }
+}
+
+const BUILTIN_RULES: &[(Category, &str)] = &[
+    (
+        Category::Profanity,
+        r"f+u+c+k\w*|shit\w*|bitch\w*|bastard|asshole|dickhead|wanker",
+    ),
+    (
+        Category::Harassment,
+        r"kill yourself|kys|you(?: are|'re) (?:so )?(?:stupid|ugly|worthless|a loser)",
+    ),
+    (
+        Category::SelfHarm,
+        r"kill myself|suicid\w*|self[- ]?harm\w*|hurt(?:ing)? myself|cut(?:ting)? myself|want to die|end my life",
+    ),
+    (
+        Category::Violence,
+        r"shoot up (?:the|my) school|bring a (?:gun|knife) to school|bomb (?:the|my) school|make a bomb",
+    ),
+    (
+        Category::Sexual,
+        r"porn\w*|nudes?|sexting|send (?:me )?pics",
+    ),
+];
+
+/// Lines of `category: pattern`; blank lines and `#` comments are skipped.
+fn parse_word_list(text: &str) -> Result<Vec<(Category, Regex)>> {
+    let mut rules = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (name, pattern) = line
+            .split_once(':')
+            .with_context(|| format!("line {}: expected `category: pattern`", number + 1))?;
+        let category = Category::parse(name.trim())
+            .with_context(|| format!("line {}: unknown category `{}`", number + 1, name.trim()))?;
+        let pattern = Regex::new(&format!(r"(?i)\b(?:{})\b", pattern.trim()))
+            .with_context(|| format!("line {}: invalid pattern", number + 1))?;
+        rules.push((category, pattern));
+    }
+    Ok(rules)
+}
+
+/// Email addresses and phone numbers. Names and addresses are left to the
+/// upstream redaction, which puts them back in the reply.
+pub struct PiiChecker;
+
+impl Checker for PiiChecker {
+    fn name(&self) -> &'static str {
+        "pii"
+    }
+
+    fn check(&self, text: &str) -> Vec<Finding> {
+        pii::find(text)
+            .into_iter()
+            .filter_map(|m| {
+                let category = match m.kind {
+                    PiiKind::Email => Category::Email,
+                    PiiKind::Phone => Category::Phone,
+                    PiiKind::Name | PiiKind::Address => return None,
+                };
+                Some(Finding {
+                    category,
+                    checker: self.name(),
+                    span: Some(m.range),
+                })
+            })
+            .collect()
+    }
+}
+
+/// The pipeline's decision about one message.
+#[derive(Debug, Clone)]
+pub struct Review {
+    /// The message to use: redacted if any redaction applied.
+    pub text: String,
+    /// The strongest action any finding called for.
+    pub action: Action,
+    /// Categories with an action other than `Allow`.
+    pub categories: Vec<Category>,
+    pub checkers: Vec<&'static str>,
+    /// Tokens sent to the moderation model; zero when it was not called.
+    pub usage: TokenUsage,
+}
+
+impl Review {
+    /// Nothing found, or moderation is off.
+    pub fn unchanged(text: String) -> Self {
+        Self {
+            text,
+            action: Action::Allow,
+            categories: Vec::new(),
+            checkers: Vec::new(),
+            usage: TokenUsage::default(),
+        }
+    }
+}
+
+/// Runs the checkers over a message and applies the configured action for
+/// each category found.
+pub struct Moderator {
+    checkers: Vec<Box<dyn Checker>>,
+    actions: HashMap<Category, Action>,
+    /// Client and model for the optional moderation endpoint call.
+    model: Option<(Client<OpenAIConfig>, String)>,
+}
+
+impl Moderator {
+    pub fn new(config: &ModerationConfig, client: Client<OpenAIConfig>) -> Result<Self> {
+        let checkers: Vec<Box<dyn Checker>> = vec![
+            Box::new(KeywordChecker::new(config.word_list.as_deref())?),
+            Box::new(PiiChecker),
+        ];
+        Ok(Self {
+            checkers,
+            actions: config.actions.clone(),
+            model: config.model.clone().map(|model| (client, model)),
+        })
+    }
+
+    fn action(&self, category: Category) -> Action {
+        self.actions
+            .get(&category)
+            .copied()
+            .unwrap_or_else(|| default_action(category))
+    }
+
+    pub async fn review(&self, text: String) -> Review {
+        let mut findings: Vec<Finding> = self
+            .checkers
+            .iter()
+            .flat_map(|checker| checker.check(&text))
+            .collect();
+        let mut usage = TokenUsage::default();
+        if let Some((client, model)) = &self.model {
+            // The endpoint only reports categories, never spans, so it can
+            // be given the text with personal details replaced and nothing
+            // has to be mapped back.
+            let outbound = Redactor::new().redact(&text);
+            match model_findings(client, model, &outbound).await {
+                Ok(found) => {
+                    findings.extend(found);
+                    usage = estimated_usage(&outbound);
+                }
+                // The local checkers still ran; don't fail the turn.
+                Err(err) => eprintln!("Moderation model call failed: {:#}", err),
+            }
+        }
+        let mut review = self.decide(text, findings);
+        review.usage = usage;
+        review
+    }
+
+    fn decide(&self, text: String, findings: Vec<Finding>) -> Review {
+        let mut action = Action::Allow;
+        let mut categories = BTreeSet::new();
+        let mut checkers = BTreeSet::new();
+        let mut redact: Vec<Range<usize>> = Vec::new();
+
+        for finding in &findings {
+            let mut wanted = self.action(finding.category);
+            if wanted == Action::Redact {
+                match &finding.span {
+                    Some(span) => redact.push(span.clone()),
+                    None => wanted = Action::Annotate,
+                }
+            }
+            if wanted != Action::Allow {
+                action = action.max(wanted);
+                categories.insert(finding.category);
+                checkers.insert(finding.checker);
+            }
+        }
+
+        Review {
+            text: redact_spans(&text, redact),
+            action,
+            categories: categories.into_iter().collect(),
+            checkers: checkers.into_iter().collect(),
+            usage: TokenUsage::default(),
+        }
+    }
+}
+
+/// The moderation endpoint reports no usage; count its input at four bytes
+/// a token, the usual rate for English text.
+fn estimated_usage(text: &str) -> TokenUsage {
+    let tokens = u32::try_from(text.len().div_ceil(4)).unwrap_or(u32::MAX);
+    TokenUsage {
+        prompt_tokens: tokens,
+        completion_tokens: 0,
+        total_tokens: tokens,
+    }
+}
+
+/// Minors are the audience, so sexual content is blocked and personal
+/// details are redacted unless configured otherwise.
+fn default_action(category: Category) -> Action {
+    match category {
+        Category::Sexual => Action::Block,
+        Category::Profanity | Category::Email | Category::Phone => Action::Redact,
+        _ => Action::Annotate,
+    }
+}
+
+fn redact_spans(text: &str, mut spans: Vec<Range<usize>>) -> String {
+    spans.sort_by_key(|span| span.start);
+    let mut out = String::with_capacity(text.len());
+    let mut cursor = 0;
+    for span in spans {
+        // Overlapping matches from different checkers merge into one.
+        if span.start < cursor {
+            cursor = cursor.max(span.end);
+            continue;
+        }
+        out.push_str(&text[cursor..span.start]);
+        out.push_str(REDACTED);
+        cursor = span.end;
+    }
+    out.push_str(&text[cursor..]);
+    out
+}
+
+async fn model_findings(
+    client: &Client<OpenAIConfig>,
+    model: &str,
+    text: &str,
+) -> Result<Vec<Finding>> {
+    let request = CreateModerationRequestArgs::default()
+        .input(text)
+        .model(model)
+        .build()?;
+    let response = client.moderations().create(request).await?;
+
+    let mut findings = Vec::new();
+    for result in response.results.iter().filter(|r| r.flagged) {
+        let c = &result.categories;
+        let flagged = [
+            (Category::Hate, c.hate || c.hate_threatening),
+            (
+                Category::Harassment,
+                c.harassment || c.harassment_threatening,
+            ),
+            (Category::Illicit, c.illicit || c.illicit_violent),
+            (
+                Category::SelfHarm,
+                c.self_harm || c.self_harm_intent || c.self_harm_instructions,
+            ),
+            (Category::Sexual, c.sexual || c.sexual_minors),
+            (Category::Violence, c.violence || c.violence_graphic),
+        ];
+        findings.extend(
+            flagged
+                .into_iter()
+                .filter(|(_, hit)| *hit)
+                .map(|(category, _)| Finding {
+                    category,
+                    checker: "model",
+                    span: None,
+                }),
+        );
+    }
+    Ok(findings)
+}
+
+/// Audit record of a message that triggered an action. The message text is
+/// not stored so that the trail does not become a copy of the PII it hides.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ModerationEvent {
+    #[serde(with = "time::serde::rfc3339")]
+    #[schema(value_type = String, format = DateTime)]
+    pub at: OffsetDateTime,
+    /// Organization the student belongs to.
+    #[serde(default = "default_organization")]
+    pub organization: String,
+    pub student_id: String,
+    pub session_id: String,
+    pub turn_id: Option<String>,
+    pub direction: Direction,
+    pub action: Action,
+    pub categories: Vec<Category>,
+    pub checkers: Vec<String>,
+}
+
+impl ModerationEvent {
+    pub fn new(
+        owner: &Owner,
+        session_id: &str,
+        turn_id: Option<&str>,
+        direction: Direction,
+        review: &Review,
+    ) -> Self {
+        Self {
+            at: OffsetDateTime::now_utc(),
+            organization: owner.organization.clone(),
+            student_id: owner.student_id.clone(),
+            session_id: session_id.to_string(),
+            turn_id: turn_id.map(str::to_string),
+            direction,
+            action: review.action,
+            categories: review.categories.clone(),
+            checkers: review.checkers.iter().map(|c| c.to_string()).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::testing::FakeUpstream;
+
+    fn moderator(actions: &[(Category, Action)]) -> Moderator {
+        let mut config = Config::for_tests().moderation;
+        config.actions = actions.iter().copied().collect();
+        Moderator::new(&config, Client::new()).unwrap()
+    }
+
+    fn finding(category: Category, span: Option<Range<usize>>) -> Finding {
+        Finding {
+            category,
+            checker: "test",
+            span,
+        }
+    }
+
+    #[test]
+    fn parses_word_lists() {
+        let rules = parse_word_list(
+            "# school-specific terms\n\n  violence: fight after school \nhate:slur\\w*\n",
+        )
+        .unwrap();
+        let categories: Vec<Category> = rules.iter().map(|(category, _)| *category).collect();
+        assert_eq!(categories, [Category::Violence, Category::Hate]);
+        // Case-insensitive, whole words only.
+        assert!(rules[0].1.is_match("a FIGHT AFTER SCHOOL today"));
+        assert!(rules[1].1.is_match("slurs"));
+        assert!(!rules[1].1.is_match("noslur"));
+    }
+
+    #[test]
+    fn word_list_errors_name_the_line() {
+        let err = parse_word_list("hate: a\nno colon here").unwrap_err();
+        assert!(err.to_string().contains("line 2"), "{err:#}");
+        let err = parse_word_list("rudeness: a").unwrap_err();
+        assert!(
+            err.to_string().contains("unknown category `rudeness`"),
+            "{err:#}"
+        );
+        let err = parse_word_list("# ok\nhate: (unclosed").unwrap_err();
+        assert!(
+            err.to_string().contains("line 2: invalid pattern"),
+            "{err:#}"
+        );
+    }
+
+    #[test]
+    fn redacts_spans() {
+        assert_eq!(redact_spans("nothing here", Vec::new()), "nothing here");
+        // Out of order spans are sorted first.
+        assert_eq!(
+            redact_spans("one two three", vec![8..13, 0..3]),
+            "[redacted] two [redacted]"
+        );
+        // Overlapping and nested spans merge into one replacement.
+        assert_eq!(
+            redact_spans("abcdefghij", vec![2..6, 4..8]),
+            "ab[redacted]ij"
+        );
+        assert_eq!(redact_spans("abcdefghij", vec![1..9, 3..5]), "a[redacted]j");
+        // Adjacent spans stay two replacements.
+        assert_eq!(
+            redact_spans("abcdef", vec![0..3, 3..6]),
+            "[redacted][redacted]"
+        );
+    }
+
+    #[test]
+    fn redacts_whole_words_in_unicode_text() {
+        let checker = KeywordChecker::new(None).unwrap();
+        let text = "Schöne shit — ça, éshit und shitstorm!";
+        let spans: Vec<Range<usize>> = checker
+            .check(text)
+            .into_iter()
+            .filter_map(|finding| finding.span)
+            .collect();
+        // `éshit` is one word; the match stops at the em dash boundary.
+        assert_eq!(
+            redact_spans(text, spans),
+            "Schöne [redacted] — ça, éshit und [redacted]!"
+        );
+    }
+
+    #[test]
+    fn default_actions_protect_minors() {
+        assert_eq!(default_action(Category::Sexual), Action::Block);
+        for category in [Category::Profanity, Category::Email, Category::Phone] {
+            assert_eq!(default_action(category), Action::Redact, "{category:?}");
+        }
+        for category in [
+            Category::Harassment,
+            Category::Hate,
+            Category::SelfHarm,
+            Category::Violence,
+            Category::Illicit,
+        ] {
+            assert_eq!(default_action(category), Action::Annotate, "{category:?}");
+        }
+    }
+
+    #[test]
+    fn decides_the_strongest_action() {
+        let text = "this shit is hard".to_string();
+        let review = moderator(&[]).decide(text.clone(), Vec::new());
+        assert_eq!(review.action, Action::Allow);
+        assert_eq!(review.text, text);
+        assert!(review.categories.is_empty());
+
+        let review = moderator(&[]).decide(
+            text.clone(),
+            vec![
+                finding(Category::Profanity, Some(5..9)),
+                finding(Category::SelfHarm, None),
+            ],
+        );
+        assert_eq!(review.action, Action::Redact);
+        assert_eq!(review.text, "this [redacted] is hard");
+        assert_eq!(review.categories, [Category::Profanity, Category::SelfHarm]);
+        assert_eq!(review.checkers, ["test"]);
+
+        let review = moderator(&[]).decide(
+            text.clone(),
+            vec![
+                finding(Category::Profanity, Some(5..9)),
+                finding(Category::Sexual, None),
+            ],
+        );
+        assert_eq!(review.action, Action::Block);
+    }
+
+    #[test]
+    fn decisions_follow_the_configured_actions() {
+        let text = "this shit is hard".to_string();
+        // Allowed categories are neither acted on nor reported.
+        let review = moderator(&[(Category::Profanity, Action::Allow)])
+            .decide(text.clone(), vec![finding(Category::Profanity, Some(5..9))]);
+        assert_eq!(review.action, Action::Allow);
+        assert_eq!(review.text, text);
+        assert!(review.categories.is_empty());
+
+        // A redaction without a span can only annotate.
+        let review = moderator(&[(Category::Hate, Action::Redact)])
+            .decide(text.clone(), vec![finding(Category::Hate, None)]);
+        assert_eq!(review.action, Action::Annotate);
+        assert_eq!(review.text, text);
+        assert_eq!(review.categories, [Category::Hate]);
+    }
+
+    #[tokio::test]
+    async fn sends_the_model_only_redacted_text() {
+        let upstream = FakeUpstream::start(|_| {
+            let error = serde_json::json!({"error":
+                {"message": "no", "type": "invalid_request_error"}});
+            (400, error)
+        });
+
+        let mut config = Config::for_tests().moderation;
+        config.model = Some("omni-moderation-latest".to_string());
+        let moderator = Moderator::new(&config, upstream.client()).unwrap();
+        let review = moderator
+            .review("My name is Ana Lopez, mail ana@example.com or ring 555-123-4567".to_string())
+            .await;
+        // The local checkers still redact what the student sees.
+        assert!(!review.text.contains("ana@example.com"), "{}", review.text);
+
+        let requests = upstream.requests();
+        let [request] = requests.as_slice() else {
+            panic!("expected one request, got {requests:?}");
+        };
+        assert!(request.contains("omni-moderation-latest"), "{request}");
+        for detail in ["Ana Lopez", "ana@example.com", "555-123-4567"] {
+            assert!(
+                !request.contains(detail),
+                "{detail} sent upstream: {request}"
+            );
+        }
+    }
+}
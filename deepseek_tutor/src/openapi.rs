@@ -0,0 +1,202 @@
+use utoipa::OpenApi;
+
+/// Top-level OpenAPI document. Paths and schemas are filled in by
+/// `routes::api_routes` from the handler annotations.
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "DeepSeek Tutor API",
+        description = "JSON API behind the personal tutor web client."
+    ),
+    tags(
+        (name = "sessions", description = "Tutoring sessions and questions"),
+        (name = "branches", description = "Editing, regenerating and switching conversation branches"),
+        (name = "export", description = "Transcript downloads"),
+        (name = "integrity", description = "Exam-integrity review"),
+        (name = "moderation", description = "Content moderation audit trail"),
+        (name = "documents", description = "Course materials that ground the tutor's answers"),
+        (name = "quizzes", description = "Practice quizzes generated from sessions and graded on the server"),
+        (name = "problems", description = "Practice problems worked through with graded hints"),
+        (name = "flashcards", description = "Flashcards drafted from tutor replies and reviewed on a spaced-repetition schedule"),
+        (name = "progress", description = "What each student has covered across sessions"),
+        (name = "personas", description = "Subject specialists that sessions are routed to"),
+        (name = "profiles", description = "What the tutor remembers about each student between sessions"),
+        (name = "legacy", description = "Unversioned routes kept for old clients; use /api/v1")
+    )
+)]
+pub struct ApiDoc;
+
+/// Where the generated document is served.
+pub const SPEC_PATH: &str = "/api/openapi.json";
+
+/// Where the bundled Swagger UI is served.
+pub const DOCS_PATH: &str = "/api/docs";
+
+#[cfg(test)]
+mod tests {
+    use super::SPEC_PATH;
+    use crate::{routes, testing::test_app};
+    use axum::{
+        body::{Body, to_bytes},
+        http::{Method, Request, StatusCode},
+    };
+    use serde_json::Value;
+    use tower::ServiceExt;
+
+    const METHODS: [&str; 5] = ["get", "post", "put", "patch", "delete"];
+
+    fn spec() -> Value {
+        let (_, openapi) = routes::api_routes().split_for_parts();
+        serde_json::to_value(openapi).unwrap()
+    }
+
+    /// Fill path parameters such as `{session_id}` with a placeholder and
+    /// add every required query parameter.
+    fn concrete_uri(template: &str, operation: &Value) -> String {
+        let parameters = operation["parameters"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default();
+        let path = template
+            .split('/')
+            .map(|seg| {
+                let Some(name) = seg.strip_prefix('{').and_then(|s| s.strip_suffix('}')) else {
+                    return seg;
+                };
+                let numeric = parameters.iter().any(|p| {
+                    p["name"] == name && matches!(p["schema"]["type"].as_str(), Some("integer"))
+                });
+                if numeric { "0" } else { "drift-check" }
+            })
+            .collect::<Vec<_>>()
+            .join("/");
+        let query: Vec<String> = parameters
+            .iter()
+            .filter(|p| p["in"] == "query" && p["required"] == true)
+            .map(|p| format!("{}=drift-check", p["name"].as_str().unwrap()))
+            .collect();
+        if query.is_empty() {
+            path
+        } else {
+            format!("{path}?{}", query.join("&"))
+        }
+    }
+
+    /// Build the smallest JSON value satisfying `schema`: required object
+    /// fields only, the first enum variant, placeholder scalars.
+    fn sample(spec: &Value, schema: &Value) -> Value {
+        if let Some(reference) = schema["$ref"].as_str() {
+            let name = reference.rsplit('/').next().unwrap();
+            return sample(spec, &spec["components"]["schemas"][name]);
+        }
+        if let Some(first) = schema["oneOf"].get(0).or(schema["allOf"].get(0)) {
+            return sample(spec, first);
+        }
+        if let Some(first) = schema["enum"].get(0) {
+            return first.clone();
+        }
+        let kind = match &schema["type"] {
+            Value::Array(kinds) => kinds[0].as_str().unwrap_or("null"),
+            kind => kind.as_str().unwrap_or("object"),
+        };
+        match kind {
+            "object" => {
+                let mut object = serde_json::Map::new();
+                for field in schema["required"].as_array().into_iter().flatten() {
+                    let field = field.as_str().unwrap();
+                    let value = sample(spec, &schema["properties"][field]);
+                    object.insert(field.to_string(), value);
+                }
+                Value::Object(object)
+            }
+            "array" => Value::Array(Vec::new()),
+            "string" => Value::from("drift-check"),
+            "integer" | "number" => Value::from(1),
+            "boolean" => Value::from(false),
+            _ => Value::Null,
+        }
+    }
+
+    /// Every documented operation must be routed, and must accept a body
+    /// built from its documented request schema.
+    #[tokio::test]
+    async fn every_documented_operation_is_routed() {
+        let spec = spec();
+        let paths = spec["paths"].as_object().expect("spec has paths");
+        assert!(!paths.is_empty());
+
+        for (path, item) in paths {
+            for method in METHODS.iter().filter(|m| item.get(**m).is_some()) {
+                let operation = &item[*method];
+                let builder = Request::builder()
+                    .method(Method::from_bytes(method.to_uppercase().as_bytes()).unwrap())
+                    .uri(concrete_uri(path, operation));
+                let body_schema =
+                    &operation["requestBody"]["content"]["application/json"]["schema"];
+                let request = if body_schema.is_null() {
+                    builder.body(Body::empty()).unwrap()
+                } else {
+                    let body = sample(&spec, body_schema);
+                    builder
+                        .header("content-type", "application/json")
+                        .body(Body::from(body.to_string()))
+                        .unwrap()
+                };
+                let (app, _dir) = test_app();
+                let response = app.oneshot(request).await.unwrap();
+                let status = response.status();
+                assert_ne!(
+                    status,
+                    StatusCode::METHOD_NOT_ALLOWED,
+                    "{method} {path} is documented but not routed"
+                );
+                assert!(
+                    status != StatusCode::UNPROCESSABLE_ENTITY
+                        && status != StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                    "{method} {path} rejected its documented request body ({status})"
+                );
+                if status == StatusCode::NOT_FOUND {
+                    // A handler may legitimately answer 404 for an unknown id;
+                    // the router's fallback says there is no route.
+                    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+                    let body: Value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+                    let message = body["error"]["message"].as_str().unwrap_or_default();
+                    assert!(
+                        !message.starts_with("no route for"),
+                        "{method} {path} is documented but not routed"
+                    );
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn served_spec_matches_generated_spec() {
+        let request = Request::get(SPEC_PATH).body(Body::empty()).unwrap();
+        let (app, _dir) = test_app();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let served: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(served, spec());
+    }
+
+    #[test]
+    fn error_envelope_schema_matches_serialization() {
+        let spec = spec();
+        let schema = &spec["components"]["schemas"]["ErrorResponse"];
+        let documented: Vec<&String> = schema["properties"].as_object().unwrap().keys().collect();
+
+        let body = serde_json::to_value(crate::models::ErrorResponse {
+            status: "error".to_string(),
+            error: crate::models::ErrorDetail {
+                message: "x".to_string(),
+                code: 400,
+            },
+            request_id: None,
+        })
+        .unwrap();
+        let serialized: Vec<&String> = body.as_object().unwrap().keys().collect();
+        assert_eq!(documented, serialized);
+    }
+}
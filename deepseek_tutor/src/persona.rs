@@ -0,0 +1,210 @@
+use anyhow::{Context, Result};
+use async_openai::types::{ChatCompletionRequestMessage, ChatCompletionRequestSystemMessageArgs};
+use regex::Regex;
+use serde::Serialize;
+use std::fs;
+use std::sync::Arc;
+use utoipa::ToSchema;
+
+use crate::config::{ModelConfig, PersonaConfig};
+use crate::tools::ToolRegistry;
+
+/// A subject specialist: extra instructions, model settings and the tools
+/// it may call.
+pub struct Persona {
+    pub id: String,
+    pub name: String,
+    prompt: String,
+    config: PersonaConfig,
+    /// Matches any of the persona's keywords; `None` when it has none and
+    /// is only chosen by the client.
+    keywords: Option<Regex>,
+}
+
+/// A persona as listed to clients.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct PersonaSummary {
+    pub id: String,
+    pub name: String,
+}
+
+impl Persona {
+    fn new(config: &PersonaConfig) -> Result<Self> {
+        let prompt = fs::read_to_string(&config.prompt).with_context(|| {
+            format!(
+                "failed to read the prompt of persona {}: {}",
+                config.id,
+                config.prompt.display()
+            )
+        })?;
+        let keywords = if config.keywords.is_empty() {
+            None
+        } else {
+            let alternatives: Vec<String> = config
+                .keywords
+                .iter()
+                .map(|keyword| regex::escape(keyword.trim()))
+                .collect();
+            Some(Regex::new(&format!(
+                r"(?i)\b(?:{})\b",
+                alternatives.join("|")
+            ))?)
+        };
+        Ok(Self {
+            id: config.id.clone(),
+            name: config.name.clone(),
+            prompt,
+            config: config.clone(),
+            keywords,
+        })
+    }
+
+    /// The organization's model settings with this persona's overrides.
+    pub fn model(&self, base: &ModelConfig) -> ModelConfig {
+        ModelConfig {
+            name: self
+                .config
+                .model_name
+                .clone()
+                .unwrap_or_else(|| base.name.clone()),
+            temperature: self.config.temperature.unwrap_or(base.temperature),
+            max_tokens: self.config.max_tokens.unwrap_or(base.max_tokens),
+        }
+    }
+
+    /// The tools this persona may call, out of those enabled.
+    pub fn tools(&self, registry: &Arc<ToolRegistry>) -> Option<Arc<ToolRegistry>> {
+        match &self.config.tools {
+            None => Some(Arc::clone(registry)),
+            Some(names) if names.is_empty() => None,
+            Some(names) => Some(Arc::new(registry.restricted(names))),
+        }
+    }
+
+    /// Add the persona's instructions right after the system prompt.
+    pub fn instruct(&self, messages: &mut Vec<ChatCompletionRequestMessage>) {
+        let message = ChatCompletionRequestSystemMessageArgs::default()
+            .content(self.prompt.as_str())
+            .build()
+            .expect("text system message always builds")
+            .into();
+        messages.insert(messages.len().min(1), message);
+    }
+
+    fn hits(&self, question: &str) -> usize {
+        self.keywords
+            .as_ref()
+            .map_or(0, |keywords| keywords.find_iter(question).count())
+    }
+}
+
+/// The configured personas, in the order of the config file.
+pub struct Personas {
+    personas: Vec<Persona>,
+}
+
+impl Personas {
+    pub fn new(configs: &[PersonaConfig]) -> Result<Self> {
+        Ok(Self {
+            personas: configs.iter().map(Persona::new).collect::<Result<_>>()?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.personas.is_empty()
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Persona> {
+        self.personas.iter().find(|persona| persona.id == id)
+    }
+
+    pub fn list(&self) -> Vec<PersonaSummary> {
+        self.personas
+            .iter()
+            .map(|persona| PersonaSummary {
+                id: persona.id.clone(),
+                name: persona.name.clone(),
+            })
+            .collect()
+    }
+
+    /// The persona whose keywords the question matches most often; the
+    /// first listed wins a tie. `None` when no keyword matches.
+    pub fn route(&self, question: &str) -> Option<&Persona> {
+        let mut best: Option<(usize, &Persona)> = None;
+        for persona in &self.personas {
+            let hits = persona.hits(question);
+            if hits > best.map_or(0, |(most, _)| most) {
+                best = Some((hits, persona));
+            }
+        }
+        best.map(|(_, persona)| persona)
+    }
+
+    /// Tools named by a persona that are not available, with the persona.
+    pub fn unknown_tools<'a>(&'a self, registry: &ToolRegistry) -> Vec<(&'a str, &'a str)> {
+        self.personas
+            .iter()
+            .flat_map(|persona| {
+                persona
+                    .config
+                    .tools
+                    .iter()
+                    .flatten()
+                    .filter(|name| !registry.has(name))
+                    .map(move |name| (persona.id.as_str(), name.as_str()))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn personas() -> Personas {
+        let prompt = Config::for_tests().paths.system_prompt;
+        let persona = |id: &str, keywords: &[&str]| PersonaConfig {
+            id: id.to_string(),
+            name: id.to_string(),
+            prompt: prompt.clone(),
+            model_name: None,
+            temperature: Some(0.2),
+            max_tokens: None,
+            tools: None,
+            keywords: keywords.iter().map(|k| k.to_string()).collect(),
+        };
+        Personas::new(&[
+            persona("math", &["equation", "derivative", "pythagorean theorem"]),
+            persona("science", &["newton's laws", "force", "cell"]),
+            persona("writing", &[]),
+        ])
+        .unwrap()
+    }
+
+    #[test]
+    fn routes_by_keyword_matches() {
+        let personas = personas();
+        let route = |question: &str| personas.route(question).map(|p| p.id.as_str());
+        assert_eq!(route("Explain the Pythagorean theorem"), Some("math"));
+        assert_eq!(route("Describe Newton's laws of motion"), Some("science"));
+        assert_eq!(
+            route("What force does a cell wall resist in this equation?"),
+            Some("science")
+        );
+        assert_eq!(route("Help me with my essay"), None);
+        // Keywords match whole words only.
+        assert_eq!(route("forcefully"), None);
+    }
+
+    #[test]
+    fn overrides_only_the_settings_it_sets() {
+        let personas = personas();
+        let base = Config::for_tests().model;
+        let model = personas.get("math").unwrap().model(&base);
+        assert_eq!(model.name, base.name);
+        assert_eq!(model.max_tokens, base.max_tokens);
+        assert_eq!(model.temperature, 0.2);
+    }
+}
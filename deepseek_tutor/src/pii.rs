@@ -0,0 +1,381 @@
+use async_openai::types::{
+    ChatCompletionRequestAssistantMessageContent, ChatCompletionRequestAssistantMessageContentPart,
+    ChatCompletionRequestMessage, ChatCompletionRequestToolMessageContent,
+    ChatCompletionRequestUserMessageContent, ChatCompletionRequestUserMessageContentPart,
+};
+use regex::Regex;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::LazyLock;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PiiKind {
+    Email,
+    Phone,
+    Name,
+    Address,
+}
+
+impl PiiKind {
+    fn label(self) -> &'static str {
+        match self {
+            PiiKind::Email => "EMAIL",
+            PiiKind::Phone => "PHONE",
+            PiiKind::Name => "NAME",
+            PiiKind::Address => "ADDRESS",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PiiMatch {
+    pub kind: PiiKind,
+    /// Byte range of the match in the scanned text.
+    pub range: Range<usize>,
+}
+
+static EMAIL: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)\b[a-z0-9._%+-]+@[a-z0-9-]+(\.[a-z0-9-]+)*\.[a-z]{2,}\b")
+        .expect("email pattern compiles")
+});
+
+// Optional country code, an area code in parentheses or followed by a
+// separator, then two more groups. Requiring a separator after the first
+// group keeps "1999-2001" and long numbers in maths questions out.
+static PHONE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(\+\d{1,3}[ .-]?)?(\(\d{2,5}\)[ .-]?|\d{2,5}[ .-])\d{3,4}[ .-]?\d{3,4}")
+        .expect("phone pattern compiles")
+});
+
+/// Digits in a phone number; fewer looks like arithmetic, more like an id.
+const PHONE_DIGITS: Range<usize> = 7..16;
+
+// Names are only recognised where the student introduces someone or after a
+// title: a capitalised word on its own is too often a subject or a place.
+static INTRODUCED_NAME: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"(?i:\b(?:my name is|my name's|i am called|i'm called|call me|my (?:friend|brother|sister|mum|mom|dad|teacher|tutor) is)) ([A-Z][a-z]+(?:[ -][A-Z][a-z]+)?)",
+    )
+    .expect("name pattern compiles")
+});
+
+static TITLED_NAME: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"\b(?:Mr|Mrs|Ms|Miss|Mx|Dr|Prof)\.? [A-Z][a-z]+(?:[ -][A-Z][a-z]+)?")
+        .expect("name pattern compiles")
+});
+
+const TITLES: &[&str] = &["Mr", "Mrs", "Ms", "Miss", "Mx", "Dr", "Prof"];
+
+// A house number and up to three capitalised words before a street type, a
+// UK postcode, or a US state and ZIP code.
+static ADDRESS: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"\b\d{1,5}[A-Za-z]? (?:[A-Z][A-Za-z'-]* ){1,3}(?:Street|St|Road|Rd|Avenue|Ave|Lane|Ln|Drive|Dr|Boulevard|Blvd|Way|Court|Ct|Place|Pl|Close|Crescent|Terrace|Gardens)\b\.?|\b[A-Z]{1,2}\d[A-Z\d]? ?\d[A-Z]{2}\b|\b[A-Z]{2} \d{5}(?:-\d{4})?\b",
+    )
+    .expect("address pattern compiles")
+});
+
+/// Every email address, phone number, name and street address in `text`,
+/// in order, without overlaps.
+pub fn find(text: &str) -> Vec<PiiMatch> {
+    let mut found: Vec<PiiMatch> = Vec::new();
+    let mut push = |kind, range| found.push(PiiMatch { kind, range });
+
+    for m in EMAIL.find_iter(text) {
+        push(PiiKind::Email, m.range());
+    }
+    for m in PHONE.find_iter(text) {
+        let digits = m.as_str().chars().filter(char::is_ascii_digit).count();
+        if PHONE_DIGITS.contains(&digits) && standalone(text, m.range()) {
+            push(PiiKind::Phone, m.range());
+        }
+    }
+    for captures in INTRODUCED_NAME.captures_iter(text) {
+        push(
+            PiiKind::Name,
+            captures.get(1).expect("name group matches").range(),
+        );
+    }
+    for m in TITLED_NAME.find_iter(text) {
+        push(PiiKind::Name, m.range());
+    }
+    for m in ADDRESS.find_iter(text) {
+        push(PiiKind::Address, m.range());
+    }
+
+    // Earliest first; of two matches starting together, the longer wins.
+    found.sort_by_key(|m| (m.range.start, Reverse(m.range.end)));
+    let mut kept: Vec<PiiMatch> = Vec::with_capacity(found.len());
+    for m in found {
+        if kept
+            .last()
+            .is_none_or(|last| last.range.end <= m.range.start)
+        {
+            kept.push(m);
+        }
+    }
+    kept
+}
+
+/// Not part of a longer word or number.
+fn standalone(text: &str, range: Range<usize>) -> bool {
+    !text[..range.start].ends_with(|c: char| c.is_alphanumeric())
+        && !text[range.end..].starts_with(|c: char| c.is_alphanumeric())
+}
+
+/// Swaps personal details for numbered placeholders such as `[EMAIL_1]`
+/// and back. One redactor covers one upstream request, so a detail that
+/// appears several times in the history gets the same placeholder each
+/// time. The mapping lives only in memory for the length of the turn.
+#[derive(Debug, Default)]
+pub struct Redactor {
+    /// `(original, placeholder)` in the order they were found.
+    entries: Vec<(String, String)>,
+    counts: HashMap<PiiKind, usize>,
+}
+
+impl Redactor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replace every detected detail, and every one seen earlier, with its
+    /// placeholder.
+    pub fn redact(&mut self, text: &str) -> String {
+        for m in find(text) {
+            let value = &text[m.range];
+            self.register(m.kind, value);
+            // A student who gives their full name may later use just the
+            // first or last name.
+            if m.kind == PiiKind::Name {
+                for part in value.split([' ', '-', '.']) {
+                    if part.len() >= 3 && !TITLES.contains(&part) {
+                        self.register(PiiKind::Name, part);
+                    }
+                }
+            }
+        }
+
+        let mut spans: Vec<(Range<usize>, usize)> = Vec::new();
+        for (index, (original, _)) in self.entries.iter().enumerate() {
+            for (start, _) in text.match_indices(original.as_str()) {
+                let range = start..start + original.len();
+                if standalone(text, range.clone()) {
+                    spans.push((range, index));
+                }
+            }
+        }
+        spans.sort_by_key(|(range, _)| (range.start, Reverse(range.end)));
+
+        let mut out = String::with_capacity(text.len());
+        let mut cursor = 0;
+        for (range, index) in spans {
+            if range.start < cursor {
+                continue;
+            }
+            out.push_str(&text[cursor..range.start]);
+            out.push_str(&self.entries[index].1);
+            cursor = range.end;
+        }
+        out.push_str(&text[cursor..]);
+        out
+    }
+
+    /// Redact the text of a student or tutor message in place. The system
+    /// prompt is ours and is left alone.
+    pub fn redact_message(&mut self, message: &mut ChatCompletionRequestMessage) {
+        match message {
+            ChatCompletionRequestMessage::User(m) => match &mut m.content {
+                ChatCompletionRequestUserMessageContent::Text(text) => *text = self.redact(text),
+                ChatCompletionRequestUserMessageContent::Array(parts) => {
+                    for part in parts {
+                        if let ChatCompletionRequestUserMessageContentPart::Text(part) = part {
+                            part.text = self.redact(&part.text);
+                        }
+                    }
+                }
+            },
+            ChatCompletionRequestMessage::Assistant(m) => match &mut m.content {
+                Some(ChatCompletionRequestAssistantMessageContent::Text(text)) => {
+                    *text = self.redact(text)
+                }
+                Some(ChatCompletionRequestAssistantMessageContent::Array(parts)) => {
+                    for part in parts {
+                        if let ChatCompletionRequestAssistantMessageContentPart::Text(part) = part {
+                            part.text = self.redact(&part.text);
+                        }
+                    }
+                }
+                None => {}
+            },
+            _ => {}
+        }
+    }
+
+    /// Undo `redact_message` on a tutor message or tool result that is
+    /// about to be stored.
+    pub fn restore_message(&self, message: &mut ChatCompletionRequestMessage) {
+        match message {
+            ChatCompletionRequestMessage::Assistant(m) => {
+                if let Some(ChatCompletionRequestAssistantMessageContent::Text(text)) =
+                    &mut m.content
+                {
+                    *text = self.restore(text);
+                }
+            }
+            ChatCompletionRequestMessage::Tool(m) => {
+                if let ChatCompletionRequestToolMessageContent::Text(text) = &mut m.content {
+                    *text = self.restore(text);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Put the originals back in place of any placeholders in `text`.
+    pub fn restore(&self, text: &str) -> String {
+        self.entries
+            .iter()
+            .fold(text.to_string(), |text, (original, placeholder)| {
+                text.replace(placeholder.as_str(), original)
+            })
+    }
+
+    fn register(&mut self, kind: PiiKind, value: &str) {
+        if self.entries.iter().any(|(original, _)| original == value) {
+            return;
+        }
+        let count = self.counts.entry(kind).or_default();
+        *count += 1;
+        let placeholder = format!("[{}_{}]", kind.label(), count);
+        self.entries.push((value.to_string(), placeholder));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(text: &str) -> Vec<(PiiKind, &str)> {
+        find(text)
+            .into_iter()
+            .map(|m| (m.kind, &text[m.range]))
+            .collect()
+    }
+
+    #[test]
+    fn finds_email_addresses() {
+        assert_eq!(
+            kinds("write to jo.smith+tutor@mail.example.co.uk today"),
+            [(PiiKind::Email, "jo.smith+tutor@mail.example.co.uk")]
+        );
+        assert_eq!(
+            kinds("Ana@School.ORG"),
+            [(PiiKind::Email, "Ana@School.ORG")]
+        );
+    }
+
+    #[test]
+    fn finds_common_phone_formats() {
+        for number in [
+            "555-123-4567",
+            "(555) 123-4567",
+            "555.123.4567",
+            "+1 555 123 4567",
+            "+44 20 7946 0958",
+            "07700 900123",
+            "+49-30-1234567",
+        ] {
+            let text = format!("ring {number} after school");
+            assert_eq!(kinds(&text), [(PiiKind::Phone, number)], "{number}");
+        }
+    }
+
+    #[test]
+    fn ignores_numbers_in_maths_questions() {
+        for text in [
+            "what happened between 1999-2001?",
+            "is 3.14159 close to pi",
+            "solve 12 34 56",
+            "compute 123456789 * 2",
+            "order ABC-123-4567X",
+        ] {
+            assert_eq!(kinds(text), [], "{text}");
+        }
+    }
+
+    #[test]
+    fn finds_introduced_and_titled_names() {
+        assert_eq!(
+            kinds("Hi, my name is Maya Patel and I need help"),
+            [(PiiKind::Name, "Maya Patel")]
+        );
+        assert_eq!(
+            kinds("My teacher is Tom, he said"),
+            [(PiiKind::Name, "Tom")]
+        );
+        assert_eq!(
+            kinds("Mrs. Okafor set this essay"),
+            [(PiiKind::Name, "Mrs. Okafor")]
+        );
+        assert_eq!(kinds("I think Paris is in France"), []);
+        assert_eq!(kinds("my name is not important"), []);
+    }
+
+    #[test]
+    fn finds_street_addresses_and_postcodes() {
+        assert_eq!(
+            kinds("I live at 42 Elm Tree Road near the park"),
+            [(PiiKind::Address, "42 Elm Tree Road")]
+        );
+        assert_eq!(
+            kinds("it's 221B Baker St. in London"),
+            [(PiiKind::Address, "221B Baker St.")]
+        );
+        assert_eq!(kinds("post to SW1A 1AA"), [(PiiKind::Address, "SW1A 1AA")]);
+        assert_eq!(
+            kinds("Springfield, IL 62704-1234"),
+            [(PiiKind::Address, "IL 62704-1234")]
+        );
+        assert_eq!(kinds("chapter 3 covers 5 main ideas"), []);
+    }
+
+    #[test]
+    fn redacts_and_restores() {
+        let mut redactor = Redactor::new();
+        let text = "I'm called Sam Lee, email sam@example.com or ring 555-123-4567.";
+        let redacted = redactor.redact(text);
+        assert_eq!(
+            redacted,
+            "I'm called [NAME_1], email [EMAIL_1] or ring [PHONE_1]."
+        );
+        assert_eq!(redactor.restore(&redacted), text);
+    }
+
+    #[test]
+    fn reuses_placeholders_across_messages() {
+        let mut redactor = Redactor::new();
+        assert_eq!(redactor.redact("my name is Sam Lee"), "my name is [NAME_1]");
+        // Parts of a known name are redacted wherever they appear later,
+        // but not inside other words.
+        assert_eq!(
+            redactor.redact("Sam asked and Lee answered. Sample text."),
+            "[NAME_2] asked and [NAME_3] answered. Sample text."
+        );
+        assert_eq!(
+            redactor.redact("a@b.org then a@b.org"),
+            "[EMAIL_1] then [EMAIL_1]"
+        );
+        assert_eq!(
+            redactor.restore("Well done, [NAME_2]! Write to [EMAIL_1]."),
+            "Well done, Sam! Write to a@b.org."
+        );
+    }
+
+    #[test]
+    fn restore_leaves_unknown_placeholders() {
+        let redactor = Redactor::new();
+        assert_eq!(redactor.restore("see [NAME_1]"), "see [NAME_1]");
+    }
+}
@@ -0,0 +1,592 @@
+use anyhow::{Context, Result, anyhow, bail};
+use async_openai::{
+    Client,
+    config::OpenAIConfig,
+    types::{
+        ChatCompletionRequestMessage, ChatCompletionRequestSystemMessageArgs,
+        ChatCompletionRequestUserMessageArgs, CreateChatCompletionRequest,
+        CreateChatCompletionRequestArgs, ResponseFormat,
+    },
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use time::OffsetDateTime;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::config::ProblemsConfig;
+use crate::error::ServiceError;
+use crate::pii::Redactor;
+use crate::quiz;
+use crate::session::TokenUsage;
+use crate::store::{self, Owned, atomic_write_json};
+use crate::structured;
+use crate::tenancy::{Owner, default_organization};
+
+const MAX_STATEMENT_CHARS: usize = 4000;
+const MAX_SOLUTION_CHARS: usize = 8000;
+pub const MAX_ANSWER_CHARS: usize = 1000;
+const MAX_HINT_CHARS: usize = 600;
+const MAX_FEEDBACK_CHARS: usize = 600;
+
+const HINTS_PROMPT: &str = "You write graded hints for a practice problem. Each hint gives a \
+little more help than the one before: the first only points at the idea to use, the last leaves \
+just the final step. No hint may state the final answer. Reply with a single JSON object and \
+nothing else, in this form:\n{\"hints\": [\"...\", \"...\"]}";
+
+const CHECK_PROMPT: &str = "You check a student's answer to a practice problem against the \
+reference solution. Accept answers that are equivalent to the reference, e.g. the same value in \
+another form. Give short feedback that says what is right or where to look again, without \
+revealing the answer or any step of the solution the student has not reached. Reply with a \
+single JSON object and nothing else, in this form:\n{\"correct\": true, \"feedback\": \"...\"}";
+
+const SOCRATIC_INSTRUCTION: &str = "The student is working on the practice problem below. Tutor \
+Socratically: answer with guiding questions and point out mistakes, but never give the final \
+answer or write out the solution, even if asked. Build on the hints they have already been given \
+and do not go beyond the last of them. Tell them they can ask for the next hint or submit an \
+answer to have it checked.";
+
+/// A problem as registered, before hints are written.
+#[derive(Debug, Clone)]
+pub struct ProblemDraft {
+    pub statement: String,
+    /// Worked solution; shown only once the problem is closed.
+    pub solution: String,
+    /// Final answer to match attempts against before asking the model.
+    pub answer: Option<String>,
+    /// Hints from the client; the model writes them when empty.
+    pub hints: Vec<String>,
+}
+
+/// A checked attempt at the answer.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Attempt {
+    #[serde(with = "time::serde::rfc3339")]
+    #[schema(value_type = String, format = DateTime)]
+    pub submitted_at: OffsetDateTime,
+    pub answer: String,
+    pub correct: bool,
+    pub feedback: String,
+}
+
+/// A practice problem with its hidden solution, hint ladder and attempts.
+/// Kept as `<problem_id>.json` in the problem directory.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Problem {
+    problem_id: String,
+    #[serde(default = "default_organization")]
+    organization: String,
+    student_id: String,
+    session_id: String,
+    #[serde(with = "time::serde::rfc3339")]
+    created_at: OffsetDateTime,
+    statement: String,
+    solution: String,
+    answer: Option<String>,
+    hints: Vec<String>,
+    /// Hints shown to the student so far.
+    revealed: usize,
+    attempts: Vec<Attempt>,
+}
+
+impl Owned for Problem {
+    fn organization(&self) -> &str {
+        &self.organization
+    }
+
+    fn student_id(&self) -> &str {
+        &self.student_id
+    }
+}
+
+/// A problem as shown to the student: the hints reached so far, and the
+/// solution once it is solved or the attempts have run out.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ProblemView {
+    pub problem_id: String,
+    pub session_id: String,
+    pub statement: String,
+    /// Hints revealed so far, easiest first.
+    pub hints: Vec<String>,
+    pub hints_total: usize,
+    /// Checked attempts, oldest first.
+    pub attempts: Vec<Attempt>,
+    pub solved: bool,
+    /// Wrong attempts left before the solution is shown; 0 once it is.
+    pub attempts_left: usize,
+    /// The worked solution; `null` until the problem is closed.
+    pub solution: Option<String>,
+}
+
+impl Problem {
+    fn solved(&self) -> bool {
+        self.attempts.iter().any(|attempt| attempt.correct)
+    }
+
+    fn wrong_attempts(&self) -> usize {
+        self.attempts
+            .iter()
+            .filter(|attempt| !attempt.correct)
+            .count()
+    }
+
+    /// Still being worked on: neither solved nor given away.
+    pub fn is_open(&self, reveal_after: usize) -> bool {
+        !self.solved() && self.wrong_attempts() < reveal_after
+    }
+
+    pub fn view(&self, reveal_after: usize) -> ProblemView {
+        let open = self.is_open(reveal_after);
+        ProblemView {
+            problem_id: self.problem_id.clone(),
+            session_id: self.session_id.clone(),
+            statement: self.statement.clone(),
+            hints: self.hints[..self.revealed].to_vec(),
+            hints_total: self.hints.len(),
+            attempts: self.attempts.clone(),
+            solved: self.solved(),
+            attempts_left: if open {
+                reveal_after - self.wrong_attempts()
+            } else {
+                0
+            },
+            solution: (!open).then(|| self.solution.clone()),
+        }
+    }
+
+    /// Put the tutor into hint-ladder mode for a turn about this problem.
+    /// The solution is left out so that no reply can give it away.
+    pub fn instruct(&self, messages: &mut Vec<ChatCompletionRequestMessage>) {
+        let mut content = format!("{}\n\nProblem:\n{}", SOCRATIC_INSTRUCTION, self.statement);
+        for (level, hint) in self.hints[..self.revealed].iter().enumerate() {
+            content.push_str(&format!("\n\nHint {}: {}", level + 1, hint));
+        }
+        messages.push(
+            ChatCompletionRequestSystemMessageArgs::default()
+                .content(content)
+                .build()
+                .expect("text system message always builds")
+                .into(),
+        );
+    }
+
+    /// An answer that matches the reference answer, so the model need not
+    /// be asked.
+    pub fn matches(&self, answer: &str) -> bool {
+        self.answer
+            .as_deref()
+            .is_some_and(|expected| quiz::same_answer(answer, expected))
+    }
+}
+
+/// Why a registered problem cannot be used.
+pub fn check_draft(draft: &ProblemDraft, max_hints: usize) -> Result<(), String> {
+    check_text("statement", &draft.statement, MAX_STATEMENT_CHARS)?;
+    check_text("solution", &draft.solution, MAX_SOLUTION_CHARS)?;
+    if let Some(answer) = &draft.answer {
+        check_text("answer", answer, MAX_ANSWER_CHARS)?;
+    }
+    if draft.hints.len() > max_hints {
+        return Err(format!("at most {} hints are allowed", max_hints));
+    }
+    for hint in &draft.hints {
+        check_text("hint", hint, MAX_HINT_CHARS)?;
+    }
+    Ok(())
+}
+
+fn check_text(what: &str, text: &str, max_chars: usize) -> Result<(), String> {
+    if text.trim().is_empty() {
+        return Err(format!("{} cannot be empty", what));
+    }
+    if text.chars().count() > max_chars {
+        return Err(format!("{} exceeds {} characters", what, max_chars));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+struct GeneratedHints {
+    hints: Vec<String>,
+}
+
+/// The model's verdict on an attempt.
+#[derive(Debug, Deserialize)]
+pub struct Verdict {
+    pub correct: bool,
+    pub feedback: String,
+}
+
+/// A problem being registered, prepared under the controller lock. When
+/// the client gave no hints, `run` asks the model for them without it.
+pub struct PendingProblem {
+    pub client: Client<OpenAIConfig>,
+    /// `None` when the draft already has its hints.
+    pub request: Option<CreateChatCompletionRequest>,
+    pub max_attempts: usize,
+    pub levels: usize,
+    pub owner: Owner,
+    pub session_id: String,
+    pub draft: ProblemDraft,
+}
+
+impl PendingProblem {
+    /// The upstream request for `levels` hints on a problem.
+    pub fn request(
+        model: &str,
+        max_tokens: u32,
+        draft: &ProblemDraft,
+        levels: usize,
+    ) -> Result<CreateChatCompletionRequest> {
+        let ask = format!(
+            "Write exactly {} hints for this problem.\n\nProblem:\n{}\n\nWorked solution:\n{}",
+            levels, draft.statement, draft.solution
+        );
+        Ok(CreateChatCompletionRequestArgs::default()
+            .model(model)
+            .messages([
+                ChatCompletionRequestSystemMessageArgs::default()
+                    .content(HINTS_PROMPT)
+                    .build()?
+                    .into(),
+                ChatCompletionRequestUserMessageArgs::default()
+                    .content(ask)
+                    .build()?
+                    .into(),
+            ])
+            .response_format(ResponseFormat::JsonObject)
+            .temperature(0.3)
+            .max_tokens(max_tokens)
+            .build()?)
+    }
+
+    /// Write the hints if needed, adding the tokens spent to `usage`.
+    pub async fn run(self, usage: &mut TokenUsage) -> Result<Problem> {
+        let mut draft = self.draft;
+        if let Some(request) = self.request {
+            let levels = self.levels;
+            draft.hints =
+                structured::complete(&self.client, request, self.max_attempts, usage, |reply| {
+                    parse_hints(reply, levels)
+                })
+                .await?;
+        }
+        Ok(Problem {
+            problem_id: Uuid::new_v4().to_string(),
+            organization: self.owner.organization,
+            student_id: self.owner.student_id,
+            session_id: self.session_id,
+            created_at: OffsetDateTime::now_utc(),
+            statement: draft.statement,
+            solution: draft.solution,
+            answer: draft.answer,
+            hints: draft.hints,
+            revealed: 0,
+            attempts: Vec::new(),
+        })
+    }
+}
+
+fn parse_hints(reply: &str, levels: usize) -> Result<Vec<String>> {
+    let generated: GeneratedHints =
+        serde_json::from_str(reply).context("reply is not hints JSON")?;
+    if generated.hints.len() != levels {
+        bail!(
+            "asked for {} hints but got {}",
+            levels,
+            generated.hints.len()
+        );
+    }
+    for (level, hint) in generated.hints.iter().enumerate() {
+        check_text("hint", hint, MAX_HINT_CHARS)
+            .map_err(|problem| anyhow!("hint {}: {}", level + 1, problem))?;
+    }
+    Ok(generated.hints)
+}
+
+/// An attempt being checked. An answer matching the reference answer is
+/// accepted without asking the model.
+pub struct PendingCheck {
+    pub client: Client<OpenAIConfig>,
+    /// `None` when the attempt already matched.
+    pub request: Option<CreateChatCompletionRequest>,
+    /// Placeholders used in `request`, to be swapped back in the feedback.
+    pub redactor: Option<Redactor>,
+    pub max_attempts: usize,
+    pub owner: Owner,
+    pub problem_id: String,
+    pub answer: String,
+}
+
+impl PendingCheck {
+    /// The upstream request asking whether `answer` solves the problem.
+    pub fn request(
+        model: &str,
+        max_tokens: u32,
+        problem: &Problem,
+        answer: &str,
+    ) -> Result<CreateChatCompletionRequest> {
+        let mut ask = format!(
+            "Problem:\n{}\n\nReference solution:\n{}",
+            problem.statement, problem.solution
+        );
+        if let Some(expected) = &problem.answer {
+            ask.push_str(&format!("\n\nFinal answer: {}", expected));
+        }
+        ask.push_str(&format!("\n\nStudent's answer:\n{}", answer));
+        Ok(CreateChatCompletionRequestArgs::default()
+            .model(model)
+            .messages([
+                ChatCompletionRequestSystemMessageArgs::default()
+                    .content(CHECK_PROMPT)
+                    .build()?
+                    .into(),
+                ChatCompletionRequestUserMessageArgs::default()
+                    .content(ask)
+                    .build()?
+                    .into(),
+            ])
+            .response_format(ResponseFormat::JsonObject)
+            .temperature(0.0)
+            .max_tokens(max_tokens)
+            .build()?)
+    }
+
+    /// Check the attempt, adding the tokens spent to `usage`.
+    pub async fn run(self, usage: &mut TokenUsage) -> Result<CheckedAttempt> {
+        let verdict = match self.request {
+            Some(request) => {
+                let mut verdict = structured::complete(
+                    &self.client,
+                    request,
+                    self.max_attempts,
+                    usage,
+                    parse_verdict,
+                )
+                .await?;
+                if let Some(redactor) = &self.redactor {
+                    verdict.feedback = redactor.restore(&verdict.feedback);
+                }
+                verdict
+            }
+            None => Verdict {
+                correct: true,
+                feedback: "Correct!".to_string(),
+            },
+        };
+        Ok(CheckedAttempt {
+            owner: self.owner,
+            problem_id: self.problem_id,
+            answer: self.answer,
+            verdict,
+        })
+    }
+}
+
+/// An attempt with its verdict, ready to be recorded.
+pub struct CheckedAttempt {
+    pub owner: Owner,
+    pub problem_id: String,
+    pub answer: String,
+    pub verdict: Verdict,
+}
+
+fn parse_verdict(reply: &str) -> Result<Verdict> {
+    let verdict: Verdict = serde_json::from_str(reply).context("reply is not verdict JSON")?;
+    check_text("feedback", &verdict.feedback, MAX_FEEDBACK_CHARS)
+        .map_err(|problem| anyhow!(problem))?;
+    Ok(verdict)
+}
+
+/// Every problem, kept in memory and written through to one file each.
+pub struct ProblemStore {
+    dir: PathBuf,
+    reveal_after: usize,
+    problems: HashMap<String, Problem>,
+}
+
+impl ProblemStore {
+    pub fn open(config: &ProblemsConfig) -> Result<Self> {
+        fs::create_dir_all(&config.dir)
+            .with_context(|| format!("failed to create {}", config.dir.display()))?;
+        let mut problems = HashMap::new();
+        for entry in fs::read_dir(&config.dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            match read_problem(&path) {
+                Ok(problem) => {
+                    problems.insert(problem.problem_id.clone(), problem);
+                }
+                Err(err) => eprintln!("Skipping problem {}: {:#}", path.display(), err),
+            }
+        }
+        Ok(Self {
+            dir: config.dir.clone(),
+            reveal_after: config.reveal_after_attempts,
+            problems,
+        })
+    }
+
+    pub fn add(&mut self, problem: Problem) -> Result<ProblemView> {
+        self.write(&problem)?;
+        let view = problem.view(self.reveal_after);
+        self.problems.insert(problem.problem_id.clone(), problem);
+        Ok(view)
+    }
+
+    /// A student's own problem; other students' problems, and problems of
+    /// the same student id in another organization, are not found.
+    pub fn get(&self, owner: &Owner, problem_id: &str) -> Result<&Problem> {
+        store::get_owned(&self.problems, owner, problem_id).ok_or_else(|| {
+            ServiceError::NotFound(format!("Problem {} not found", problem_id)).into()
+        })
+    }
+
+    pub fn view(&self, owner: &Owner, problem_id: &str) -> Result<ProblemView> {
+        Ok(self.get(owner, problem_id)?.view(self.reveal_after))
+    }
+
+    /// The newest open problem of a session, which its turns are about.
+    pub fn active(&self, owner: &Owner, session_id: &str) -> Option<&Problem> {
+        store::owned_by(&self.problems, owner)
+            .filter(|problem| problem.session_id == session_id)
+            .filter(|problem| problem.is_open(self.reveal_after))
+            .max_by_key(|problem| problem.created_at)
+    }
+
+    /// Show the next hint. Fails when every hint is already shown.
+    pub fn reveal_hint(&mut self, owner: &Owner, problem_id: &str) -> Result<ProblemView> {
+        let mut problem = self.get(owner, problem_id)?.clone();
+        if problem.revealed == problem.hints.len() {
+            bail!("Problem {} has no more hints", problem_id);
+        }
+        problem.revealed += 1;
+        self.replace(problem)
+    }
+
+    /// Record a checked attempt.
+    pub fn record_attempt(&mut self, checked: CheckedAttempt) -> Result<ProblemView> {
+        let mut problem = self.get(&checked.owner, &checked.problem_id)?.clone();
+        problem.attempts.push(Attempt {
+            submitted_at: OffsetDateTime::now_utc(),
+            answer: checked.answer,
+            correct: checked.verdict.correct,
+            feedback: checked.verdict.feedback,
+        });
+        self.replace(problem)
+    }
+
+    fn replace(&mut self, problem: Problem) -> Result<ProblemView> {
+        self.write(&problem)?;
+        let view = problem.view(self.reveal_after);
+        self.problems.insert(problem.problem_id.clone(), problem);
+        Ok(view)
+    }
+
+    fn write(&self, problem: &Problem) -> Result<()> {
+        let path = self.dir.join(format!("{}.json", problem.problem_id));
+        atomic_write_json(&path, problem)
+    }
+}
+
+fn read_problem(path: &Path) -> Result<Problem> {
+    Ok(serde_json::from_slice(&fs::read(path)?)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TempDir};
+
+    fn problem() -> Problem {
+        Problem {
+            problem_id: "p1".to_string(),
+            organization: default_organization(),
+            student_id: "s1".to_string(),
+            session_id: "session".to_string(),
+            created_at: OffsetDateTime::UNIX_EPOCH,
+            statement: "Solve 2x + 3 = 11.".to_string(),
+            solution: "Subtract 3 to get 2x = 8, then divide by 2: x = 4.".to_string(),
+            answer: Some("4".to_string()),
+            hints: vec![
+                "Undo the +3 first.".to_string(),
+                "Now divide by 2.".to_string(),
+            ],
+            revealed: 1,
+            attempts: Vec::new(),
+        }
+    }
+
+    fn attempt(correct: bool) -> Attempt {
+        Attempt {
+            submitted_at: OffsetDateTime::UNIX_EPOCH,
+            answer: "x".to_string(),
+            correct,
+            feedback: String::new(),
+        }
+    }
+
+    #[test]
+    fn shows_the_solution_only_once_closed() {
+        let mut problem = problem();
+        let view = problem.view(2);
+        assert_eq!(view.hints, ["Undo the +3 first."]);
+        assert_eq!(view.hints_total, 2);
+        assert_eq!(view.attempts_left, 2);
+        assert!(view.solution.is_none());
+
+        problem.attempts.push(attempt(false));
+        assert!(problem.is_open(2));
+        problem.attempts.push(attempt(false));
+        let view = problem.view(2);
+        assert!(!view.solved);
+        assert_eq!(view.attempts_left, 0);
+        assert!(view.solution.is_some());
+
+        let mut problem = self::problem();
+        problem.attempts.push(attempt(true));
+        assert!(!problem.is_open(2));
+        assert!(problem.view(2).solved);
+    }
+
+    #[test]
+    fn matches_the_reference_answer_and_checks_hint_replies() {
+        let problem = problem();
+        assert!(problem.matches(" 4.0"));
+        assert!(!problem.matches("x = 5"));
+
+        assert_eq!(parse_hints(r#"{"hints": ["a", "b"]}"#, 2).unwrap().len(), 2);
+        assert!(parse_hints(r#"{"hints": ["a"]}"#, 2).is_err());
+        assert!(parse_hints(r#"{"hints": ["a", " "]}"#, 2).is_err());
+    }
+
+    #[test]
+    fn keeps_the_solution_out_of_tutoring_turns() {
+        let mut messages = Vec::new();
+        problem().instruct(&mut messages);
+        let (_, text) = crate::session::message_text(&messages[0]);
+        assert!(text.contains("Hint 1: Undo the +3 first."));
+        assert!(!text.contains("Now divide by 2."));
+        assert!(!text.contains("x = 4"));
+    }
+
+    #[test]
+    fn problems_stay_in_their_organization() {
+        let dir = TempDir::new("problems");
+        let mut config = crate::config::Config::for_tests().problems;
+        config.dir = dir.path().to_path_buf();
+        let mut store = ProblemStore::open(&config).unwrap();
+        store.add(problem()).unwrap();
+        let owner = |organization| testing::owner(organization, "s1");
+
+        assert!(store.view(&owner("default"), "p1").is_ok());
+        assert!(store.view(&owner("northside"), "p1").is_err());
+        assert!(store.reveal_hint(&owner("northside"), "p1").is_err());
+        assert!(store.active(&owner("northside"), "session").is_none());
+        assert!(store.active(&owner("default"), "session").is_some());
+    }
+}
@@ -0,0 +1,489 @@
+use anyhow::{Context, Result, bail};
+use async_openai::{
+    Client,
+    config::OpenAIConfig,
+    types::{
+        ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestUserMessageArgs,
+        CreateChatCompletionRequest, CreateChatCompletionRequestArgs, ResponseFormat,
+    },
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use time::OffsetDateTime;
+use utoipa::ToSchema;
+
+use crate::config::ProfileConfig;
+use crate::error::ServiceError;
+use crate::pii::Redactor;
+use crate::session::TokenUsage;
+use crate::store::atomic_write_json;
+use crate::structured;
+use crate::tenancy::{Owner, default_organization};
+
+/// Longest grade, style, goal or misconception, in characters.
+pub const MAX_FIELD_CHARS: usize = 300;
+/// Most goals and misconceptions kept; the oldest are dropped first.
+pub const MAX_LIST_ITEMS: usize = 10;
+
+const EXTRACTION_PROMPT: &str = "You keep notes on a student for their tutor, so that later \
+sessions can start where this one left off. From the tutoring session, note only what the \
+student said or clearly showed about themselves: their grade or level, what they want to \
+achieve, how they like things explained, and misconceptions they showed that were not cleared \
+up. Also list any misconceptions from the current notes that the student got right this time. \
+Leave out guesses and anything already in the current notes. Reply with a single JSON object \
+and nothing else, in this form:\n\
+{\"grade\": null, \"explanation_style\": null, \"goals\": [], \"misconceptions\": [], \
+\"resolved_misconceptions\": []}\n\
+Use null or an empty list when the session says nothing new. `resolved_misconceptions` repeats \
+entries from the current notes word for word.";
+
+/// What the tutor remembers about a student between sessions. Kept as
+/// `<Owner::file_stem>.json` in the profile directory.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct StudentProfile {
+    /// Organization the student belongs to.
+    #[serde(default = "default_organization")]
+    pub organization: String,
+    pub student_id: String,
+    /// School grade or level, in the student's words, e.g. "Year 10".
+    pub grade: Option<String>,
+    /// What the student is working towards, oldest first.
+    pub goals: Vec<String>,
+    /// How the student likes things explained.
+    pub explanation_style: Option<String>,
+    /// Mistaken ideas the tutor should watch for, oldest first.
+    pub misconceptions: Vec<String>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    #[schema(value_type = Option<String>, format = DateTime)]
+    pub updated_at: Option<OffsetDateTime>,
+}
+
+/// Body of `PATCH /api/v1/students/{student_id}/profile`. Fields left out
+/// are kept; an empty string or list clears the field.
+#[derive(Debug, Default, Deserialize, ToSchema)]
+pub struct ProfileUpdate {
+    #[serde(default)]
+    pub grade: Option<String>,
+    #[serde(default)]
+    pub goals: Option<Vec<String>>,
+    #[serde(default)]
+    pub explanation_style: Option<String>,
+    #[serde(default)]
+    pub misconceptions: Option<Vec<String>>,
+}
+
+/// What the model noticed in one session, to be merged into the profile.
+#[derive(Debug, Default, Deserialize)]
+pub struct ProfileNotes {
+    #[serde(default)]
+    grade: Option<String>,
+    #[serde(default)]
+    explanation_style: Option<String>,
+    #[serde(default)]
+    goals: Vec<String>,
+    #[serde(default)]
+    misconceptions: Vec<String>,
+    #[serde(default)]
+    resolved_misconceptions: Vec<String>,
+}
+
+impl StudentProfile {
+    fn new(owner: &Owner) -> Self {
+        Self {
+            organization: owner.organization.clone(),
+            student_id: owner.student_id.clone(),
+            grade: None,
+            goals: Vec::new(),
+            explanation_style: None,
+            misconceptions: Vec::new(),
+            updated_at: None,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.grade.is_none()
+            && self.goals.is_empty()
+            && self.explanation_style.is_none()
+            && self.misconceptions.is_empty()
+    }
+
+    /// The profile as a section of the system prompt, or `None` when
+    /// nothing is known about the student yet.
+    pub fn render(&self) -> Option<String> {
+        if self.is_empty() {
+            return None;
+        }
+        let mut section = "# About this student\nRemembered from earlier sessions. Use it \
+                           instead of asking again, and check whether it still holds."
+            .to_string();
+        if let Some(grade) = &self.grade {
+            section.push_str(&format!("\n- Grade or level: {}", grade));
+        }
+        if !self.goals.is_empty() {
+            section.push_str(&format!("\n- Goals: {}", self.goals.join("; ")));
+        }
+        if let Some(style) = &self.explanation_style {
+            section.push_str(&format!("\n- Preferred explanation style: {}", style));
+        }
+        if !self.misconceptions.is_empty() {
+            section.push_str(&format!(
+                "\n- Misconceptions to watch for: {}",
+                self.misconceptions.join("; ")
+            ));
+        }
+        Some(section)
+    }
+
+    fn apply(&mut self, update: ProfileUpdate) {
+        if let Some(grade) = update.grade {
+            self.grade = non_empty(grade);
+        }
+        if let Some(style) = update.explanation_style {
+            self.explanation_style = non_empty(style);
+        }
+        if let Some(goals) = update.goals {
+            self.goals = Vec::new();
+            add_items(&mut self.goals, goals);
+        }
+        if let Some(misconceptions) = update.misconceptions {
+            self.misconceptions = Vec::new();
+            add_items(&mut self.misconceptions, misconceptions);
+        }
+    }
+
+    /// Merge notes from a session: new grade and style replace the old
+    /// ones, new goals and misconceptions are added and resolved
+    /// misconceptions removed.
+    fn merge(&mut self, notes: ProfileNotes) {
+        if let Some(grade) = notes.grade.and_then(non_empty) {
+            self.grade = Some(grade);
+        }
+        if let Some(style) = notes.explanation_style.and_then(non_empty) {
+            self.explanation_style = Some(style);
+        }
+        self.misconceptions.retain(|item| {
+            !notes
+                .resolved_misconceptions
+                .iter()
+                .any(|resolved| same_item(item, resolved))
+        });
+        add_items(&mut self.goals, notes.goals);
+        add_items(&mut self.misconceptions, notes.misconceptions);
+    }
+}
+
+fn non_empty(text: String) -> Option<String> {
+    let text = text.trim();
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+fn same_item(a: &str, b: &str) -> bool {
+    a.trim().eq_ignore_ascii_case(b.trim())
+}
+
+/// Append the items not already in `list`, then drop the oldest past
+/// `MAX_LIST_ITEMS`.
+fn add_items(list: &mut Vec<String>, items: Vec<String>) {
+    for item in items.into_iter().filter_map(non_empty) {
+        if !list.iter().any(|known| same_item(known, &item)) {
+            list.push(item);
+        }
+    }
+    let excess = list.len().saturating_sub(MAX_LIST_ITEMS);
+    list.drain(..excess);
+}
+
+/// Why an explicit update cannot be saved, if it cannot.
+pub fn check_update(update: &ProfileUpdate) -> Result<(), String> {
+    let fields = update.grade.iter().chain(update.explanation_style.iter());
+    let items = update.goals.iter().chain(update.misconceptions.iter());
+    if items.clone().any(|list| list.len() > MAX_LIST_ITEMS) {
+        return Err(format!(
+            "goals and misconceptions hold at most {} items",
+            MAX_LIST_ITEMS
+        ));
+    }
+    if fields
+        .chain(items.flatten())
+        .any(|text| text.chars().count() > MAX_FIELD_CHARS)
+    {
+        return Err(format!(
+            "profile entries must be at most {} characters",
+            MAX_FIELD_CHARS
+        ));
+    }
+    Ok(())
+}
+
+/// The notes in a model reply, checked against the profile limits.
+fn parse(reply: &str) -> Result<ProfileNotes> {
+    let notes: ProfileNotes = serde_json::from_str(reply).context("reply is not profile JSON")?;
+    let lists = [
+        &notes.goals,
+        &notes.misconceptions,
+        &notes.resolved_misconceptions,
+    ];
+    if lists.iter().any(|list| list.len() > MAX_LIST_ITEMS) {
+        bail!("lists must hold at most {} items", MAX_LIST_ITEMS);
+    }
+    let too_long = notes
+        .grade
+        .iter()
+        .chain(notes.explanation_style.iter())
+        .chain(lists.into_iter().flatten())
+        .any(|text| text.chars().count() > MAX_FIELD_CHARS);
+    if too_long {
+        bail!("entries must be at most {} characters", MAX_FIELD_CHARS);
+    }
+    Ok(notes)
+}
+
+/// An end-of-session extraction prepared under the controller lock and
+/// sent without it.
+pub struct PendingProfile {
+    pub client: Client<OpenAIConfig>,
+    pub request: CreateChatCompletionRequest,
+    /// Placeholders used in `request`, to be swapped back in the notes.
+    pub redactor: Option<Redactor>,
+    pub max_attempts: usize,
+    pub owner: Owner,
+}
+
+impl PendingProfile {
+    /// The upstream request for notes on a session, given what the profile
+    /// already holds.
+    pub fn request(
+        model: &str,
+        max_tokens: u32,
+        current: &str,
+        transcript: &str,
+    ) -> Result<CreateChatCompletionRequest> {
+        let ask = format!(
+            "Current notes:\n{current}\n\nNote what is new in this tutoring session:\n\n{transcript}"
+        );
+        Ok(CreateChatCompletionRequestArgs::default()
+            .model(model)
+            .messages([
+                ChatCompletionRequestSystemMessageArgs::default()
+                    .content(EXTRACTION_PROMPT)
+                    .build()?
+                    .into(),
+                ChatCompletionRequestUserMessageArgs::default()
+                    .content(ask)
+                    .build()?
+                    .into(),
+            ])
+            .response_format(ResponseFormat::JsonObject)
+            .temperature(0.0)
+            .max_tokens(max_tokens)
+            .build()?)
+    }
+
+    /// Take the notes, adding the tokens spent to `usage`.
+    pub async fn run(self, usage: &mut TokenUsage) -> Result<(Owner, ProfileNotes)> {
+        let mut notes =
+            structured::complete(&self.client, self.request, self.max_attempts, usage, parse)
+                .await?;
+        if let Some(redactor) = &self.redactor {
+            let restore = |text: &mut String| *text = redactor.restore(text);
+            notes.grade.iter_mut().for_each(restore);
+            notes.explanation_style.iter_mut().for_each(restore);
+            notes.goals.iter_mut().for_each(restore);
+            notes.misconceptions.iter_mut().for_each(restore);
+            notes.resolved_misconceptions.iter_mut().for_each(restore);
+        }
+        Ok((self.owner, notes))
+    }
+}
+
+/// Every student profile, kept in memory and written through to one file
+/// per student.
+pub struct ProfileStore {
+    dir: PathBuf,
+    profiles: HashMap<Owner, StudentProfile>,
+}
+
+impl ProfileStore {
+    pub fn open(config: &ProfileConfig) -> Result<Self> {
+        fs::create_dir_all(&config.dir)
+            .with_context(|| format!("failed to create {}", config.dir.display()))?;
+        let mut profiles = HashMap::new();
+        for entry in fs::read_dir(&config.dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            match read_profile(&path) {
+                Ok(profile) => {
+                    let owner = Owner {
+                        organization: profile.organization.clone(),
+                        student_id: profile.student_id.clone(),
+                    };
+                    profiles.insert(owner, profile);
+                }
+                Err(err) => eprintln!("Skipping profile {}: {:#}", path.display(), err),
+            }
+        }
+        Ok(Self {
+            dir: config.dir.clone(),
+            profiles,
+        })
+    }
+
+    /// A student's profile; empty for a student not seen before.
+    pub fn get(&self, owner: &Owner) -> StudentProfile {
+        self.profiles
+            .get(owner)
+            .cloned()
+            .unwrap_or_else(|| StudentProfile::new(owner))
+    }
+
+    pub fn update(&mut self, owner: &Owner, update: ProfileUpdate) -> Result<StudentProfile> {
+        self.change(owner, |profile| profile.apply(update))
+    }
+
+    pub fn merge(&mut self, owner: &Owner, notes: ProfileNotes) -> Result<StudentProfile> {
+        self.change(owner, |profile| profile.merge(notes))
+    }
+
+    pub fn remove(&mut self, owner: &Owner) -> Result<()> {
+        if self.profiles.remove(owner).is_none() {
+            return Err(ServiceError::NotFound(format!(
+                "Profile for {} not found",
+                owner.student_id
+            ))
+            .into());
+        }
+        let path = self.path(owner);
+        fs::remove_file(&path).with_context(|| format!("failed to remove {}", path.display()))
+    }
+
+    fn change(
+        &mut self,
+        owner: &Owner,
+        change: impl FnOnce(&mut StudentProfile),
+    ) -> Result<StudentProfile> {
+        let mut profile = self.get(owner);
+        change(&mut profile);
+        profile.updated_at = Some(OffsetDateTime::now_utc());
+        let path = self.path(owner);
+        atomic_write_json(&path, &profile)?;
+        self.profiles.insert(owner.clone(), profile.clone());
+        Ok(profile)
+    }
+
+    fn path(&self, owner: &Owner) -> PathBuf {
+        self.dir.join(format!("{}.json", owner.file_stem()))
+    }
+}
+
+fn read_profile(path: &Path) -> Result<StudentProfile> {
+    Ok(serde_json::from_slice(&fs::read(path)?)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TempDir};
+
+    fn strings(items: &[&str]) -> Vec<String> {
+        items.iter().map(|item| item.to_string()).collect()
+    }
+
+    fn owner(organization: &str) -> Owner {
+        testing::owner(organization, "s1")
+    }
+
+    #[test]
+    fn merges_session_notes_into_the_profile() {
+        let mut profile = StudentProfile::new(&owner("default"));
+        profile.grade = Some("Year 9".to_string());
+        profile.misconceptions = strings(&["thinks 0.5 < 0.25", "confuses mass and weight"]);
+
+        profile.merge(ProfileNotes {
+            grade: Some("Year 10".to_string()),
+            explanation_style: Some("  ".to_string()),
+            goals: strings(&["pass the GCSE maths exam", ""]),
+            misconceptions: strings(&["Confuses mass and weight", "divides by zero"]),
+            resolved_misconceptions: strings(&["THINKS 0.5 < 0.25"]),
+        });
+
+        assert_eq!(profile.grade.as_deref(), Some("Year 10"));
+        assert_eq!(profile.explanation_style, None);
+        assert_eq!(profile.goals, ["pass the GCSE maths exam"]);
+        assert_eq!(
+            profile.misconceptions,
+            ["confuses mass and weight", "divides by zero"]
+        );
+    }
+
+    #[test]
+    fn explicit_updates_replace_and_clear_fields() {
+        let mut profile = StudentProfile::new(&owner("default"));
+        profile.goals = strings(&["learn calculus"]);
+        profile.explanation_style = Some("lots of diagrams".to_string());
+        profile.apply(ProfileUpdate {
+            grade: Some("University".to_string()),
+            goals: None,
+            explanation_style: Some(String::new()),
+            misconceptions: Some(Vec::new()),
+        });
+        assert_eq!(profile.grade.as_deref(), Some("University"));
+        assert_eq!(profile.goals, ["learn calculus"]);
+        assert_eq!(profile.explanation_style, None);
+
+        let long = ProfileUpdate {
+            grade: Some("x".repeat(MAX_FIELD_CHARS + 1)),
+            ..ProfileUpdate::default()
+        };
+        assert!(check_update(&long).is_err());
+    }
+
+    #[test]
+    fn renders_only_what_is_known() {
+        let mut profile = StudentProfile::new(&owner("default"));
+        assert_eq!(profile.render(), None);
+        profile.grade = Some("Year 7".to_string());
+        profile.misconceptions = strings(&["a", "b"]);
+        let section = profile.render().unwrap();
+        assert!(section.contains("- Grade or level: Year 7"));
+        assert!(section.contains("- Misconceptions to watch for: a; b"));
+        assert!(!section.contains("Goals"));
+    }
+
+    #[test]
+    fn keeps_the_newest_list_items() {
+        let mut goals = Vec::new();
+        add_items(
+            &mut goals,
+            (0..MAX_LIST_ITEMS + 2).map(|n| n.to_string()).collect(),
+        );
+        assert_eq!(goals.len(), MAX_LIST_ITEMS);
+        assert_eq!(goals[0], "2");
+    }
+
+    #[test]
+    fn profiles_stay_in_their_organization() {
+        let dir = TempDir::new("profiles");
+        let mut config = crate::config::Config::for_tests().profile;
+        config.dir = dir.path().to_path_buf();
+        let mut store = ProfileStore::open(&config).unwrap();
+        let update = ProfileUpdate {
+            grade: Some("Year 9".to_string()),
+            ..ProfileUpdate::default()
+        };
+        store.update(&owner("northside"), update).unwrap();
+
+        assert_eq!(
+            store.get(&owner("northside")).grade.as_deref(),
+            Some("Year 9")
+        );
+        assert_eq!(store.get(&owner("default")).grade, None);
+        assert!(store.remove(&owner("default")).is_err());
+        let mut store = ProfileStore::open(&config).unwrap();
+        assert_eq!(store.get(&owner("default")).grade, None);
+        store.remove(&owner("northside")).unwrap();
+    }
+}
@@ -0,0 +1,513 @@
+use anyhow::{Context, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use time::{Duration, OffsetDateTime};
+use utoipa::ToSchema;
+
+use crate::config::ProgressConfig;
+use crate::store::atomic_write_json;
+use crate::tenancy::{Owner, default_organization};
+
+/// Most topics a single turn is tagged with.
+const MAX_TOPICS_PER_TURN: usize = 3;
+/// Topics and quiz scores named in the summary given to the tutor.
+const SUMMARY_TOPICS: usize = 8;
+const SUMMARY_QUIZZES: usize = 5;
+/// Quiz scores kept per student, newest kept.
+const MAX_QUIZ_SCORES: usize = 200;
+
+struct Topic {
+    name: &'static str,
+    pattern: Regex,
+}
+
+impl Topic {
+    fn new(name: &'static str, keywords: &str) -> Self {
+        Self {
+            name,
+            pattern: Regex::new(&format!(r"(?i)\b(?:{keywords})\b"))
+                .expect("topic pattern compiles"),
+        }
+    }
+}
+
+/// Keyword classifier that tags a turn with the school topics it touches.
+/// Topics are ranked by how many of their keywords appear.
+pub struct TopicClassifier {
+    topics: Vec<Topic>,
+}
+
+impl TopicClassifier {
+    pub fn new() -> Self {
+        Self {
+            topics: vec![
+                Topic::new(
+                    "arithmetic",
+                    r"fractions?|decimals?|percent(?:age)?s?|long division|multiplication|remainders?|prime numbers?|lcm|gcd",
+                ),
+                Topic::new(
+                    "algebra",
+                    r"algebra(?:ic)?|equations?|quadratics?|polynomials?|variables?|inequalit(?:y|ies)|factori[sz]e|linear|exponents?|logarithms?",
+                ),
+                Topic::new(
+                    "geometry",
+                    r"geometry|triangles?|circles?|angles?|polygons?|area|perimeter|volume|pythagoras|pythagorean|congruen(?:t|ce)",
+                ),
+                Topic::new(
+                    "trigonometry",
+                    r"trigonometry|sine|cosine|tangent|sin|cos|tan|radians?|unit circle",
+                ),
+                Topic::new(
+                    "calculus",
+                    r"calculus|derivatives?|differentiat(?:e|ion)|integrals?|integrat(?:e|ion)|limits?|chain rule",
+                ),
+                Topic::new(
+                    "statistics and probability",
+                    r"statistics?|probabilit(?:y|ies)|mean|median|mode|variance|standard deviation|distributions?|random",
+                ),
+                Topic::new(
+                    "physics",
+                    r"physics|newton(?:'s)?|forces?|velocity|acceleration|momentum|energy|gravity|friction|electricity|circuits?|waves?",
+                ),
+                Topic::new(
+                    "chemistry",
+                    r"chemistry|chemical|atoms?|molecules?|elements?|periodic table|reactions?|acids?|bases|moles?|bonds?|electrons?",
+                ),
+                Topic::new(
+                    "biology",
+                    r"biology|cells?|mitosis|meiosis|dna|genes?|genetics|evolution|photosynthesis|organisms?|ecosystems?|proteins?",
+                ),
+                Topic::new(
+                    "history",
+                    r"history|historical|wars?|wwi|wwii|revolution|empires?|civili[sz]ations?|medieval|ancient|dynast(?:y|ies)",
+                ),
+                Topic::new(
+                    "geography",
+                    r"geography|continents?|climate|rivers?|mountains?|maps?|latitude|longitude|population|plate tectonics",
+                ),
+                Topic::new(
+                    "economics",
+                    r"economics?|economy|supply|demand|inflation|markets?|gdp|interest rates?|taxe?s?",
+                ),
+                Topic::new(
+                    "literature",
+                    r"literature|novels?|poems?|poetry|shakespeare|characters?|themes?|metaphors?|narrators?",
+                ),
+                Topic::new(
+                    "grammar and writing",
+                    r"grammar|sentences?|verbs?|nouns?|adjectives?|punctuation|essays?|paragraphs?|thesis|spelling",
+                ),
+                Topic::new(
+                    "programming",
+                    r"programming|code|functions?|loops?|arrays?|recursion|algorithms?|python|rust|javascript|compiler|bugs?",
+                ),
+            ],
+        }
+    }
+
+    /// Topics the text touches, most keyword matches first.
+    pub fn classify(&self, text: &str) -> Vec<&'static str> {
+        let mut scored: Vec<(usize, &'static str)> = self
+            .topics
+            .iter()
+            .map(|topic| (topic.pattern.find_iter(text).count(), topic.name))
+            .filter(|(hits, _)| *hits > 0)
+            .collect();
+        scored.sort_by_key(|(hits, _)| std::cmp::Reverse(*hits));
+        scored
+            .into_iter()
+            .take(MAX_TOPICS_PER_TURN)
+            .map(|(_, name)| name)
+            .collect()
+    }
+}
+
+/// Time and turns in one session.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SessionActivity {
+    #[serde(with = "time::serde::rfc3339")]
+    started_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    last_active_at: OffsetDateTime,
+    turns: usize,
+    /// Sum of the gaps between activity, leaving out idle gaps.
+    active_seconds: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct TopicProgress {
+    pub topic: String,
+    /// Turns tagged with the topic.
+    pub turns: usize,
+    #[serde(with = "time::serde::rfc3339")]
+    #[schema(value_type = String, format = DateTime)]
+    pub last_seen_at: OffsetDateTime,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct QuizScore {
+    pub quiz_id: String,
+    /// Topic the quiz was asked to focus on, if any.
+    pub topic: Option<String>,
+    pub score: usize,
+    pub total: usize,
+    #[serde(with = "time::serde::rfc3339")]
+    #[schema(value_type = String, format = DateTime)]
+    pub submitted_at: OffsetDateTime,
+}
+
+/// Everything tracked for one student. Kept as `<Owner::file_stem>.json`
+/// in the progress directory.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StudentProgress {
+    #[serde(default = "default_organization")]
+    organization: String,
+    student_id: String,
+    sessions: HashMap<String, SessionActivity>,
+    topics: BTreeMap<String, TopicProgress>,
+    /// Oldest first.
+    quiz_scores: Vec<QuizScore>,
+}
+
+/// A student's progress as returned by the API.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ProgressReport {
+    pub student_id: String,
+    pub sessions: usize,
+    /// Tutor replies across all sessions.
+    pub turns: usize,
+    /// Time between turns, leaving out gaps longer than
+    /// `progress.idle_minutes`.
+    pub time_spent_seconds: i64,
+    #[serde(with = "time::serde::rfc3339::option")]
+    #[schema(value_type = Option<String>, format = DateTime)]
+    pub first_session_at: Option<OffsetDateTime>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    #[schema(value_type = Option<String>, format = DateTime)]
+    pub last_active_at: Option<OffsetDateTime>,
+    /// Most practised first.
+    pub topics: Vec<TopicProgress>,
+    /// Quiz submissions, newest first.
+    pub quizzes: Vec<QuizScore>,
+    /// Share of quiz questions answered correctly, from 0 to 1.
+    pub quiz_accuracy: Option<f64>,
+}
+
+impl StudentProgress {
+    fn new(owner: &Owner) -> Self {
+        Self {
+            organization: owner.organization.clone(),
+            student_id: owner.student_id.clone(),
+            sessions: HashMap::new(),
+            topics: BTreeMap::new(),
+            quiz_scores: Vec::new(),
+        }
+    }
+
+    fn owner(&self) -> Owner {
+        Owner {
+            organization: self.organization.clone(),
+            student_id: self.student_id.clone(),
+        }
+    }
+
+    fn report(&self) -> ProgressReport {
+        let mut topics: Vec<TopicProgress> = self.topics.values().cloned().collect();
+        topics.sort_by(|a, b| {
+            b.turns
+                .cmp(&a.turns)
+                .then(b.last_seen_at.cmp(&a.last_seen_at))
+        });
+        let (right, asked) = self
+            .quiz_scores
+            .iter()
+            .fold((0, 0), |(right, asked), quiz| {
+                (right + quiz.score, asked + quiz.total)
+            });
+        ProgressReport {
+            student_id: self.student_id.clone(),
+            sessions: self.sessions.len(),
+            turns: self.sessions.values().map(|s| s.turns).sum(),
+            time_spent_seconds: self.sessions.values().map(|s| s.active_seconds).sum(),
+            first_session_at: self.sessions.values().map(|s| s.started_at).min(),
+            last_active_at: self.sessions.values().map(|s| s.last_active_at).max(),
+            topics,
+            quizzes: self.quiz_scores.iter().rev().cloned().collect(),
+            quiz_accuracy: (asked > 0).then(|| right as f64 / asked as f64),
+        }
+    }
+}
+
+impl ProgressReport {
+    /// What the tutor is told about the student at the start of a session,
+    /// or `None` for a student with no history yet.
+    pub fn summary(&self) -> Option<String> {
+        if self.turns == 0 && self.quizzes.is_empty() {
+            return None;
+        }
+        let minutes = self.time_spent_seconds / 60;
+        let mut summary = format!(
+            "# Student progress\nThis student has had {} earlier session{} with you ({} tutor \
+             replies, about {} minute{} of study).",
+            self.sessions,
+            plural(self.sessions),
+            self.turns,
+            minutes,
+            plural(minutes as usize),
+        );
+        if !self.topics.is_empty() {
+            let topics: Vec<String> = self
+                .topics
+                .iter()
+                .take(SUMMARY_TOPICS)
+                .map(|topic| {
+                    format!(
+                        "{} ({} turn{})",
+                        topic.topic,
+                        topic.turns,
+                        plural(topic.turns)
+                    )
+                })
+                .collect();
+            summary.push_str(&format!(
+                "\nTopics covered so far, most practised first: {}.",
+                topics.join(", ")
+            ));
+        }
+        if !self.quizzes.is_empty() {
+            let quizzes: Vec<String> = self
+                .quizzes
+                .iter()
+                .take(SUMMARY_QUIZZES)
+                .map(|quiz| match &quiz.topic {
+                    Some(topic) => format!("{}/{} on {}", quiz.score, quiz.total, topic),
+                    None => format!("{}/{}", quiz.score, quiz.total),
+                })
+                .collect();
+            summary.push_str(&format!(
+                "\nRecent practice quiz scores, newest first: {}.",
+                quizzes.join(", ")
+            ));
+        }
+        summary.push_str(
+            "\nBuild on what the student has already covered, and revisit topics where their \
+             quiz scores are low.",
+        );
+        Some(summary)
+    }
+}
+
+fn plural(count: usize) -> &'static str {
+    if count == 1 { "" } else { "s" }
+}
+
+/// Every student's progress, kept in memory and written through to one
+/// file per student.
+pub struct ProgressStore {
+    dir: PathBuf,
+    idle: Duration,
+    students: HashMap<Owner, StudentProgress>,
+}
+
+impl ProgressStore {
+    pub fn open(config: &ProgressConfig) -> Result<Self> {
+        fs::create_dir_all(&config.dir)
+            .with_context(|| format!("failed to create {}", config.dir.display()))?;
+        let mut students = HashMap::new();
+        for entry in fs::read_dir(&config.dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            match read_progress(&path) {
+                Ok(progress) => {
+                    students.insert(progress.owner(), progress);
+                }
+                Err(err) => eprintln!("Skipping progress {}: {:#}", path.display(), err),
+            }
+        }
+        Ok(Self {
+            dir: config.dir.clone(),
+            idle: Duration::minutes(config.idle_minutes as i64),
+            students,
+        })
+    }
+
+    pub fn report(&self, owner: &Owner) -> ProgressReport {
+        match self.students.get(owner) {
+            Some(progress) => progress.report(),
+            None => StudentProgress::new(owner).report(),
+        }
+    }
+
+    pub fn start_session(
+        &mut self,
+        owner: &Owner,
+        session_id: &str,
+        now: OffsetDateTime,
+    ) -> Result<()> {
+        self.update(owner, |progress| {
+            progress.sessions.insert(
+                session_id.to_string(),
+                SessionActivity {
+                    started_at: now,
+                    last_active_at: now,
+                    turns: 0,
+                    active_seconds: 0,
+                },
+            );
+        })
+    }
+
+    /// Count a tutor reply and the topics it was tagged with.
+    pub fn record_turn(
+        &mut self,
+        owner: &Owner,
+        session_id: &str,
+        topics: &[&str],
+        now: OffsetDateTime,
+    ) -> Result<()> {
+        let idle = self.idle;
+        self.update(owner, |progress| {
+            let activity =
+                progress
+                    .sessions
+                    .entry(session_id.to_string())
+                    .or_insert(SessionActivity {
+                        started_at: now,
+                        last_active_at: now,
+                        turns: 0,
+                        active_seconds: 0,
+                    });
+            let gap = now - activity.last_active_at;
+            if gap.is_positive() && gap <= idle {
+                activity.active_seconds += gap.whole_seconds();
+            }
+            activity.last_active_at = activity.last_active_at.max(now);
+            activity.turns += 1;
+            for topic in topics {
+                let entry = progress
+                    .topics
+                    .entry(topic.to_string())
+                    .or_insert(TopicProgress {
+                        topic: topic.to_string(),
+                        turns: 0,
+                        last_seen_at: now,
+                    });
+                entry.turns += 1;
+                entry.last_seen_at = now;
+            }
+        })
+    }
+
+    pub fn record_quiz(&mut self, owner: &Owner, score: QuizScore) -> Result<()> {
+        self.update(owner, |progress| {
+            progress.quiz_scores.push(score);
+            let excess = progress.quiz_scores.len().saturating_sub(MAX_QUIZ_SCORES);
+            progress.quiz_scores.drain(..excess);
+        })
+    }
+
+    fn update(&mut self, owner: &Owner, change: impl FnOnce(&mut StudentProgress)) -> Result<()> {
+        let progress = self
+            .students
+            .entry(owner.clone())
+            .or_insert_with(|| StudentProgress::new(owner));
+        change(progress);
+        let path = self.dir.join(format!("{}.json", owner.file_stem()));
+        atomic_write_json(&path, progress)
+    }
+}
+
+fn read_progress(path: &Path) -> Result<StudentProgress> {
+    Ok(serde_json::from_slice(&fs::read(path)?)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TempDir};
+
+    #[test]
+    fn tags_turns_with_the_topics_they_mention() {
+        let classifier = TopicClassifier::new();
+        assert_eq!(
+            classifier.classify(
+                "How do I solve this quadratic equation? The equation has two variables."
+            ),
+            ["algebra"]
+        );
+        assert_eq!(
+            classifier.classify("Why does a cell divide by mitosis, and how is meiosis different?"),
+            ["biology"]
+        );
+        assert!(classifier.classify("Thanks, that helps!").is_empty());
+    }
+
+    #[test]
+    fn summarises_sessions_topics_and_quizzes() {
+        let start = OffsetDateTime::UNIX_EPOCH;
+        let owner = |student_id: &str| Owner {
+            organization: default_organization(),
+            student_id: student_id.to_string(),
+        };
+        let mut progress = StudentProgress::new(&owner("s1"));
+        progress.sessions.insert(
+            "a".to_string(),
+            SessionActivity {
+                started_at: start,
+                last_active_at: start + Duration::minutes(20),
+                turns: 3,
+                active_seconds: 20 * 60,
+            },
+        );
+        progress.topics.insert(
+            "algebra".to_string(),
+            TopicProgress {
+                topic: "algebra".to_string(),
+                turns: 3,
+                last_seen_at: start,
+            },
+        );
+        progress.quiz_scores.push(QuizScore {
+            quiz_id: "q".to_string(),
+            topic: Some("quadratics".to_string()),
+            score: 2,
+            total: 4,
+            submitted_at: start,
+        });
+
+        let report = progress.report();
+        assert_eq!(report.quiz_accuracy, Some(0.5));
+        let summary = report.summary().unwrap();
+        assert!(summary.contains("1 earlier session with you (3 tutor replies, about 20 minutes"));
+        assert!(summary.contains("algebra (3 turns)"));
+        assert!(summary.contains("2/4 on quadratics"));
+        assert_eq!(StudentProgress::new(&owner("s2")).report().summary(), None);
+    }
+
+    #[test]
+    fn progress_stays_in_its_organization() {
+        let dir = TempDir::new("progress");
+        let mut config = crate::config::Config::for_tests().progress;
+        config.dir = dir.path().to_path_buf();
+        let mut store = ProgressStore::open(&config).unwrap();
+        let owner = |organization| testing::owner(organization, "ana");
+        let now = OffsetDateTime::now_utc();
+        store.start_session(&owner("northside"), "s1", now).unwrap();
+        store
+            .record_turn(&owner("northside"), "s1", &["algebra"], now)
+            .unwrap();
+
+        assert_eq!(store.report(&owner("northside")).turns, 1);
+        assert_eq!(store.report(&owner("default")).turns, 0);
+        store.start_session(&owner("default"), "s2", now).unwrap();
+        let store = ProgressStore::open(&config).unwrap();
+        assert_eq!(store.report(&owner("northside")).sessions, 1);
+        assert_eq!(store.report(&owner("default")).sessions, 1);
+        assert_eq!(store.report(&owner("default")).turns, 0);
+    }
+}
@@ -0,0 +1,632 @@
+use anyhow::{Context, Result, bail};
+use async_openai::{
+    Client,
+    config::OpenAIConfig,
+    types::{
+        ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestUserMessageArgs,
+        CreateChatCompletionRequest, CreateChatCompletionRequestArgs, ResponseFormat,
+    },
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use time::OffsetDateTime;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::config::QuizConfig;
+use crate::error::ServiceError;
+use crate::pii::Redactor;
+use crate::session::TokenUsage;
+use crate::store::{self, Owned, atomic_write_json};
+use crate::structured;
+use crate::tenancy::{Owner, default_organization};
+
+/// Most of a session sent to the model, in characters; older messages are
+/// dropped first.
+const MAX_TRANSCRIPT_CHARS: usize = 24_000;
+const MAX_PROMPT_CHARS: usize = 1000;
+const MAX_CHOICE_CHARS: usize = 300;
+const MAX_SHORT_ANSWER_CHARS: usize = 100;
+
+const GENERATION_PROMPT: &str = "You write practice quizzes for students from their tutoring \
+sessions. Ask about what the session covered, mixing multiple-choice and short-answer questions. \
+Short answers must be a word, a name, a number or a short phrase that can be checked exactly. \
+Reply with a single JSON object and nothing else, in this form:\n\
+{\"questions\": [\n\
+  {\"kind\": \"multiple_choice\", \"prompt\": \"...\", \"choices\": [\"...\", \"...\", \"...\", \"...\"], \
+\"answer\": 0, \"explanation\": \"...\"},\n\
+  {\"kind\": \"short_answer\", \"prompt\": \"...\", \"accepted\": [\"...\"], \"explanation\": \"...\"}\n\
+]}\n\
+`answer` is the 0-based index of the correct choice. `accepted` lists every acceptable short \
+answer. `explanation` says why the answer is right.";
+
+/// One question with its answer key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Question {
+    MultipleChoice {
+        prompt: String,
+        choices: Vec<String>,
+        answer: usize,
+        explanation: String,
+    },
+    ShortAnswer {
+        prompt: String,
+        accepted: Vec<String>,
+        explanation: String,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+struct GeneratedQuiz {
+    questions: Vec<Question>,
+}
+
+/// A quiz as stored, answer keys and submissions included. Kept as
+/// `<quiz_id>.json` in the quiz directory.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Quiz {
+    quiz_id: String,
+    #[serde(default = "default_organization")]
+    organization: String,
+    student_id: String,
+    session_id: String,
+    topic: Option<String>,
+    #[serde(with = "time::serde::rfc3339")]
+    created_at: OffsetDateTime,
+    questions: Vec<Question>,
+    submissions: Vec<Submission>,
+}
+
+impl Owned for Quiz {
+    fn organization(&self) -> &str {
+        &self.organization
+    }
+
+    fn student_id(&self) -> &str {
+        &self.student_id
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum QuestionKind {
+    MultipleChoice,
+    ShortAnswer,
+}
+
+/// A question as shown to the student, without its answer.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct QuestionView {
+    pub kind: QuestionKind,
+    pub prompt: String,
+    /// Options for a multiple-choice question; answer with an index.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub choices: Option<Vec<String>>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct QuizView {
+    pub quiz_id: String,
+    pub session_id: String,
+    pub topic: Option<String>,
+    #[serde(with = "time::serde::rfc3339")]
+    #[schema(value_type = String, format = DateTime)]
+    pub created_at: OffsetDateTime,
+    pub questions: Vec<QuestionView>,
+    /// Graded submissions so far, oldest first.
+    pub submissions: Vec<Submission>,
+}
+
+/// A student's answer: the index of a choice, or the text of a short answer.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(untagged)]
+pub enum QuizAnswer {
+    Choice(usize),
+    Text(String),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct GradedAnswer {
+    pub correct: bool,
+    pub given: QuizAnswer,
+    /// The correct choice, or the first accepted short answer.
+    pub expected: String,
+    pub explanation: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Submission {
+    #[serde(with = "time::serde::rfc3339")]
+    #[schema(value_type = String, format = DateTime)]
+    pub submitted_at: OffsetDateTime,
+    /// Questions answered correctly, out of `total`.
+    pub score: usize,
+    pub total: usize,
+    /// One result per question, in order.
+    pub results: Vec<GradedAnswer>,
+}
+
+impl Question {
+    fn view(&self) -> QuestionView {
+        match self {
+            Self::MultipleChoice {
+                prompt, choices, ..
+            } => QuestionView {
+                kind: QuestionKind::MultipleChoice,
+                prompt: prompt.clone(),
+                choices: Some(choices.clone()),
+            },
+            Self::ShortAnswer { prompt, .. } => QuestionView {
+                kind: QuestionKind::ShortAnswer,
+                prompt: prompt.clone(),
+                choices: None,
+            },
+        }
+    }
+
+    /// Check what the model wrote against the rules the prompt gave it.
+    fn validate(&self) -> Result<()> {
+        let (prompt, explanation) = match self {
+            Self::MultipleChoice {
+                prompt,
+                choices,
+                answer,
+                explanation,
+            } => {
+                if !(2..=6).contains(&choices.len()) {
+                    bail!("needs 2 to 6 choices, not {}", choices.len());
+                }
+                for (index, choice) in choices.iter().enumerate() {
+                    check_text("choice", choice, MAX_CHOICE_CHARS)?;
+                    if choices[..index]
+                        .iter()
+                        .any(|c| normalize(c) == normalize(choice))
+                    {
+                        bail!("choice \"{}\" appears twice", choice);
+                    }
+                }
+                if *answer >= choices.len() {
+                    bail!("answer {} is not the index of a choice", answer);
+                }
+                (prompt, explanation)
+            }
+            Self::ShortAnswer {
+                prompt,
+                accepted,
+                explanation,
+            } => {
+                if !(1..=5).contains(&accepted.len()) {
+                    bail!("needs 1 to 5 accepted answers, not {}", accepted.len());
+                }
+                for answer in accepted {
+                    check_text("accepted answer", answer, MAX_SHORT_ANSWER_CHARS)?;
+                    if normalize(answer).is_empty() {
+                        bail!("accepted answer \"{}\" has no letters or digits", answer);
+                    }
+                }
+                (prompt, explanation)
+            }
+        };
+        check_text("prompt", prompt, MAX_PROMPT_CHARS)?;
+        check_text("explanation", explanation, MAX_PROMPT_CHARS)
+    }
+
+    fn grade(&self, given: &QuizAnswer) -> GradedAnswer {
+        let (correct, expected, explanation) = match (self, given) {
+            (
+                Self::MultipleChoice {
+                    choices,
+                    answer,
+                    explanation,
+                    ..
+                },
+                given,
+            ) => (
+                matches!(given, QuizAnswer::Choice(choice) if choice == answer),
+                &choices[*answer],
+                explanation,
+            ),
+            (
+                Self::ShortAnswer {
+                    accepted,
+                    explanation,
+                    ..
+                },
+                given,
+            ) => (
+                matches!(given, QuizAnswer::Text(text) if accepted.iter().any(|a| same_answer(text, a))),
+                &accepted[0],
+                explanation,
+            ),
+        };
+        GradedAnswer {
+            correct,
+            given: given.clone(),
+            expected: expected.clone(),
+            explanation: explanation.clone(),
+        }
+    }
+
+    fn restore(&mut self, redactor: &Redactor) {
+        let texts: Vec<&mut String> = match self {
+            Self::MultipleChoice {
+                prompt,
+                choices,
+                explanation,
+                ..
+            } => [prompt, explanation].into_iter().chain(choices).collect(),
+            Self::ShortAnswer {
+                prompt,
+                accepted,
+                explanation,
+            } => [prompt, explanation].into_iter().chain(accepted).collect(),
+        };
+        for text in texts {
+            *text = redactor.restore(text);
+        }
+    }
+}
+
+impl Quiz {
+    pub fn view(&self) -> QuizView {
+        QuizView {
+            quiz_id: self.quiz_id.clone(),
+            session_id: self.session_id.clone(),
+            topic: self.topic.clone(),
+            created_at: self.created_at,
+            questions: self.questions.iter().map(Question::view).collect(),
+            submissions: self.submissions.clone(),
+        }
+    }
+}
+
+/// Why a set of answers cannot be graded against `questions`.
+pub fn check_answers(questions: &[QuestionView], answers: &[QuizAnswer]) -> Result<(), String> {
+    if answers.len() != questions.len() {
+        return Err(format!(
+            "expected {} answers, got {}",
+            questions.len(),
+            answers.len()
+        ));
+    }
+    for (number, (question, answer)) in questions.iter().zip(answers).enumerate() {
+        match (&question.choices, answer) {
+            (Some(choices), QuizAnswer::Choice(choice)) if *choice >= choices.len() => {
+                return Err(format!(
+                    "answer {} must be a choice index below {}",
+                    number + 1,
+                    choices.len()
+                ));
+            }
+            (Some(_), QuizAnswer::Text(_)) => {
+                return Err(format!("answer {} must be a choice index", number + 1));
+            }
+            (None, QuizAnswer::Choice(_)) => {
+                return Err(format!("answer {} must be text", number + 1));
+            }
+            (None, QuizAnswer::Text(text)) if text.chars().count() > MAX_PROMPT_CHARS => {
+                return Err(format!(
+                    "answer {} exceeds {} characters",
+                    number + 1,
+                    MAX_PROMPT_CHARS
+                ));
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// A quiz request prepared under the controller lock and sent without it.
+pub struct PendingQuiz {
+    pub client: Client<OpenAIConfig>,
+    pub request: CreateChatCompletionRequest,
+    /// Placeholders used in `request`, to be swapped back in the questions.
+    pub redactor: Option<Redactor>,
+    pub questions: usize,
+    pub max_attempts: usize,
+    pub owner: Owner,
+    pub session_id: String,
+    pub topic: Option<String>,
+}
+
+impl PendingQuiz {
+    /// The upstream request for a quiz of `questions` questions on the
+    /// transcript of a session.
+    pub fn request(
+        model: &str,
+        temperature: f32,
+        max_tokens: u32,
+        transcript: &str,
+        questions: usize,
+        topic: Option<&str>,
+    ) -> Result<CreateChatCompletionRequest> {
+        let mut ask = format!("Write a quiz of exactly {questions} questions");
+        if let Some(topic) = topic {
+            ask.push_str(&format!(" focused on {topic}"));
+        }
+        ask.push_str(" from this tutoring session:\n\n");
+        ask.push_str(transcript);
+        Ok(CreateChatCompletionRequestArgs::default()
+            .model(model)
+            .messages([
+                ChatCompletionRequestSystemMessageArgs::default()
+                    .content(GENERATION_PROMPT)
+                    .build()?
+                    .into(),
+                ChatCompletionRequestUserMessageArgs::default()
+                    .content(ask)
+                    .build()?
+                    .into(),
+            ])
+            .response_format(ResponseFormat::JsonObject)
+            .temperature(temperature)
+            .max_tokens(max_tokens)
+            .build()?)
+    }
+
+    /// Ask for the quiz, sending malformed replies back to the model with
+    /// what was wrong until one passes or the attempts run out. The tokens
+    /// spent are added to `usage`.
+    pub async fn run(self, usage: &mut TokenUsage) -> Result<Quiz> {
+        let count = self.questions;
+        let mut questions = structured::complete(
+            &self.client,
+            self.request,
+            self.max_attempts,
+            usage,
+            |reply| parse(reply, count),
+        )
+        .await?;
+        if let Some(redactor) = &self.redactor {
+            questions.iter_mut().for_each(|q| q.restore(redactor));
+        }
+        Ok(Quiz {
+            quiz_id: Uuid::new_v4().to_string(),
+            organization: self.owner.organization,
+            student_id: self.owner.student_id,
+            session_id: self.session_id,
+            topic: self.topic,
+            created_at: OffsetDateTime::now_utc(),
+            questions,
+            submissions: Vec::new(),
+        })
+    }
+}
+
+/// The questions in a model reply, checked against the quiz format.
+fn parse(reply: &str, count: usize) -> Result<Vec<Question>> {
+    let quiz: GeneratedQuiz = serde_json::from_str(reply).context("reply is not quiz JSON")?;
+    if quiz.questions.len() != count {
+        bail!(
+            "asked for {} questions but got {}",
+            count,
+            quiz.questions.len()
+        );
+    }
+    for (number, question) in quiz.questions.iter().enumerate() {
+        question
+            .validate()
+            .with_context(|| format!("question {}", number + 1))?;
+    }
+    Ok(quiz.questions)
+}
+
+fn check_text(what: &str, text: &str, max_chars: usize) -> Result<()> {
+    if text.trim().is_empty() {
+        bail!("{} is empty", what);
+    }
+    if text.chars().count() > max_chars {
+        bail!("{} is longer than {} characters", what, max_chars);
+    }
+    Ok(())
+}
+
+/// Short answers match when their words agree ignoring case, punctuation
+/// and a leading article, or when both are the same number.
+pub fn same_answer(given: &str, accepted: &str) -> bool {
+    let number = |text: &str| text.trim().replace(',', "").parse::<f64>().ok();
+    if let (Some(given), Some(accepted)) = (number(given), number(accepted)) {
+        return (given - accepted).abs() <= 1e-9 * accepted.abs().max(1.0);
+    }
+    let given = normalize(given);
+    !given.is_empty() && given == normalize(accepted)
+}
+
+fn normalize(text: &str) -> String {
+    let lower = text.to_lowercase();
+    let mut words: Vec<&str> = lower
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect();
+    if words.len() > 1 && ["a", "an", "the"].contains(&words[0]) {
+        words.remove(0);
+    }
+    words.join(" ")
+}
+
+/// The text of a session for the model to write a quiz from, or `None`
+/// when the tutor has not answered anything yet.
+pub fn transcript<'a>(turns: impl Iterator<Item = (&'a str, String)>) -> Option<String> {
+    let mut lines: Vec<String> = turns
+        .filter_map(|(role, text)| match role {
+            "user" => Some(format!("Student: {}", text)),
+            "assistant" if !text.is_empty() => Some(format!("Tutor: {}", text)),
+            _ => None,
+        })
+        .collect();
+    if !lines.iter().any(|line| line.starts_with("Tutor: ")) {
+        return None;
+    }
+    // Keep the end of long sessions, which is what the student just studied.
+    let mut total = 0;
+    let keep = lines
+        .iter()
+        .rev()
+        .take_while(|line| {
+            total += line.chars().count() + 2;
+            total <= MAX_TRANSCRIPT_CHARS
+        })
+        .count()
+        .max(1);
+    let mut text = lines.split_off(lines.len() - keep).join("\n\n");
+    if let Some((cut, _)) = text.char_indices().rev().nth(MAX_TRANSCRIPT_CHARS) {
+        text = text[cut..].to_string();
+    }
+    Some(text)
+}
+
+/// Every quiz, kept in memory and written through to one file each.
+pub struct QuizStore {
+    dir: PathBuf,
+    quizzes: HashMap<String, Quiz>,
+}
+
+impl QuizStore {
+    pub fn open(config: &QuizConfig) -> Result<Self> {
+        fs::create_dir_all(&config.dir)
+            .with_context(|| format!("failed to create {}", config.dir.display()))?;
+        let mut quizzes = HashMap::new();
+        for entry in fs::read_dir(&config.dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            match read_quiz(&path) {
+                Ok(quiz) => {
+                    quizzes.insert(quiz.quiz_id.clone(), quiz);
+                }
+                Err(err) => eprintln!("Skipping quiz {}: {:#}", path.display(), err),
+            }
+        }
+        Ok(Self {
+            dir: config.dir.clone(),
+            quizzes,
+        })
+    }
+
+    pub fn add(&mut self, quiz: Quiz) -> Result<QuizView> {
+        self.write(&quiz)?;
+        let view = quiz.view();
+        self.quizzes.insert(quiz.quiz_id.clone(), quiz);
+        Ok(view)
+    }
+
+    /// A student's own quiz; other students' quizzes are not found.
+    pub fn get(&self, owner: &Owner, quiz_id: &str) -> Result<&Quiz> {
+        store::get_owned(&self.quizzes, owner, quiz_id)
+            .ok_or_else(|| ServiceError::NotFound(format!("Quiz {} not found", quiz_id)).into())
+    }
+
+    /// Grade answers already checked with `check_answers` and keep the
+    /// result with the quiz.
+    pub fn submit(
+        &mut self,
+        owner: &Owner,
+        quiz_id: &str,
+        answers: &[QuizAnswer],
+    ) -> Result<Submission> {
+        let mut quiz = self.get(owner, quiz_id)?.clone();
+        let results: Vec<GradedAnswer> = quiz
+            .questions
+            .iter()
+            .zip(answers)
+            .map(|(question, answer)| question.grade(answer))
+            .collect();
+        let submission = Submission {
+            submitted_at: OffsetDateTime::now_utc(),
+            score: results.iter().filter(|result| result.correct).count(),
+            total: results.len(),
+            results,
+        };
+        quiz.submissions.push(submission.clone());
+        self.write(&quiz)?;
+        self.quizzes.insert(quiz.quiz_id.clone(), quiz);
+        Ok(submission)
+    }
+
+    fn write(&self, quiz: &Quiz) -> Result<()> {
+        let path = self.dir.join(format!("{}.json", quiz.quiz_id));
+        atomic_write_json(&path, quiz)
+    }
+}
+
+fn read_quiz(path: &Path) -> Result<Quiz> {
+    Ok(serde_json::from_slice(&fs::read(path)?)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TempDir};
+
+    const REPLY: &str = r#"{"questions": [
+  {"kind": "multiple_choice", "prompt": "How many cells does meiosis produce?",
+   "choices": ["Two", "Four", "Eight"], "answer": 1,
+   "explanation": "Two divisions give four cells."},
+  {"kind": "short_answer", "prompt": "In which phase does crossing over happen?",
+   "accepted": ["Prophase I", "prophase 1"], "explanation": "Homologues pair in prophase I."},
+  {"kind": "short_answer", "prompt": "How many chromosomes are in a human gamete?",
+   "accepted": ["23"], "explanation": "Half of 46."}
+]}"#;
+
+    #[test]
+    fn rejects_quizzes_that_break_the_format() {
+        assert_eq!(parse(REPLY, 3).unwrap().len(), 3);
+        let err = parse(REPLY, 4).unwrap_err();
+        assert!(err.to_string().contains("asked for 4"), "{err:#}");
+        let bad_index = REPLY.replace(r#""answer": 1"#, r#""answer": 3"#);
+        let err = parse(&bad_index, 3).unwrap_err();
+        assert!(format!("{err:#}").contains("question 1"), "{err:#}");
+        assert!(parse("Here is your quiz!", 3).is_err());
+    }
+
+    #[test]
+    fn grades_choices_and_short_answers() {
+        let questions = parse(REPLY, 3).unwrap();
+        let grade = |index: usize, answer: QuizAnswer| questions[index].grade(&answer).correct;
+        assert!(grade(0, QuizAnswer::Choice(1)));
+        assert!(!grade(0, QuizAnswer::Choice(0)));
+        assert!(grade(1, QuizAnswer::Text("  prophase i.".to_string())));
+        assert!(grade(1, QuizAnswer::Text("the Prophase 1".to_string())));
+        assert!(!grade(1, QuizAnswer::Text("metaphase".to_string())));
+        assert!(grade(2, QuizAnswer::Text("23.0".to_string())));
+        assert!(!grade(2, QuizAnswer::Text("".to_string())));
+    }
+
+    #[test]
+    fn quizzes_stay_in_their_organization() {
+        let dir = TempDir::new("quizzes");
+        let mut config = crate::config::Config::for_tests().quiz;
+        config.dir = dir.path().to_path_buf();
+        let mut store = QuizStore::open(&config).unwrap();
+        let owner = |organization| testing::owner(organization, "ana");
+        let view = store
+            .add(Quiz {
+                quiz_id: Uuid::new_v4().to_string(),
+                organization: "northside".to_string(),
+                student_id: "ana".to_string(),
+                session_id: "s1".to_string(),
+                topic: None,
+                created_at: OffsetDateTime::now_utc(),
+                questions: parse(REPLY, 3).unwrap(),
+                submissions: Vec::new(),
+            })
+            .unwrap();
+
+        assert!(store.get(&owner("northside"), &view.quiz_id).is_ok());
+        assert!(store.get(&owner("default"), &view.quiz_id).is_err());
+        let answers = [QuizAnswer::Choice(1)];
+        assert!(
+            store
+                .submit(&owner("default"), &view.quiz_id, &answers)
+                .is_err()
+        );
+        // Reopened from disk, the quiz keeps its organization.
+        let store = QuizStore::open(&config).unwrap();
+        assert!(store.get(&owner("default"), &view.quiz_id).is_err());
+    }
+}
@@ -0,0 +1,309 @@
+use anyhow::{Context, Result};
+use async_openai::types::{ChatCompletionRequestMessage, ChatCompletionRequestSystemMessageArgs};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use time::OffsetDateTime;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::config::RetrievalConfig;
+use crate::error::ServiceError;
+use crate::store::atomic_write_json;
+
+mod chunk;
+mod embed;
+mod extract;
+
+pub use extract::Format;
+
+const GROUNDING_INSTRUCTION: &str = "Passages from the course materials that may help with the \
+student's latest message follow. Base your answer on them where they are relevant and cite them \
+by number, like [1]. If they do not cover the question, say so before answering from general \
+knowledge.";
+
+/// Who a document is shared with. A session searches the documents of its
+/// course and of its organization.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize, ToSchema)]
+#[serde(tag = "kind", content = "id", rename_all = "snake_case")]
+pub enum Scope {
+    Course(String),
+    Organization(String),
+}
+
+/// Where a passage sits in its document.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Position {
+    /// Index of the passage within the document, from 0.
+    pub index: usize,
+    /// Page number, for PDFs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub page: Option<u32>,
+    /// Nearest heading above the passage, for Markdown.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub section: Option<String>,
+    /// Byte range within the page, or within the file for text formats.
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Chunk {
+    text: String,
+    position: Position,
+    vector: Vec<f32>,
+}
+
+/// An uploaded file cut into passages, with a vector per passage. Stored
+/// as `<document_id>.json` in the library directory.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Document {
+    document_id: String,
+    title: String,
+    file_name: String,
+    format: Format,
+    scope: Scope,
+    #[serde(with = "time::serde::rfc3339")]
+    uploaded_at: OffsetDateTime,
+    /// Embedding the vectors were computed with.
+    embedding: String,
+    chunks: Vec<Chunk>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DocumentSummary {
+    pub document_id: String,
+    pub title: String,
+    pub file_name: String,
+    pub format: Format,
+    pub scope: Scope,
+    #[serde(with = "time::serde::rfc3339")]
+    #[schema(value_type = String, format = DateTime)]
+    pub uploaded_at: OffsetDateTime,
+    /// Number of passages the document was cut into.
+    pub passages: usize,
+}
+
+/// A file received for the library.
+pub struct Upload {
+    pub file_name: String,
+    pub content_type: Option<String>,
+    /// Shown in citations; the file name without its extension by default.
+    pub title: Option<String>,
+    pub scope: Scope,
+    pub bytes: Vec<u8>,
+}
+
+/// A passage retrieved for a question.
+#[derive(Debug, Clone)]
+pub struct Passage {
+    pub document_id: String,
+    pub title: String,
+    pub text: String,
+    pub position: Position,
+}
+
+impl Document {
+    /// Extract, cut and embed an uploaded file. This is CPU-bound, so run
+    /// it on a blocking thread.
+    pub fn prepare(upload: Upload, config: &RetrievalConfig) -> Result<Self> {
+        let format = Format::detect(&upload.file_name, upload.content_type.as_deref())?;
+        let pages = extract::pages(format, &upload.bytes)?;
+        let chunks = chunk::split(&pages, format, config.chunk_size, config.chunk_overlap)
+            .into_iter()
+            .enumerate()
+            .map(|(index, piece)| Chunk {
+                vector: embed::embed(&piece.text),
+                position: Position {
+                    index,
+                    page: piece.page,
+                    section: piece.section,
+                    start: piece.start,
+                    end: piece.end,
+                },
+                text: piece.text,
+            })
+            .collect();
+        let title = upload
+            .title
+            .map(|title| title.trim().to_string())
+            .filter(|title| !title.is_empty())
+            .unwrap_or_else(|| {
+                let stem = Path::new(&upload.file_name).file_stem();
+                stem.map(|s| s.to_string_lossy().into_owned())
+                    .unwrap_or_else(|| upload.file_name.clone())
+            });
+        Ok(Self {
+            document_id: Uuid::new_v4().to_string(),
+            title,
+            file_name: upload.file_name,
+            format,
+            scope: upload.scope,
+            uploaded_at: OffsetDateTime::now_utc(),
+            embedding: embed::MODEL.to_string(),
+            chunks,
+        })
+    }
+
+    fn summary(&self) -> DocumentSummary {
+        DocumentSummary {
+            document_id: self.document_id.clone(),
+            title: self.title.clone(),
+            file_name: self.file_name.clone(),
+            format: self.format,
+            scope: self.scope.clone(),
+            uploaded_at: self.uploaded_at,
+            passages: self.chunks.len(),
+        }
+    }
+}
+
+/// Every uploaded document, kept in memory and searched by brute force;
+/// a course's notes run to thousands of passages, not millions.
+pub struct Library {
+    dir: PathBuf,
+    top_k: usize,
+    min_score: f32,
+    documents: HashMap<String, Document>,
+}
+
+impl Library {
+    /// Load the documents in the configured directory, creating it if
+    /// needed. Vectors from an older embedding are recomputed.
+    pub fn open(config: &RetrievalConfig) -> Result<Self> {
+        fs::create_dir_all(&config.dir)
+            .with_context(|| format!("failed to create {}", config.dir.display()))?;
+        let mut library = Self {
+            dir: config.dir.clone(),
+            top_k: config.top_k,
+            min_score: config.min_score,
+            documents: HashMap::new(),
+        };
+        for entry in fs::read_dir(&config.dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            let mut document: Document = match read_document(&path) {
+                Ok(document) => document,
+                Err(err) => {
+                    eprintln!("Skipping document {}: {:#}", path.display(), err);
+                    continue;
+                }
+            };
+            if document.embedding != embed::MODEL {
+                for chunk in &mut document.chunks {
+                    chunk.vector = embed::embed(&chunk.text);
+                }
+                document.embedding = embed::MODEL.to_string();
+                library.write(&document)?;
+            }
+            library
+                .documents
+                .insert(document.document_id.clone(), document);
+        }
+        Ok(library)
+    }
+
+    pub fn add(&mut self, document: Document) -> Result<DocumentSummary> {
+        self.write(&document)?;
+        let summary = document.summary();
+        self.documents
+            .insert(document.document_id.clone(), document);
+        Ok(summary)
+    }
+
+    pub fn summary(&self, document_id: &str) -> Option<DocumentSummary> {
+        self.documents.get(document_id).map(Document::summary)
+    }
+
+    pub fn remove(&mut self, document_id: &str) -> Result<()> {
+        if self.documents.remove(document_id).is_none() {
+            return Err(
+                ServiceError::NotFound(format!("Document {} not found", document_id)).into(),
+            );
+        }
+        fs::remove_file(self.path(document_id))?;
+        Ok(())
+    }
+
+    /// Documents shared with `scope`, or all of them; oldest first.
+    pub fn list(&self, scope: Option<&Scope>) -> Vec<DocumentSummary> {
+        let mut documents: Vec<DocumentSummary> = self
+            .documents
+            .values()
+            .filter(|document| scope.is_none_or(|scope| document.scope == *scope))
+            .map(Document::summary)
+            .collect();
+        documents.sort_by_key(|document| document.uploaded_at);
+        documents
+    }
+
+    /// The passages of documents in `scopes` closest to `query`, best
+    /// first.
+    pub fn search(&self, scopes: &[Scope], query: &str) -> Vec<Passage> {
+        let query = &embed::embed(query);
+        let mut scored: Vec<(f32, &Document, &Chunk)> =
+            self.documents
+                .values()
+                .filter(|document| scopes.contains(&document.scope))
+                .flat_map(|document| {
+                    document.chunks.iter().map(move |chunk| {
+                        (embed::similarity(query, &chunk.vector), document, chunk)
+                    })
+                })
+                .filter(|(score, _, _)| *score >= self.min_score)
+                .collect();
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+        scored
+            .into_iter()
+            .take(self.top_k)
+            .map(|(_, document, chunk)| Passage {
+                document_id: document.document_id.clone(),
+                title: document.title.clone(),
+                text: chunk.text.clone(),
+                position: chunk.position.clone(),
+            })
+            .collect()
+    }
+
+    fn path(&self, document_id: &str) -> PathBuf {
+        self.dir.join(format!("{document_id}.json"))
+    }
+
+    /// Write through a temporary file so that a crash never leaves half a
+    /// document behind.
+    fn write(&self, document: &Document) -> Result<()> {
+        let path = self.path(&document.document_id);
+        atomic_write_json(&path, document)
+    }
+}
+
+fn read_document(path: &Path) -> Result<Document> {
+    Ok(serde_json::from_slice(&fs::read(path)?)?)
+}
+
+/// Append the retrieved passages to a conversation about to be sent, as
+/// numbered sources the reply can cite.
+pub fn ground(messages: &mut Vec<ChatCompletionRequestMessage>, passages: &[Passage]) {
+    let mut content = GROUNDING_INSTRUCTION.to_string();
+    for (number, passage) in passages.iter().enumerate() {
+        content.push_str(&format!("\n\n[{}] {}", number + 1, passage.title));
+        if let Some(page) = passage.position.page {
+            content.push_str(&format!(", page {}", page));
+        }
+        if let Some(section) = &passage.position.section {
+            content.push_str(&format!(", section \"{}\"", section));
+        }
+        content.push('\n');
+        content.push_str(&passage.text);
+    }
+    messages.push(
+        ChatCompletionRequestSystemMessageArgs::default()
+            .content(content)
+            .build()
+            .expect("text system message always builds")
+            .into(),
+    );
+}
@@ -0,0 +1,191 @@
+use super::extract::Format;
+
+/// A passage cut from one page, with where it came from.
+#[derive(Debug)]
+pub struct Piece {
+    pub text: String,
+    /// 1-based page number, for PDFs.
+    pub page: Option<u32>,
+    /// Nearest Markdown heading above the passage.
+    pub section: Option<String>,
+    /// Byte range of the passage within its page.
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Cut pages into passages of at most `size` bytes, which must be more than
+/// `overlap`. Passages end at paragraph breaks where possible, never span pages, and start a new one at every
+/// Markdown heading. Each passage after the first in a section repeats up
+/// to `overlap` bytes of the one before, so that a sentence cut at a
+/// boundary is still found whole in one of them.
+pub fn split(pages: &[String], format: Format, size: usize, overlap: usize) -> Vec<Piece> {
+    let markdown = format == Format::Markdown;
+    let mut pieces = Vec::new();
+    let mut section = None;
+    for (number, page) in pages.iter().enumerate() {
+        let page_number = (format == Format::Pdf).then_some(number as u32 + 1);
+        let mut emit = |start: usize, end: usize, section: &Option<String>| {
+            pieces.push(Piece {
+                text: page[start..end].to_string(),
+                page: page_number,
+                section: section.clone(),
+                start,
+                end,
+            });
+        };
+
+        // Start, end and section of the passage being built, and whether
+        // it has anything besides headings.
+        let mut current: Option<(usize, usize, Option<String>, bool)> = None;
+        // Paragraphs are cut short enough that a carried-over tail still fits.
+        for (start, end) in units(page, markdown, size - overlap) {
+            let heading = markdown.then(|| heading(&page[start..end])).flatten();
+            if let Some(title) = &heading {
+                section = Some(title.clone());
+            }
+            current = match current.take() {
+                None => Some((start, end, section.clone(), heading.is_none())),
+                Some((from, to, at, true)) if heading.is_some() => {
+                    emit(from, to, &at);
+                    Some((start, end, section.clone(), false))
+                }
+                Some((from, to, at, _)) if end - from > size => {
+                    emit(from, to, &at);
+                    let carried = overlap_start(page, from, to, overlap).unwrap_or(start);
+                    Some((carried, end, at, true))
+                }
+                Some((from, _, at, body)) => Some((from, end, at, body || heading.is_none())),
+            };
+        }
+        if let Some((from, to, at, _)) = current {
+            emit(from, to, &at);
+        }
+    }
+    pieces
+}
+
+/// Paragraphs of a page as byte ranges, with Markdown headings on their
+/// own and anything longer than `size` cut at whitespace.
+fn units(page: &str, markdown: bool, size: usize) -> Vec<(usize, usize)> {
+    let mut paragraphs = Vec::new();
+    let mut current: Option<(usize, usize)> = None;
+    let mut offset = 0;
+    for line in page.split_inclusive('\n') {
+        let line_start = offset;
+        offset += line.len();
+        let content = line.trim_end();
+        if content.trim().is_empty() {
+            paragraphs.extend(current.take());
+            continue;
+        }
+        let start = line_start + content.len() - content.trim_start().len();
+        let end = line_start + content.len();
+        if markdown && heading(content).is_some() {
+            paragraphs.extend(current.take());
+            paragraphs.push((start, end));
+            continue;
+        }
+        match &mut current {
+            Some(paragraph) => paragraph.1 = end,
+            None => current = Some((start, end)),
+        }
+    }
+    paragraphs.extend(current);
+
+    let mut units = Vec::new();
+    for (mut start, end) in paragraphs {
+        while end - start > size {
+            let mut cut = start + size;
+            while !page.is_char_boundary(cut) {
+                cut -= 1;
+            }
+            if let Some(space) = page[start..cut]
+                .rfind(char::is_whitespace)
+                .filter(|&i| i > 0)
+            {
+                cut = start + space;
+            }
+            units.push((start, cut));
+            start = skip_whitespace(page, cut, end);
+        }
+        if start < end {
+            units.push((start, end));
+        }
+    }
+    units
+}
+
+/// The text of a Markdown heading line.
+fn heading(line: &str) -> Option<String> {
+    let line = line.trim_start();
+    let hashes = line.len() - line.trim_start_matches('#').len();
+    let title = line[hashes..]
+        .strip_prefix(' ')?
+        .trim()
+        .trim_end_matches('#')
+        .trim();
+    ((1..=6).contains(&hashes) && !title.is_empty()).then(|| title.to_string())
+}
+
+/// Where to start the next passage so that it repeats the end of the
+/// previous one, on a word boundary. `None` when nothing is carried over.
+fn overlap_start(page: &str, from: usize, to: usize, overlap: usize) -> Option<usize> {
+    if overlap == 0 {
+        return None;
+    }
+    let mut start = to.saturating_sub(overlap).max(from);
+    while !page.is_char_boundary(start) {
+        start += 1;
+    }
+    if start > from {
+        start += page[start..to].find(char::is_whitespace)?;
+    }
+    let start = skip_whitespace(page, start, to);
+    (start > from && start < to).then_some(start)
+}
+
+fn skip_whitespace(page: &str, at: usize, end: usize) -> usize {
+    let rest = &page[at..end];
+    at + rest.len() - rest.trim_start().len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_passages_near_the_target_size_with_overlap() {
+        let text = (1..=40)
+            .map(|n| format!("Sentence number {n} about cell division."))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let pieces = split(std::slice::from_ref(&text), Format::Text, 200, 50);
+        assert!(pieces.len() > 5);
+        for piece in &pieces {
+            assert!(piece.text.len() <= 200, "{}", piece.text);
+            assert_eq!(piece.text, text[piece.start..piece.end]);
+        }
+        for pair in pieces.windows(2) {
+            assert!(pair[1].start < pair[0].end, "neighbours overlap");
+            assert!(pair[1].start > pair[0].start);
+        }
+        assert_eq!(pieces.last().unwrap().end, text.len());
+    }
+
+    #[test]
+    fn starts_a_passage_at_each_heading() {
+        let text = "# Cells\n\nIntro.\n\n## Mitosis\n### Phases\n\nProphase first.\n\n## Meiosis\n\nTwo divisions.\n";
+        let pieces = split(&[text.to_string()], Format::Markdown, 1000, 100);
+        let sections: Vec<_> = pieces.iter().map(|p| p.section.as_deref()).collect();
+        assert_eq!(sections, [Some("Cells"), Some("Mitosis"), Some("Meiosis")]);
+        assert_eq!(pieces[1].text, "## Mitosis\n### Phases\n\nProphase first.");
+    }
+
+    #[test]
+    fn numbers_pdf_pages() {
+        let pages = ["First page.".to_string(), "Second page.".to_string()];
+        let pieces = split(&pages, Format::Pdf, 100, 10);
+        let numbers: Vec<_> = pieces.iter().map(|p| p.page).collect();
+        assert_eq!(numbers, [Some(1), Some(2)]);
+    }
+}
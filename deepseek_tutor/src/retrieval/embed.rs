@@ -0,0 +1,101 @@
+/// Name stored with every vector, so that an index built with different
+/// settings is recomputed on load.
+pub const MODEL: &str = "hashed-ngrams-384-v1";
+
+const DIMENSIONS: usize = 384;
+
+const WORD_WEIGHT: f32 = 1.0;
+const PAIR_WEIGHT: f32 = 0.5;
+const TRIGRAM_WEIGHT: f32 = 0.2;
+
+#[rustfmt::skip]
+const STOPWORDS: &[&str] = &[
+    "a", "about", "an", "and", "are", "as", "at", "be", "but", "by", "can", "do", "does", "for",
+    "from", "has", "have", "how", "i", "if", "in", "into", "is", "it", "its", "me", "my", "of",
+    "on", "or", "so", "than", "that", "the", "their", "them", "then", "there", "these", "they",
+    "this", "to", "was", "we", "what", "when", "where", "which", "while", "who", "why", "will",
+    "with", "you", "your",
+];
+
+/// A unit-length vector for `text`, built locally by hashing its words,
+/// word pairs and character trigrams into signed buckets. It captures
+/// shared vocabulary rather than meaning, which suits course notes searched
+/// with the course's own terms. All zeros when there are no content words.
+pub fn embed(text: &str) -> Vec<f32> {
+    let mut vector = vec![0.0; DIMENSIONS];
+    let words = words(text);
+    for word in &words {
+        add(&mut vector, &["w", word], WORD_WEIGHT);
+        let padded: Vec<char> = format!("#{word}#").chars().collect();
+        for trigram in padded.windows(3) {
+            add(
+                &mut vector,
+                &["c", &trigram.iter().collect::<String>()],
+                TRIGRAM_WEIGHT,
+            );
+        }
+    }
+    for pair in words.windows(2) {
+        add(&mut vector, &["p", &pair[0], &pair[1]], PAIR_WEIGHT);
+    }
+    let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|x| *x /= norm);
+    }
+    vector
+}
+
+/// Cosine similarity of two vectors from `embed`.
+pub fn similarity(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+/// Lower-cased content words with plural endings removed.
+fn words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .filter(|word| !STOPWORDS.contains(&word.as_str()))
+        .map(|word| singular(&word))
+        .collect()
+}
+
+fn singular(word: &str) -> String {
+    if word.chars().count() <= 3 || word.ends_with("ss") || word.ends_with("is") {
+        return word.to_string();
+    }
+    if let Some(stem) = word.strip_suffix("ies") {
+        return format!("{stem}y");
+    }
+    word.strip_suffix('s').unwrap_or(word).to_string()
+}
+
+/// FNV-1a over the parts; the top bit picks the sign so that collisions
+/// tend to cancel out instead of piling up.
+fn add(vector: &mut [f32], parts: &[&str], weight: f32) {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for part in parts {
+        for byte in part.bytes().chain([0xff]) {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    let sign = if hash >> 63 == 0 { 1.0 } else { -1.0 };
+    vector[(hash % DIMENSIONS as u64) as usize] += sign * weight;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranks_passages_sharing_terms_first() {
+        let query = embed("What happens to chromosomes during meiosis?");
+        let meiosis = embed("Meiosis halves the chromosome number and produces four gametes.");
+        let photosynthesis = embed("Photosynthesis turns light energy into chemical energy.");
+        assert!(similarity(&query, &meiosis) > 0.3);
+        assert!(similarity(&query, &meiosis) > 2.0 * similarity(&query, &photosynthesis));
+        assert!((similarity(&meiosis, &meiosis) - 1.0).abs() < 1e-5);
+        assert!(embed("the of and").iter().all(|x| *x == 0.0));
+    }
+}
@@ -0,0 +1,57 @@
+use anyhow::{Result, anyhow, bail};
+use serde::{Deserialize, Serialize};
+use std::panic::{self, AssertUnwindSafe};
+use utoipa::ToSchema;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Markdown,
+    Text,
+    Pdf,
+}
+
+impl Format {
+    /// Decide from the file name, falling back to the declared content
+    /// type for names without a known extension.
+    pub fn detect(file_name: &str, content_type: Option<&str>) -> Result<Self> {
+        let extension = file_name
+            .rsplit_once('.')
+            .map(|(_, ext)| ext.to_ascii_lowercase());
+        let format = match extension.as_deref() {
+            Some("md" | "markdown") => Some(Self::Markdown),
+            Some("txt" | "text") => Some(Self::Text),
+            Some("pdf") => Some(Self::Pdf),
+            _ => match content_type.map(|t| t.split(';').next().unwrap_or("").trim()) {
+                Some("text/markdown") => Some(Self::Markdown),
+                Some("text/plain") => Some(Self::Text),
+                Some("application/pdf") => Some(Self::Pdf),
+                _ => None,
+            },
+        };
+        format.ok_or_else(|| anyhow!("{} is not a Markdown, plain text or PDF file", file_name))
+    }
+}
+
+/// The text of a file, one entry per page for PDFs and a single entry
+/// otherwise.
+pub fn pages(format: Format, bytes: &[u8]) -> Result<Vec<String>> {
+    let pages = match format {
+        Format::Markdown | Format::Text => {
+            let text = std::str::from_utf8(bytes)
+                .map_err(|_| anyhow!("file is not UTF-8 text"))?
+                .trim_start_matches('\u{feff}');
+            vec![text.replace("\r\n", "\n")]
+        }
+        // The PDF parser panics on some malformed files.
+        Format::Pdf => panic::catch_unwind(AssertUnwindSafe(|| {
+            pdf_extract::extract_text_from_mem_by_pages(bytes)
+        }))
+        .map_err(|_| anyhow!("PDF could not be read"))?
+        .map_err(|err| anyhow!("PDF could not be read: {}", err))?,
+    };
+    if pages.iter().all(|page| page.trim().is_empty()) {
+        bail!("file contains no text; scanned PDFs need OCR first");
+    }
+    Ok(pages)
+}
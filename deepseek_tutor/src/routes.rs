@@ -1,16 +1,138 @@
 use askama::Template;
 use axum::{
     Json,
-    extract::Extension,
-    response::{Html, IntoResponse},
+    extract::{
+        DefaultBodyLimit, Extension, Multipart, Path,
+        multipart::{MultipartError, MultipartRejection},
+    },
+    http::{HeaderMap, HeaderValue, Method, StatusCode, Uri, header},
+    middleware,
+    response::{Html, IntoResponse, Response},
 };
+use serde::Deserialize;
 use std::sync::Arc;
 use tokio::sync::Mutex;
+use utoipa_axum::{
+    router::{OpenApiRouter, UtoipaMethodRouterExt},
+    routes,
+};
+
+use utoipa::IntoParams;
 
 use crate::controller::TutorController;
+use crate::csp;
+use crate::dashboard::{self, DashboardTemplate};
+use crate::export::{self, ExportFormat};
+use crate::flashcards::{Card, CardCandidates, CardDraft};
+use crate::integrity::FlaggedEvent;
 use crate::models::{
-    AppError, CreateSessionRequest, CreateSessionResponse, SendQueryRequest, SendQueryResponse,
+    ApiJson, ApiPath, ApiQuery, ApiResponse, AppError, CancelTurnResponse, CardCandidatesRequest,
+    CardsQuery, CreateCardRequest, CreateProblemRequest, CreateQuizRequest, CreateSessionRequest,
+    CreateSessionResponse, DocumentForm, DocumentsQuery, ErrorResponse, EventsQuery,
+    ImportSessionRequest, ImportSessionResponse, ProblemAttemptRequest, QueryRequest,
+    RegenerateRequest, ReviewCardRequest, SendQueryRequest, SendQueryResponse,
+    SessionPersonaResponse, SetPersonaRequest, StudentQuery, StudentRequest, SubmitQuizRequest,
+    UpdateCardRequest, UploadDocumentForm,
 };
+use crate::moderation::ModerationEvent;
+use crate::openapi::{ApiDoc, DOCS_PATH};
+use crate::persona::PersonaSummary;
+use crate::problem::{ProblemDraft, ProblemView};
+use crate::profile::{ProfileUpdate, StudentProfile};
+use crate::progress::ProgressReport;
+use crate::quiz::{QuizView, Submission};
+use crate::retrieval::DocumentSummary;
+use crate::session::{ActiveMessage, BranchSummary, NodeId};
+
+type SharedController = Arc<Mutex<TutorController>>;
+
+/// All JSON API routes. Handlers registered here are added to the OpenAPI
+/// document from their `#[utoipa::path]` annotations.
+#[allow(deprecated)]
+pub fn api_routes() -> OpenApiRouter {
+    let legacy = OpenApiRouter::new()
+        .routes(routes!(create_session))
+        .routes(routes!(send_query))
+        .layer(middleware::map_response(mark_deprecated));
+
+    OpenApiRouter::with_openapi(<ApiDoc as utoipa::OpenApi>::openapi())
+        .routes(routes!(v1_create_session))
+        .routes(routes!(v1_send_query))
+        .routes(routes!(cancel_turn))
+        .routes(routes!(list_messages))
+        .routes(routes!(edit_message))
+        .routes(routes!(regenerate_reply))
+        .routes(routes!(list_branches))
+        .routes(routes!(activate_branch))
+        .routes(routes!(list_personas))
+        .routes(routes!(set_persona))
+        .routes(routes!(import_session))
+        .routes(routes!(clone_session))
+        .routes(routes!(export_session))
+        .routes(routes!(export_student))
+        .routes(routes!(integrity_events))
+        .routes(routes!(moderation_audit))
+        // Uploads are limited by `retrieval.max_upload_mb` instead.
+        .routes(routes!(upload_document).layer(DefaultBodyLimit::disable()))
+        .routes(routes!(list_documents))
+        .routes(routes!(delete_document))
+        .routes(routes!(create_quiz))
+        .routes(routes!(get_quiz))
+        .routes(routes!(submit_quiz))
+        .routes(routes!(create_problem))
+        .routes(routes!(get_problem))
+        .routes(routes!(reveal_hint))
+        .routes(routes!(attempt_problem))
+        .routes(routes!(draft_cards))
+        .routes(routes!(create_card))
+        .routes(routes!(update_card, delete_card))
+        .routes(routes!(review_card))
+        .routes(routes!(list_cards))
+        .routes(routes!(due_cards))
+        .routes(routes!(student_progress))
+        .routes(routes!(get_profile, update_profile, delete_profile))
+        .routes(routes!(extract_profile))
+        .merge(legacy)
+}
+
+/// When the unversioned routes were deprecated in favour of `/api/v1`
+/// (2026-10-19T00:00:00Z), as an RFC 9745 structured-field date.
+const LEGACY_DEPRECATED_AT: &str = "@1792368000";
+
+/// Tag responses from the unversioned routes so clients notice the move to
+/// `/api/v1` (RFC 9745).
+async fn mark_deprecated(mut res: Response) -> Response {
+    let headers = res.headers_mut();
+    headers.insert(
+        "deprecation",
+        HeaderValue::from_static(LEGACY_DEPRECATED_AT),
+    );
+    headers.insert(
+        header::LINK,
+        HeaderValue::from_str(&format!("<{DOCS_PATH}/>; rel=\"deprecation\""))
+            .expect("docs path is a valid header value"),
+    );
+    res
+}
+
+/// Unknown API paths get the error envelope; other paths a plain 404.
+pub async fn not_found(method: Method, uri: Uri) -> Response {
+    if uri.path().starts_with("/api/") {
+        AppError::NotFound(format!("no route for {method} {}", uri.path())).into_response()
+    } else {
+        StatusCode::NOT_FOUND.into_response()
+    }
+}
+
+/// Like [`not_found`], for known paths called with the wrong method.
+pub async fn method_not_allowed(method: Method, uri: Uri) -> Response {
+    if uri.path().starts_with("/api/") {
+        AppError::MethodNotAllowed(format!("{method} is not supported on {}", uri.path()))
+            .into_response()
+    } else {
+        StatusCode::METHOD_NOT_ALLOWED.into_response()
+    }
+}
 
 #[derive(Template)]
 #[template(path = "tutor.html")]
@@ -24,31 +146,1802 @@ pub async fn root() -> impl IntoResponse {
     }))
 }
 
-pub async fn create_session(
-    Extension(controller): Extension<Arc<Mutex<TutorController>>>,
-    Json(payload): Json<CreateSessionRequest>,
-) -> Result<Json<CreateSessionResponse>, AppError> {
+#[derive(Template)]
+#[template(path = "review.html")]
+pub struct ReviewTemplate;
+
+/// Flashcard review page; the student comes from `?student=` or the id the
+/// tutor page saved.
+pub async fn review() -> impl IntoResponse {
+    let template = ReviewTemplate {};
+    Html(template.render().unwrap_or_else(|e| {
+        eprintln!("Error rendering template: {}", e);
+        "Error rendering page".to_string()
+    }))
+}
+
+/// Teacher dashboard: each assigned student's recent sessions, flagged
+/// events and progress. Teachers sign in with HTTP Basic auth.
+pub async fn teacher_dashboard(
+    Extension(controller): Extension<SharedController>,
+    headers: HeaderMap,
+) -> Result<Html<String>, AppError> {
+    let (teacher, organization, students) = {
+        let controller_guard = controller.lock().await;
+        let teacher = controller_guard.teacher(authorization(&headers))?;
+        let organization = controller_guard.organization_name(&teacher);
+        let students = controller_guard.class_overview(&teacher)?;
+        (teacher, organization, students)
+    };
+
+    let page = DashboardTemplate {
+        teacher_name: &teacher.name,
+        organization: &organization,
+        students: &students,
+    }
+    .render()
+    .map_err(|e| AppError::Internal(e.to_string()))?;
+    Ok(Html(page))
+}
+
+/// The dashboard roster as a CSV download.
+pub async fn teacher_roster_csv(
+    Extension(controller): Extension<SharedController>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let (teacher, students) = {
+        let controller_guard = controller.lock().await;
+        let teacher = controller_guard.teacher(authorization(&headers))?;
+        let students = controller_guard.class_overview(&teacher)?;
+        (teacher, students)
+    };
+
+    Ok(download(
+        "text/csv; charset=utf-8",
+        &format!("students-{}.csv", teacher.id),
+        dashboard::roster_csv(&students),
+    ))
+}
+
+/// Read-only transcript of a session of one of the teacher's students.
+pub async fn teacher_transcript(
+    Extension(controller): Extension<SharedController>,
+    Path((student_id, session_id)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let export = {
+        let controller_guard = controller.lock().await;
+        let teacher = controller_guard.teacher(authorization(&headers))?;
+        controller_guard.teacher_transcript(&teacher, student_id, session_id)?
+    };
+
+    // The transcript inlines its styles so that the same file works
+    // offline; the nonce lets them and the math scripts past the page's
+    // policy.
+    let nonce = csp::nonce();
+    let page = export
+        .html_page(&nonce)
+        .map_err(|e| AppError::Internal(e.to_string()))?;
+    Ok((
+        [(header::CONTENT_SECURITY_POLICY, csp::with_nonce(&nonce))],
+        Html(page),
+    )
+        .into_response())
+}
+
+fn authorization(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+}
+
+async fn start_session(
+    controller: &SharedController,
+    request: CreateSessionRequest,
+) -> Result<CreateSessionResponse, AppError> {
     let session_id = {
         let mut controller_guard = controller.lock().await;
-        controller_guard.create_session(payload.student_id)?
+        controller_guard.create_session(
+            request.student_id,
+            request.course_id,
+            request.organization_id,
+            request.persona,
+        )?
     };
 
-    Ok(Json(CreateSessionResponse {
+    Ok(CreateSessionResponse {
         session_id,
         message: "Tutoring session created successfully".into(),
+    })
+}
+
+async fn ask(
+    controller: &SharedController,
+    student_id: String,
+    session_id: String,
+    query: String,
+    turn_id: Option<String>,
+) -> Result<SendQueryResponse, AppError> {
+    let query = TutorController::moderate(
+        controller,
+        &student_id,
+        &session_id,
+        query,
+        turn_id.as_deref(),
+    )
+    .await?;
+    let turn = {
+        let mut controller_guard = controller.lock().await;
+        controller_guard.start_query(student_id, session_id, query, turn_id)?
+    };
+
+    let reply = TutorController::run_turn(controller, turn).await?;
+    Ok(reply.into())
+}
+
+/// Start a new tutoring session for a student.
+#[utoipa::path(
+    post,
+    path = "/api/v1/sessions",
+    tag = "sessions",
+    request_body = CreateSessionRequest,
+    responses(
+        (status = 200, description = "Session created", body = ApiResponse<CreateSessionResponse>),
+        (status = 400, description = "Invalid student id", body = ErrorResponse),
+        (status = 422, description = "Malformed body", body = ErrorResponse),
+    )
+)]
+pub async fn v1_create_session(
+    Extension(controller): Extension<SharedController>,
+    ApiJson(payload): ApiJson<CreateSessionRequest>,
+) -> Result<ApiResponse<CreateSessionResponse>, AppError> {
+    let created = start_session(&controller, payload).await?;
+    Ok(ApiResponse::new(created))
+}
+
+/// Ask the tutor a question within an existing session.
+#[utoipa::path(
+    post,
+    path = "/api/v1/sessions/{session_id}/queries",
+    tag = "sessions",
+    params(("session_id" = String, Path, description = "Session returned by POST /api/v1/sessions")),
+    request_body = QueryRequest,
+    responses(
+        (status = 200, description = "Tutor reply, or an empty cancelled reply", body = ApiResponse<SendQueryResponse>),
+        (status = 400, description = "Missing or oversized fields", body = ErrorResponse),
+        (status = 404, description = "Unknown session", body = ErrorResponse),
+        (status = 410, description = "Session expired after inactivity", body = ErrorResponse),
+        (status = 409, description = "Another turn is in progress in this session", body = ErrorResponse),
+        (status = 422, description = "Malformed body", body = ErrorResponse),
+        (status = 500, description = "Upstream model failure", body = ErrorResponse),
+    )
+)]
+pub async fn v1_send_query(
+    Extension(controller): Extension<SharedController>,
+    ApiPath(session_id): ApiPath<String>,
+    ApiJson(payload): ApiJson<QueryRequest>,
+) -> Result<ApiResponse<SendQueryResponse>, AppError> {
+    let reply = ask(
+        &controller,
+        payload.student_id,
+        session_id,
+        payload.query,
+        payload.turn_id,
+    )
+    .await?;
+    Ok(ApiResponse::new(reply))
+}
+
+/// Stop a tutor turn that is still waiting for the model. The pending
+/// query request then returns with `cancelled: true` and the session is
+/// free for the next question.
+#[utoipa::path(
+    post,
+    path = "/api/v1/turns/{turn_id}/cancel",
+    tag = "sessions",
+    params(("turn_id" = String, Path, description = "Id sent with, or returned by, the query")),
+    request_body = StudentRequest,
+    responses(
+        (status = 200, description = "Cancellation requested", body = ApiResponse<CancelTurnResponse>),
+        (status = 404, description = "No such turn in progress", body = ErrorResponse),
+        (status = 422, description = "Malformed body", body = ErrorResponse),
+    )
+)]
+pub async fn cancel_turn(
+    Extension(controller): Extension<SharedController>,
+    ApiPath(turn_id): ApiPath<String>,
+    ApiJson(payload): ApiJson<StudentRequest>,
+) -> Result<ApiResponse<CancelTurnResponse>, AppError> {
+    let session_id = {
+        let mut controller_guard = controller.lock().await;
+        controller_guard.cancel_turn(payload.student_id, turn_id.clone())?
+    };
+
+    Ok(ApiResponse::new(CancelTurnResponse {
+        turn_id,
+        session_id,
     }))
 }
 
+/// The active branch of a session, with the ids needed to edit or
+/// regenerate a message.
+#[utoipa::path(
+    get,
+    path = "/api/v1/sessions/{session_id}/messages",
+    tag = "branches",
+    params(("session_id" = String, Path, description = "Session to read"), StudentQuery),
+    responses(
+        (status = 200, description = "Messages on the active branch, oldest first", body = ApiResponse<Vec<ActiveMessage>>),
+        (status = 404, description = "Unknown session", body = ErrorResponse),
+        (status = 410, description = "Session expired after inactivity", body = ErrorResponse),
+    )
+)]
+pub async fn list_messages(
+    Extension(controller): Extension<SharedController>,
+    ApiPath(session_id): ApiPath<String>,
+    ApiQuery(query): ApiQuery<StudentQuery>,
+) -> Result<ApiResponse<Vec<ActiveMessage>>, AppError> {
+    let messages = {
+        let mut controller_guard = controller.lock().await;
+        controller_guard.active_messages(query.student_id, session_id)?
+    };
+
+    Ok(ApiResponse::new(messages))
+}
+
+/// Replace an earlier question. The original stays in history on its own
+/// branch; the edit and the tutor's new answer become the active branch.
+#[utoipa::path(
+    post,
+    path = "/api/v1/sessions/{session_id}/messages/{message_id}/edit",
+    tag = "branches",
+    params(
+        ("session_id" = String, Path, description = "Session containing the message"),
+        ("message_id" = usize, Path, description = "Id of a student message"),
+    ),
+    request_body = QueryRequest,
+    responses(
+        (status = 200, description = "Tutor reply to the edited question", body = ApiResponse<SendQueryResponse>),
+        (status = 400, description = "Not a student message, or invalid query", body = ErrorResponse),
+        (status = 404, description = "Unknown session or message", body = ErrorResponse),
+        (status = 410, description = "Session expired after inactivity", body = ErrorResponse),
+        (status = 409, description = "Another turn is in progress in this session", body = ErrorResponse),
+        (status = 500, description = "Upstream model failure", body = ErrorResponse),
+    )
+)]
+pub async fn edit_message(
+    Extension(controller): Extension<SharedController>,
+    ApiPath((session_id, message_id)): ApiPath<(String, NodeId)>,
+    ApiJson(payload): ApiJson<QueryRequest>,
+) -> Result<ApiResponse<SendQueryResponse>, AppError> {
+    let query = TutorController::moderate(
+        &controller,
+        &payload.student_id,
+        &session_id,
+        payload.query,
+        payload.turn_id.as_deref(),
+    )
+    .await?;
+    let turn = {
+        let mut controller_guard = controller.lock().await;
+        controller_guard.start_edit(
+            payload.student_id,
+            session_id,
+            message_id,
+            query,
+            payload.turn_id,
+        )?
+    };
+
+    let reply = TutorController::run_turn(&controller, turn).await?;
+    Ok(ApiResponse::new(reply.into()))
+}
+
+/// Ask for a new answer in place of a tutor reply. The old reply stays in
+/// history on its own branch.
+#[utoipa::path(
+    post,
+    path = "/api/v1/sessions/{session_id}/messages/{message_id}/regenerate",
+    tag = "branches",
+    params(
+        ("session_id" = String, Path, description = "Session containing the message"),
+        ("message_id" = usize, Path, description = "Id of a tutor message"),
+    ),
+    request_body = RegenerateRequest,
+    responses(
+        (status = 200, description = "The new tutor reply", body = ApiResponse<SendQueryResponse>),
+        (status = 400, description = "Not a tutor message", body = ErrorResponse),
+        (status = 404, description = "Unknown session or message", body = ErrorResponse),
+        (status = 410, description = "Session expired after inactivity", body = ErrorResponse),
+        (status = 409, description = "Another turn is in progress in this session", body = ErrorResponse),
+        (status = 500, description = "Upstream model failure", body = ErrorResponse),
+    )
+)]
+pub async fn regenerate_reply(
+    Extension(controller): Extension<SharedController>,
+    ApiPath((session_id, message_id)): ApiPath<(String, NodeId)>,
+    ApiJson(payload): ApiJson<RegenerateRequest>,
+) -> Result<ApiResponse<SendQueryResponse>, AppError> {
+    let turn = {
+        let mut controller_guard = controller.lock().await;
+        controller_guard.start_regenerate(
+            payload.student_id,
+            session_id,
+            message_id,
+            payload.turn_id,
+        )?
+    };
+
+    let reply = TutorController::run_turn(&controller, turn).await?;
+    Ok(ApiResponse::new(reply.into()))
+}
+
+/// Every branch of the conversation tree, one per leaf message.
+#[utoipa::path(
+    get,
+    path = "/api/v1/sessions/{session_id}/branches",
+    tag = "branches",
+    params(("session_id" = String, Path, description = "Session to read"), StudentQuery),
+    responses(
+        (status = 200, description = "Branches in creation order", body = ApiResponse<Vec<BranchSummary>>),
+        (status = 404, description = "Unknown session", body = ErrorResponse),
+        (status = 410, description = "Session expired after inactivity", body = ErrorResponse),
+    )
+)]
+pub async fn list_branches(
+    Extension(controller): Extension<SharedController>,
+    ApiPath(session_id): ApiPath<String>,
+    ApiQuery(query): ApiQuery<StudentQuery>,
+) -> Result<ApiResponse<Vec<BranchSummary>>, AppError> {
+    let branches = {
+        let mut controller_guard = controller.lock().await;
+        controller_guard.branches(query.student_id, session_id)?
+    };
+
+    Ok(ApiResponse::new(branches))
+}
+
+/// Switch to the branch through `message_id`. Any message may be given; the
+/// newest continuation below it becomes active.
+#[utoipa::path(
+    post,
+    path = "/api/v1/sessions/{session_id}/branches/{message_id}/activate",
+    tag = "branches",
+    params(
+        ("session_id" = String, Path, description = "Session to change"),
+        ("message_id" = usize, Path, description = "Leaf or alternative to switch to"),
+    ),
+    request_body = StudentRequest,
+    responses(
+        (status = 200, description = "The new active branch", body = ApiResponse<Vec<ActiveMessage>>),
+        (status = 404, description = "Unknown session or message", body = ErrorResponse),
+        (status = 410, description = "Session expired after inactivity", body = ErrorResponse),
+        (status = 409, description = "A turn is in progress in this session", body = ErrorResponse),
+    )
+)]
+pub async fn activate_branch(
+    Extension(controller): Extension<SharedController>,
+    ApiPath((session_id, message_id)): ApiPath<(String, NodeId)>,
+    ApiJson(payload): ApiJson<StudentRequest>,
+) -> Result<ApiResponse<Vec<ActiveMessage>>, AppError> {
+    let messages = {
+        let mut controller_guard = controller.lock().await;
+        controller_guard.activate_branch(payload.student_id, session_id, message_id)?
+    };
+
+    Ok(ApiResponse::new(messages))
+}
+
+/// Subject personas a session can be given.
+#[utoipa::path(
+    get,
+    path = "/api/v1/personas",
+    tag = "personas",
+    responses(
+        (status = 200, description = "Configured personas; empty when there are none", body = ApiResponse<Vec<PersonaSummary>>),
+    )
+)]
+pub async fn list_personas(
+    Extension(controller): Extension<SharedController>,
+) -> ApiResponse<Vec<PersonaSummary>> {
+    let personas = controller.lock().await.personas();
+    ApiResponse::new(personas)
+}
+
+/// Choose the persona that answers in a session, overriding the one the
+/// first question picked.
+#[utoipa::path(
+    put,
+    path = "/api/v1/sessions/{session_id}/persona",
+    tag = "personas",
+    params(("session_id" = String, Path, description = "Session to change")),
+    request_body = SetPersonaRequest,
+    responses(
+        (status = 200, description = "The session's persona", body = ApiResponse<SessionPersonaResponse>),
+        (status = 400, description = "Unknown persona", body = ErrorResponse),
+        (status = 404, description = "Unknown session", body = ErrorResponse),
+        (status = 410, description = "Session expired after inactivity", body = ErrorResponse),
+        (status = 409, description = "A turn is in progress in this session", body = ErrorResponse),
+    )
+)]
+pub async fn set_persona(
+    Extension(controller): Extension<SharedController>,
+    ApiPath(session_id): ApiPath<String>,
+    ApiJson(payload): ApiJson<SetPersonaRequest>,
+) -> Result<ApiResponse<SessionPersonaResponse>, AppError> {
+    {
+        let mut controller_guard = controller.lock().await;
+        controller_guard.set_persona(
+            payload.student_id,
+            session_id.clone(),
+            payload.persona.clone(),
+        )?;
+    }
+
+    Ok(ApiResponse::new(SessionPersonaResponse {
+        session_id,
+        persona: payload.persona,
+    }))
+}
+
+/// Create a session from a transcript exported here or by another tool. Its
+/// messages are moderated like live ones; moderation, citations and token
+/// usage recorded in the file are ignored.
+#[utoipa::path(
+    post,
+    path = "/api/v1/sessions/import",
+    tag = "sessions",
+    request_body = ImportSessionRequest,
+    responses(
+        (status = 200, description = "Session created from the transcript", body = ApiResponse<ImportSessionResponse>),
+        (status = 400, description = "Unsupported roles, oversized or empty transcript, or a question blocked by moderation", body = ErrorResponse),
+        (status = 422, description = "Malformed body", body = ErrorResponse),
+    )
+)]
+pub async fn import_session(
+    Extension(controller): Extension<SharedController>,
+    ApiJson(payload): ApiJson<ImportSessionRequest>,
+) -> Result<ApiResponse<ImportSessionResponse>, AppError> {
+    let (session_id, message_count) =
+        TutorController::import_session(&controller, payload.student_id, payload.transcript)
+            .await?;
+
+    Ok(ApiResponse::new(ImportSessionResponse {
+        session_id,
+        message_count,
+    }))
+}
+
+/// Start a new session with a copy of an existing session's prompt and history.
+#[utoipa::path(
+    post,
+    path = "/api/v1/sessions/{session_id}/clone",
+    tag = "sessions",
+    params(("session_id" = String, Path, description = "Session to copy")),
+    request_body = StudentRequest,
+    responses(
+        (status = 200, description = "Copy created", body = ApiResponse<CreateSessionResponse>),
+        (status = 404, description = "Unknown session", body = ErrorResponse),
+        (status = 410, description = "Session expired after inactivity", body = ErrorResponse),
+        (status = 422, description = "Malformed body", body = ErrorResponse),
+    )
+)]
+pub async fn clone_session(
+    Extension(controller): Extension<SharedController>,
+    ApiPath(session_id): ApiPath<String>,
+    ApiJson(payload): ApiJson<StudentRequest>,
+) -> Result<ApiResponse<CreateSessionResponse>, AppError> {
+    let new_id = {
+        let mut controller_guard = controller.lock().await;
+        controller_guard.clone_session(payload.student_id, session_id)?
+    };
+
+    Ok(ApiResponse::new(CreateSessionResponse {
+        session_id: new_id,
+        message: "Tutoring session cloned successfully".into(),
+    }))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ExportQuery {
+    /// Owner of the session.
+    pub student_id: String,
+    /// Output format; defaults to `json`.
+    #[serde(default)]
+    #[param(inline)]
+    pub format: ExportFormat,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct BulkExportQuery {
+    /// Format of each file in the archive; defaults to `json`.
+    #[serde(default)]
+    #[param(inline)]
+    pub format: ExportFormat,
+}
+
+fn download(content_type: &str, file_name: &str, body: impl IntoResponse) -> Response {
+    // Ids come from clients; keep the header value plain.
+    let file_name: String = file_name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || "._-".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let disposition = format!("attachment; filename=\"{file_name}\"");
+    (
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+/// Download one session as a Markdown, JSON or standalone HTML transcript.
+///
+/// The JSON format is lossless: it keeps timestamps, token usage and the raw
+/// chat messages, and can be imported again.
+#[utoipa::path(
+    get,
+    path = "/api/v1/sessions/{session_id}/export",
+    tag = "export",
+    params(("session_id" = String, Path, description = "Session to export"), ExportQuery),
+    responses(
+        (status = 200, description = "Transcript file", content(
+            (String = "text/markdown"),
+            (String = "application/json"),
+            (String = "text/html"),
+        )),
+        (status = 400, description = "Missing student id or bad format", body = ErrorResponse),
+        (status = 404, description = "Unknown session", body = ErrorResponse),
+        (status = 410, description = "Session expired after inactivity", body = ErrorResponse),
+    )
+)]
+pub async fn export_session(
+    Extension(controller): Extension<SharedController>,
+    ApiPath(session_id): ApiPath<String>,
+    ApiQuery(query): ApiQuery<ExportQuery>,
+) -> Result<Response, AppError> {
+    let export = {
+        let mut controller_guard = controller.lock().await;
+        controller_guard.export_session(query.student_id, session_id)?
+    };
+
+    let body = export
+        .render(query.format)
+        .map_err(|e| AppError::Internal(e.to_string()))?;
+    Ok(download(
+        query.format.content_type(),
+        &export.file_name(query.format),
+        body,
+    ))
+}
+
+/// Download every session of a student as a zip archive.
+#[utoipa::path(
+    get,
+    path = "/api/v1/students/{student_id}/export",
+    tag = "export",
+    params(("student_id" = String, Path, description = "Student whose sessions to export"), BulkExportQuery),
+    responses(
+        (status = 200, description = "Zip archive with one file per session", content_type = "application/zip", body = String),
+        (status = 400, description = "Bad format", body = ErrorResponse),
+        (status = 404, description = "Student has no sessions", body = ErrorResponse),
+    )
+)]
+pub async fn export_student(
+    Extension(controller): Extension<SharedController>,
+    ApiPath(student_id): ApiPath<String>,
+    ApiQuery(query): ApiQuery<BulkExportQuery>,
+) -> Result<Response, AppError> {
+    let sessions = {
+        let controller_guard = controller.lock().await;
+        controller_guard.export_student(student_id.clone())?
+    };
+
+    let archive = export::zip_sessions(&sessions, query.format)
+        .map_err(|e| AppError::Internal(e.to_string()))?;
+    Ok(download(
+        "application/zip",
+        &format!("sessions-{student_id}.zip"),
+        archive,
+    ))
+}
+
+/// Questions that were answered in hint-only mode because they looked like
+/// exam or homework answer requests, for teacher review. Teachers sign in
+/// with HTTP Basic auth and see their assigned students only.
+#[utoipa::path(
+    get,
+    path = "/api/v1/integrity/events",
+    tag = "integrity",
+    params(EventsQuery),
+    responses(
+        (status = 200, description = "Flagged turns, oldest first", body = ApiResponse<Vec<FlaggedEvent>>),
+        (status = 400, description = "Invalid limit", body = ErrorResponse),
+        (status = 401, description = "Missing or wrong teacher credentials", body = ErrorResponse),
+        (status = 404, description = "Student not assigned to the teacher, or the dashboard is disabled", body = ErrorResponse),
+    )
+)]
+pub async fn integrity_events(
+    Extension(controller): Extension<SharedController>,
+    headers: HeaderMap,
+    ApiQuery(query): ApiQuery<EventsQuery>,
+) -> Result<ApiResponse<Vec<FlaggedEvent>>, AppError> {
+    let events = {
+        let controller_guard = controller.lock().await;
+        let teacher = controller_guard.teacher(authorization(&headers))?;
+        controller_guard.integrity_events(&teacher, query.student_id, query.limit)?
+    };
+
+    Ok(ApiResponse::new(events))
+}
+
+/// Messages that moderation redacted, annotated or blocked. Entries record
+/// the categories and checkers, not the message text. Teachers sign in with
+/// HTTP Basic auth and see their assigned students only.
+#[utoipa::path(
+    get,
+    path = "/api/v1/moderation/audit",
+    tag = "moderation",
+    params(EventsQuery),
+    responses(
+        (status = 200, description = "Moderation decisions, oldest first", body = ApiResponse<Vec<ModerationEvent>>),
+        (status = 400, description = "Invalid limit", body = ErrorResponse),
+        (status = 401, description = "Missing or wrong teacher credentials", body = ErrorResponse),
+        (status = 404, description = "Student not assigned to the teacher, or the dashboard is disabled", body = ErrorResponse),
+    )
+)]
+pub async fn moderation_audit(
+    Extension(controller): Extension<SharedController>,
+    headers: HeaderMap,
+    ApiQuery(query): ApiQuery<EventsQuery>,
+) -> Result<ApiResponse<Vec<ModerationEvent>>, AppError> {
+    let events = {
+        let controller_guard = controller.lock().await;
+        let teacher = controller_guard.teacher(authorization(&headers))?;
+        controller_guard.moderation_events(&teacher, query.student_id, query.limit)?
+    };
+
+    Ok(ApiResponse::new(events))
+}
+
+/// Add a Markdown, plain text or PDF file to the materials of a course or
+/// organization. Sessions created for that course or organization are
+/// answered with its passages as cited sources. Requires teacher
+/// credentials; the course or organization must be the teacher's.
+#[utoipa::path(
+    post,
+    path = "/api/v1/documents",
+    tag = "documents",
+    request_body(content = UploadDocumentForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "Document indexed", body = ApiResponse<DocumentSummary>),
+        (status = 400, description = "Missing fields, another organization's course, unsupported or oversized file, or no text", body = ErrorResponse),
+        (status = 401, description = "Missing or wrong teacher credentials", body = ErrorResponse),
+        (status = 404, description = "Course materials or the teacher dashboard are disabled", body = ErrorResponse),
+    )
+)]
+pub async fn upload_document(
+    Extension(controller): Extension<SharedController>,
+    headers: HeaderMap,
+    multipart: Result<Multipart, MultipartRejection>,
+) -> Result<ApiResponse<DocumentSummary>, AppError> {
+    let (teacher, max_bytes) = {
+        let controller_guard = controller.lock().await;
+        let teacher = controller_guard.teacher(authorization(&headers))?;
+        (teacher, controller_guard.max_upload_bytes()?)
+    };
+    let mut multipart =
+        multipart.map_err(|rejection| AppError::BadRequest(rejection.body_text()))?;
+    let bad_form = |err: MultipartError| AppError::BadRequest(err.body_text());
+
+    let mut form = DocumentForm::default();
+    while let Some(mut field) = multipart.next_field().await.map_err(bad_form)? {
+        match field.name() {
+            Some("file") => {
+                form.file_name = Some(field.file_name().unwrap_or("upload").to_string());
+                form.content_type = field.content_type().map(str::to_string);
+                let mut bytes = Vec::new();
+                while let Some(chunk) = field.chunk().await.map_err(bad_form)? {
+                    if bytes.len() + chunk.len() > max_bytes {
+                        return Err(AppError::BadRequest(format!(
+                            "file exceeds {} bytes",
+                            max_bytes
+                        )));
+                    }
+                    bytes.extend_from_slice(&chunk);
+                }
+                form.file = Some(bytes);
+            }
+            Some("course_id") => form.course_id = Some(field.text().await.map_err(bad_form)?),
+            Some("organization_id") => {
+                form.organization_id = Some(field.text().await.map_err(bad_form)?)
+            }
+            Some("title") => form.title = Some(field.text().await.map_err(bad_form)?),
+            _ => {}
+        }
+    }
+
+    let document = TutorController::upload_document(&controller, &teacher, form).await?;
+    Ok(ApiResponse::new(document))
+}
+
+/// Course materials shared within the teacher's organization, oldest
+/// first. Requires teacher credentials.
+#[utoipa::path(
+    get,
+    path = "/api/v1/documents",
+    tag = "documents",
+    params(DocumentsQuery),
+    responses(
+        (status = 200, description = "The organization's documents, filtered by course or organization when given", body = ApiResponse<Vec<DocumentSummary>>),
+        (status = 400, description = "Both filters given, or a filter outside the teacher's organization", body = ErrorResponse),
+        (status = 401, description = "Missing or wrong teacher credentials", body = ErrorResponse),
+        (status = 404, description = "Course materials or the teacher dashboard are disabled", body = ErrorResponse),
+    )
+)]
+pub async fn list_documents(
+    Extension(controller): Extension<SharedController>,
+    headers: HeaderMap,
+    ApiQuery(query): ApiQuery<DocumentsQuery>,
+) -> Result<ApiResponse<Vec<DocumentSummary>>, AppError> {
+    let documents = {
+        let controller_guard = controller.lock().await;
+        let teacher = controller_guard.teacher(authorization(&headers))?;
+        controller_guard.documents(&teacher, query.course_id, query.organization_id)?
+    };
+
+    Ok(ApiResponse::new(documents))
+}
+
+/// Remove a document from the course materials. Requires the credentials
+/// of a teacher in the organization the document is shared with.
+#[utoipa::path(
+    delete,
+    path = "/api/v1/documents/{document_id}",
+    tag = "documents",
+    params(("document_id" = String, Path, description = "Document returned by the upload")),
+    responses(
+        (status = 200, description = "Document removed", body = ApiResponse<String>),
+        (status = 401, description = "Missing or wrong teacher credentials", body = ErrorResponse),
+        (status = 404, description = "Unknown document or another organization's, or course materials or the teacher dashboard are disabled", body = ErrorResponse),
+    )
+)]
+pub async fn delete_document(
+    Extension(controller): Extension<SharedController>,
+    headers: HeaderMap,
+    ApiPath(document_id): ApiPath<String>,
+) -> Result<ApiResponse<String>, AppError> {
+    {
+        let mut controller_guard = controller.lock().await;
+        let teacher = controller_guard.teacher(authorization(&headers))?;
+        controller_guard.delete_document(&teacher, document_id.clone())?;
+    }
+
+    Ok(ApiResponse::new(document_id))
+}
+
+/// Write a practice quiz from the active branch of a session.
+///
+/// The model is asked for multiple-choice and short-answer questions with
+/// answer keys; malformed replies are sent back for correction up to
+/// `quiz.max_attempts` times. The answer keys stay on the server.
+#[utoipa::path(
+    post,
+    path = "/api/v1/sessions/{session_id}/quiz",
+    tag = "quizzes",
+    params(("session_id" = String, Path, description = "Session to quiz on")),
+    request_body = CreateQuizRequest,
+    responses(
+        (status = 200, description = "The new quiz, without answers", body = ApiResponse<QuizView>),
+        (status = 400, description = "Bad question count or topic, or nothing to quiz on yet", body = ErrorResponse),
+        (status = 404, description = "Unknown session, or quizzes are disabled", body = ErrorResponse),
+        (status = 410, description = "Session expired after inactivity", body = ErrorResponse),
+        (status = 422, description = "Malformed body", body = ErrorResponse),
+        (status = 500, description = "Upstream failure or no valid quiz after every attempt", body = ErrorResponse),
+    )
+)]
+pub async fn create_quiz(
+    Extension(controller): Extension<SharedController>,
+    ApiPath(session_id): ApiPath<String>,
+    ApiJson(payload): ApiJson<CreateQuizRequest>,
+) -> Result<ApiResponse<QuizView>, AppError> {
+    let pending = {
+        let mut controller_guard = controller.lock().await;
+        controller_guard.start_quiz(
+            payload.student_id,
+            session_id,
+            payload.questions,
+            payload.topic,
+        )?
+    };
+
+    let quiz = TutorController::generate_quiz(&controller, pending).await?;
+    Ok(ApiResponse::new(quiz))
+}
+
+/// Start a practice problem in a session. Until it is solved or the
+/// solution is shown, the tutor answers questions in the session with
+/// guiding questions and the hints revealed so far, never the solution.
+#[utoipa::path(
+    post,
+    path = "/api/v1/sessions/{session_id}/problems",
+    tag = "problems",
+    params(("session_id" = String, Path, description = "Session to work on the problem in")),
+    request_body = CreateProblemRequest,
+    responses(
+        (status = 200, description = "The problem, with no hints shown yet", body = ApiResponse<ProblemView>),
+        (status = 400, description = "Empty or oversized statement, solution or hints", body = ErrorResponse),
+        (status = 404, description = "Unknown session, or practice problems are disabled", body = ErrorResponse),
+        (status = 410, description = "Session expired after inactivity", body = ErrorResponse),
+        (status = 422, description = "Malformed body", body = ErrorResponse),
+        (status = 500, description = "Upstream failure or no valid hints after every attempt", body = ErrorResponse),
+    )
+)]
+pub async fn create_problem(
+    Extension(controller): Extension<SharedController>,
+    ApiPath(session_id): ApiPath<String>,
+    ApiJson(payload): ApiJson<CreateProblemRequest>,
+) -> Result<ApiResponse<ProblemView>, AppError> {
+    let pending = {
+        let mut controller_guard = controller.lock().await;
+        controller_guard.start_problem(
+            payload.student_id,
+            session_id,
+            ProblemDraft {
+                statement: payload.statement,
+                solution: payload.solution,
+                answer: payload.answer,
+                hints: payload.hints,
+            },
+        )?
+    };
+
+    let problem = TutorController::register_problem(&controller, pending).await?;
+    Ok(ApiResponse::new(problem))
+}
+
+/// A practice problem with the hints shown and attempts made so far.
+#[utoipa::path(
+    get,
+    path = "/api/v1/problems/{problem_id}",
+    tag = "problems",
+    params(("problem_id" = String, Path, description = "Problem to read"), StudentQuery),
+    responses(
+        (status = 200, description = "The problem; the solution only once it is closed", body = ApiResponse<ProblemView>),
+        (status = 404, description = "Unknown problem, or practice problems are disabled", body = ErrorResponse),
+    )
+)]
+pub async fn get_problem(
+    Extension(controller): Extension<SharedController>,
+    ApiPath(problem_id): ApiPath<String>,
+    ApiQuery(query): ApiQuery<StudentQuery>,
+) -> Result<ApiResponse<ProblemView>, AppError> {
+    let problem = {
+        let controller_guard = controller.lock().await;
+        controller_guard.problem(query.student_id, problem_id)?
+    };
+
+    Ok(ApiResponse::new(problem))
+}
+
+/// Reveal the next hint, each giving more help than the last.
+#[utoipa::path(
+    post,
+    path = "/api/v1/problems/{problem_id}/hints",
+    tag = "problems",
+    params(("problem_id" = String, Path, description = "Problem to get a hint for")),
+    request_body = StudentRequest,
+    responses(
+        (status = 200, description = "The problem with one more hint shown", body = ApiResponse<ProblemView>),
+        (status = 400, description = "Every hint is already shown", body = ErrorResponse),
+        (status = 404, description = "Unknown problem, or practice problems are disabled", body = ErrorResponse),
+    )
+)]
+pub async fn reveal_hint(
+    Extension(controller): Extension<SharedController>,
+    ApiPath(problem_id): ApiPath<String>,
+    ApiJson(payload): ApiJson<StudentRequest>,
+) -> Result<ApiResponse<ProblemView>, AppError> {
+    let problem = {
+        let mut controller_guard = controller.lock().await;
+        controller_guard.reveal_hint(payload.student_id, problem_id)?
+    };
+
+    Ok(ApiResponse::new(problem))
+}
+
+/// Check an answer against the reference solution. The feedback does not
+/// give the answer away; after `problems.reveal_after_attempts` wrong
+/// answers the worked solution is shown.
+#[utoipa::path(
+    post,
+    path = "/api/v1/problems/{problem_id}/attempts",
+    tag = "problems",
+    params(("problem_id" = String, Path, description = "Problem to answer")),
+    request_body = ProblemAttemptRequest,
+    responses(
+        (status = 200, description = "The problem with the checked attempt", body = ApiResponse<ProblemView>),
+        (status = 400, description = "Empty or oversized answer, or the problem is closed", body = ErrorResponse),
+        (status = 404, description = "Unknown problem, or practice problems are disabled", body = ErrorResponse),
+        (status = 422, description = "Malformed body", body = ErrorResponse),
+        (status = 500, description = "Upstream failure or no valid verdict after every attempt", body = ErrorResponse),
+    )
+)]
+pub async fn attempt_problem(
+    Extension(controller): Extension<SharedController>,
+    ApiPath(problem_id): ApiPath<String>,
+    ApiJson(payload): ApiJson<ProblemAttemptRequest>,
+) -> Result<ApiResponse<ProblemView>, AppError> {
+    let pending = {
+        let mut controller_guard = controller.lock().await;
+        controller_guard.start_attempt(payload.student_id, problem_id, payload.answer)?
+    };
+
+    let problem = TutorController::check_attempt(&controller, pending).await?;
+    Ok(ApiResponse::new(problem))
+}
+
+/// A quiz and the student's graded submissions.
+#[utoipa::path(
+    get,
+    path = "/api/v1/quizzes/{quiz_id}",
+    tag = "quizzes",
+    params(("quiz_id" = String, Path, description = "Quiz to read"), StudentQuery),
+    responses(
+        (status = 200, description = "The quiz, without answers", body = ApiResponse<QuizView>),
+        (status = 404, description = "Unknown quiz, or quizzes are disabled", body = ErrorResponse),
+    )
+)]
+pub async fn get_quiz(
+    Extension(controller): Extension<SharedController>,
+    ApiPath(quiz_id): ApiPath<String>,
+    ApiQuery(query): ApiQuery<StudentQuery>,
+) -> Result<ApiResponse<QuizView>, AppError> {
+    let quiz = {
+        let controller_guard = controller.lock().await;
+        controller_guard.quiz(query.student_id, quiz_id)?
+    };
+
+    Ok(ApiResponse::new(quiz))
+}
+
+/// Grade answers to a quiz. Every submission is kept with the quiz.
+#[utoipa::path(
+    post,
+    path = "/api/v1/quizzes/{quiz_id}/submissions",
+    tag = "quizzes",
+    params(("quiz_id" = String, Path, description = "Quiz being answered")),
+    request_body = SubmitQuizRequest,
+    responses(
+        (status = 200, description = "Score, with the right answer and an explanation for each question", body = ApiResponse<Submission>),
+        (status = 400, description = "Wrong number or kind of answers", body = ErrorResponse),
+        (status = 404, description = "Unknown quiz, or quizzes are disabled", body = ErrorResponse),
+        (status = 422, description = "Malformed body", body = ErrorResponse),
+    )
+)]
+pub async fn submit_quiz(
+    Extension(controller): Extension<SharedController>,
+    ApiPath(quiz_id): ApiPath<String>,
+    ApiJson(payload): ApiJson<SubmitQuizRequest>,
+) -> Result<ApiResponse<Submission>, AppError> {
+    let submission = {
+        let mut controller_guard = controller.lock().await;
+        controller_guard.submit_quiz(payload.student_id, quiz_id, payload.answers)?
+    };
+
+    Ok(ApiResponse::new(submission))
+}
+
+/// Draft flashcards from a tutor reply for the student to keep, edit or drop.
+///
+/// Drafts are not saved; keep one with `POST /api/v1/cards`. Malformed
+/// model replies are sent back for correction up to
+/// `flashcards.max_attempts` times.
+#[utoipa::path(
+    post,
+    path = "/api/v1/sessions/{session_id}/cards/candidates",
+    tag = "flashcards",
+    params(("session_id" = String, Path, description = "Session holding the reply")),
+    request_body = CardCandidatesRequest,
+    responses(
+        (status = 200, description = "Draft cards and the message they came from", body = ApiResponse<CardCandidates>),
+        (status = 400, description = "The message is not a tutor reply, or there is no reply yet", body = ErrorResponse),
+        (status = 404, description = "Unknown session or message, or flashcards are disabled", body = ErrorResponse),
+        (status = 410, description = "Session expired after inactivity", body = ErrorResponse),
+        (status = 422, description = "Malformed body", body = ErrorResponse),
+        (status = 500, description = "Upstream failure or no valid drafts after every attempt", body = ErrorResponse),
+    )
+)]
+pub async fn draft_cards(
+    Extension(controller): Extension<SharedController>,
+    ApiPath(session_id): ApiPath<String>,
+    ApiJson(payload): ApiJson<CardCandidatesRequest>,
+) -> Result<ApiResponse<CardCandidates>, AppError> {
+    let pending = {
+        let mut controller_guard = controller.lock().await;
+        controller_guard.start_cards(payload.student_id, session_id, payload.message_id)?
+    };
+
+    let candidates = TutorController::draft_cards(&controller, pending).await?;
+    Ok(ApiResponse::new(candidates))
+}
+
+/// Save a flashcard. It is due for review straight away.
+#[utoipa::path(
+    post,
+    path = "/api/v1/cards",
+    tag = "flashcards",
+    request_body = CreateCardRequest,
+    responses(
+        (status = 200, description = "The saved card", body = ApiResponse<Card>),
+        (status = 400, description = "Empty or overlong side, or half a source", body = ErrorResponse),
+        (status = 404, description = "Unknown source session or message, or flashcards are disabled", body = ErrorResponse),
+        (status = 422, description = "Malformed body", body = ErrorResponse),
+    )
+)]
+pub async fn create_card(
+    Extension(controller): Extension<SharedController>,
+    ApiJson(payload): ApiJson<CreateCardRequest>,
+) -> Result<ApiResponse<Card>, AppError> {
+    let card = {
+        let mut controller_guard = controller.lock().await;
+        controller_guard.add_card(
+            payload.student_id,
+            CardDraft {
+                front: payload.front,
+                back: payload.back,
+            },
+            payload.session_id,
+            payload.message_id,
+        )?
+    };
+
+    Ok(ApiResponse::new(card))
+}
+
+/// Change the front or back of a card. Its review schedule is kept.
+#[utoipa::path(
+    patch,
+    path = "/api/v1/cards/{card_id}",
+    tag = "flashcards",
+    params(("card_id" = String, Path, description = "Card to change")),
+    request_body = UpdateCardRequest,
+    responses(
+        (status = 200, description = "The changed card", body = ApiResponse<Card>),
+        (status = 400, description = "Nothing to change, or an empty or overlong side", body = ErrorResponse),
+        (status = 404, description = "Unknown card, or flashcards are disabled", body = ErrorResponse),
+        (status = 422, description = "Malformed body", body = ErrorResponse),
+    )
+)]
+pub async fn update_card(
+    Extension(controller): Extension<SharedController>,
+    ApiPath(card_id): ApiPath<String>,
+    ApiJson(payload): ApiJson<UpdateCardRequest>,
+) -> Result<ApiResponse<Card>, AppError> {
+    let card = {
+        let mut controller_guard = controller.lock().await;
+        controller_guard.edit_card(payload.student_id, card_id, payload.front, payload.back)?
+    };
+
+    Ok(ApiResponse::new(card))
+}
+
+/// Delete a card and its review history.
+#[utoipa::path(
+    delete,
+    path = "/api/v1/cards/{card_id}",
+    tag = "flashcards",
+    params(("card_id" = String, Path, description = "Card to delete"), StudentQuery),
+    responses(
+        (status = 200, description = "Id of the deleted card", body = ApiResponse<String>),
+        (status = 404, description = "Unknown card, or flashcards are disabled", body = ErrorResponse),
+    )
+)]
+pub async fn delete_card(
+    Extension(controller): Extension<SharedController>,
+    ApiPath(card_id): ApiPath<String>,
+    ApiQuery(query): ApiQuery<StudentQuery>,
+) -> Result<ApiResponse<String>, AppError> {
+    {
+        let mut controller_guard = controller.lock().await;
+        controller_guard.remove_card(query.student_id, card_id.clone())?;
+    }
+
+    Ok(ApiResponse::new(card_id))
+}
+
+/// Record a review and schedule the card's next one (SM-2).
+#[utoipa::path(
+    post,
+    path = "/api/v1/cards/{card_id}/reviews",
+    tag = "flashcards",
+    params(("card_id" = String, Path, description = "Card reviewed")),
+    request_body = ReviewCardRequest,
+    responses(
+        (status = 200, description = "The card with its new schedule", body = ApiResponse<Card>),
+        (status = 400, description = "Grade out of range", body = ErrorResponse),
+        (status = 404, description = "Unknown card, or flashcards are disabled", body = ErrorResponse),
+        (status = 422, description = "Malformed body", body = ErrorResponse),
+    )
+)]
+pub async fn review_card(
+    Extension(controller): Extension<SharedController>,
+    ApiPath(card_id): ApiPath<String>,
+    ApiJson(payload): ApiJson<ReviewCardRequest>,
+) -> Result<ApiResponse<Card>, AppError> {
+    let card = {
+        let mut controller_guard = controller.lock().await;
+        controller_guard.review_card(payload.student_id, card_id, payload.grade)?
+    };
+
+    Ok(ApiResponse::new(card))
+}
+
+/// Every card of a student, soonest due first.
+#[utoipa::path(
+    get,
+    path = "/api/v1/students/{student_id}/cards",
+    tag = "flashcards",
+    params(("student_id" = String, Path, description = "Student whose cards to list"), CardsQuery),
+    responses(
+        (status = 200, description = "Cards with their schedules", body = ApiResponse<Vec<Card>>),
+        (status = 400, description = "Limit out of range", body = ErrorResponse),
+        (status = 404, description = "Flashcards are disabled", body = ErrorResponse),
+    )
+)]
+pub async fn list_cards(
+    Extension(controller): Extension<SharedController>,
+    ApiPath(student_id): ApiPath<String>,
+    ApiQuery(query): ApiQuery<CardsQuery>,
+) -> Result<ApiResponse<Vec<Card>>, AppError> {
+    let cards = {
+        let controller_guard = controller.lock().await;
+        controller_guard.cards(student_id, false, query.limit)?
+    };
+
+    Ok(ApiResponse::new(cards))
+}
+
+/// Cards due for review now, most overdue first.
+#[utoipa::path(
+    get,
+    path = "/api/v1/students/{student_id}/cards/due",
+    tag = "flashcards",
+    params(("student_id" = String, Path, description = "Student reviewing"), CardsQuery),
+    responses(
+        (status = 200, description = "Due cards", body = ApiResponse<Vec<Card>>),
+        (status = 400, description = "Limit out of range", body = ErrorResponse),
+        (status = 404, description = "Flashcards are disabled", body = ErrorResponse),
+    )
+)]
+pub async fn due_cards(
+    Extension(controller): Extension<SharedController>,
+    ApiPath(student_id): ApiPath<String>,
+    ApiQuery(query): ApiQuery<CardsQuery>,
+) -> Result<ApiResponse<Vec<Card>>, AppError> {
+    let cards = {
+        let controller_guard = controller.lock().await;
+        controller_guard.cards(student_id, true, query.limit)?
+    };
+
+    Ok(ApiResponse::new(cards))
+}
+
+/// Topics covered, quiz scores, session counts and time spent across all
+/// of a student's sessions.
+#[utoipa::path(
+    get,
+    path = "/api/v1/students/{student_id}/progress",
+    tag = "progress",
+    params(("student_id" = String, Path, description = "Student whose progress to report")),
+    responses(
+        (status = 200, description = "Progress so far; empty for a new student", body = ApiResponse<ProgressReport>),
+        (status = 404, description = "Progress tracking is disabled", body = ErrorResponse),
+    )
+)]
+pub async fn student_progress(
+    Extension(controller): Extension<SharedController>,
+    ApiPath(student_id): ApiPath<String>,
+) -> Result<ApiResponse<ProgressReport>, AppError> {
+    let report = {
+        let controller_guard = controller.lock().await;
+        controller_guard.progress(student_id)?
+    };
+
+    Ok(ApiResponse::new(report))
+}
+
+/// What the tutor remembers about a student. Empty for a new student.
+#[utoipa::path(
+    get,
+    path = "/api/v1/students/{student_id}/profile",
+    tag = "profiles",
+    params(("student_id" = String, Path, description = "Student whose profile to read")),
+    responses(
+        (status = 200, description = "The profile", body = ApiResponse<StudentProfile>),
+        (status = 404, description = "Student profiles are disabled", body = ErrorResponse),
+    )
+)]
+pub async fn get_profile(
+    Extension(controller): Extension<SharedController>,
+    ApiPath(student_id): ApiPath<String>,
+) -> Result<ApiResponse<StudentProfile>, AppError> {
+    let profile = {
+        let controller_guard = controller.lock().await;
+        controller_guard.profile(student_id)?
+    };
+
+    Ok(ApiResponse::new(profile))
+}
+
+/// Set parts of a student's profile. Fields left out are kept; an empty
+/// string or list clears the field.
+#[utoipa::path(
+    patch,
+    path = "/api/v1/students/{student_id}/profile",
+    tag = "profiles",
+    params(("student_id" = String, Path, description = "Student whose profile to change")),
+    request_body = ProfileUpdate,
+    responses(
+        (status = 200, description = "The updated profile", body = ApiResponse<StudentProfile>),
+        (status = 400, description = "Overlong entry or too many items", body = ErrorResponse),
+        (status = 404, description = "Student profiles are disabled", body = ErrorResponse),
+        (status = 422, description = "Malformed body", body = ErrorResponse),
+    )
+)]
+pub async fn update_profile(
+    Extension(controller): Extension<SharedController>,
+    ApiPath(student_id): ApiPath<String>,
+    ApiJson(payload): ApiJson<ProfileUpdate>,
+) -> Result<ApiResponse<StudentProfile>, AppError> {
+    let profile = {
+        let mut controller_guard = controller.lock().await;
+        controller_guard.update_profile(student_id, payload)?
+    };
+
+    Ok(ApiResponse::new(profile))
+}
+
+/// Forget everything the tutor remembers about a student.
+#[utoipa::path(
+    delete,
+    path = "/api/v1/students/{student_id}/profile",
+    tag = "profiles",
+    params(("student_id" = String, Path, description = "Student whose profile to remove")),
+    responses(
+        (status = 200, description = "Profile removed", body = ApiResponse<String>),
+        (status = 404, description = "No profile for the student, or student profiles are disabled", body = ErrorResponse),
+    )
+)]
+pub async fn delete_profile(
+    Extension(controller): Extension<SharedController>,
+    ApiPath(student_id): ApiPath<String>,
+) -> Result<ApiResponse<String>, AppError> {
+    {
+        let mut controller_guard = controller.lock().await;
+        controller_guard.remove_profile(student_id.clone())?;
+    }
+
+    Ok(ApiResponse::new(student_id))
+}
+
+/// Update the student's profile from what a session showed about them.
+///
+/// Meant to run when a session ends. The model notes the student's grade,
+/// goals, preferred explanation style and misconceptions, and which known
+/// misconceptions were cleared up; malformed replies are sent back for
+/// correction up to `profile.max_attempts` times.
+#[utoipa::path(
+    post,
+    path = "/api/v1/sessions/{session_id}/profile",
+    tag = "profiles",
+    params(("session_id" = String, Path, description = "Session to learn from")),
+    request_body = StudentRequest,
+    responses(
+        (status = 200, description = "The updated profile", body = ApiResponse<StudentProfile>),
+        (status = 400, description = "Nothing to learn from yet", body = ErrorResponse),
+        (status = 404, description = "Unknown session, or student profiles are disabled", body = ErrorResponse),
+        (status = 410, description = "Session expired after inactivity", body = ErrorResponse),
+        (status = 422, description = "Malformed body", body = ErrorResponse),
+        (status = 500, description = "Upstream failure or no valid notes after every attempt", body = ErrorResponse),
+    )
+)]
+pub async fn extract_profile(
+    Extension(controller): Extension<SharedController>,
+    ApiPath(session_id): ApiPath<String>,
+    ApiJson(payload): ApiJson<StudentRequest>,
+) -> Result<ApiResponse<StudentProfile>, AppError> {
+    let pending = {
+        let mut controller_guard = controller.lock().await;
+        controller_guard.start_profile(payload.student_id, session_id)?
+    };
+
+    let profile = TutorController::extract_profile(&controller, pending).await?;
+    Ok(ApiResponse::new(profile))
+}
+
+/// Start a new tutoring session for a student.
+///
+/// Deprecated: use `POST /api/v1/sessions`.
+#[utoipa::path(
+    post,
+    path = "/api/create_session",
+    tag = "legacy",
+    request_body = CreateSessionRequest,
+    responses(
+        (status = 200, description = "Session created", body = CreateSessionResponse),
+        (status = 400, description = "Invalid student id", body = ErrorResponse),
+    )
+)]
+#[deprecated = "use POST /api/v1/sessions"]
+pub async fn create_session(
+    Extension(controller): Extension<SharedController>,
+    Json(payload): Json<CreateSessionRequest>,
+) -> Result<Json<CreateSessionResponse>, AppError> {
+    Ok(Json(start_session(&controller, payload).await?))
+}
+
+/// Ask the tutor a question within an existing session.
+///
+/// Deprecated: use `POST /api/v1/sessions/{session_id}/queries`.
+#[utoipa::path(
+    post,
+    path = "/api/send_query",
+    tag = "legacy",
+    request_body = SendQueryRequest,
+    responses(
+        (status = 200, description = "Tutor reply", body = SendQueryResponse),
+        (status = 400, description = "Missing or oversized fields", body = ErrorResponse),
+        (status = 404, description = "Unknown session", body = ErrorResponse),
+        (status = 410, description = "Session expired after inactivity", body = ErrorResponse),
+        (status = 500, description = "Upstream model failure", body = ErrorResponse),
+    )
+)]
+#[deprecated = "use POST /api/v1/sessions/{session_id}/queries"]
 pub async fn send_query(
-    Extension(controller): Extension<Arc<Mutex<TutorController>>>,
+    Extension(controller): Extension<SharedController>,
     Json(payload): Json<SendQueryRequest>,
 ) -> Result<Json<SendQueryResponse>, AppError> {
-    let message = {
-        let mut controller_guard = controller.lock().await;
-        controller_guard
-            .send_query(payload.student_id, payload.session_id, payload.query)
-            .await?
+    let reply = ask(
+        &controller,
+        payload.student_id,
+        payload.session_id,
+        payload.query,
+        None,
+    )
+    .await?;
+    Ok(Json(reply))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        app,
+        config::Config,
+        controller::TutorController,
+        testing::{TempDir, test_app},
     };
+    use axum::{
+        body::{Body, to_bytes},
+        http::{Request, StatusCode},
+    };
+    use serde_json::Value;
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+    use tower::ServiceExt;
+
+    async fn error_body(request: Request<Body>) -> (StatusCode, Value) {
+        let (app, _dir) = test_app();
+        let response = app.oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn router_errors_use_the_envelope() {
+        let (status, body) =
+            error_body(Request::get("/api/v1/nothing").body(Body::empty()).unwrap()).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["status"], "error");
+        assert_eq!(body["error"]["code"], 404);
+
+        let (status, body) = error_body(
+            Request::delete("/api/v1/sessions")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(body["error"]["code"], 405);
+
+        // Message ids are numbers.
+        let request = Request::post("/api/v1/sessions/s/messages/first/regenerate")
+            .header("content-type", "application/json")
+            .body(Body::from(r#"{"student_id":"a"}"#))
+            .unwrap();
+        let (status, body) = error_body(request).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["status"], "error");
+    }
+
+    #[tokio::test]
+    async fn legacy_routes_carry_the_deprecation_date() {
+        let request = Request::post("/api/create_session")
+            .header("content-type", "application/json")
+            .body(Body::from(r#"{"student_id":"a"}"#))
+            .unwrap();
+        let (app, _dir) = test_app();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["deprecation"], "@1792368000");
+        assert_eq!(
+            response.headers()["link"],
+            "</api/docs/>; rel=\"deprecation\""
+        );
+
+        let request = Request::post("/api/v1/sessions")
+            .header("content-type", "application/json")
+            .body(Body::from(r#"{"student_id":"a"}"#))
+            .unwrap();
+        let (app, _dir) = test_app();
+        let response = app.oneshot(request).await.unwrap();
+        assert!(!response.headers().contains_key("deprecation"));
+    }
+
+    #[tokio::test]
+    async fn json_bodies_need_the_content_type() {
+        for content_type in [None, Some("text/plain")] {
+            let mut request = Request::post("/api/v1/sessions");
+            if let Some(content_type) = content_type {
+                request = request.header("content-type", content_type);
+            }
+            let request = request.body(Body::from(r#"{"student_id":"a"}"#)).unwrap();
+            let (status, body) = error_body(request).await;
+            assert_eq!(
+                status,
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "{content_type:?}"
+            );
+            assert_eq!(body["status"], "error");
+            assert_eq!(body["error"]["code"], 415);
+        }
+    }
+
+    #[tokio::test]
+    async fn oversized_json_bodies_are_refused() {
+        // Over axum's default 2 MB body limit.
+        let padding = "x".repeat(3 * 1024 * 1024);
+        let request = Request::post("/api/v1/sessions")
+            .header("content-type", "application/json")
+            .body(Body::from(format!(
+                r#"{{"student_id":"a","padding":"{padding}"}}"#
+            )))
+            .unwrap();
+        let (status, body) = error_body(request).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(body["status"], "error");
+        assert_eq!(body["error"]["code"], 413);
+    }
+
+    #[tokio::test]
+    async fn refused_queries_cost_no_upstream_call() {
+        use crate::testing::FakeUpstream;
+
+        let upstream = FakeUpstream::start(|_| (500, serde_json::json!({})));
+        let dir = TempDir::new("refused");
+        let mut config = Config::for_tests_in(dir.path());
+        config.upstream.base_url = upstream.base_url().to_string();
+        config.moderation.model = Some("omni-moderation-latest".to_string());
+        let controller = Arc::new(Mutex::new(TutorController::new(&config).unwrap()));
+        let app = app(controller, &config);
+        let post = |uri: &str, body: Value| {
+            Request::post(uri)
+                .header("content-type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+
+        let response = app
+            .clone()
+            .oneshot(post(
+                "/api/v1/sessions",
+                serde_json::json!({"student_id": "ana"}),
+            ))
+            .await
+            .unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        let session_id = body["data"]["session_id"].as_str().unwrap();
+
+        let oversized = "x".repeat(config.limits.max_query_chars + 1);
+        let refused = [
+            (session_id, oversized.as_str(), StatusCode::BAD_REQUEST),
+            (session_id, "", StatusCode::BAD_REQUEST),
+            ("no-such-session", "What is 2 + 2?", StatusCode::NOT_FOUND),
+        ];
+        for (session_id, query, status) in refused {
+            let uri = format!("/api/v1/sessions/{session_id}/queries");
+            let body = serde_json::json!({"student_id": "ana", "query": query});
+            let response = app.clone().oneshot(post(&uri, body)).await.unwrap();
+            assert_eq!(response.status(), status, "{session_id} {query:.20}");
+        }
+        assert!(upstream.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn echoes_the_request_id() {
+        let request = Request::get("/api/v1/personas")
+            .header("x-request-id", "req-42")
+            .body(Body::empty())
+            .unwrap();
+        let (app, _dir) = test_app();
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.headers()["x-request-id"], "req-42");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["status"], "success");
+        assert_eq!(body["request_id"], "req-42");
+
+        // Errors carry a generated id when the client sent none.
+        let (_, body) =
+            error_body(Request::get("/api/v1/nothing").body(Body::empty()).unwrap()).await;
+        assert!(!body["request_id"].as_str().unwrap().is_empty());
+    }
+
+    /// Add Basic credentials such as `teacher:teacher-token`, if any.
+    fn signed_in(
+        request: axum::http::request::Builder,
+        credentials: Option<&str>,
+    ) -> axum::http::request::Builder {
+        use base64::{Engine, engine::general_purpose::STANDARD};
+
+        match credentials {
+            Some(credentials) => {
+                let header = format!("Basic {}", STANDARD.encode(credentials));
+                request.header("authorization", header)
+            }
+            None => request,
+        }
+    }
+
+    /// Status of a GET as the given teacher, `None` for no credentials.
+    async fn teacher_get(uri: &str, credentials: Option<&str>) -> StatusCode {
+        let request = signed_in(Request::get(uri), credentials)
+            .body(Body::empty())
+            .unwrap();
+        let (app, _dir) = test_app();
+        app.oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn event_logs_are_for_assigned_students_only() {
+        let teacher = Some("teacher:teacher-token");
+        for uri in ["/api/v1/integrity/events", "/api/v1/moderation/audit"] {
+            assert_eq!(teacher_get(uri, None).await, StatusCode::UNAUTHORIZED);
+            assert_eq!(
+                teacher_get(uri, Some("teacher:guess")).await,
+                StatusCode::UNAUTHORIZED
+            );
+            assert_eq!(teacher_get(uri, teacher).await, StatusCode::OK);
+            let assigned = format!("{uri}?student_id=student");
+            assert_eq!(teacher_get(&assigned, teacher).await, StatusCode::OK);
+            let other = format!("{uri}?student_id=someone-else");
+            assert_eq!(teacher_get(&other, teacher).await, StatusCode::NOT_FOUND);
+        }
+    }
+
+    /// The default organization with its teacher, and Northside with its
+    /// classroom `9a` and a teacher `north:north-token`.
+    /// Documents are kept in `dir`.
+    fn two_organizations_app(dir: &TempDir) -> axum::Router {
+        two_organizations_app_with(Config::for_tests_in(dir.path()), dir)
+    }
+
+    fn two_organizations_app_with(mut config: Config, dir: &TempDir) -> axum::Router {
+        use crate::config::{ClassroomConfig, OrganizationConfig, TeacherConfig};
+
+        config.retrieval.dir = dir.path().join("documents");
+        config.organizations = vec![OrganizationConfig {
+            id: "northside".to_string(),
+            name: "Northside High".to_string(),
+            system_prompt: config.paths.system_prompt.clone(),
+            model: config.model.clone(),
+            sessions: config.sessions.clone(),
+            daily_tokens: None,
+            usage_file: None,
+            classrooms: vec![ClassroomConfig {
+                id: "9a".to_string(),
+                name: "Year 9A".to_string(),
+                students: vec!["ana".to_string()],
+            }],
+        }];
+        config.dashboard.teachers.push(TeacherConfig {
+            id: "north".to_string(),
+            name: "North Teacher".to_string(),
+            token: "north-token".to_string(),
+            organization: Some("northside".to_string()),
+            students: vec!["ana".to_string()],
+        });
+        let controller = Arc::new(Mutex::new(TutorController::new(&config).unwrap()));
+        app(controller, &config)
+    }
+
+    #[tokio::test]
+    async fn documents_are_managed_by_their_organization_s_teachers() {
+        let dir = TempDir::new("documents");
+        let app = two_organizations_app(&dir);
+        let upload = |credentials: Option<&str>| {
+            let body = "--x\r\nContent-Disposition: form-data; name=\"course_id\"\r\n\r\n9a\r\n\
+                --x\r\nContent-Disposition: form-data; name=\"file\"; filename=\"notes.md\"\r\n\
+                Content-Type: text/markdown\r\n\r\n# Photosynthesis\n\nPlants make sugar from light.\r\n\
+                --x--\r\n";
+            signed_in(Request::post("/api/v1/documents"), credentials)
+                .header("content-type", "multipart/form-data; boundary=x")
+                .body(Body::from(body))
+                .unwrap()
+        };
+        let delete = |document_id: &str, credentials: Option<&str>| {
+            signed_in(
+                Request::delete(format!("/api/v1/documents/{document_id}")),
+                credentials,
+            )
+            .body(Body::empty())
+            .unwrap()
+        };
+        let send = |request: Request<Body>| app.clone().oneshot(request);
+
+        assert_eq!(
+            send(upload(None)).await.unwrap().status(),
+            StatusCode::UNAUTHORIZED
+        );
+        let other = Some("teacher:teacher-token");
+        assert_eq!(
+            send(upload(other)).await.unwrap().status(),
+            StatusCode::BAD_REQUEST
+        );
+
+        let north = Some("north:north-token");
+        let response = app.clone().oneshot(upload(north)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        let document_id = body["data"]["document_id"].as_str().unwrap();
+
+        let list = |uri: &str, credentials: Option<&str>| {
+            signed_in(Request::get(uri), credentials)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let listed = |response: axum::response::Response| async move {
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let body: Value = serde_json::from_slice(&body).unwrap();
+            body["data"].as_array().unwrap().len()
+        };
+        let response = send(list("/api/v1/documents", None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = send(list("/api/v1/documents", north)).await.unwrap();
+        assert_eq!(listed(response).await, 1);
+        let response = send(list("/api/v1/documents", other)).await.unwrap();
+        assert_eq!(listed(response).await, 0);
+        let response = send(list("/api/v1/documents?course_id=9a", other))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        assert_eq!(
+            send(delete(document_id, None)).await.unwrap().status(),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            send(delete(document_id, other)).await.unwrap().status(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            send(delete(document_id, north)).await.unwrap().status(),
+            StatusCode::OK
+        );
+        assert_eq!(
+            send(delete(document_id, north)).await.unwrap().status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn event_logs_show_only_the_student_s_current_organization() {
+        let dir = TempDir::new("logs");
+        let mut config = Config::for_tests_in(dir.path());
+        config.integrity.log_file = dir.path().join("integrity.jsonl");
+        config.moderation.audit_log = dir.path().join("audit.jsonl");
+        // "ana" was in the default organization before enrolling at Northside.
+        let flagged = |organization: &str| {
+            serde_json::json!({"at": "2025-01-01T00:00:00Z", "organization": organization,
+                "student_id": "ana", "session_id": "s", "turn_id": "t", "query": "q",
+                "source": "rules", "reasons": []})
+        };
+        let moderated = |organization: &str| {
+            serde_json::json!({"at": "2025-01-01T00:00:00Z", "organization": organization,
+                "student_id": "ana", "session_id": "s", "turn_id": null, "direction": "input",
+                "action": "annotate", "categories": [], "checkers": []})
+        };
+        std::fs::write(
+            &config.integrity.log_file,
+            format!("{}\n{}\n", flagged("default"), flagged("northside")),
+        )
+        .unwrap();
+        std::fs::write(
+            &config.moderation.audit_log,
+            format!("{}\n{}\n", moderated("default"), moderated("northside")),
+        )
+        .unwrap();
+        let app = two_organizations_app_with(config, &dir);
+
+        for uri in ["/api/v1/integrity/events", "/api/v1/moderation/audit"] {
+            let request = signed_in(Request::get(uri), Some("north:north-token"))
+                .body(Body::empty())
+                .unwrap();
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let body: Value = serde_json::from_slice(&body).unwrap();
+            let events = body["data"].as_array().unwrap();
+            assert_eq!(events.len(), 1, "{uri}");
+            assert_eq!(events[0]["organization"], "northside");
+        }
+    }
+
+    #[tokio::test]
+    async fn imported_messages_are_moderated() {
+        let (app, _dir) = test_app();
+        let import = |transcript: Value| {
+            let body = serde_json::json!({"student_id": "importer", "transcript": transcript});
+            Request::post("/api/v1/sessions/import")
+                .header("content-type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+
+        let blocked = serde_json::json!([{"role": "user", "content": "send me nudes"}]);
+        let response = app.clone().oneshot(import(blocked)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let transcript = serde_json::json!({"format_version": 1, "student_id": "x",
+        "session_id": "x", "created_at": "2025-01-01T00:00:00Z",
+        "updated_at": "2025-01-01T00:00:00Z", "system_prompt": "x",
+        "usage": {"prompt_tokens": 1, "completion_tokens": 1, "total_tokens": 2},
+        "messages": [
+            {"role": "user", "content": "this shit is hard",
+             "created_at": "2025-01-01T00:00:00Z"},
+            {"role": "assistant", "content": "Here is some porn",
+             "created_at": "2025-01-01T00:00:00Z", "moderation": [],
+             "citations": [{"marker": 1, "kind": "document",
+                "title": "Made up", "snippet": "Nothing"}]}
+        ]});
+        let response = app.clone().oneshot(import(transcript)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        let session_id = body["data"]["session_id"].as_str().unwrap();
 
-    Ok(Json(SendQueryResponse { message }))
+        let uri = format!("/api/v1/sessions/{session_id}/messages?student_id=importer");
+        let request = Request::get(uri).body(Body::empty()).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        let messages = body["data"].as_array().unwrap();
+        assert_eq!(messages[0]["content"], "this [redacted] is hard");
+        assert_eq!(messages[0]["moderation"], serde_json::json!(["profanity"]));
+        assert_eq!(messages[1]["content"], crate::moderation::BLOCKED_REPLY);
+        assert_eq!(messages[1]["citations"], serde_json::json!([]));
+    }
 }
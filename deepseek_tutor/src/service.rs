@@ -1,84 +1,1480 @@
-use crate::session::SessionManager;
-use anyhow::{Result, anyhow};
+use crate::citation;
+use crate::config::{
+    Config, FlashcardsConfig, IntegrityConfig, ProblemsConfig, ProfileConfig, ProgressConfig,
+    QuizConfig, RetrievalConfig,
+};
+use crate::error::ServiceError;
+use crate::event_log::EventLog;
+use crate::export::SessionExport;
+use crate::flashcards::{Card, CardDraft, CardSource, CardStore, PendingCards};
+use crate::import::ImportedTranscript;
+use crate::integrity::{self, FlaggedEvent, IntegrityChecker};
+use crate::moderation::{self, Action, Direction, ModerationEvent, Moderator, Review};
+use crate::persona::{PersonaSummary, Personas};
+use crate::pii::Redactor;
+use crate::problem::{
+    CheckedAttempt, PendingCheck, PendingProblem, Problem, ProblemDraft, ProblemStore, ProblemView,
+};
+use crate::profile::{PendingProfile, ProfileNotes, ProfileStore, ProfileUpdate, StudentProfile};
+use crate::progress::{ProgressReport, ProgressStore, QuizScore, TopicClassifier};
+use crate::quiz::{self, PendingQuiz, Quiz, QuizAnswer, QuizStore, QuizView, Submission};
+use crate::retrieval::{self, Document, DocumentSummary, Library, Scope};
+use crate::session::{
+    ActiveMessage, BranchSummary, Checkpoint, NodeId, SessionData, SweepStats, TokenUsage,
+    message_text, text_message,
+};
+use crate::tenancy::{Owner, Tenants};
+use crate::tools::ToolRegistry;
+use crate::turn::{PendingTurn, TurnKind, TurnOutcome, TurnReply, TurnTicket};
+use anyhow::{Context, Result, anyhow};
 use async_openai::{Client, config::OpenAIConfig, types::CreateChatCompletionRequestArgs};
-use std::{env, fs};
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Arc;
+use time::OffsetDateTime;
+use tokio::sync::oneshot;
 use uuid::Uuid;
 
 pub struct TutorService {
-    session_manager: SessionManager,
+    /// Sessions, prompt, model and quotas of each organization.
+    tenants: Tenants,
     client: Client<OpenAIConfig>,
-    system_prompt: String,
+    /// Turns waiting for the model, by turn id.
+    turns: HashMap<String, ActiveTurn>,
+    /// `None` when the integrity screen is disabled.
+    integrity: Option<IntegrityChecker>,
+    integrity_config: IntegrityConfig,
+    integrity_log: EventLog<FlaggedEvent>,
+    /// `None` when moderation is disabled.
+    moderator: Option<Arc<Moderator>>,
+    moderation_log: EventLog<ModerationEvent>,
+    /// Replace personal details with placeholders in upstream requests.
+    redact_upstream: bool,
+    /// `None` when tools are disabled.
+    tools: Option<Arc<ToolRegistry>>,
+    /// Course materials; `None` when retrieval is disabled.
+    library: Option<Library>,
+    retrieval_config: RetrievalConfig,
+    /// `None` when quizzes are disabled.
+    quizzes: Option<QuizStore>,
+    quiz_config: QuizConfig,
+    /// `None` when flashcards are disabled.
+    cards: Option<CardStore>,
+    flashcards_config: FlashcardsConfig,
+    /// `None` when progress tracking is disabled.
+    progress: Option<ProgressStore>,
+    progress_config: ProgressConfig,
+    topics: TopicClassifier,
+    /// `None` when student profiles are disabled.
+    profiles: Option<ProfileStore>,
+    profile_config: ProfileConfig,
+    /// Subject personas; empty when none are configured.
+    personas: Personas,
+    /// `None` when practice problems are disabled.
+    problems: Option<ProblemStore>,
+    problems_config: ProblemsConfig,
+}
+
+struct ActiveTurn {
+    student_id: String,
+    session_id: String,
+    /// Taken when the turn is cancelled.
+    cancel: Option<oneshot::Sender<()>>,
 }
 
 impl TutorService {
-    pub fn new() -> Self {
-        dotenv::dotenv().ok();
-        let api_key = env::var("OPENAI_API_KEY").expect("OPENAI_API_KEY not set");
-        let base_url = format!(
-            "{}v1",
-            env::var("OPENAI_BASE_URL").expect("OPENAI_BASE_URL not set")
-        );
+    pub fn new(config: &Config) -> Result<Self> {
+        let openai_config = OpenAIConfig::new()
+            .with_api_key(config.upstream.api_key.as_str())
+            .with_api_base(config.upstream.base_url.as_str());
+        let client = Client::with_config(openai_config);
 
-        let config = OpenAIConfig::new()
-            .with_api_key(api_key)
-            .with_api_base(base_url);
-        let client = Client::with_config(config);
+        let system_prompt = fs::read_to_string(&config.paths.system_prompt).with_context(|| {
+            format!(
+                "failed to read system prompt {}",
+                config.paths.system_prompt.display()
+            )
+        })?;
 
-        let system_prompt = fs::read_to_string("data/system_prompt.txt").unwrap_or_else(|e| {
-            eprintln!("Error loading system prompt: {}", e);
-            "You are a helpful tutor.".to_string()
-        });
+        let moderator = if config.moderation.enabled {
+            Some(Arc::new(Moderator::new(
+                &config.moderation,
+                client.clone(),
+            )?))
+        } else {
+            None
+        };
 
-        Self {
-            session_manager: SessionManager::new(),
-            client,
-            system_prompt,
+        let library = if config.retrieval.enabled {
+            Some(Library::open(&config.retrieval).context("failed to open the document library")?)
+        } else {
+            None
+        };
+
+        let quizzes = if config.quiz.enabled {
+            Some(QuizStore::open(&config.quiz).context("failed to open the quiz store")?)
+        } else {
+            None
+        };
+
+        let cards = if config.flashcards.enabled {
+            Some(
+                CardStore::open(&config.flashcards)
+                    .context("failed to open the flashcard store")?,
+            )
+        } else {
+            None
+        };
+
+        let progress = if config.progress.enabled {
+            Some(
+                ProgressStore::open(&config.progress)
+                    .context("failed to open the progress store")?,
+            )
+        } else {
+            None
+        };
+
+        let tools = config
+            .tools
+            .enabled
+            .then(|| Arc::new(ToolRegistry::new(&config.tools)));
+        let personas = Personas::new(&config.personas)?;
+        if let Some(tools) = &tools {
+            for (persona, tool) in personas.unknown_tools(tools) {
+                eprintln!(
+                    "Persona {} names tool {}, which is not available",
+                    persona, tool
+                );
+            }
         }
+
+        let problems = if config.problems.enabled {
+            Some(ProblemStore::open(&config.problems).context("failed to open the problem store")?)
+        } else {
+            None
+        };
+
+        let profiles = if config.profile.enabled {
+            Some(ProfileStore::open(&config.profile).context("failed to open the profile store")?)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            tenants: Tenants::new(config, system_prompt)?,
+            client,
+            turns: HashMap::new(),
+            integrity: config.integrity.enabled.then(IntegrityChecker::new),
+            integrity_config: config.integrity.clone(),
+            integrity_log: EventLog::new(config.integrity.log_file.clone()),
+            moderator,
+            moderation_log: EventLog::new(config.moderation.audit_log.clone()),
+            redact_upstream: config.privacy.redact_upstream,
+            tools,
+            library,
+            retrieval_config: config.retrieval.clone(),
+            quizzes,
+            quiz_config: config.quiz.clone(),
+            cards,
+            flashcards_config: config.flashcards.clone(),
+            progress,
+            progress_config: config.progress.clone(),
+            topics: TopicClassifier::new(),
+            profiles,
+            profile_config: config.profile.clone(),
+            personas,
+            problems,
+            problems_config: config.problems.clone(),
+        })
     }
 
-    pub fn create_session(&mut self, student_id: &str) -> String {
+    /// Start a session whose answers draw on the materials of `scopes`. The
+    /// tutor is told what it remembers about the student and what they have
+    /// covered in earlier sessions. Without a `persona`, one is picked from
+    /// the first question.
+    pub fn create_session(
+        &mut self,
+        student_id: &str,
+        scopes: Vec<Scope>,
+        persona: Option<String>,
+    ) -> String {
         let session_id = Uuid::new_v4().to_string();
-        self.session_manager
-            .create_session(student_id, &session_id, &self.system_prompt);
+        let owner = self.tenants.owner(student_id);
+        let mut system_prompt = self.tenants.of(student_id).system_prompt.clone();
+        if let Some(section) = self
+            .profiles
+            .as_ref()
+            .and_then(|profiles| profiles.get(&owner).render())
+        {
+            system_prompt = format!("{}\n\n{}", system_prompt.trim_end(), section);
+        }
+        if let Some(progress) = &mut self.progress {
+            if self.progress_config.summary_in_prompt
+                && let Some(summary) = progress.report(&owner).summary()
+            {
+                system_prompt = format!("{}\n\n{}", system_prompt.trim_end(), summary);
+            }
+            let now = OffsetDateTime::now_utc();
+            // Progress is a side record; a failed write must not stop the
+            // student from starting a session.
+            if let Err(err) = progress.start_session(&owner, &session_id, now) {
+                eprintln!("Failed to record progress for {}: {:#}", student_id, err);
+            }
+        }
+        self.tenants.sessions_mut(student_id).create_session(
+            student_id,
+            &session_id,
+            &system_prompt,
+            scopes,
+            persona,
+        );
         session_id
     }
 
-    pub async fn process_query(
+    /// Create a session from an imported transcript and the moderation
+    /// review of each of its messages. Imported questions and replies are
+    /// treated like live ones: a blocked question refuses the import and a
+    /// blocked reply is replaced. Returns the new session's id.
+    pub fn import_session(
+        &mut self,
+        student_id: &str,
+        transcript: ImportedTranscript,
+        reviews: Vec<Review>,
+    ) -> Result<String> {
+        let session_id = Uuid::new_v4().to_string();
+        let owner = self.tenants.owner(student_id);
+        let mut messages = Vec::with_capacity(transcript.messages.len());
+        for (index, (mut stored, review)) in
+            transcript.messages.into_iter().zip(reviews).enumerate()
+        {
+            let (role, _) = message_text(&stored.message);
+            let direction = match role {
+                "user" => Direction::Input,
+                _ => Direction::Output,
+            };
+            if review.action != Action::Allow {
+                self.log_moderation(ModerationEvent::new(
+                    &owner,
+                    &session_id,
+                    None,
+                    direction,
+                    &review,
+                ));
+            }
+            let text = match (review.action, direction) {
+                (Action::Block, Direction::Input) => {
+                    let categories: Vec<&str> =
+                        review.categories.iter().map(|c| c.as_str()).collect();
+                    return Err(ServiceError::Rejected(format!(
+                        "Message {} blocked by moderation: {}",
+                        index,
+                        categories.join(", ")
+                    ))
+                    .into());
+                }
+                (Action::Block, Direction::Output) => moderation::BLOCKED_REPLY.to_string(),
+                _ => review.text,
+            };
+            stored.message = text_message(role, &text)?;
+            stored.moderation = review.categories;
+            messages.push(stored);
+        }
+
+        let data = SessionData::with_history(
+            self.tenants.of(student_id).system_prompt.clone(),
+            messages,
+            TokenUsage::default(),
+        );
+        self.tenants
+            .sessions_mut(student_id)
+            .insert_session(student_id, &session_id, data);
+        Ok(session_id)
+    }
+
+    pub fn clone_session(&mut self, student_id: &str, session_id: &str) -> Result<String> {
+        let new_id = Uuid::new_v4().to_string();
+        self.tenants
+            .sessions_mut(student_id)
+            .clone_session(student_id, session_id, &new_id)?;
+        Ok(new_id)
+    }
+
+    /// The moderation pipeline, for reviewing a message outside the
+    /// controller lock.
+    pub fn moderator(&self) -> Option<Arc<Moderator>> {
+        self.moderator.clone()
+    }
+
+    /// Name of the organization with the given id.
+    pub fn organization_name(&self, id: &str) -> Option<String> {
+        self.tenants.get(id).map(|tenant| tenant.name.clone())
+    }
+
+    /// Name of the classroom a student is enrolled in.
+    pub fn classroom_name(&self, student_id: &str) -> Option<String> {
+        self.tenants
+            .classroom(student_id)
+            .map(|classroom| classroom.name.clone())
+    }
+
+    pub fn personas(&self) -> Vec<PersonaSummary> {
+        self.personas.list()
+    }
+
+    pub fn has_persona(&self, id: &str) -> bool {
+        self.personas.get(id).is_some()
+    }
+
+    /// Choose the persona of a session. `None` returns it to the general
+    /// tutor, or lets the first question pick one if none was asked yet.
+    pub fn set_persona(
+        &mut self,
+        student_id: &str,
+        session_id: &str,
+        persona: Option<String>,
+    ) -> Result<()> {
+        self.ensure_idle(student_id, session_id)?;
+        let session = self
+            .tenants
+            .sessions_mut(student_id)
+            .get_session_mut(student_id, session_id)?;
+        session.persona = persona;
+        Ok(())
+    }
+
+    /// Refuse course materials from outside the student's organization.
+    pub fn check_scope(&self, student_id: &str, scope: &Scope) -> Result<(), String> {
+        self.tenants.check_scope(student_id, scope)
+    }
+
+    /// Refuse course materials from outside a teacher's organization.
+    pub fn check_organization_scope(
+        &self,
+        organization: Option<&str>,
+        scope: &Scope,
+    ) -> Result<(), String> {
+        self.tenants.check_organization_scope(organization, scope)
+    }
+
+    /// Add the student's question and prepare the upstream request. The
+    /// caller runs the returned turn and hands it back to `finish_turn`.
+    pub fn begin_query(
+        &mut self,
+        student_id: &str,
+        session_id: &str,
+        query: Review,
+        turn_id: Option<String>,
+    ) -> Result<PendingTurn> {
+        self.tenants
+            .sessions_mut(student_id)
+            .get_session_mut(student_id, session_id)?;
+        let turn_id = self.claim_turn_id(student_id, session_id, turn_id)?;
+        self.admit_query(student_id, session_id, &turn_id, &query)?;
+
+        let checkpoint = self.checkpoint(student_id, session_id)?;
+        self.add_query(student_id, session_id, turn_id, query)
+            .inspect_err(|_| self.roll_back(student_id, session_id, checkpoint))
+    }
+
+    /// Replace an earlier student question: the edit becomes a new branch
+    /// next to the original and the tutor answers it.
+    pub fn begin_edit(
+        &mut self,
+        student_id: &str,
+        session_id: &str,
+        message_id: NodeId,
+        query: Review,
+        turn_id: Option<String>,
+    ) -> Result<PendingTurn> {
+        let turn_id = self.claim_turn_id(student_id, session_id, turn_id)?;
+        self.tenants
+            .sessions_mut(student_id)
+            .get_session_mut(student_id, session_id)?;
+        self.admit_query(student_id, session_id, &turn_id, &query)?;
+        let checkpoint = self.checkpoint(student_id, session_id)?;
+        self.tenants
+            .sessions_mut(student_id)
+            .get_session_mut(student_id, session_id)?
+            .rewind_before(message_id, "user")?;
+
+        self.add_query(student_id, session_id, turn_id, query)
+            .inspect_err(|_| self.roll_back(student_id, session_id, checkpoint))
+    }
+
+    /// Ask for a different answer to the question before `message_id`. The
+    /// new reply becomes a branch next to the old one.
+    pub fn begin_regenerate(
         &mut self,
         student_id: &str,
         session_id: &str,
-        query: &str,
+        message_id: NodeId,
+        turn_id: Option<String>,
+    ) -> Result<PendingTurn> {
+        let turn_id = self.claim_turn_id(student_id, session_id, turn_id)?;
+        let session = self
+            .tenants
+            .sessions_mut(student_id)
+            .get_session_mut(student_id, session_id)?;
+        let previous_head = session.head();
+        session.rewind_before(message_id, "assistant")?;
+        // Redo the whole turn, tool calls included.
+        session.rewind_tool_exchange();
+
+        let kind = TurnKind::Regenerate { previous_head };
+        self.start_turn(student_id, session_id, turn_id, kind)
+            .inspect_err(|_| self.restore_head(student_id, session_id, kind))
+    }
+
+    /// Record the result of a turn started by one of the `begin_` methods.
+    pub fn finish_turn(&mut self, ticket: TurnTicket, outcome: TurnOutcome) -> Result<TurnReply> {
+        self.turns.remove(&ticket.turn_id);
+        self.tenants.sessions_mut(&ticket.student_id).set_busy(
+            &ticket.student_id,
+            &ticket.session_id,
+            false,
+        );
+        let TurnTicket {
+            turn_id,
+            student_id,
+            session_id,
+            kind,
+            question,
+            integrity,
+            passages,
+        } = ticket;
+        let asked = question.clone();
+        let owner = self.tenants.owner(&student_id);
+        let persona = self
+            .tenants
+            .sessions(&student_id)
+            .get_session(&student_id, &session_id)
+            .and_then(|session| session.persona.clone());
+
+        let hint_only = integrity.is_some();
+        if let Some(flag) = integrity {
+            let event = FlaggedEvent {
+                at: OffsetDateTime::now_utc(),
+                organization: owner.organization.clone(),
+                student_id: student_id.clone(),
+                session_id: session_id.clone(),
+                turn_id: turn_id.clone(),
+                query: question,
+                flag,
+            };
+            // A lost log line must not cost the student their answer.
+            if let Err(err) = self.integrity_log.append(&event) {
+                eprintln!("Failed to log flagged turn {}: {:#}", turn_id, err);
+            }
+        }
+
+        match outcome {
+            TurnOutcome::Replied {
+                reply,
+                usage,
+                exchange,
+                tools,
+            } => {
+                if reply.action != Action::Allow {
+                    self.log_moderation(ModerationEvent::new(
+                        &owner,
+                        &session_id,
+                        Some(&turn_id),
+                        Direction::Output,
+                        &reply,
+                    ));
+                }
+                let (tutor_response, citations) = if reply.action == Action::Block {
+                    (moderation::BLOCKED_REPLY.to_string(), Vec::new())
+                } else {
+                    let citations = citation::collect(&reply.text, &passages, &tools);
+                    (reply.text, citations)
+                };
+
+                for message in exchange {
+                    self.tenants.sessions_mut(&student_id).add_raw_message(
+                        &student_id,
+                        &session_id,
+                        message,
+                    )?;
+                }
+
+                self.tenants.sessions_mut(&student_id).add_message(
+                    &student_id,
+                    &session_id,
+                    "assistant",
+                    &tutor_response,
+                )?;
+                if !reply.categories.is_empty() {
+                    self.tenants.sessions_mut(&student_id).annotate_head(
+                        &student_id,
+                        &session_id,
+                        reply.categories,
+                    )?;
+                }
+                if !citations.is_empty() {
+                    self.tenants.sessions_mut(&student_id).cite_head(
+                        &student_id,
+                        &session_id,
+                        citations.clone(),
+                    )?;
+                }
+                if let Some(usage) = usage {
+                    self.tenants.sessions_mut(&student_id).record_usage(
+                        &student_id,
+                        &session_id,
+                        usage,
+                    )?;
+                    self.record_tokens(&student_id, &usage);
+                }
+                self.record_turn(&owner, &session_id, &asked, &tutor_response);
+
+                Ok(TurnReply {
+                    turn_id,
+                    message: tutor_response,
+                    cancelled: false,
+                    hint_only,
+                    tools,
+                    citations,
+                    persona,
+                })
+            }
+            TurnOutcome::Cancelled { usage } => {
+                self.tenants
+                    .sessions_mut(&student_id)
+                    .add_cancelled_reply(&student_id, &session_id)?;
+                if let Some(usage) = usage {
+                    self.tenants.sessions_mut(&student_id).record_usage(
+                        &student_id,
+                        &session_id,
+                        usage,
+                    )?;
+                    self.record_tokens(&student_id, &usage);
+                }
+                self.restore_head(&student_id, &session_id, kind);
+                Ok(TurnReply {
+                    turn_id,
+                    message: String::new(),
+                    cancelled: true,
+                    hint_only,
+                    tools: Vec::new(),
+                    citations: Vec::new(),
+                    persona,
+                })
+            }
+            TurnOutcome::Failed { error, usage } => {
+                if let Some(usage) = usage {
+                    self.record_tokens(&student_id, &usage);
+                }
+                self.restore_head(&student_id, &session_id, kind);
+                Err(error)
+            }
+        }
+    }
+
+    /// Fail unless a turn could start in the session now: it exists, has
+    /// no turn in progress and the organization has tokens left.
+    pub fn check_turn(&mut self, student_id: &str, session_id: &str) -> Result<()> {
+        self.tenants
+            .sessions_mut(student_id)
+            .get_session_mut(student_id, session_id)?;
+        self.ensure_idle(student_id, session_id)?;
+        self.check_quota(student_id)
+    }
+
+    /// Fail when the student's organization has used its tokens for today.
+    /// Checked before every upstream call made for a student.
+    pub fn check_quota(&self, student_id: &str) -> Result<()> {
+        self.tenants
+            .check_quota(student_id, OffsetDateTime::now_utc())
+    }
+
+    /// Count tokens spent upstream for a student against their
+    /// organization's daily quota.
+    pub fn record_tokens(&mut self, student_id: &str, usage: &TokenUsage) {
+        self.tenants
+            .record_tokens(student_id, usage.total_tokens, OffsetDateTime::now_utc());
+    }
+
+    /// Stop a turn that is waiting for the model. Returns its session id.
+    pub fn cancel_turn(&mut self, student_id: &str, turn_id: &str) -> Result<String> {
+        let turn = self
+            .turns
+            .get_mut(turn_id)
+            .filter(|turn| turn.student_id == student_id)
+            .ok_or_else(|| ServiceError::NotFound("Turn not found".to_string()))?;
+        if let Some(cancel) = turn.cancel.take() {
+            // The receiver is gone only if the turn already finished.
+            let _ = cancel.send(());
+        }
+        Ok(turn.session_id.clone())
+    }
+
+    pub fn active_messages(
+        &mut self,
+        student_id: &str,
+        session_id: &str,
+    ) -> Result<Vec<ActiveMessage>> {
+        let session = self
+            .tenants
+            .sessions_mut(student_id)
+            .get_session_mut(student_id, session_id)?;
+        Ok(session.active_messages())
+    }
+
+    pub fn branches(&mut self, student_id: &str, session_id: &str) -> Result<Vec<BranchSummary>> {
+        let session = self
+            .tenants
+            .sessions_mut(student_id)
+            .get_session_mut(student_id, session_id)?;
+        Ok(session.branches())
+    }
+
+    /// Make the branch through `message_id` the active one.
+    pub fn activate_branch(
+        &mut self,
+        student_id: &str,
+        session_id: &str,
+        message_id: NodeId,
+    ) -> Result<Vec<ActiveMessage>> {
+        self.ensure_idle(student_id, session_id)?;
+        let session = self
+            .tenants
+            .sessions_mut(student_id)
+            .get_session_mut(student_id, session_id)?;
+        session.activate(message_id)?;
+        Ok(session.active_messages())
+    }
+
+    /// Pick the id for a new turn, refusing to start a second turn in the
+    /// same session: its reply would land on a branch that has moved.
+    fn claim_turn_id(
+        &self,
+        student_id: &str,
+        session_id: &str,
+        turn_id: Option<String>,
     ) -> Result<String> {
-        self.session_manager
-            .get_session(student_id, session_id)
-            .ok_or_else(|| anyhow!("Session not found"))?;
+        self.ensure_idle(student_id, session_id)?;
+        self.check_quota(student_id)?;
+        let turn_id = turn_id.unwrap_or_else(|| Uuid::new_v4().to_string());
+        if self.turns.contains_key(&turn_id) {
+            return Err(
+                ServiceError::Busy(format!("Turn {} is already in progress", turn_id)).into(),
+            );
+        }
+        Ok(turn_id)
+    }
+
+    /// Log a student message that moderation acted on, and refuse it if it
+    /// was blocked.
+    fn admit_query(
+        &self,
+        student_id: &str,
+        session_id: &str,
+        turn_id: &str,
+        query: &Review,
+    ) -> Result<()> {
+        if query.action == Action::Allow {
+            return Ok(());
+        }
+        self.log_moderation(ModerationEvent::new(
+            &self.tenants.owner(student_id),
+            session_id,
+            Some(turn_id),
+            Direction::Input,
+            query,
+        ));
+        if query.action == Action::Block {
+            let categories: Vec<&str> = query.categories.iter().map(|c| c.as_str()).collect();
+            return Err(ServiceError::Rejected(format!(
+                "Message blocked by moderation: {}",
+                categories.join(", ")
+            ))
+            .into());
+        }
+        Ok(())
+    }
+
+    fn add_query(
+        &mut self,
+        student_id: &str,
+        session_id: &str,
+        turn_id: String,
+        query: Review,
+    ) -> Result<PendingTurn> {
+        self.route_persona(student_id, session_id, &query.text)?;
+        self.tenants.sessions_mut(student_id).add_message(
+            student_id,
+            session_id,
+            "user",
+            &query.text,
+        )?;
+        if !query.categories.is_empty() {
+            self.tenants.sessions_mut(student_id).annotate_head(
+                student_id,
+                session_id,
+                query.categories,
+            )?;
+        }
+
+        self.start_turn(student_id, session_id, turn_id, TurnKind::Query)
+    }
+
+    /// Give a session the persona its first question is about, unless the
+    /// client already chose one.
+    fn route_persona(&mut self, student_id: &str, session_id: &str, question: &str) -> Result<()> {
+        if self.personas.is_empty() {
+            return Ok(());
+        }
+        let session = self
+            .tenants
+            .sessions_mut(student_id)
+            .get_session_mut(student_id, session_id)?;
+        if session.persona.is_none() && session.nodes().is_empty() {
+            session.persona = self
+                .personas
+                .route(question)
+                .map(|persona| persona.id.clone());
+        }
+        Ok(())
+    }
+
+    /// Count a tutor reply towards the student's progress, tagged with the
+    /// topics of the question and the answer.
+    fn record_turn(&mut self, owner: &Owner, session_id: &str, question: &str, reply: &str) {
+        let Some(progress) = &mut self.progress else {
+            return;
+        };
+        let topics = self.topics.classify(&format!("{}\n{}", question, reply));
+        let now = OffsetDateTime::now_utc();
+        if let Err(err) = progress.record_turn(owner, session_id, &topics, now) {
+            eprintln!(
+                "Failed to record progress for {}: {:#}",
+                owner.student_id, err
+            );
+        }
+    }
+
+    fn log_moderation(&self, event: ModerationEvent) {
+        // As with the integrity log, a lost line must not fail the turn.
+        if let Err(err) = self.moderation_log.append(&event) {
+            eprintln!("Failed to write moderation audit log: {:#}", err);
+        }
+    }
 
-        self.session_manager
-            .add_message(student_id, session_id, "user", query)?;
+    fn ensure_idle(&self, student_id: &str, session_id: &str) -> Result<()> {
+        let busy = self
+            .turns
+            .values()
+            .any(|turn| turn.student_id == student_id && turn.session_id == session_id);
+        if busy {
+            return Err(ServiceError::Busy(format!(
+                "Session {} already has a turn in progress",
+                session_id
+            ))
+            .into());
+        }
+        Ok(())
+    }
 
-        let conversation = self
-            .session_manager
+    /// Build the upstream request for the active branch and register the
+    /// turn so it can be cancelled.
+    fn start_turn(
+        &mut self,
+        student_id: &str,
+        session_id: &str,
+        turn_id: String,
+        kind: TurnKind,
+    ) -> Result<PendingTurn> {
+        let persona = self
+            .tenants
+            .sessions(student_id)
+            .get_session(student_id, session_id)
+            .and_then(|session| session.persona.as_deref())
+            .and_then(|id| self.personas.get(id));
+        let model = match persona {
+            Some(persona) => persona.model(&self.tenants.of(student_id).model),
+            None => self.tenants.of(student_id).model.clone(),
+        };
+        let tools = match persona {
+            Some(persona) => self.tools.as_ref().and_then(|tools| persona.tools(tools)),
+            None => self.tools.clone(),
+        };
+        let mut conversation = self
+            .tenants
+            .sessions_mut(student_id)
             .get_conversation(student_id, session_id);
 
-        let request = CreateChatCompletionRequestArgs::default()
-            .model("deepseek-ai/DeepSeek-V3")
+        // Screen the question being answered; for a regeneration that is an
+        // earlier one, so its verdict carries over.
+        let question = conversation
+            .iter()
+            .rev()
+            .map(message_text)
+            .find(|(role, _)| *role == "user")
+            .map(|(_, text)| text)
+            .unwrap_or_default();
+        // Nothing the student wrote leaves the server unredacted, including
+        // the question sent to the classifier.
+        let mut redactor = self.redact_upstream.then(Redactor::new);
+        if let Some(redactor) = &mut redactor {
+            for message in &mut conversation {
+                redactor.redact_message(message);
+            }
+        }
+
+        // The persona's instructions and course materials are the teacher's,
+        // not the student's, so they are added after redaction.
+        if let Some(persona) = persona {
+            persona.instruct(&mut conversation);
+        }
+        let scopes = self
+            .tenants
+            .sessions(student_id)
+            .get_session(student_id, session_id)
+            .map(|session| session.scopes.as_slice())
+            .unwrap_or_default();
+        let mut passages = Vec::new();
+        if let Some(library) = self.library.as_ref().filter(|_| !scopes.is_empty()) {
+            passages = library.search(scopes, &question);
+            if !passages.is_empty() {
+                retrieval::ground(&mut conversation, &passages);
+            }
+        }
+
+        // While a practice problem is open, the tutor gives hints only.
+        if let Some(problem) = self
+            .problems
+            .as_ref()
+            .and_then(|problems| problems.active(&self.tenants.owner(student_id), session_id))
+        {
+            problem.instruct(&mut conversation);
+        }
+
+        let mut integrity = None;
+        let mut classifier = None;
+        if let Some(checker) = &self.integrity {
+            integrity = checker.check(&question);
+            if integrity.is_some() {
+                integrity::restrict_to_hints(&mut conversation);
+            } else if self.integrity_config.model_classifier {
+                let outbound = match &mut redactor {
+                    Some(redactor) => redactor.redact(&question),
+                    None => question.clone(),
+                };
+                classifier = Some(integrity::classifier_request(&model.name, &outbound)?);
+            }
+        }
+
+        let mut request = CreateChatCompletionRequestArgs::default();
+        request
+            .model(model.name.as_str())
             .messages(conversation)
-            .temperature(0.6)
-            .max_tokens(500_u32)
-            .build()?;
-        let response = self.client.chat().create(request).await?;
-
-        let tutor_response = response.choices[0]
-            .message
-            .content
-            .as_deref()
+            .temperature(model.temperature)
+            .max_tokens(model.max_tokens);
+        if let Some(tools) = &tools {
+            request.tools(tools.definitions()?);
+        }
+        let request = request.build()?;
+
+        let (cancel, cancelled) = oneshot::channel();
+        self.turns.insert(
+            turn_id.clone(),
+            ActiveTurn {
+                student_id: student_id.to_string(),
+                session_id: session_id.to_string(),
+                cancel: Some(cancel),
+            },
+        );
+        self.tenants
+            .sessions_mut(student_id)
+            .set_busy(student_id, session_id, true);
+
+        Ok(PendingTurn {
+            ticket: TurnTicket {
+                turn_id,
+                student_id: student_id.to_string(),
+                session_id: session_id.to_string(),
+                kind,
+                question,
+                integrity,
+                passages,
+            },
+            client: self.client.clone(),
+            request,
+            classifier,
+            moderator: self.moderator.clone(),
+            redactor,
+            tools,
+            cancelled,
+        })
+    }
+
+    fn checkpoint(&mut self, student_id: &str, session_id: &str) -> Result<Checkpoint> {
+        Ok(self
+            .tenants
+            .sessions_mut(student_id)
+            .get_session_mut(student_id, session_id)?
+            .checkpoint())
+    }
+
+    /// Undo a question whose turn could not be started, so that a failed
+    /// request leaves neither the question nor a routed persona behind.
+    fn roll_back(&mut self, student_id: &str, session_id: &str, checkpoint: Checkpoint) {
+        if let Ok(session) = self
+            .tenants
+            .sessions_mut(student_id)
+            .get_session_mut(student_id, session_id)
+        {
+            session.roll_back(checkpoint);
+        }
+    }
+
+    /// Return to the reply the student was looking at before a
+    /// regeneration that produced nothing.
+    fn restore_head(&mut self, student_id: &str, session_id: &str, kind: TurnKind) {
+        if let TurnKind::Regenerate { previous_head } = kind
+            && let Ok(session) = self
+                .tenants
+                .sessions_mut(student_id)
+                .get_session_mut(student_id, session_id)
+        {
+            session.restore_head(previous_head);
+        }
+    }
+
+    pub fn export_session(&mut self, student_id: &str, session_id: &str) -> Result<SessionExport> {
+        let session = self
+            .tenants
+            .sessions_mut(student_id)
+            .get_session_mut(student_id, session_id)?;
+        Ok(SessionExport::new(student_id, session_id, session))
+    }
+
+    pub fn export_student(&self, student_id: &str) -> Vec<SessionExport> {
+        let sessions = self.tenants.sessions(student_id);
+        let mut exports: Vec<SessionExport> = sessions
+            .student_sessions(student_id)
+            .into_iter()
+            .map(|(session_id, session)| SessionExport::new(student_id, session_id, session))
+            .collect();
+        exports.extend(sessions.spilled_sessions(student_id));
+        exports.sort_by_key(|export| export.created_at);
+        exports
+    }
+
+    /// Flagged turns for teacher review, newest last.
+    pub fn integrity_events(&self, students: &[String], limit: usize) -> Result<Vec<FlaggedEvent>> {
+        self.integrity_log.read(
+            |event| self.is_current(students, &event.organization, &event.student_id),
+            limit,
+        )
+    }
+
+    /// Moderation decisions for the audit trail, newest last.
+    pub fn moderation_events(
+        &self,
+        students: &[String],
+        limit: usize,
+    ) -> Result<Vec<ModerationEvent>> {
+        self.moderation_log.read(
+            |event| self.is_current(students, &event.organization, &event.student_id),
+            limit,
+        )
+    }
+
+    /// Every integrity flag and moderation event of the given students,
+    /// oldest first. Each log is read once.
+    pub fn student_events(
+        &self,
+        students: &[String],
+    ) -> Result<(Vec<FlaggedEvent>, Vec<ModerationEvent>)> {
+        let flagged = self.integrity_log.read(
+            |event| self.is_current(students, &event.organization, &event.student_id),
+            usize::MAX,
+        )?;
+        let moderated = self.moderation_log.read(
+            |event| self.is_current(students, &event.organization, &event.student_id),
+            usize::MAX,
+        )?;
+        Ok((flagged, moderated))
+    }
+
+    /// Whether a logged event is about one of `students` in the
+    /// organization they belong to now, so that a student id reused in
+    /// another organization does not show its events.
+    fn is_current(&self, students: &[String], organization: &str, student_id: &str) -> bool {
+        students.iter().any(|student| student == student_id)
+            && self.tenants.of(student_id).id == organization
+    }
+
+    /// Settings for preparing uploads; `None` when retrieval is disabled.
+    pub fn retrieval_config(&self) -> Option<&RetrievalConfig> {
+        self.library.as_ref().map(|_| &self.retrieval_config)
+    }
+
+    pub fn add_document(&mut self, document: Document) -> Result<DocumentSummary> {
+        self.library_mut()?.add(document)
+    }
+
+    pub fn remove_document(&mut self, document_id: &str) -> Result<()> {
+        self.library_mut()?.remove(document_id)
+    }
+
+    pub fn document(&self, document_id: &str) -> Option<DocumentSummary> {
+        self.library.as_ref()?.summary(document_id)
+    }
+
+    pub fn documents(&self, scope: Option<&Scope>) -> Vec<DocumentSummary> {
+        self.library
+            .as_ref()
+            .map(|library| library.list(scope))
             .unwrap_or_default()
-            .trim()
-            .to_string();
+    }
+
+    fn library_mut(&mut self) -> Result<&mut Library> {
+        self.library
+            .as_mut()
+            .ok_or_else(|| anyhow!("Document library is disabled"))
+    }
+
+    /// Quiz limits; `None` when quizzes are disabled.
+    pub fn quiz_config(&self) -> Option<&QuizConfig> {
+        self.quizzes.as_ref().map(|_| &self.quiz_config)
+    }
+
+    /// Prepare a quiz on the active branch of a session, to be run with
+    /// `PendingQuiz::run` outside the lock. `None` when the tutor has not
+    /// answered anything yet.
+    pub fn begin_quiz(
+        &mut self,
+        student_id: &str,
+        session_id: &str,
+        questions: usize,
+        topic: Option<String>,
+    ) -> Result<Option<PendingQuiz>> {
+        self.check_quota(student_id)?;
+        let model = self.tenants.of(student_id).model.clone();
+        let session = self
+            .tenants
+            .sessions_mut(student_id)
+            .get_session_mut(student_id, session_id)?;
+        let turns = session
+            .messages()
+            .into_iter()
+            .filter(|stored| !stored.cancelled)
+            .map(|stored| message_text(&stored.message));
+        let Some(transcript) = quiz::transcript(turns) else {
+            return Ok(None);
+        };
+        let mut redactor = self.redact_upstream.then(Redactor::new);
+        let transcript = match &mut redactor {
+            Some(redactor) => redactor.redact(&transcript),
+            None => transcript,
+        };
+        let request = PendingQuiz::request(
+            &model.name,
+            model.temperature,
+            self.quiz_config.max_tokens,
+            &transcript,
+            questions,
+            topic.as_deref(),
+        )?;
+        Ok(Some(PendingQuiz {
+            client: self.client.clone(),
+            request,
+            redactor,
+            questions,
+            max_attempts: self.quiz_config.max_attempts,
+            owner: self.tenants.owner(student_id),
+            session_id: session_id.to_string(),
+            topic,
+        }))
+    }
+
+    pub fn add_quiz(&mut self, quiz: Quiz) -> Result<QuizView> {
+        self.quizzes_mut()?.add(quiz)
+    }
+
+    pub fn quiz(&self, student_id: &str, quiz_id: &str) -> Result<QuizView> {
+        let quizzes = self
+            .quizzes
+            .as_ref()
+            .ok_or_else(|| anyhow!("Quizzes are disabled"))?;
+        Ok(quizzes
+            .get(&self.tenants.owner(student_id), quiz_id)?
+            .view())
+    }
+
+    pub fn submit_quiz(
+        &mut self,
+        student_id: &str,
+        quiz_id: &str,
+        answers: &[QuizAnswer],
+    ) -> Result<Submission> {
+        let owner = self.tenants.owner(student_id);
+        let quizzes = self.quizzes_mut()?;
+        let submission = quizzes.submit(&owner, quiz_id, answers)?;
+        let topic = quizzes.get(&owner, quiz_id)?.view().topic;
+        if let Some(progress) = &mut self.progress {
+            let score = QuizScore {
+                quiz_id: quiz_id.to_string(),
+                topic,
+                score: submission.score,
+                total: submission.total,
+                submitted_at: submission.submitted_at,
+            };
+            if let Err(err) = progress.record_quiz(&owner, score) {
+                eprintln!("Failed to record progress for {}: {:#}", student_id, err);
+            }
+        }
+        Ok(submission)
+    }
+
+    /// Practice problem limits; `None` when practice problems are disabled.
+    pub fn problems_config(&self) -> Option<&ProblemsConfig> {
+        self.problems.as_ref().map(|_| &self.problems_config)
+    }
+
+    /// Prepare a practice problem for a session, to be run with
+    /// `PendingProblem::run` outside the lock. The model writes the hints
+    /// when the draft has none.
+    pub fn begin_problem(
+        &mut self,
+        student_id: &str,
+        session_id: &str,
+        draft: ProblemDraft,
+    ) -> Result<PendingProblem> {
+        let model = self.tenants.of(student_id).model.clone();
+        self.tenants
+            .sessions_mut(student_id)
+            .get_session_mut(student_id, session_id)?;
+        let levels = self.problems_config.hint_levels;
+        let request = if draft.hints.is_empty() {
+            self.check_quota(student_id)?;
+            Some(PendingProblem::request(
+                &model.name,
+                self.problems_config.max_tokens,
+                &draft,
+                levels,
+            )?)
+        } else {
+            None
+        };
+        Ok(PendingProblem {
+            client: self.client.clone(),
+            request,
+            max_attempts: self.problems_config.max_attempts,
+            levels,
+            owner: self.tenants.owner(student_id),
+            session_id: session_id.to_string(),
+            draft,
+        })
+    }
+
+    pub fn add_problem(&mut self, problem: Problem) -> Result<ProblemView> {
+        self.problems_mut()?.add(problem)
+    }
+
+    pub fn problem(&self, student_id: &str, problem_id: &str) -> Result<ProblemView> {
+        self.problems
+            .as_ref()
+            .ok_or_else(|| anyhow!("Practice problems are disabled"))?
+            .view(&self.tenants.owner(student_id), problem_id)
+    }
+
+    pub fn reveal_hint(&mut self, student_id: &str, problem_id: &str) -> Result<ProblemView> {
+        let owner = self.tenants.owner(student_id);
+        self.problems_mut()?.reveal_hint(&owner, problem_id)
+    }
+
+    /// Prepare the check of an attempt, to be run with `PendingCheck::run`
+    /// outside the lock. An answer equal to the reference answer needs no
+    /// model call.
+    pub fn begin_attempt(
+        &mut self,
+        student_id: &str,
+        problem_id: &str,
+        answer: String,
+    ) -> Result<PendingCheck> {
+        let model = self.tenants.of(student_id).model.clone();
+        let owner = self.tenants.owner(student_id);
+        let problem = self
+            .problems
+            .as_ref()
+            .ok_or_else(|| anyhow!("Practice problems are disabled"))?
+            .get(&owner, problem_id)?;
+        let mut redactor = None;
+        let request = if problem.matches(&answer) {
+            None
+        } else {
+            self.check_quota(student_id)?;
+            // The problem is the teacher's; only the answer is redacted.
+            redactor = self.redact_upstream.then(Redactor::new);
+            let outbound = match &mut redactor {
+                Some(redactor) => redactor.redact(&answer),
+                None => answer.clone(),
+            };
+            Some(PendingCheck::request(
+                &model.name,
+                self.problems_config.max_tokens,
+                problem,
+                &outbound,
+            )?)
+        };
+        Ok(PendingCheck {
+            client: self.client.clone(),
+            request,
+            redactor,
+            max_attempts: self.problems_config.max_attempts,
+            owner,
+            problem_id: problem_id.to_string(),
+            answer,
+        })
+    }
+
+    pub fn finish_attempt(&mut self, checked: CheckedAttempt) -> Result<ProblemView> {
+        self.problems_mut()?.record_attempt(checked)
+    }
+
+    fn problems_mut(&mut self) -> Result<&mut ProblemStore> {
+        self.problems
+            .as_mut()
+            .ok_or_else(|| anyhow!("Practice problems are disabled"))
+    }
+
+    fn quizzes_mut(&mut self) -> Result<&mut QuizStore> {
+        self.quizzes
+            .as_mut()
+            .ok_or_else(|| anyhow!("Quizzes are disabled"))
+    }
+
+    /// Flashcard limits; `None` when flashcards are disabled.
+    pub fn flashcards_config(&self) -> Option<&FlashcardsConfig> {
+        self.cards.as_ref().map(|_| &self.flashcards_config)
+    }
+
+    /// Prepare card drafts from one tutor reply, the latest on the active
+    /// branch unless `message_id` is given, to be run with
+    /// `PendingCards::run` outside the lock. `None` when there is no reply
+    /// to draft from.
+    pub fn begin_cards(
+        &mut self,
+        student_id: &str,
+        session_id: &str,
+        message_id: Option<NodeId>,
+    ) -> Result<Option<PendingCards>> {
+        self.check_quota(student_id)?;
+        let model = self.tenants.of(student_id).model.clone();
+        let session = self
+            .tenants
+            .sessions_mut(student_id)
+            .get_session_mut(student_id, session_id)?;
+        let reply = match message_id {
+            Some(id) => {
+                let node = session
+                    .nodes()
+                    .get(id)
+                    .ok_or_else(|| ServiceError::NotFound(format!("Message {} not found", id)))?;
+                let (role, text) = message_text(&node.stored.message);
+                if role != "assistant" || node.stored.cancelled || text.trim().is_empty() {
+                    return Err(ServiceError::Rejected(format!(
+                        "Message {} is not a tutor reply",
+                        id
+                    ))
+                    .into());
+                }
+                Some((id, text))
+            }
+            None => session
+                .active_messages()
+                .into_iter()
+                .rev()
+                .find(|message| {
+                    message.role == "assistant"
+                        && !message.cancelled
+                        && !message.content.trim().is_empty()
+                })
+                .map(|message| (message.id, message.content)),
+        };
+        let Some((message_id, reply)) = reply else {
+            return Ok(None);
+        };
+        let mut redactor = self.redact_upstream.then(Redactor::new);
+        let reply = match &mut redactor {
+            Some(redactor) => redactor.redact(&reply),
+            None => reply,
+        };
+        let request = PendingCards::request(
+            &model.name,
+            self.flashcards_config.max_tokens,
+            &reply,
+            self.flashcards_config.max_candidates,
+        )?;
+        Ok(Some(PendingCards {
+            client: self.client.clone(),
+            request,
+            redactor,
+            max_cards: self.flashcards_config.max_candidates,
+            max_attempts: self.flashcards_config.max_attempts,
+            student_id: student_id.to_string(),
+            session_id: session_id.to_string(),
+            message_id,
+        }))
+    }
+
+    /// Save a card for a student. A card kept from a draft names the
+    /// session and message it came from, which must belong to the student.
+    pub fn add_card(
+        &mut self,
+        student_id: &str,
+        draft: CardDraft,
+        source: Option<CardSource>,
+    ) -> Result<Card> {
+        if let Some(source) = &source {
+            let session = self
+                .tenants
+                .sessions_mut(student_id)
+                .get_session_mut(student_id, &source.session_id)?;
+            if source.message_id >= session.nodes().len() {
+                return Err(ServiceError::NotFound(format!(
+                    "Message {} not found",
+                    source.message_id
+                ))
+                .into());
+            }
+        }
+        let owner = self.tenants.owner(student_id);
+        self.cards_mut()?.add(&owner, draft, source)
+    }
 
-        self.session_manager
-            .add_message(student_id, session_id, "assistant", &tutor_response)?;
+    pub fn edit_card(
+        &mut self,
+        student_id: &str,
+        card_id: &str,
+        front: Option<String>,
+        back: Option<String>,
+    ) -> Result<Card> {
+        let owner = self.tenants.owner(student_id);
+        self.cards_mut()?.edit(&owner, card_id, front, back)
+    }
+
+    pub fn review_card(&mut self, student_id: &str, card_id: &str, grade: u8) -> Result<Card> {
+        let owner = self.tenants.owner(student_id);
+        self.cards_mut()?.review(&owner, card_id, grade)
+    }
+
+    pub fn remove_card(&mut self, student_id: &str, card_id: &str) -> Result<()> {
+        let owner = self.tenants.owner(student_id);
+        self.cards_mut()?.remove(&owner, card_id)
+    }
+
+    /// A student's cards, soonest due first; only those due now when `due`.
+    pub fn cards(&self, student_id: &str, due: bool, limit: usize) -> Result<Vec<Card>> {
+        let cards = self
+            .cards
+            .as_ref()
+            .ok_or_else(|| anyhow!("Flashcards are disabled"))?;
+        let due_by = due.then(OffsetDateTime::now_utc);
+        Ok(cards.list(&self.tenants.owner(student_id), due_by, limit))
+    }
+
+    fn cards_mut(&mut self) -> Result<&mut CardStore> {
+        self.cards
+            .as_mut()
+            .ok_or_else(|| anyhow!("Flashcards are disabled"))
+    }
+
+    /// A student's progress; `None` when progress tracking is disabled.
+    pub fn progress(&self, student_id: &str) -> Option<ProgressReport> {
+        self.progress
+            .as_ref()
+            .map(|progress| progress.report(&self.tenants.owner(student_id)))
+    }
+
+    /// Profile limits; `None` when student profiles are disabled.
+    pub fn profile_config(&self) -> Option<&ProfileConfig> {
+        self.profiles.as_ref().map(|_| &self.profile_config)
+    }
+
+    pub fn profile(&self, student_id: &str) -> Result<StudentProfile> {
+        let profiles = self
+            .profiles
+            .as_ref()
+            .ok_or_else(|| anyhow!("Student profiles are disabled"))?;
+        Ok(profiles.get(&self.tenants.owner(student_id)))
+    }
+
+    pub fn update_profile(
+        &mut self,
+        student_id: &str,
+        update: ProfileUpdate,
+    ) -> Result<StudentProfile> {
+        let owner = self.tenants.owner(student_id);
+        self.profiles_mut()?.update(&owner, update)
+    }
+
+    pub fn remove_profile(&mut self, student_id: &str) -> Result<()> {
+        let owner = self.tenants.owner(student_id);
+        self.profiles_mut()?.remove(&owner)
+    }
+
+    /// Prepare notes on a session for the student's profile, to be run
+    /// with `PendingProfile::run` outside the lock. `None` when the tutor
+    /// has not answered anything yet.
+    pub fn begin_profile(
+        &mut self,
+        student_id: &str,
+        session_id: &str,
+    ) -> Result<Option<PendingProfile>> {
+        let current = self.profile(student_id)?;
+        self.check_quota(student_id)?;
+        let model = self.tenants.of(student_id).model.clone();
+        let session = self
+            .tenants
+            .sessions_mut(student_id)
+            .get_session_mut(student_id, session_id)?;
+        let turns = session
+            .messages()
+            .into_iter()
+            .filter(|stored| !stored.cancelled)
+            .map(|stored| message_text(&stored.message));
+        let Some(transcript) = quiz::transcript(turns) else {
+            return Ok(None);
+        };
+        let current = current.render().unwrap_or_else(|| "(none yet)".to_string());
+        let mut redactor = self.redact_upstream.then(Redactor::new);
+        let (current, transcript) = match &mut redactor {
+            Some(redactor) => (redactor.redact(&current), redactor.redact(&transcript)),
+            None => (current, transcript),
+        };
+        let request = PendingProfile::request(
+            &model.name,
+            self.profile_config.max_tokens,
+            &current,
+            &transcript,
+        )?;
+        Ok(Some(PendingProfile {
+            client: self.client.clone(),
+            request,
+            redactor,
+            max_attempts: self.profile_config.max_attempts,
+            owner: self.tenants.owner(student_id),
+        }))
+    }
+
+    pub fn merge_profile(&mut self, owner: &Owner, notes: ProfileNotes) -> Result<StudentProfile> {
+        self.profiles_mut()?.merge(owner, notes)
+    }
+
+    fn profiles_mut(&mut self) -> Result<&mut ProfileStore> {
+        self.profiles
+            .as_mut()
+            .ok_or_else(|| anyhow!("Student profiles are disabled"))
+    }
 
-        Ok(tutor_response)
+    /// Remove idle sessions; run periodically by the sweeper task.
+    pub fn sweep_sessions(&mut self) -> SweepStats {
+        self.tenants.sweep(OffsetDateTime::now_utc())
     }
 }
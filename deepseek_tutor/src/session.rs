@@ -1,23 +1,458 @@
 use anyhow::{Result, bail};
 use async_openai::types::{
-    ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestMessage,
-    ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestUserMessageArgs,
+    ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestAssistantMessageContent,
+    ChatCompletionRequestMessage, ChatCompletionRequestSystemMessageArgs,
+    ChatCompletionRequestSystemMessageContent, ChatCompletionRequestUserMessageArgs,
+    ChatCompletionRequestUserMessageContent, CompletionUsage,
 };
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use time::OffsetDateTime;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::citation::Citation;
+use crate::config::SessionsConfig;
+use crate::error::ServiceError;
+use crate::export::SessionExport;
+use crate::moderation::Category;
+use crate::retrieval::Scope;
+use crate::store::atomic_write_json;
+
+/// How long the ids of removed sessions are remembered, so that requests
+/// for them get 410 Gone rather than 404.
+const EXPIRED_RETENTION: time::Duration = time::Duration::days(7);
+
+/// Token counts reported by the upstream API.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct TokenUsage {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    pub total_tokens: u32,
+}
+
+impl From<&CompletionUsage> for TokenUsage {
+    fn from(usage: &CompletionUsage) -> Self {
+        Self {
+            prompt_tokens: usage.prompt_tokens,
+            completion_tokens: usage.completion_tokens,
+            total_tokens: usage.total_tokens,
+        }
+    }
+}
+
+impl std::ops::AddAssign for TokenUsage {
+    fn add_assign(&mut self, other: Self) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.total_tokens += other.total_tokens;
+    }
+}
+
+/// A history entry: the message as sent upstream plus what we know about it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredMessage {
+    #[serde(flatten)]
+    pub message: ChatCompletionRequestMessage,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+    /// Usage of the completion that produced this message, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<TokenUsage>,
+    /// Set on tutor replies that were stopped before the model answered.
+    /// Such messages are kept in history but never sent upstream.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub cancelled: bool,
+    /// Moderation categories recorded on the message.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub moderation: Vec<Category>,
+    /// Sources of a tutor reply.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub citations: Vec<Citation>,
+}
+
+/// Index of a message node within its session; stable for the session's life.
+pub type NodeId = usize;
+
+/// One message in the conversation tree. Editing a question or regenerating
+/// a reply adds a sibling node instead of overwriting history.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageNode {
+    pub id: NodeId,
+    pub parent: Option<NodeId>,
+    #[serde(flatten)]
+    pub stored: StoredMessage,
+}
+
+/// A message on the active branch, as shown to clients.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ActiveMessage {
+    pub id: NodeId,
+    pub role: String,
+    pub content: String,
+    #[serde(with = "time::serde::rfc3339")]
+    #[schema(value_type = String, format = DateTime)]
+    pub created_at: OffsetDateTime,
+    /// True for a tutor reply that was stopped before it arrived.
+    pub cancelled: bool,
+    /// Moderation categories found in the message.
+    pub moderation: Vec<Category>,
+    /// Sources of a tutor reply; `[n]` in `content` points to the citation
+    /// with that marker.
+    pub citations: Vec<Citation>,
+    /// Tools a tutor message called; the results follow as `tool` messages.
+    pub tool_calls: Vec<ToolCallSummary>,
+    /// For a `tool` message, the call it answers.
+    pub tool_call_id: Option<String>,
+    /// Ids of every alternative at this position, this message included,
+    /// oldest first. More than one means the conversation branches here.
+    pub alternatives: Vec<NodeId>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ToolCallSummary {
+    pub id: String,
+    pub name: String,
+    /// JSON arguments as sent by the model.
+    pub arguments: String,
+}
+
+/// One leaf of the conversation tree.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct BranchSummary {
+    /// Last message of the branch; pass it to the activate endpoint.
+    pub leaf_id: NodeId,
+    pub active: bool,
+    pub message_count: usize,
+    /// The latest student question on the branch.
+    pub last_question: Option<String>,
+    #[serde(with = "time::serde::rfc3339")]
+    #[schema(value_type = String, format = DateTime)]
+    pub updated_at: OffsetDateTime,
+}
 
 pub struct SessionData {
     pub system_prompt: String,
-    pub messages: Vec<ChatCompletionRequestMessage>,
+    /// Every message ever added, in creation order; `nodes[i].id == i`.
+    nodes: Vec<MessageNode>,
+    /// Last message of the active branch; `None` before the first message
+    /// or after rewinding to the start.
+    head: Option<NodeId>,
+    pub created_at: OffsetDateTime,
+    pub updated_at: OffsetDateTime,
+    /// Sum of the usage of every completion in this session.
+    pub usage: TokenUsage,
+    /// Last time the session was read or written; drives idle expiry and
+    /// LRU eviction.
+    pub last_used: OffsetDateTime,
+    /// Course and organization whose materials ground the answers.
+    pub scopes: Vec<Scope>,
+    /// Subject persona answering in this session; `None` for the general
+    /// tutor.
+    pub persona: Option<String>,
+    /// A turn is waiting for the model. Such sessions are never evicted or
+    /// expired, so that the reply has somewhere to go.
+    busy: bool,
+}
+
+impl SessionData {
+    pub fn new(system_prompt: String) -> Self {
+        let now = OffsetDateTime::now_utc();
+        SessionData {
+            system_prompt,
+            nodes: Vec::new(),
+            head: None,
+            created_at: now,
+            updated_at: now,
+            usage: TokenUsage::default(),
+            last_used: now,
+            scopes: Vec::new(),
+            persona: None,
+            busy: false,
+        }
+    }
+
+    /// A session whose history is a single branch, e.g. from an import.
+    pub fn with_history(
+        system_prompt: String,
+        messages: Vec<StoredMessage>,
+        usage: TokenUsage,
+    ) -> Self {
+        let mut data = SessionData::new(system_prompt);
+        if let Some(first) = messages.first() {
+            data.created_at = first.created_at;
+        }
+        for stored in messages {
+            data.updated_at = stored.created_at;
+            data.push(stored);
+        }
+        data.usage = usage;
+        data
+    }
+
+    /// Append a message after the head and make it the new head.
+    fn push(&mut self, stored: StoredMessage) -> NodeId {
+        let id = self.nodes.len();
+        self.nodes.push(MessageNode {
+            id,
+            parent: self.head,
+            stored,
+        });
+        self.head = Some(id);
+        id
+    }
+
+    pub fn nodes(&self) -> &[MessageNode] {
+        &self.nodes
+    }
+
+    pub fn head(&self) -> Option<NodeId> {
+        self.head
+    }
+
+    fn node(&self, id: NodeId) -> Result<&MessageNode> {
+        self.nodes
+            .get(id)
+            .ok_or_else(|| ServiceError::NotFound(format!("Message not found: {}", id)).into())
+    }
+
+    /// Node ids from the root to `leaf`.
+    fn path_to(&self, leaf: Option<NodeId>) -> Vec<NodeId> {
+        let mut path = Vec::new();
+        let mut cursor = leaf;
+        while let Some(id) = cursor {
+            path.push(id);
+            cursor = self.nodes[id].parent;
+        }
+        path.reverse();
+        path
+    }
+
+    /// Children of every node with any, oldest first, in one pass.
+    fn children(&self) -> HashMap<Option<NodeId>, Vec<NodeId>> {
+        let mut children: HashMap<_, Vec<_>> = HashMap::new();
+        for node in &self.nodes {
+            children.entry(node.parent).or_default().push(node.id);
+        }
+        children
+    }
+
+    /// Messages on the active branch, oldest first.
+    pub fn messages(&self) -> Vec<&StoredMessage> {
+        self.path_to(self.head)
+            .into_iter()
+            .map(|id| &self.nodes[id].stored)
+            .collect()
+    }
+
+    pub fn active_messages(&self) -> Vec<ActiveMessage> {
+        let children = self.children();
+        self.path_to(self.head)
+            .into_iter()
+            .map(|id| {
+                let node = &self.nodes[id];
+                let (role, content) = message_text(&node.stored.message);
+                let (tool_calls, tool_call_id) = match &node.stored.message {
+                    ChatCompletionRequestMessage::Assistant(m) => (
+                        m.tool_calls
+                            .iter()
+                            .flatten()
+                            .map(|call| ToolCallSummary {
+                                id: call.id.clone(),
+                                name: call.function.name.clone(),
+                                arguments: call.function.arguments.clone(),
+                            })
+                            .collect(),
+                        None,
+                    ),
+                    ChatCompletionRequestMessage::Tool(m) => {
+                        (Vec::new(), Some(m.tool_call_id.clone()))
+                    }
+                    _ => (Vec::new(), None),
+                };
+                ActiveMessage {
+                    id,
+                    role: role.to_string(),
+                    content,
+                    created_at: node.stored.created_at,
+                    cancelled: node.stored.cancelled,
+                    moderation: node.stored.moderation.clone(),
+                    citations: node.stored.citations.clone(),
+                    tool_calls,
+                    tool_call_id,
+                    alternatives: children.get(&node.parent).cloned().unwrap_or_default(),
+                }
+            })
+            .collect()
+    }
+
+    pub fn branches(&self) -> Vec<BranchSummary> {
+        let children = self.children();
+        self.nodes
+            .iter()
+            .filter(|n| !children.contains_key(&Some(n.id)))
+            .map(|leaf| {
+                let path = self.path_to(Some(leaf.id));
+                let last_question = path.iter().rev().find_map(|&id| {
+                    match message_text(&self.nodes[id].stored.message) {
+                        ("user", text) => Some(text),
+                        _ => None,
+                    }
+                });
+                BranchSummary {
+                    leaf_id: leaf.id,
+                    active: self.head == Some(leaf.id),
+                    message_count: path.len(),
+                    last_question,
+                    updated_at: leaf.stored.created_at,
+                }
+            })
+            .collect()
+    }
+
+    /// Follow the most recently created child from `id` down to a leaf.
+    fn latest_leaf_under(&self, mut id: NodeId) -> NodeId {
+        let children = self.children();
+        while let Some(&child) = children.get(&Some(id)).and_then(|c| c.last()) {
+            id = child;
+        }
+        id
+    }
+
+    /// Move the head to `id` and then down its newest descendants, so that
+    /// picking any alternative shows that alternative's full continuation.
+    pub fn activate(&mut self, id: NodeId) -> Result<()> {
+        self.node(id)?;
+        self.head = Some(self.latest_leaf_under(id));
+        Ok(())
+    }
+
+    /// Put the head back where it was, e.g. after a failed regeneration.
+    pub fn restore_head(&mut self, head: Option<NodeId>) {
+        self.head = head;
+    }
+
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            nodes: self.nodes.len(),
+            head: self.head,
+            updated_at: self.updated_at,
+            persona: self.persona.clone(),
+        }
+    }
+
+    /// Drop the messages added since `checkpoint` and put the head and
+    /// persona back, e.g. when a question's turn could not be started.
+    pub fn roll_back(&mut self, checkpoint: Checkpoint) {
+        self.nodes.truncate(checkpoint.nodes);
+        self.head = checkpoint.head;
+        self.updated_at = checkpoint.updated_at;
+        self.persona = checkpoint.persona;
+    }
+
+    /// Step the head back over tool calls and their results, to the
+    /// message that started the turn.
+    pub fn rewind_tool_exchange(&mut self) {
+        while let Some(id) = self.head {
+            let message = &self.nodes[id].stored.message;
+            let plumbing = match message {
+                ChatCompletionRequestMessage::Tool(_) => true,
+                ChatCompletionRequestMessage::Assistant(m) => m.tool_calls.is_some(),
+                _ => false,
+            };
+            if !plumbing {
+                break;
+            }
+            self.head = self.nodes[id].parent;
+        }
+    }
+
+    /// Move the head to the parent of `id`, which must have the given role,
+    /// so that the next message becomes a sibling of `id`.
+    pub fn rewind_before(&mut self, id: NodeId, role: &str) -> Result<()> {
+        let node = self.node(id)?;
+        let (actual, _) = message_text(&node.stored.message);
+        if actual != role {
+            return Err(ServiceError::Rejected(format!(
+                "Message {} has role {}, expected {}",
+                id, actual, role
+            ))
+            .into());
+        }
+        self.head = node.parent;
+        Ok(())
+    }
+}
+
+/// Where a session stood before a change that may have to be undone.
+#[derive(Debug, Clone)]
+pub struct Checkpoint {
+    nodes: usize,
+    head: Option<NodeId>,
+    updated_at: OffsetDateTime,
+    persona: Option<String>,
+}
+
+/// Rebuild a session from its lossless export, e.g. one spilled to disk.
+/// Fails if a node id, parent or the head does not fit the tree, since the
+/// file may have been edited or cut short.
+impl TryFrom<SessionExport> for SessionData {
+    type Error = anyhow::Error;
+
+    fn try_from(export: SessionExport) -> Result<Self> {
+        // Nodes are only ever appended, so each parent comes before its child.
+        for (index, node) in export.nodes.iter().enumerate() {
+            if node.id != index {
+                bail!("node {} is stored at position {}", node.id, index);
+            }
+            if node.parent.is_some_and(|parent| parent >= index) {
+                bail!("node {} has parent {:?}", index, node.parent);
+            }
+        }
+        if export.head.is_some_and(|head| head >= export.nodes.len()) {
+            bail!("head {:?} is not a node", export.head);
+        }
+        let mut data = if export.nodes.is_empty() {
+            // Version 1 exports only carry the active branch.
+            SessionData::with_history(export.system_prompt, export.messages, export.usage)
+        } else {
+            let mut data = SessionData::new(export.system_prompt);
+            data.nodes = export.nodes;
+            data.head = export.head;
+            data.usage = export.usage;
+            data
+        };
+        data.created_at = export.created_at;
+        data.updated_at = export.updated_at;
+        data.scopes = export.scopes;
+        data.persona = export.persona;
+        Ok(data)
+    }
+}
+
+/// What a sweep removed.
+#[derive(Debug, Default)]
+pub struct SweepStats {
+    pub expired: usize,
+    pub expired_on_disk: usize,
 }
 
 pub struct SessionManager {
     sessions: HashMap<String, HashMap<String, SessionData>>,
+    /// Student and session ids of sessions removed for inactivity or over
+    /// a limit, with the time of removal.
+    expired: HashMap<(String, String), OffsetDateTime>,
+    config: SessionsConfig,
 }
 
 impl SessionManager {
-    pub fn new() -> Self {
+    pub fn new(config: SessionsConfig) -> Self {
         SessionManager {
             sessions: HashMap::new(),
+            expired: HashMap::new(),
+            config,
         }
     }
 
@@ -26,15 +461,52 @@ impl SessionManager {
         student_id: S,
         session_id: S,
         system_prompt: S,
+        scopes: Vec<Scope>,
+        persona: Option<String>,
     ) {
         let sid = student_id.into();
         let sess = session_id.into();
         let prompt = system_prompt.into();
-        let data = SessionData {
-            system_prompt: prompt,
-            messages: Vec::new(),
-        };
-        self.sessions.entry(sid).or_default().insert(sess, data);
+        let mut data = SessionData::new(prompt);
+        data.scopes = scopes;
+        data.persona = persona;
+        self.insert_session(&sid, &sess, data);
+    }
+
+    /// Create a session that already has history, e.g. from an import.
+    pub fn insert_session(&mut self, student_id: &str, session_id: &str, mut data: SessionData) {
+        data.last_used = OffsetDateTime::now_utc();
+        self.sessions
+            .entry(student_id.to_string())
+            .or_default()
+            .insert(session_id.to_string(), data);
+        self.enforce_student_limit(student_id, session_id);
+        self.enforce_memory_limit(student_id, session_id);
+    }
+
+    /// Copy the prompt and history of an existing session under a new id.
+    pub fn clone_session(&mut self, student_id: &str, from: &str, to: &str) -> Result<()> {
+        let source = self.get_session_mut(student_id, from)?;
+        // Branches come along; tokens were spent by the original, so the
+        // clone starts its own count.
+        let mut copy = SessionData::new(source.system_prompt.clone());
+        copy.nodes = source.nodes.clone();
+        copy.head = source.head;
+        copy.scopes = source.scopes.clone();
+        copy.persona = source.persona.clone();
+        self.insert_session(student_id, to, copy);
+        Ok(())
+    }
+
+    /// Mark a session as waiting for the model, or as done waiting.
+    pub fn set_busy(&mut self, student_id: &str, session_id: &str, busy: bool) {
+        if let Some(session) = self
+            .sessions
+            .get_mut(student_id)
+            .and_then(|m| m.get_mut(session_id))
+        {
+            session.busy = busy;
+        }
     }
 
     pub fn get_session(&self, student_id: &str, session_id: &str) -> Option<&SessionData> {
@@ -43,46 +515,359 @@ impl SessionManager {
             .and_then(|m| m.get(session_id))
     }
 
-    pub fn add_message(
+    /// Look a session up for use, loading it back from disk if it was
+    /// spilled. Fails with "Session expired" for sessions removed by the
+    /// sweeper or a limit, so callers can tell them from unknown ids.
+    pub fn get_session_mut(
         &mut self,
         student_id: &str,
         session_id: &str,
-        role: &str,
-        content: &str,
-    ) -> Result<()> {
+    ) -> Result<&mut SessionData> {
+        if self.get_session(student_id, session_id).is_none() {
+            match self.load_spilled(student_id, session_id)? {
+                Some(data) => self.insert_session(student_id, session_id, data),
+                None if self
+                    .expired
+                    .contains_key(&(student_id.to_string(), session_id.to_string())) =>
+                {
+                    return Err(ServiceError::Expired("Session expired".to_string()).into());
+                }
+                None => return Err(ServiceError::NotFound("Session not found".to_string()).into()),
+            }
+        }
+
         let session = self
             .sessions
             .get_mut(student_id)
             .and_then(|m| m.get_mut(session_id))
-            .ok_or_else(|| anyhow::anyhow!("Session not found"))?;
-
-        let msg = match role {
-            "system" => ChatCompletionRequestSystemMessageArgs::default()
-                .content(content)
-                .build()?
-                .into(),
-            "user" => ChatCompletionRequestUserMessageArgs::default()
-                .content(content)
-                .build()?
-                .into(),
-            "assistant" => ChatCompletionRequestAssistantMessageArgs::default()
-                .content(content)
-                .build()?
-                .into(),
-            _ => bail!("Unknown role: {}", role),
+            .ok_or_else(|| ServiceError::NotFound("Session not found".to_string()))?;
+        session.last_used = OffsetDateTime::now_utc();
+        Ok(session)
+    }
+
+    /// Remove sessions idle for longer than the TTL, in memory and on disk,
+    /// and forget old expiry records.
+    pub fn sweep(&mut self, now: OffsetDateTime) -> SweepStats {
+        let mut stats = SweepStats::default();
+        let cutoff = now - self.config.idle_ttl;
+
+        let idle: Vec<(String, String)> = self
+            .sessions
+            .iter()
+            .flat_map(|(student_id, sessions)| {
+                sessions
+                    .iter()
+                    .filter(|(_, data)| data.last_used < cutoff && !data.busy)
+                    .map(|(session_id, _)| (student_id.clone(), session_id.clone()))
+            })
+            .collect();
+        for (student_id, session_id) in idle {
+            self.expire(&student_id, &session_id, now);
+            stats.expired += 1;
+        }
+
+        // Spilled sessions expire one TTL after they were written.
+        for path in self.spilled_files(None) {
+            let stale = fs::metadata(&path)
+                .and_then(|m| m.modified())
+                .is_ok_and(|modified| OffsetDateTime::from(modified) < cutoff);
+            if stale && self.expire_spilled(&path, now) {
+                stats.expired_on_disk += 1;
+            }
+        }
+
+        self.expired.retain(|_, at| *at > now - EXPIRED_RETENTION);
+        stats
+    }
+
+    /// Sessions of a student that are spilled to disk, as exports.
+    pub fn spilled_sessions(&self, student_id: &str) -> Vec<SessionExport> {
+        self.spilled_files(Some(student_id))
+            .into_iter()
+            .filter_map(|path| read_spill(&path))
+            .filter(|export| export.student_id == student_id)
+            .collect()
+    }
+
+    fn remove(&mut self, student_id: &str, session_id: &str) {
+        if let Some(sessions) = self.sessions.get_mut(student_id) {
+            sessions.remove(session_id);
+            if sessions.is_empty() {
+                self.sessions.remove(student_id);
+            }
+        }
+    }
+
+    fn expire(&mut self, student_id: &str, session_id: &str, now: OffsetDateTime) {
+        self.remove(student_id, session_id);
+        self.expired
+            .insert((student_id.to_string(), session_id.to_string()), now);
+    }
+
+    /// Delete a spill file and remember its session as expired.
+    fn expire_spilled(&mut self, path: &Path, now: OffsetDateTime) -> bool {
+        if fs::remove_file(path).is_err() {
+            return false;
+        }
+        let student_id = path
+            .parent()
+            .and_then(|dir| dir.file_name())
+            .and_then(|name| unhex(name.to_str()?));
+        let session_id = path.file_stem().and_then(|stem| stem.to_str());
+        if let (Some(student_id), Some(session_id)) = (student_id, session_id) {
+            self.expired
+                .insert((student_id, session_id.to_string()), now);
+        }
+        true
+    }
+
+    /// Drop the student's least recently used sessions, other than `keep`
+    /// and busy ones, until they are within the per-student limit. Spilled
+    /// sessions count towards the limit too.
+    fn enforce_student_limit(&mut self, student_id: &str, keep: &str) {
+        let now = OffsetDateTime::now_utc();
+        let spilled = self.spilled_files(Some(student_id));
+        let in_memory = self.sessions.get(student_id).map_or(0, HashMap::len);
+        let mut excess = (in_memory + spilled.len()).saturating_sub(self.config.max_per_student);
+
+        // Spilled sessions are the ones used longest ago; drop them first.
+        for path in spilled {
+            if excess == 0 {
+                break;
+            }
+            if self.expire_spilled(&path, now) {
+                excess -= 1;
+            }
+        }
+        for _ in 0..excess {
+            let Some(oldest) = self.sessions.get(student_id).and_then(|sessions| {
+                sessions
+                    .iter()
+                    .filter(|(id, data)| id.as_str() != keep && !data.busy)
+                    .min_by_key(|(_, data)| data.last_used)
+                    .map(|(id, _)| id.clone())
+            }) else {
+                break;
+            };
+            self.expire(student_id, &oldest, now);
+        }
+    }
+
+    /// Evict the least recently used sessions, other than the given one and
+    /// busy ones, until the in-memory count is within the limit.
+    fn enforce_memory_limit(&mut self, keep_student: &str, keep_session: &str) {
+        let mut total: usize = self.sessions.values().map(HashMap::len).sum();
+        while total > self.config.max_in_memory {
+            let Some((student_id, session_id)) = self
+                .sessions
+                .iter()
+                .flat_map(|(student_id, sessions)| {
+                    sessions
+                        .iter()
+                        .filter(|(_, data)| !data.busy)
+                        .map(move |(session_id, data)| (student_id, session_id, data.last_used))
+                })
+                .filter(|(student_id, session_id, _)| {
+                    (student_id.as_str(), session_id.as_str()) != (keep_student, keep_session)
+                })
+                .min_by_key(|(_, _, last_used)| *last_used)
+                .map(|(student_id, session_id, _)| (student_id.clone(), session_id.clone()))
+            else {
+                break;
+            };
+
+            let spilled = self.spill(&student_id, &session_id).unwrap_or_else(|err| {
+                eprintln!("Failed to spill session {}: {:#}", session_id, err);
+                false
+            });
+            if spilled {
+                self.remove(&student_id, &session_id);
+            } else {
+                self.expire(&student_id, &session_id, OffsetDateTime::now_utc());
+            }
+            total -= 1;
+        }
+    }
+
+    /// Write a session to disk. Returns false when spilling is off.
+    fn spill(&self, student_id: &str, session_id: &str) -> Result<bool> {
+        let (Some(path), Some(data)) = (
+            self.spill_path(student_id, session_id),
+            self.get_session(student_id, session_id),
+        ) else {
+            return Ok(false);
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        atomic_write_json(&path, &SessionExport::new(student_id, session_id, data))?;
+        Ok(true)
+    }
+
+    /// Take a spilled session off disk, if there is one for this student.
+    /// A spill that does not restore stays on disk.
+    fn load_spilled(&self, student_id: &str, session_id: &str) -> Result<Option<SessionData>> {
+        let Some(path) = self.spill_path(student_id, session_id) else {
+            return Ok(None);
+        };
+        let Some(export) = read_spill(&path).filter(|e| e.student_id == student_id) else {
+            return Ok(None);
         };
+        let data = SessionData::try_from(export)
+            .map_err(|e| e.context(format!("spilled session {} is corrupt", session_id)))?;
+        let _ = fs::remove_file(&path);
+        Ok(Some(data))
+    }
+
+    /// `<spill_dir>/<hex student id>/<session id>.json`. Only server-issued
+    /// (UUID) session ids are spilled, which keeps paths well-formed.
+    fn spill_path(&self, student_id: &str, session_id: &str) -> Option<PathBuf> {
+        let dir = self.config.spill_dir.as_ref()?;
+        Uuid::parse_str(session_id).ok()?;
+        Some(
+            dir.join(hex(student_id))
+                .join(format!("{}.json", session_id)),
+        )
+    }
+
+    /// Spill files of one student, or of everyone, oldest first.
+    fn spilled_files(&self, student_id: Option<&str>) -> Vec<PathBuf> {
+        let Some(dir) = &self.config.spill_dir else {
+            return Vec::new();
+        };
+        let student_dirs: Vec<PathBuf> = match student_id {
+            Some(student_id) => vec![dir.join(hex(student_id))],
+            None => fs::read_dir(dir)
+                .into_iter()
+                .flatten()
+                .flatten()
+                .map(|entry| entry.path())
+                .collect(),
+        };
+        let mut files: Vec<(std::time::SystemTime, PathBuf)> = student_dirs
+            .iter()
+            .flat_map(|d| fs::read_dir(d).into_iter().flatten().flatten())
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .filter_map(|path| Some((fs::metadata(&path).ok()?.modified().ok()?, path)))
+            .collect();
+        files.sort();
+        files.into_iter().map(|(_, path)| path).collect()
+    }
+
+    /// All sessions of a student, oldest first.
+    pub fn student_sessions(&self, student_id: &str) -> Vec<(&str, &SessionData)> {
+        let mut sessions: Vec<_> = self
+            .sessions
+            .get(student_id)
+            .into_iter()
+            .flatten()
+            .map(|(id, data)| (id.as_str(), data))
+            .collect();
+        sessions.sort_by_key(|(_, data)| data.created_at);
+        sessions
+    }
+
+    pub fn add_message(
+        &mut self,
+        student_id: &str,
+        session_id: &str,
+        role: &str,
+        content: &str,
+    ) -> Result<NodeId> {
+        let session = self.get_session_mut(student_id, session_id)?;
+        let msg = text_message(role, content)?;
+        Self::append(session, msg)
+    }
+
+    /// Add a message built elsewhere, e.g. a tool call and its result.
+    pub fn add_raw_message(
+        &mut self,
+        student_id: &str,
+        session_id: &str,
+        message: ChatCompletionRequestMessage,
+    ) -> Result<NodeId> {
+        let session = self.get_session_mut(student_id, session_id)?;
+        Self::append(session, message)
+    }
+
+    fn append(session: &mut SessionData, message: ChatCompletionRequestMessage) -> Result<NodeId> {
+        let now = OffsetDateTime::now_utc();
+        let id = session.push(StoredMessage {
+            message,
+            created_at: now,
+            usage: None,
+            cancelled: false,
+            moderation: Vec::new(),
+            citations: Vec::new(),
+        });
+        session.updated_at = now;
+        Ok(id)
+    }
+
+    /// Record a tutor turn that was stopped before the model answered.
+    pub fn add_cancelled_reply(&mut self, student_id: &str, session_id: &str) -> Result<NodeId> {
+        let id = self.add_message(student_id, session_id, "assistant", "")?;
+        let session = self.get_session_mut(student_id, session_id)?;
+        session.nodes[id].stored.cancelled = true;
+        Ok(id)
+    }
+
+    /// Record moderation categories on the head message.
+    pub fn annotate_head(
+        &mut self,
+        student_id: &str,
+        session_id: &str,
+        categories: Vec<Category>,
+    ) -> Result<()> {
+        let session = self.get_session_mut(student_id, session_id)?;
+
+        if let Some(head) = session.head {
+            session.nodes[head].stored.moderation = categories;
+        }
+        Ok(())
+    }
+
+    /// Record the sources of the tutor reply at the head.
+    pub fn cite_head(
+        &mut self,
+        student_id: &str,
+        session_id: &str,
+        citations: Vec<Citation>,
+    ) -> Result<()> {
+        let session = self.get_session_mut(student_id, session_id)?;
+
+        if let Some(head) = session.head {
+            session.nodes[head].stored.citations = citations;
+        }
+        Ok(())
+    }
+
+    /// Attach completion usage to the head message and the session total.
+    pub fn record_usage(
+        &mut self,
+        student_id: &str,
+        session_id: &str,
+        usage: TokenUsage,
+    ) -> Result<()> {
+        let session = self.get_session_mut(student_id, session_id)?;
 
-        session.messages.push(msg);
+        if let Some(head) = session.head {
+            session.nodes[head].stored.usage = Some(usage);
+        }
+        session.usage += usage;
         Ok(())
     }
 
+    /// The system prompt followed by the active branch.
     pub fn get_conversation(
         &self,
         student_id: &str,
         session_id: &str,
     ) -> Vec<ChatCompletionRequestMessage> {
         if let Some(session) = self.get_session(student_id, session_id) {
-            let mut convo = Vec::with_capacity(1 + session.messages.len());
+            let messages = session.messages();
+            let mut convo = Vec::with_capacity(1 + messages.len());
             convo.push(
                 ChatCompletionRequestSystemMessageArgs::default()
                     .content(session.system_prompt.as_str()) // Changed from &session.system_prompt
@@ -90,10 +875,396 @@ impl SessionManager {
                     .unwrap()
                     .into(),
             );
-            convo.extend(session.messages.clone());
+            convo.extend(
+                messages
+                    .into_iter()
+                    .filter(|m| !m.cancelled)
+                    .map(|m| m.message.clone()),
+            );
             convo
         } else {
             Vec::new()
         }
     }
 }
+
+fn read_spill(path: &Path) -> Option<SessionExport> {
+    let bytes = fs::read(path).ok()?;
+    serde_json::from_slice(&bytes).ok()
+}
+
+pub fn hex(text: &str) -> String {
+    text.bytes().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(text: &str) -> Option<String> {
+    let bytes = (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+/// A plain text message from the system, the student or the tutor.
+pub fn text_message(role: &str, content: &str) -> Result<ChatCompletionRequestMessage> {
+    Ok(match role {
+        "system" => ChatCompletionRequestSystemMessageArgs::default()
+            .content(content)
+            .build()?
+            .into(),
+        "user" => ChatCompletionRequestUserMessageArgs::default()
+            .content(content)
+            .build()?
+            .into(),
+        "assistant" => ChatCompletionRequestAssistantMessageArgs::default()
+            .content(content)
+            .build()?
+            .into(),
+        _ => bail!("Unknown role: {}", role),
+    })
+}
+
+/// Role name and plain text of a message. Multi-part content is joined with
+/// blank lines; parts without text (images, audio) are skipped.
+pub fn message_text(message: &ChatCompletionRequestMessage) -> (&'static str, String) {
+    use async_openai::types::{
+        ChatCompletionRequestAssistantMessageContentPart as AssistantPart,
+        ChatCompletionRequestDeveloperMessageContent,
+        ChatCompletionRequestSystemMessageContentPart as SystemPart,
+        ChatCompletionRequestToolMessageContent,
+        ChatCompletionRequestToolMessageContentPart as ToolPart,
+        ChatCompletionRequestUserMessageContentPart as UserPart,
+    };
+
+    match message {
+        ChatCompletionRequestMessage::System(m) => match &m.content {
+            ChatCompletionRequestSystemMessageContent::Text(text) => ("system", text.clone()),
+            ChatCompletionRequestSystemMessageContent::Array(parts) => (
+                "system",
+                parts
+                    .iter()
+                    .map(|SystemPart::Text(p)| p.text.as_str())
+                    .collect::<Vec<_>>()
+                    .join("\n\n"),
+            ),
+        },
+        ChatCompletionRequestMessage::User(m) => match &m.content {
+            ChatCompletionRequestUserMessageContent::Text(text) => ("user", text.clone()),
+            ChatCompletionRequestUserMessageContent::Array(parts) => (
+                "user",
+                parts
+                    .iter()
+                    .filter_map(|p| match p {
+                        UserPart::Text(p) => Some(p.text.as_str()),
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n\n"),
+            ),
+        },
+        ChatCompletionRequestMessage::Assistant(m) => match &m.content {
+            Some(ChatCompletionRequestAssistantMessageContent::Text(text)) => {
+                ("assistant", text.clone())
+            }
+            Some(ChatCompletionRequestAssistantMessageContent::Array(parts)) => (
+                "assistant",
+                parts
+                    .iter()
+                    .map(|p| match p {
+                        AssistantPart::Text(p) => p.text.as_str(),
+                        AssistantPart::Refusal(p) => p.refusal.as_str(),
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n\n"),
+            ),
+            None => ("assistant", String::new()),
+        },
+        ChatCompletionRequestMessage::Developer(m) => match &m.content {
+            ChatCompletionRequestDeveloperMessageContent::Text(text) => ("developer", text.clone()),
+            ChatCompletionRequestDeveloperMessageContent::Array(parts) => (
+                "developer",
+                parts
+                    .iter()
+                    .map(|p| p.text.as_str())
+                    .collect::<Vec<_>>()
+                    .join("\n\n"),
+            ),
+        },
+        ChatCompletionRequestMessage::Tool(m) => match &m.content {
+            ChatCompletionRequestToolMessageContent::Text(text) => ("tool", text.clone()),
+            ChatCompletionRequestToolMessageContent::Array(parts) => (
+                "tool",
+                parts
+                    .iter()
+                    .map(|ToolPart::Text(p)| p.text.as_str())
+                    .collect::<Vec<_>>()
+                    .join("\n\n"),
+            ),
+        },
+        ChatCompletionRequestMessage::Function(m) => {
+            ("function", m.content.clone().unwrap_or_default())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::testing::TempDir;
+
+    fn manager() -> SessionManager {
+        let mut sessions = SessionManager::new(Config::for_tests().sessions);
+        sessions.create_session("ana", "s1", "Be brief.", Vec::new(), None);
+        sessions
+    }
+
+    fn contents(sessions: &mut SessionManager) -> Vec<String> {
+        let session = sessions.get_session_mut("ana", "s1").unwrap();
+        session
+            .active_messages()
+            .into_iter()
+            .map(|m| m.content)
+            .collect()
+    }
+
+    #[test]
+    fn regenerating_and_editing_add_branches() {
+        let mut sessions = manager();
+        let question = sessions.add_message("ana", "s1", "user", "2 + 2?").unwrap();
+        let reply = sessions.add_message("ana", "s1", "assistant", "4").unwrap();
+
+        // Regenerate: the new reply is a sibling of the old one.
+        let session = sessions.get_session_mut("ana", "s1").unwrap();
+        assert!(session.rewind_before(reply, "user").is_err());
+        session.rewind_before(reply, "assistant").unwrap();
+        let regenerated = sessions
+            .add_message("ana", "s1", "assistant", "Four")
+            .unwrap();
+        assert_eq!(contents(&mut sessions), ["2 + 2?", "Four"]);
+
+        // Edit: the new question starts a branch from the root.
+        let session = sessions.get_session_mut("ana", "s1").unwrap();
+        session.rewind_before(question, "user").unwrap();
+        sessions.add_message("ana", "s1", "user", "3 + 3?").unwrap();
+        sessions.add_message("ana", "s1", "assistant", "6").unwrap();
+
+        let session = sessions.get_session_mut("ana", "s1").unwrap();
+        let active = session.active_messages();
+        assert_eq!(active[0].alternatives, [question, 3]);
+        let branches = session.branches();
+        assert_eq!(
+            branches
+                .iter()
+                .map(|b| (b.leaf_id, b.active))
+                .collect::<Vec<_>>(),
+            [(reply, false), (regenerated, false), (4, true)]
+        );
+        assert_eq!(branches[0].last_question.as_deref(), Some("2 + 2?"));
+
+        // Picking the first question follows its newest reply.
+        session.activate(question).unwrap();
+        assert_eq!(session.head(), Some(regenerated));
+        session.activate(reply).unwrap();
+        assert_eq!(contents(&mut sessions), ["2 + 2?", "4"]);
+        let session = sessions.get_session_mut("ana", "s1").unwrap();
+        assert!(session.activate(99).is_err());
+    }
+
+    #[test]
+    fn restores_the_head_after_a_cancelled_regeneration() {
+        let mut sessions = manager();
+        sessions.add_message("ana", "s1", "user", "2 + 2?").unwrap();
+        let reply = sessions.add_message("ana", "s1", "assistant", "4").unwrap();
+
+        let session = sessions.get_session_mut("ana", "s1").unwrap();
+        let previous = session.head();
+        session.rewind_before(reply, "assistant").unwrap();
+        sessions.add_cancelled_reply("ana", "s1").unwrap();
+        let conversation = sessions.get_conversation("ana", "s1");
+        assert_eq!(
+            conversation.len(),
+            2,
+            "cancelled replies are not sent upstream"
+        );
+
+        let session = sessions.get_session_mut("ana", "s1").unwrap();
+        session.restore_head(previous);
+        assert_eq!(contents(&mut sessions), ["2 + 2?", "4"]);
+        let session = sessions.get_session_mut("ana", "s1").unwrap();
+        assert_eq!(session.branches().len(), 2);
+    }
+
+    #[test]
+    fn rolls_back_an_edit_that_could_not_start() {
+        let mut sessions = manager();
+        let question = sessions.add_message("ana", "s1", "user", "2 + 2?").unwrap();
+        sessions.add_message("ana", "s1", "assistant", "4").unwrap();
+
+        let session = sessions.get_session_mut("ana", "s1").unwrap();
+        let checkpoint = session.checkpoint();
+        session.rewind_before(question, "user").unwrap();
+        session.persona = Some("math".to_string());
+        sessions.add_message("ana", "s1", "user", "2 + 3?").unwrap();
+
+        let session = sessions.get_session_mut("ana", "s1").unwrap();
+        session.roll_back(checkpoint);
+        assert_eq!(session.nodes().len(), 2);
+        assert_eq!(session.branches().len(), 1);
+        assert_eq!(session.persona, None);
+        assert_eq!(contents(&mut sessions), ["2 + 2?", "4"]);
+    }
+
+    #[test]
+    fn rewinds_over_tool_calls() {
+        use async_openai::types::{
+            ChatCompletionMessageToolCall, ChatCompletionRequestToolMessageArgs,
+            ChatCompletionToolType, FunctionCall,
+        };
+
+        let mut sessions = manager();
+        let question = sessions
+            .add_message("ana", "s1", "user", "3 km in miles?")
+            .unwrap();
+        let call = ChatCompletionRequestAssistantMessageArgs::default()
+            .tool_calls(vec![ChatCompletionMessageToolCall {
+                id: "c1".to_string(),
+                r#type: ChatCompletionToolType::Function,
+                function: FunctionCall {
+                    name: "convert_units".to_string(),
+                    arguments: "{}".to_string(),
+                },
+            }])
+            .build()
+            .unwrap();
+        let result = ChatCompletionRequestToolMessageArgs::default()
+            .tool_call_id("c1")
+            .content("1.86 mi")
+            .build()
+            .unwrap();
+        sessions.add_raw_message("ana", "s1", call.into()).unwrap();
+        sessions
+            .add_raw_message("ana", "s1", result.into())
+            .unwrap();
+
+        let session = sessions.get_session_mut("ana", "s1").unwrap();
+        session.rewind_tool_exchange();
+        assert_eq!(session.head(), Some(question));
+    }
+
+    /// What looking the session up gives.
+    fn status(sessions: &mut SessionManager, student_id: &str, session_id: &str) -> &'static str {
+        match sessions.get_session_mut(student_id, session_id) {
+            Ok(_) => "found",
+            Err(err) => match err.downcast_ref::<ServiceError>() {
+                Some(ServiceError::NotFound(_)) => "not found",
+                Some(ServiceError::Expired(_)) => "expired",
+                _ => "other",
+            },
+        }
+    }
+
+    #[test]
+    fn idle_sessions_expire_and_are_reported_gone() {
+        let mut sessions = manager();
+        sessions.create_session("ana", "s2", "Be brief.", Vec::new(), None);
+        sessions.set_busy("ana", "s2", true);
+
+        let later = OffsetDateTime::now_utc() + Config::for_tests().sessions.idle_ttl;
+        let stats = sessions.sweep(later + time::Duration::seconds(1));
+        assert_eq!(stats.expired, 1);
+        assert!(
+            sessions.get_session("ana", "s2").is_some(),
+            "busy sessions stay"
+        );
+        assert_eq!(status(&mut sessions, "ana", "s1"), "expired");
+        assert_eq!(status(&mut sessions, "ana", "s9"), "not found");
+        assert_eq!(status(&mut sessions, "bo", "s1"), "not found");
+
+        // Expiry records are forgotten after a week; then it is just unknown.
+        sessions.sweep(later + EXPIRED_RETENTION + time::Duration::days(1));
+        assert_eq!(status(&mut sessions, "ana", "s1"), "not found");
+    }
+
+    #[test]
+    fn spills_the_least_recently_used_session_and_reloads_it() {
+        let dir = TempDir::new("spill");
+        let mut config = Config::for_tests().sessions;
+        config.max_in_memory = 1;
+        config.spill_dir = Some(dir.path().to_path_buf());
+        let mut sessions = SessionManager::new(config);
+        let (first, second) = (Uuid::new_v4().to_string(), Uuid::new_v4().to_string());
+
+        sessions.create_session("ana", &first, "Be brief.", Vec::new(), None);
+        sessions
+            .add_message("ana", &first, "user", "2 + 2?")
+            .unwrap();
+        sessions.create_session("ana", &second, "Be brief.", Vec::new(), None);
+        assert!(sessions.get_session("ana", &first).is_none());
+        let spilled = sessions.spilled_sessions("ana");
+        assert_eq!(spilled.len(), 1);
+        assert_eq!(spilled[0].session_id, first);
+        assert!(sessions.spilled_sessions("bo").is_empty());
+
+        // Using it loads it back, which spills the other one.
+        let reloaded = sessions.get_session_mut("ana", &first).unwrap();
+        assert_eq!(reloaded.messages().len(), 1);
+        assert!(sessions.get_session("ana", &second).is_none());
+        assert_eq!(sessions.spilled_sessions("ana")[0].session_id, second);
+        assert_eq!(status(&mut sessions, "bo", &second), "not found");
+    }
+
+    #[test]
+    fn refuses_spills_whose_ids_do_not_fit_the_tree() {
+        let dir = TempDir::new("spill-corrupt");
+        let mut config = Config::for_tests().sessions;
+        config.max_in_memory = 1;
+        config.spill_dir = Some(dir.path().to_path_buf());
+        let mut sessions = SessionManager::new(config);
+        let (first, second) = (Uuid::new_v4().to_string(), Uuid::new_v4().to_string());
+        sessions.create_session("ana", &first, "Be brief.", Vec::new(), None);
+        sessions
+            .add_message("ana", &first, "user", "2 + 2?")
+            .unwrap();
+        sessions.create_session("ana", &second, "Be brief.", Vec::new(), None);
+
+        let path = dir.path().join(hex("ana")).join(format!("{first}.json"));
+        let spill: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        for (pointer, value) in [("/head", 7), ("/nodes/0/parent", 0), ("/nodes/0/id", 3)] {
+            let mut corrupt = spill.clone();
+            *corrupt.pointer_mut(pointer).unwrap() = value.into();
+            fs::write(&path, corrupt.to_string()).unwrap();
+            let error = sessions.get_session_mut("ana", &first).err();
+            assert!(
+                error.is_some_and(|e| e.to_string().contains("corrupt")),
+                "{pointer} was accepted"
+            );
+            assert!(path.exists());
+        }
+    }
+
+    #[test]
+    fn never_evicts_busy_sessions() {
+        let mut config = Config::for_tests().sessions;
+        config.max_per_student = 2;
+        let mut sessions = SessionManager::new(config);
+        for session_id in ["s1", "s2"] {
+            sessions.create_session("ana", session_id, "Be brief.", Vec::new(), None);
+        }
+        sessions.set_busy("ana", "s1", true);
+        sessions.create_session("ana", "s3", "Be brief.", Vec::new(), None);
+        assert!(sessions.get_session("ana", "s1").is_some());
+        assert_eq!(status(&mut sessions, "ana", "s2"), "expired");
+    }
+
+    #[test]
+    fn memory_limit_tells_students_apart() {
+        let mut config = Config::for_tests().sessions;
+        config.max_in_memory = 1;
+        let mut sessions = SessionManager::new(config);
+        sessions.create_session("ana", "s1", "Be brief.", Vec::new(), None);
+        sessions.create_session("bo", "s1", "Be brief.", Vec::new(), None);
+        assert!(sessions.get_session("bo", "s1").is_some());
+        assert_eq!(status(&mut sessions, "ana", "s1"), "expired");
+    }
+}
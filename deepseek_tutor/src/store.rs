@@ -0,0 +1,134 @@
+//! What the stores share: the files they keep on disk and which of their
+//! records a student may see.
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+
+use crate::tenancy::Owner;
+
+/// A record that belongs to one student of one organization.
+pub trait Owned {
+    fn organization(&self) -> &str;
+    fn student_id(&self) -> &str;
+}
+
+/// The record with `id`, when it is `owner`'s; everyone else's records are
+/// not found.
+pub fn get_owned<'a, T: Owned>(
+    records: &'a HashMap<String, T>,
+    owner: &Owner,
+    id: &str,
+) -> Option<&'a T> {
+    records.get(id).filter(|record| owner.owns(*record))
+}
+
+/// Every record of `owner`.
+pub fn owned_by<'a, T: Owned>(
+    records: &'a HashMap<String, T>,
+    owner: &Owner,
+) -> impl Iterator<Item = &'a T> {
+    records.values().filter(|record| owner.owns(*record))
+}
+
+/// Write `value` as JSON to `path` so that readers, and the store itself
+/// after a crash, see either the previous file or the complete new one. The
+/// JSON goes to `<path>.tmp` first, is flushed to disk and then renamed over
+/// `path`.
+pub fn atomic_write_json<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    let json = serde_json::to_vec(value)?;
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".tmp");
+    let partial = Path::new(&partial);
+    let written = File::create(partial)
+        .and_then(|mut file| {
+            file.write_all(&json)?;
+            file.sync_all()
+        })
+        .and_then(|()| fs::rename(partial, path));
+    if let Err(err) = written {
+        let _ = fs::remove_file(partial);
+        return Err(err).with_context(|| format!("failed to write {}", path.display()));
+    }
+    // Make the rename itself durable. Not every platform can open a
+    // directory for this, and the file is complete either way.
+    if let Some(dir) = path.parent().and_then(|dir| File::open(dir).ok()) {
+        let _ = dir.sync_all();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    struct Note(&'static str, &'static str);
+
+    impl Owned for Note {
+        fn organization(&self) -> &str {
+            self.0
+        }
+
+        fn student_id(&self) -> &str {
+            self.1
+        }
+    }
+
+    #[test]
+    fn hands_out_only_the_owner_s_records() {
+        let records = HashMap::from([
+            ("a".to_string(), Note("northside", "ana")),
+            ("b".to_string(), Note("default", "ana")),
+            ("c".to_string(), Note("northside", "bo")),
+        ]);
+        let owner = |organization: &str, student_id: &str| Owner {
+            organization: organization.to_string(),
+            student_id: student_id.to_string(),
+        };
+        let northside_ana = owner("northside", "ana");
+
+        assert!(get_owned(&records, &northside_ana, "a").is_some());
+        assert!(get_owned(&records, &northside_ana, "b").is_none());
+        assert!(get_owned(&records, &northside_ana, "c").is_none());
+        assert!(get_owned(&records, &northside_ana, "z").is_none());
+        let ids = |owner: &Owner| {
+            let mut ids: Vec<_> = owned_by(&records, owner).map(|note| note.0).collect();
+            ids.sort();
+            ids
+        };
+        assert_eq!(ids(&northside_ana), ["northside"]);
+        assert_eq!(ids(&owner("default", "ana")), ["default"]);
+        assert!(ids(&owner("southside", "ana")).is_empty());
+    }
+
+    #[test]
+    fn replaces_the_file_and_leaves_nothing_behind() {
+        let dir = TempDir::new("store");
+        let path = dir.path().join("item.json");
+        atomic_write_json(&path, &vec![1, 2, 3]).unwrap();
+        atomic_write_json(&path, &vec![4]).unwrap();
+        let read: Vec<u32> = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(read, [4]);
+        let names: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, ["item.json"]);
+    }
+
+    #[test]
+    fn keeps_the_old_file_when_the_write_fails() {
+        let dir = TempDir::new("store");
+        let path = dir.path().join("item.json");
+        atomic_write_json(&path, &"old").unwrap();
+        // A directory in the way of the temporary file.
+        fs::create_dir(dir.path().join("item.json.tmp")).unwrap();
+        let err = atomic_write_json(&path, &"new").unwrap_err();
+        assert!(err.to_string().contains("item.json"), "{err:#}");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "\"old\"");
+    }
+}
@@ -0,0 +1,105 @@
+use anyhow::{Result, anyhow};
+use async_openai::{
+    Client,
+    config::OpenAIConfig,
+    types::{
+        ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestUserMessageArgs,
+        CreateChatCompletionRequest,
+    },
+};
+
+use crate::session::TokenUsage;
+
+/// Ask for a JSON reply and turn it into `T` with `parse`. A reply that
+/// `parse` rejects is sent back to the model with the problem, until one
+/// passes or `max_attempts` completions have been made. The usage of every
+/// completion is added to `usage`, whether or not one passes.
+pub async fn complete<T>(
+    client: &Client<OpenAIConfig>,
+    mut request: CreateChatCompletionRequest,
+    max_attempts: usize,
+    usage: &mut TokenUsage,
+    parse: impl Fn(&str) -> Result<T>,
+) -> Result<T> {
+    let chat = client.chat();
+    let mut problem = anyhow!("no attempt made");
+    for _ in 0..max_attempts {
+        let response = chat.create(request.clone()).await?;
+        if let Some(completion) = &response.usage {
+            *usage += TokenUsage::from(completion);
+        }
+        let reply = response
+            .choices
+            .into_iter()
+            .next()
+            .and_then(|choice| choice.message.content)
+            .unwrap_or_default();
+        match parse(unfence(&reply)) {
+            Ok(value) => return Ok(value),
+            Err(err) => {
+                request.messages.push(
+                    ChatCompletionRequestAssistantMessageArgs::default()
+                        .content(reply)
+                        .build()?
+                        .into(),
+                );
+                request.messages.push(
+                    ChatCompletionRequestUserMessageArgs::default()
+                        .content(format!(
+                            "That reply is not valid: {:#}. Reply with the corrected JSON object only.",
+                            err
+                        ))
+                        .build()?
+                        .into(),
+                );
+                problem = err;
+            }
+        }
+    }
+    Err(problem.context(format!(
+        "The model gave no valid reply in {} attempts",
+        max_attempts
+    )))
+}
+
+/// Some models wrap JSON in a code fence even when asked not to.
+fn unfence(reply: &str) -> &str {
+    let json = reply.trim();
+    json.strip_prefix("```json")
+        .or_else(|| json.strip_prefix("```"))
+        .and_then(|rest| rest.strip_suffix("```"))
+        .unwrap_or(json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{FakeUpstream, completion};
+    use async_openai::types::ChatCompletionRequestUserMessageArgs;
+
+    #[tokio::test]
+    async fn counts_the_usage_of_every_attempt() {
+        // Every reply is text that is not JSON.
+        let upstream = FakeUpstream::start(|_| {
+            let message = serde_json::json!({"role": "assistant", "content": "Sure!"});
+            (200, completion("stop", message))
+        });
+        let client = upstream.client();
+        let question = ChatCompletionRequestUserMessageArgs::default()
+            .content("Reply with JSON.")
+            .build()
+            .unwrap();
+        let request = CreateChatCompletionRequest {
+            model: "m".to_string(),
+            messages: vec![question.into()],
+            ..Default::default()
+        };
+        let mut usage = TokenUsage::default();
+        let parsed = complete(&client, request, 3, &mut usage, |reply| {
+            serde_json::from_str::<serde_json::Value>(reply).map_err(Into::into)
+        })
+        .await;
+        assert!(parsed.is_err());
+        assert_eq!(usage.total_tokens, 18);
+    }
+}
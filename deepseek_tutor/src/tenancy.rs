@@ -0,0 +1,376 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use time::{Date, OffsetDateTime};
+
+use crate::config::{ClassroomConfig, Config, DEFAULT_ORGANIZATION, ModelConfig};
+use crate::error::ServiceError;
+use crate::retrieval::Scope;
+use crate::session::{SessionManager, SweepStats, hex};
+use crate::store::{Owned, atomic_write_json};
+
+time::serde::format_description!(day_format, Date, "[year]-[month]-[day]");
+
+/// One organization: its settings and the sessions of its students.
+pub struct Tenant {
+    pub id: String,
+    pub name: String,
+    pub system_prompt: String,
+    pub model: ModelConfig,
+    pub sessions: SessionManager,
+    daily_tokens: Option<u64>,
+    classrooms: Vec<ClassroomConfig>,
+    usage: DailyUsage,
+    /// Where `usage` is saved; `None` when the organization has no quota.
+    usage_file: Option<PathBuf>,
+}
+
+/// Tokens an organization's students used upstream on one UTC day.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+struct DailyUsage {
+    #[serde(with = "day_format")]
+    day: Date,
+    tokens: u64,
+}
+
+impl DailyUsage {
+    fn none(day: Date) -> Self {
+        Self { day, tokens: 0 }
+    }
+
+    /// The count saved at `path`, or none when there is no file yet. A file
+    /// that does not parse is reported and counting starts over.
+    fn load(path: &Path, today: Date) -> Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("failed to create {}", dir.display()))?;
+        }
+        match fs::read(path) {
+            Ok(bytes) => Ok(serde_json::from_slice(&bytes).unwrap_or_else(|err| {
+                eprintln!("Ignoring token usage in {}: {}", path.display(), err);
+                Self::none(today)
+            })),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Self::none(today)),
+            Err(err) => Err(err).with_context(|| format!("failed to read {}", path.display())),
+        }
+    }
+}
+
+/// A student together with the organization they resolve to. Stores save
+/// both with every record and match on both, so that nothing saved for a
+/// student id in one organization is found from another.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Owner {
+    pub organization: String,
+    pub student_id: String,
+}
+
+impl Owner {
+    /// Whether `record` is this student's. The same student id in another
+    /// organization is another student.
+    pub fn owns(&self, record: &impl Owned) -> bool {
+        self.organization == record.organization() && self.student_id == record.student_id()
+    }
+
+    /// Name of the owner's file in stores with one file per student.
+    /// Students of the default organization keep the name they had before
+    /// stores knew about organizations.
+    pub fn file_stem(&self) -> String {
+        if self.organization == DEFAULT_ORGANIZATION {
+            hex(&self.student_id)
+        } else {
+            format!("{}.{}", hex(&self.organization), hex(&self.student_id))
+        }
+    }
+}
+
+/// The organization of records saved before stores kept one.
+pub fn default_organization() -> String {
+    DEFAULT_ORGANIZATION.to_string()
+}
+
+/// Every organization, with each student resolved to exactly one of them.
+/// Students are isolated by organization: their sessions live in that
+/// organization's `SessionManager`, under its prompt, model and quotas.
+pub struct Tenants {
+    /// The default organization comes first.
+    tenants: Vec<Tenant>,
+    /// Index of the organization of each enrolled student.
+    enrolled: HashMap<String, usize>,
+}
+
+impl Tenants {
+    pub fn new(config: &Config, system_prompt: String) -> Result<Self> {
+        let today = OffsetDateTime::now_utc().date();
+        let mut tenants = vec![Tenant {
+            id: DEFAULT_ORGANIZATION.to_string(),
+            name: String::new(),
+            system_prompt,
+            model: config.model.clone(),
+            sessions: SessionManager::new(config.sessions.clone()),
+            daily_tokens: None,
+            classrooms: Vec::new(),
+            usage: DailyUsage::none(today),
+            usage_file: None,
+        }];
+        let mut enrolled = HashMap::new();
+        for org in &config.organizations {
+            let system_prompt = fs::read_to_string(&org.system_prompt).with_context(|| {
+                format!(
+                    "failed to read the system prompt of {}: {}",
+                    org.id,
+                    org.system_prompt.display()
+                )
+            })?;
+            let usage = match &org.usage_file {
+                Some(path) => DailyUsage::load(path, today)?,
+                None => DailyUsage::none(today),
+            };
+            for student in org.classrooms.iter().flat_map(|c| &c.students) {
+                enrolled.insert(student.clone(), tenants.len());
+            }
+            tenants.push(Tenant {
+                id: org.id.clone(),
+                name: org.name.clone(),
+                system_prompt,
+                model: org.model.clone(),
+                sessions: SessionManager::new(org.sessions.clone()),
+                daily_tokens: org.daily_tokens,
+                classrooms: org.classrooms.clone(),
+                usage,
+                usage_file: org.usage_file.clone(),
+            });
+        }
+        Ok(Self { tenants, enrolled })
+    }
+
+    fn index(&self, student_id: &str) -> usize {
+        self.enrolled.get(student_id).copied().unwrap_or(0)
+    }
+
+    /// The organization a student belongs to.
+    pub fn of(&self, student_id: &str) -> &Tenant {
+        &self.tenants[self.index(student_id)]
+    }
+
+    /// The student as resolved to their organization, for store lookups.
+    pub fn owner(&self, student_id: &str) -> Owner {
+        Owner {
+            organization: self.of(student_id).id.clone(),
+            student_id: student_id.to_string(),
+        }
+    }
+
+    /// The organization with the given id.
+    pub fn get(&self, id: &str) -> Option<&Tenant> {
+        self.tenants.iter().find(|tenant| tenant.id == id)
+    }
+
+    /// The classroom a student is enrolled in, outside the default
+    /// organization.
+    pub fn classroom(&self, student_id: &str) -> Option<&ClassroomConfig> {
+        self.of(student_id)
+            .classrooms
+            .iter()
+            .find(|classroom| classroom.students.iter().any(|s| s == student_id))
+    }
+
+    pub fn sessions(&self, student_id: &str) -> &SessionManager {
+        &self.of(student_id).sessions
+    }
+
+    pub fn sessions_mut(&mut self, student_id: &str) -> &mut SessionManager {
+        let index = self.index(student_id);
+        &mut self.tenants[index].sessions
+    }
+
+    /// Refuse course materials that belong to another organization. In an
+    /// organization, courses are its classrooms.
+    pub fn check_scope(&self, student_id: &str, scope: &Scope) -> Result<(), String> {
+        self.check_tenant_scope(self.index(student_id), scope)
+    }
+
+    /// `check_scope` for a teacher of `organization`; `None` is the default
+    /// organization.
+    pub fn check_organization_scope(
+        &self,
+        organization: Option<&str>,
+        scope: &Scope,
+    ) -> Result<(), String> {
+        let index = organization
+            .and_then(|id| self.tenants.iter().position(|tenant| tenant.id == id))
+            .unwrap_or(0);
+        self.check_tenant_scope(index, scope)
+    }
+
+    fn check_tenant_scope(&self, index: usize, scope: &Scope) -> Result<(), String> {
+        let tenant = &self.tenants[index];
+        let allowed = match scope {
+            Scope::Organization(id) => {
+                if tenant.id == DEFAULT_ORGANIZATION {
+                    !self.tenants.iter().any(|other| &other.id == id)
+                } else {
+                    &tenant.id == id
+                }
+            }
+            Scope::Course(id) => {
+                let owner = self
+                    .tenants
+                    .iter()
+                    .position(|other| other.classrooms.iter().any(|c| &c.id == id));
+                match owner {
+                    Some(owner) => owner == index,
+                    None => tenant.id == DEFAULT_ORGANIZATION,
+                }
+            }
+        };
+        if allowed {
+            Ok(())
+        } else {
+            Err(format!(
+                "course_id and organization_id must belong to organization {}",
+                tenant.id
+            ))
+        }
+    }
+
+    /// Fail when the student's organization has used its tokens for today.
+    pub fn check_quota(&self, student_id: &str, now: OffsetDateTime) -> Result<()> {
+        let tenant = self.of(student_id);
+        if let Some(limit) = tenant.daily_tokens
+            && tenant.usage.day == now.date()
+            && tenant.usage.tokens >= limit
+        {
+            return Err(ServiceError::OverQuota(format!(
+                "Organization {} has used its daily token quota; try again tomorrow",
+                tenant.id
+            ))
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Count tokens spent upstream against the daily quota, and save the
+    /// count of an organization that has one.
+    pub fn record_tokens(&mut self, student_id: &str, tokens: u32, now: OffsetDateTime) {
+        if tokens == 0 {
+            return;
+        }
+        let index = self.index(student_id);
+        let tenant = &mut self.tenants[index];
+        if tenant.usage.day != now.date() {
+            tenant.usage = DailyUsage::none(now.date());
+        }
+        tenant.usage.tokens += u64::from(tokens);
+        if let Some(path) = &tenant.usage_file
+            && let Err(err) = atomic_write_json(path, &tenant.usage)
+        {
+            // The count in memory still holds until the next restart.
+            eprintln!("Failed to save token usage of {}: {:#}", tenant.id, err);
+        }
+    }
+
+    /// Remove idle sessions in every organization.
+    pub fn sweep(&mut self, now: OffsetDateTime) -> SweepStats {
+        let mut total = SweepStats::default();
+        for tenant in &mut self.tenants {
+            let stats = tenant.sessions.sweep(now);
+            total.expired += stats.expired;
+            total.expired_on_disk += stats.expired_on_disk;
+        }
+        total
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::OrganizationConfig;
+    use crate::testing::TempDir;
+    use time::Duration;
+
+    fn tenants() -> Tenants {
+        tenants_with_usage_file(None)
+    }
+
+    fn tenants_with_usage_file(usage_file: Option<PathBuf>) -> Tenants {
+        let mut config = Config::for_tests();
+        config.organizations = vec![OrganizationConfig {
+            id: "northside".to_string(),
+            name: "Northside High".to_string(),
+            system_prompt: config.paths.system_prompt.clone(),
+            model: config.model.clone(),
+            sessions: config.sessions.clone(),
+            daily_tokens: Some(100),
+            usage_file,
+            classrooms: vec![ClassroomConfig {
+                id: "9a".to_string(),
+                name: "Year 9A".to_string(),
+                students: vec!["ana".to_string()],
+            }],
+        }];
+        Tenants::new(&config, "prompt".to_string()).unwrap()
+    }
+
+    #[test]
+    fn keeps_materials_within_the_organization() {
+        let tenants = tenants();
+        assert_eq!(tenants.of("ana").id, "northside");
+        assert_eq!(tenants.of("bo").id, DEFAULT_ORGANIZATION);
+        assert_eq!(
+            tenants.classroom("ana").map(|c| c.name.as_str()),
+            Some("Year 9A")
+        );
+        assert!(tenants.classroom("bo").is_none());
+
+        let course = |id: &str| Scope::Course(id.to_string());
+        let org = |id: &str| Scope::Organization(id.to_string());
+        assert!(tenants.check_scope("ana", &course("9a")).is_ok());
+        assert!(tenants.check_scope("ana", &org("northside")).is_ok());
+        assert!(
+            tenants
+                .check_scope("ana", &course("chemistry-101"))
+                .is_err()
+        );
+        assert!(tenants.check_scope("bo", &course("9a")).is_err());
+        assert!(tenants.check_scope("bo", &org("northside")).is_err());
+        assert!(tenants.check_scope("bo", &course("chemistry-101")).is_ok());
+
+        let teacher = |org, scope| tenants.check_organization_scope(org, &scope);
+        assert!(teacher(Some("northside"), course("9a")).is_ok());
+        assert!(teacher(Some("northside"), org("default")).is_err());
+        assert!(teacher(None, course("9a")).is_err());
+        assert!(teacher(None, course("chemistry-101")).is_ok());
+    }
+
+    #[test]
+    fn enforces_the_daily_token_quota() {
+        let mut tenants = tenants();
+        let now = OffsetDateTime::UNIX_EPOCH;
+        tenants.record_tokens("ana", 100, now);
+        tenants.record_tokens("bo", 1000, now);
+        assert!(tenants.check_quota("ana", now).is_err());
+        assert!(tenants.check_quota("bo", now).is_ok());
+        assert!(tenants.check_quota("ana", now + Duration::days(1)).is_ok());
+    }
+
+    #[test]
+    fn keeps_the_day_s_usage_across_restarts() {
+        let dir = TempDir::new("usage");
+        let path = dir.path().join("northside").join("usage.json");
+        let now = OffsetDateTime::now_utc();
+        let mut tenants = tenants_with_usage_file(Some(path.clone()));
+        tenants.record_tokens("ana", 60, now);
+        tenants.record_tokens("ana", 40, now);
+
+        let tenants = tenants_with_usage_file(Some(path.clone()));
+        assert!(tenants.check_quota("ana", now).is_err());
+        assert!(tenants.check_quota("ana", now + Duration::days(1)).is_ok());
+
+        fs::write(&path, "not json").unwrap();
+        let tenants = tenants_with_usage_file(Some(path));
+        assert!(tenants.check_quota("ana", now).is_ok());
+    }
+}
@@ -0,0 +1,179 @@
+//! Fixtures shared by the unit tests of several modules.
+
+use async_openai::{Client, config::OpenAIConfig};
+use axum::Router;
+use serde_json::Value;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex as StdMutex};
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use crate::tenancy::Owner;
+use crate::{app, config::Config, controller::TutorController};
+
+/// The full router over `Config::for_tests`, which never reaches the
+/// upstream API, with its logs and stores in the returned directory.
+pub fn test_app() -> (Router, TempDir) {
+    let dir = TempDir::new("app");
+    let config = Config::for_tests_in(dir.path());
+    let controller = Arc::new(Mutex::new(TutorController::new(&config).unwrap()));
+    (app(controller, &config), dir)
+}
+
+/// A fresh directory under the system temp dir, removed again on drop so
+/// that failing tests clean up too.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(prefix: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("tutor-{prefix}-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// `student_id` as a student of `organization`.
+pub fn owner(organization: &str, student_id: &str) -> Owner {
+    Owner {
+        organization: organization.to_string(),
+        student_id: student_id.to_string(),
+    }
+}
+
+/// An upstream API on a local port that answers every request through
+/// `respond` and keeps the requests it was sent.
+pub struct FakeUpstream {
+    base_url: String,
+    requests: Arc<StdMutex<Vec<String>>>,
+}
+
+impl FakeUpstream {
+    /// `respond` gets the number of the request, from 0, and returns the
+    /// status and JSON body to answer it with.
+    pub fn start(respond: impl Fn(usize) -> (u16, Value) + Send + 'static) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}/v1", listener.local_addr().unwrap());
+        let requests = Arc::new(StdMutex::new(Vec::new()));
+        let seen = Arc::clone(&requests);
+        std::thread::spawn(move || {
+            for socket in listener.incoming() {
+                let mut socket = socket.unwrap();
+                let request = read_request(&mut socket);
+                let index = {
+                    let mut seen = seen.lock().unwrap();
+                    seen.push(request);
+                    seen.len() - 1
+                };
+                let (status, body) = respond(index);
+                let body = body.to_string();
+                let response = format!(
+                    "HTTP/1.1 {status} Fake\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                // The client may have stopped waiting, as a cancelled turn does.
+                let _ = socket.write_all(response.as_bytes());
+            }
+        });
+        Self { base_url, requests }
+    }
+
+    /// Base URL of the fake API, `/v1` included.
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// A client for the fake API.
+    pub fn client(&self) -> Client<OpenAIConfig> {
+        Client::with_config(
+            OpenAIConfig::new()
+                .with_api_base(&self.base_url)
+                .with_api_key("k"),
+        )
+    }
+
+    /// Every request received so far, head and body, as text.
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+/// Read one request: the head, then as much body as it announces.
+fn read_request(socket: &mut TcpStream) -> String {
+    let mut request = Vec::new();
+    let mut buf = [0; 4096];
+    loop {
+        let n = socket.read(&mut buf).unwrap();
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+        let text = String::from_utf8_lossy(&request);
+        let Some(end) = text.find("\r\n\r\n") else {
+            continue;
+        };
+        let length = text[..end]
+            .lines()
+            .find_map(|line| {
+                line.to_ascii_lowercase()
+                    .strip_prefix("content-length:")
+                    .map(|v| v.trim().parse::<usize>().unwrap())
+            })
+            .unwrap_or(0);
+        if request.len() >= end + 4 + length {
+            break;
+        }
+    }
+    String::from_utf8_lossy(&request).into_owned()
+}
+
+/// A moderation body for one input that flags nothing.
+pub fn unflagged_moderation() -> Value {
+    let names = [
+        "hate",
+        "hate/threatening",
+        "harassment",
+        "harassment/threatening",
+        "illicit",
+        "illicit/violent",
+        "self-harm",
+        "self-harm/intent",
+        "self-harm/instructions",
+        "sexual",
+        "sexual/minors",
+        "violence",
+        "violence/graphic",
+    ];
+    let each = |value: Value| -> serde_json::Map<String, Value> {
+        names
+            .iter()
+            .map(|name| (name.to_string(), value.clone()))
+            .collect()
+    };
+    serde_json::json!({"id": "m", "model": "omni-moderation-latest", "results": [{
+        "flagged": false,
+        "categories": each(false.into()),
+        "category_scores": each(0.0.into()),
+        "category_applied_input_types": each(serde_json::json!([])),
+    }]})
+}
+
+/// A chat completion body with one choice holding `message`.
+pub fn completion(finish_reason: &str, message: Value) -> Value {
+    serde_json::json!({
+        "id": "c", "object": "chat.completion", "created": 0, "model": "m",
+        "choices": [{"index": 0, "finish_reason": finish_reason, "message": message}],
+        "usage": {"prompt_tokens": 5, "completion_tokens": 1, "total_tokens": 6}
+    })
+}
@@ -0,0 +1,190 @@
+use anyhow::Result;
+use async_openai::types::{ChatCompletionTool, ChatCompletionToolArgs, FunctionObjectArgs};
+use serde::Serialize;
+use serde_json::{Value, json};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use utoipa::ToSchema;
+
+use crate::config::ToolsConfig;
+
+mod calculator;
+#[cfg(target_os = "linux")]
+mod rust_runner;
+mod units;
+
+/// A function the model may call during a turn. Tools run on a blocking
+/// thread, so `call` may take its time.
+pub trait Tool: Send + Sync {
+    /// Name the model calls the tool by.
+    fn name(&self) -> &'static str;
+    fn description(&self) -> &'static str;
+    /// JSON Schema of the arguments object.
+    fn parameters(&self) -> Value;
+    /// `cancelled` is set when the turn is cancelled; nobody waits for the
+    /// result any more, so slow tools should give up when they see it.
+    fn call(&self, arguments: Value, cancelled: &AtomicBool) -> Result<Value>;
+}
+
+/// Sets the flag when dropped, which is how a cancelled turn reaches a
+/// tool still running on its blocking thread.
+struct CancelOnDrop(Arc<AtomicBool>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// One tool call made during a turn, as shown to the student.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ToolUse {
+    pub name: String,
+    /// Arguments as the model sent them, a JSON object in a string.
+    pub arguments: String,
+    /// What the tool returned to the model, also JSON.
+    pub output: String,
+}
+
+/// The tools offered to the model, and the limit on how many rounds of
+/// calls one turn may make.
+pub struct ToolRegistry {
+    tools: Vec<Arc<dyn Tool>>,
+    pub max_iterations: usize,
+}
+
+impl ToolRegistry {
+    pub fn new(config: &ToolsConfig) -> Self {
+        #[allow(unused_mut)]
+        let mut tools: Vec<Arc<dyn Tool>> = vec![
+            Arc::new(calculator::Calculator),
+            Arc::new(units::UnitConverter),
+        ];
+        #[cfg(target_os = "linux")]
+        if config.rust.enabled {
+            match rust_runner::RustRunner::new(&config.rust) {
+                Some(runner) => tools.push(Arc::new(runner)),
+                None => eprintln!(
+                    "Rust runner disabled: {} --print sysroot failed",
+                    config.rust.rustc.display()
+                ),
+            }
+        }
+        Self {
+            tools,
+            max_iterations: config.max_iterations,
+        }
+    }
+
+    pub fn has(&self, name: &str) -> bool {
+        self.tools.iter().any(|tool| tool.name() == name)
+    }
+
+    /// The same registry offering only the named tools.
+    pub fn restricted(&self, names: &[String]) -> Self {
+        Self {
+            tools: self
+                .tools
+                .iter()
+                .filter(|tool| names.iter().any(|name| name == tool.name()))
+                .cloned()
+                .collect(),
+            max_iterations: self.max_iterations,
+        }
+    }
+
+    pub fn definitions(&self) -> Result<Vec<ChatCompletionTool>> {
+        self.tools
+            .iter()
+            .map(|tool| {
+                Ok(ChatCompletionToolArgs::default()
+                    .function(
+                        FunctionObjectArgs::default()
+                            .name(tool.name())
+                            .description(tool.description())
+                            .parameters(tool.parameters())
+                            .build()?,
+                    )
+                    .build()?)
+            })
+            .collect()
+    }
+
+    /// Run a call from the model. Failures are returned to the model as an
+    /// `error` object so that it can correct its arguments.
+    pub async fn call(&self, name: &str, arguments: &str) -> String {
+        let Some(tool) = self.tools.iter().find(|tool| tool.name() == name) else {
+            return json!({ "error": format!("unknown tool `{}`", name) }).to_string();
+        };
+        let arguments = match serde_json::from_str(arguments) {
+            Ok(arguments) => arguments,
+            Err(err) => {
+                return json!({ "error": format!("arguments are not valid JSON: {}", err) })
+                    .to_string();
+            }
+        };
+        let tool = Arc::clone(tool);
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let _cancel_on_drop = CancelOnDrop(Arc::clone(&cancelled));
+        let output = tokio::task::spawn_blocking(move || tool.call(arguments, &cancelled))
+            .await
+            .unwrap_or_else(|err| Err(anyhow::anyhow!("tool panicked: {}", err)));
+        match output {
+            Ok(value) => value.to_string(),
+            Err(err) => json!({ "error": format!("{:#}", err) }).to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// Waits until it is cancelled.
+    struct Patient(Arc<AtomicBool>);
+
+    impl Tool for Patient {
+        fn name(&self) -> &'static str {
+            "patient"
+        }
+
+        fn description(&self) -> &'static str {
+            "Waits"
+        }
+
+        fn parameters(&self) -> Value {
+            json!({"type": "object"})
+        }
+
+        fn call(&self, _arguments: Value, cancelled: &AtomicBool) -> Result<Value> {
+            while !cancelled.load(Ordering::Relaxed) {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            self.0.store(true, Ordering::Relaxed);
+            Ok(json!({}))
+        }
+    }
+
+    #[tokio::test]
+    async fn dropping_a_call_cancels_the_tool() {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let registry = ToolRegistry {
+            tools: vec![Arc::new(Patient(Arc::clone(&stopped)))],
+            max_iterations: 1,
+        };
+        let call = registry.call("patient", "{}");
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), call)
+                .await
+                .is_err()
+        );
+        for _ in 0..100 {
+            if stopped.load(Ordering::Relaxed) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("the tool kept running");
+    }
+}
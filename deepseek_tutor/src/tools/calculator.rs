@@ -0,0 +1,398 @@
+use anyhow::{Result, anyhow, bail};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::fmt;
+use std::sync::atomic::AtomicBool;
+
+use super::Tool;
+
+/// Fractional digits shown for a decimal that does not terminate.
+const DECIMAL_DIGITS: usize = 20;
+/// Longest expression accepted, in characters.
+const MAX_EXPRESSION_CHARS: usize = 1000;
+/// Deepest nesting of parentheses, signs and exponents. The parser recurses
+/// once per level, and a blocking thread's stack cannot take many thousand.
+const MAX_DEPTH: usize = 256;
+
+/// An exact fraction, always in lowest terms with a positive denominator.
+/// Arithmetic fails rather than overflowing or rounding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rational {
+    num: i128,
+    den: i128,
+}
+
+impl Rational {
+    pub fn new(num: i128, den: i128) -> Result<Self> {
+        if den == 0 {
+            bail!("division by zero");
+        }
+        let divisor = gcd(num, den).max(1);
+        let (num, den) = (num / divisor, den / divisor);
+        if den < 0 {
+            return Ok(Self {
+                num: overflow(num.checked_neg())?,
+                den: overflow(den.checked_neg())?,
+            });
+        }
+        Ok(Self { num, den })
+    }
+
+    pub fn integer(value: i128) -> Self {
+        Self { num: value, den: 1 }
+    }
+
+    pub fn checked_add(self, other: Self) -> Result<Self> {
+        let num = self
+            .num
+            .checked_mul(other.den)
+            .zip(other.num.checked_mul(self.den))
+            .and_then(|(a, b)| a.checked_add(b));
+        Self::new(overflow(num)?, overflow(self.den.checked_mul(other.den))?)
+    }
+
+    pub fn checked_sub(self, other: Self) -> Result<Self> {
+        self.checked_add(Self {
+            num: overflow(other.num.checked_neg())?,
+            den: other.den,
+        })
+    }
+
+    pub fn checked_mul(self, other: Self) -> Result<Self> {
+        // Cross-reduce first so that products stay small.
+        let a = gcd(self.num, other.den).max(1);
+        let b = gcd(other.num, self.den).max(1);
+        Self::new(
+            overflow((self.num / a).checked_mul(other.num / b))?,
+            overflow((self.den / b).checked_mul(other.den / a))?,
+        )
+    }
+
+    pub fn checked_div(self, other: Self) -> Result<Self> {
+        if other.num == 0 {
+            bail!("division by zero");
+        }
+        self.checked_mul(Self::new(other.den, other.num)?)
+    }
+
+    pub fn checked_pow(self, exponent: Self) -> Result<Self> {
+        if exponent.den != 1 {
+            bail!("only whole-number exponents are exact");
+        }
+        let power = u32::try_from(exponent.num.unsigned_abs())
+            .map_err(|_| anyhow!("exponent too large"))?;
+        let base = if exponent.num < 0 {
+            Self::integer(1).checked_div(self)?
+        } else {
+            self
+        };
+        Self::new(
+            overflow(base.num.checked_pow(power))?,
+            overflow(base.den.checked_pow(power))?,
+        )
+    }
+
+    /// Decimal expansion, cut at `DECIMAL_DIGITS` places with a trailing
+    /// `…` when it does not terminate. The flag says whether it is exact.
+    pub fn to_decimal(self) -> (String, bool) {
+        let mut out = String::new();
+        if self.num < 0 {
+            out.push('-');
+        }
+        let num = self.num.unsigned_abs();
+        let den = self.den.unsigned_abs();
+        out.push_str(&(num / den).to_string());
+        let mut remainder = num % den;
+        if remainder == 0 {
+            return (out, true);
+        }
+        out.push('.');
+        for _ in 0..DECIMAL_DIGITS {
+            let Some(shifted) = remainder.checked_mul(10) else {
+                break;
+            };
+            remainder = shifted;
+            out.push(char::from(b'0' + (remainder / den) as u8));
+            remainder %= den;
+            if remainder == 0 {
+                return (out, true);
+            }
+        }
+        out.push('…');
+        (out, false)
+    }
+}
+
+impl fmt::Display for Rational {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.den == 1 {
+            write!(f, "{}", self.num)
+        } else {
+            write!(f, "{}/{}", self.num, self.den)
+        }
+    }
+}
+
+fn gcd(a: i128, b: i128) -> i128 {
+    let (mut a, mut b) = (a.unsigned_abs(), b.unsigned_abs());
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    // Both operands fit in i128, so their gcd does too.
+    a as i128
+}
+
+fn overflow(value: Option<i128>) -> Result<i128> {
+    value.ok_or_else(|| anyhow!("numbers too large for exact arithmetic"))
+}
+
+/// Evaluate an arithmetic expression exactly. Supports `+ - * / ^`,
+/// parentheses, unary minus, and decimal or scientific literals.
+pub fn evaluate(expression: &str) -> Result<Rational> {
+    if expression.chars().count() > MAX_EXPRESSION_CHARS {
+        bail!("expression longer than {} characters", MAX_EXPRESSION_CHARS);
+    }
+    let mut parser = Parser {
+        chars: expression.chars().filter(|c| !c.is_whitespace()).collect(),
+        pos: 0,
+        depth: 0,
+    };
+    let value = parser.expression()?;
+    if let Some(c) = parser.peek() {
+        bail!("unexpected `{}` at position {}", c, parser.pos + 1);
+    }
+    Ok(value)
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    /// Levels of `unary` being parsed; every recursion passes through it.
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, options: &[char]) -> Option<char> {
+        let c = self.peek().filter(|c| options.contains(c))?;
+        self.pos += 1;
+        Some(c)
+    }
+
+    fn expression(&mut self) -> Result<Rational> {
+        let mut value = self.term()?;
+        while let Some(op) = self.eat(&['+', '-', '−']) {
+            let rhs = self.term()?;
+            value = match op {
+                '+' => value.checked_add(rhs)?,
+                _ => value.checked_sub(rhs)?,
+            };
+        }
+        Ok(value)
+    }
+
+    fn term(&mut self) -> Result<Rational> {
+        let mut value = self.unary()?;
+        while let Some(op) = self.eat(&['*', '/', '×', '÷']) {
+            let rhs = self.unary()?;
+            value = match op {
+                '*' | '×' => value.checked_mul(rhs)?,
+                _ => value.checked_div(rhs)?,
+            };
+        }
+        Ok(value)
+    }
+
+    // Unary minus binds looser than `^`, so `-2^2` is -4.
+    fn unary(&mut self) -> Result<Rational> {
+        if self.depth == MAX_DEPTH {
+            bail!("expression nested more than {} levels deep", MAX_DEPTH);
+        }
+        self.depth += 1;
+        let value = match self.eat(&['-', '−', '+']) {
+            Some('+') => self.unary(),
+            Some(_) => self
+                .unary()
+                .and_then(|value| Rational::integer(0).checked_sub(value)),
+            None => self.power(),
+        };
+        self.depth -= 1;
+        value
+    }
+
+    fn power(&mut self) -> Result<Rational> {
+        let base = self.primary()?;
+        if self.eat(&['^']).is_some() {
+            let exponent = self.unary()?;
+            return base.checked_pow(exponent);
+        }
+        Ok(base)
+    }
+
+    fn primary(&mut self) -> Result<Rational> {
+        if self.eat(&['(']).is_some() {
+            let value = self.expression()?;
+            if self.eat(&[')']).is_none() {
+                bail!("missing `)`");
+            }
+            return Ok(value);
+        }
+        self.number()
+    }
+
+    fn number(&mut self) -> Result<Rational> {
+        let start = self.pos;
+        let digits = |parser: &mut Self| {
+            let from = parser.pos;
+            while parser.peek().is_some_and(|c| c.is_ascii_digit()) {
+                parser.pos += 1;
+            }
+            parser.chars[from..parser.pos].iter().collect::<String>()
+        };
+
+        let whole = digits(self);
+        let fraction = if self.eat(&['.']).is_some() {
+            digits(self)
+        } else {
+            String::new()
+        };
+        if whole.is_empty() && fraction.is_empty() {
+            return match self.peek() {
+                Some(c) => Err(anyhow!("unexpected `{}` at position {}", c, start + 1)),
+                None => Err(anyhow!("expression ends early")),
+            };
+        }
+        let mut exponent: i32 = 0;
+        if self.eat(&['e', 'E']).is_some() {
+            let negative = self.eat(&['-', '+']) == Some('-');
+            let text = digits(self);
+            exponent = text
+                .parse()
+                .map_err(|_| anyhow!("bad exponent at position {}", start + 1))?;
+            if negative {
+                exponent = -exponent;
+            }
+        }
+
+        let mantissa: i128 = format!("{whole}{fraction}")
+            .parse()
+            .map_err(|_| anyhow!("number too long at position {}", start + 1))?;
+        let scale = exponent - fraction.len() as i32;
+        let ten = Rational::integer(10).checked_pow(Rational::integer(scale.into()))?;
+        Rational::integer(mantissa).checked_mul(ten)
+    }
+}
+
+#[derive(Deserialize)]
+struct CalculatorArgs {
+    expression: String,
+}
+
+/// Exact arithmetic so that worked examples do not carry rounding or
+/// mental-arithmetic slips.
+pub struct Calculator;
+
+impl Tool for Calculator {
+    fn name(&self) -> &'static str {
+        "calculator"
+    }
+
+    fn description(&self) -> &'static str {
+        "Evaluate an arithmetic expression exactly with fractions. Supports + - * / ^ \
+         (whole-number exponents), parentheses, decimals and scientific notation such \
+         as 6.02e23. Use it for every calculation in an answer."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "expression": {
+                    "type": "string",
+                    "description": "For example (3/4 + 0.2) * 2^-3"
+                }
+            },
+            "required": ["expression"],
+            "additionalProperties": false
+        })
+    }
+
+    fn call(&self, arguments: Value, _cancelled: &AtomicBool) -> Result<Value> {
+        let args: CalculatorArgs = serde_json::from_value(arguments)?;
+        let value = evaluate(&args.expression)?;
+        let (decimal, exact) = value.to_decimal();
+        Ok(json!({
+            "expression": args.expression,
+            "fraction": value.to_string(),
+            "decimal": decimal,
+            "decimal_is_exact": exact,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(text: &str) -> String {
+        evaluate(text).unwrap().to_string()
+    }
+
+    #[test]
+    fn keeps_fractions_exact() {
+        assert_eq!(eval("1/3 + 1/6"), "1/2");
+        assert_eq!(eval("0.1 + 0.2"), "3/10");
+        assert_eq!(eval("(3/4 + 0.25) * 2^-3"), "1/8");
+        assert_eq!(eval("-2^2"), "-4");
+        assert_eq!(eval("2^3^2"), "512");
+        assert_eq!(eval("6.02e23 / 2"), "301000000000000000000000");
+        assert_eq!(eval("9.81 × 2 ÷ 4"), "981/200");
+    }
+
+    #[test]
+    fn shows_decimals() {
+        assert_eq!(
+            evaluate("1/8").unwrap().to_decimal(),
+            ("0.125".to_string(), true)
+        );
+        assert_eq!(
+            evaluate("-2/3").unwrap().to_decimal(),
+            ("-0.66666666666666666666…".to_string(), false)
+        );
+    }
+
+    #[test]
+    fn reports_errors() {
+        for bad in [
+            "1/0",
+            "2^(1/2)",
+            "(1+2",
+            "3 +",
+            "4 $ 2",
+            "10^100",
+            "1/(-170141183460469231731687303715884105727-1)",
+        ] {
+            assert!(evaluate(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn refuses_deep_nesting() {
+        let nested = |depth: usize| format!("{}1{}", "(".repeat(depth), ")".repeat(depth));
+        assert_eq!(eval(&nested(MAX_DEPTH - 1)), "1");
+        let err = evaluate(&nested(300)).unwrap_err();
+        assert!(err.to_string().contains("levels deep"), "{err}");
+        let err = evaluate(&format!("{}1", "-".repeat(300))).unwrap_err();
+        assert!(err.to_string().contains("levels deep"), "{err}");
+        assert_eq!(eval(&format!("{}1", "-".repeat(200))), "1");
+
+        // Too long to parse at all, let alone to recurse into.
+        let err = evaluate(&nested(5000)).unwrap_err();
+        assert!(err.to_string().contains("longer than"), "{err}");
+        let err = evaluate(&format!("{}1", "-".repeat(5000))).unwrap_err();
+        assert!(err.to_string().contains("longer than"), "{err}");
+    }
+}
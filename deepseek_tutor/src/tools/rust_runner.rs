@@ -0,0 +1,818 @@
+use anyhow::{Context, Result, bail};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::ffi::CString;
+use std::io::{self, Read};
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use super::Tool;
+use crate::config::RustRunnerConfig;
+
+/// Longest snippet accepted, in bytes.
+const MAX_SOURCE_BYTES: usize = 64 * 1024;
+
+/// The program runs as this user inside its own user namespace.
+const SANDBOX_ID: u32 = 65534;
+
+/// Processes and threads the compiler, with the linker it starts, and the
+/// program may have at once.
+const COMPILE_PROCESSES: u64 = 128;
+const RUN_PROCESSES: u64 = 16;
+
+/// What the compiler and linker need from the host, bound read-only into the
+/// compile sandbox next to the toolchain. `include!`, `#[path]` and the like
+/// can read these and the snippet's own directory, nothing else. Symlinks,
+/// such as `/bin` on a merged `/usr`, are recreated rather than bound.
+const SYSTEM_PATHS: &[&str] = &[
+    "/usr",
+    "/bin",
+    "/lib",
+    "/lib64",
+    "/etc/ld.so.cache",
+    "/etc/alternatives",
+    "/dev/null",
+];
+
+#[derive(Deserialize)]
+struct RunArgs {
+    code: String,
+}
+
+/// Compiles a snippet with the local toolchain and runs it with no network,
+/// limited memory, CPU and time, and only its own temporary directory as
+/// the filesystem.
+pub struct RustRunner {
+    config: RustRunnerConfig,
+    /// The toolchain `rustc` resolves to, mounted at the same path in the
+    /// sandbox.
+    sysroot: PathBuf,
+    /// One snippet at a time, so that a busy class cannot exhaust the host.
+    running: Mutex<()>,
+}
+
+impl RustRunner {
+    /// `None` when the configured compiler does not run.
+    pub fn new(config: &RustRunnerConfig) -> Option<Self> {
+        let output = Command::new(&config.rustc)
+            .args(["--print", "sysroot"])
+            .stdin(Stdio::null())
+            .output()
+            .ok()
+            .filter(|output| output.status.success())?;
+        let sysroot = String::from_utf8(output.stdout).ok()?;
+        Some(Self {
+            config: config.clone(),
+            sysroot: PathBuf::from(sysroot.trim()),
+            running: Mutex::new(()),
+        })
+    }
+
+    /// The compiler runs in the same sandbox as the program, so the snippet
+    /// cannot read the server's files or environment at compile time.
+    fn compile(&self, dir: &Path, cancelled: &AtomicBool) -> Result<(bool, String)> {
+        std::fs::create_dir(dir.join("tmp"))?;
+        let mut binds: Vec<PathBuf> = SYSTEM_PATHS.iter().map(PathBuf::from).collect();
+        binds.push(self.sysroot.clone());
+        let sandbox = Sandbox::new(dir, &binds)?;
+
+        let mut command = Command::new(self.sysroot.join("bin/rustc"));
+        command
+            .arg("--sysroot")
+            .arg(&self.sysroot)
+            .args(["--edition", &self.config.edition])
+            .args(["--color", "never", "--crate-name", "main"])
+            .args(["-C", "target-feature=+crt-static", "-o", "main", "main.rs"])
+            // The bundled lld's wrapper needs /proc; link with the system's.
+            .args(["-C", "linker-features=-lld"])
+            .env_clear()
+            .env("PATH", "/usr/bin:/bin")
+            .env("TMPDIR", "/tmp")
+            // There is no /proc to resolve rustc's `$ORIGIN` rpath from.
+            .env("LD_LIBRARY_PATH", self.sysroot.join("lib"));
+        let limits = Limits {
+            memory: self.config.compile_memory_bytes,
+            cpu_secs: self.config.compile_timeout.as_secs(),
+            processes: COMPILE_PROCESSES,
+        };
+        // SAFETY: the hook only makes async-signal-safe system calls on
+        // memory prepared before the fork.
+        unsafe {
+            command.pre_exec(move || {
+                limits.apply()?;
+                sandbox.enter(limits.processes)
+            });
+        }
+        let run = supervise(
+            command,
+            self.config.compile_timeout,
+            self.config.max_output_bytes,
+            cancelled,
+        )
+        .context("could not start the compiler")?;
+        if run.timed_out {
+            bail!(
+                "compilation took longer than {} seconds",
+                self.config.compile_timeout.as_secs()
+            );
+        }
+        Ok((run.status.success(), run.stderr.text))
+    }
+
+    fn execute(&self, dir: &Path, cancelled: &AtomicBool) -> Result<Supervised> {
+        let sandbox = Sandbox::new(dir, &[])?.without_root();
+        let limits = Limits {
+            memory: self.config.run_memory_bytes,
+            cpu_secs: self.config.run_timeout.as_secs(),
+            processes: RUN_PROCESSES,
+        };
+        let mut command = Command::new("/main");
+        command.env_clear().env("PATH", "/");
+        // SAFETY: the hook only makes async-signal-safe system calls on
+        // memory prepared before the fork.
+        unsafe {
+            command.pre_exec(move || {
+                limits.apply()?;
+                sandbox.enter(limits.processes)
+            });
+        }
+        supervise(
+            command,
+            self.config.run_timeout,
+            self.config.max_output_bytes,
+            cancelled,
+        )
+        .context("could not start the sandbox")
+    }
+}
+
+impl Tool for RustRunner {
+    fn name(&self) -> &'static str {
+        "run_rust"
+    }
+
+    fn description(&self) -> &'static str {
+        "Compile and run a self-contained Rust program (a single file with `fn main`, \
+         standard library only) and return the compiler diagnostics, exit status, stdout \
+         and stderr. The program has no network, no files outside its working directory, \
+         no stdin, and a few seconds to finish. Use it to check code before showing it and \
+         to run the student's code."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "code": {
+                    "type": "string",
+                    "description": "Complete contents of main.rs"
+                }
+            },
+            "required": ["code"],
+            "additionalProperties": false
+        })
+    }
+
+    fn call(&self, arguments: Value, cancelled: &AtomicBool) -> Result<Value> {
+        let args: RunArgs = serde_json::from_value(arguments)?;
+        if args.code.len() > MAX_SOURCE_BYTES {
+            bail!("code is longer than {} bytes", MAX_SOURCE_BYTES);
+        }
+
+        let _running = self.running.lock().unwrap_or_else(|err| err.into_inner());
+        // Compiling or running is killed when the turn is cancelled, and
+        // what it produced by then is not reported.
+        let check_cancelled = || {
+            if cancelled.load(Ordering::Relaxed) {
+                bail!("the turn was cancelled");
+            }
+            Ok(())
+        };
+        check_cancelled()?;
+        let dir = TempDir::new()?;
+        std::fs::write(dir.0.join("main.rs"), &args.code)?;
+
+        let (compiled, diagnostics) = self.compile(&dir.0, cancelled)?;
+        check_cancelled()?;
+        if !compiled {
+            return Ok(json!({ "compiled": false, "diagnostics": diagnostics }));
+        }
+        let run = self.execute(&dir.0, cancelled)?;
+        check_cancelled()?;
+        Ok(json!({
+            "compiled": true,
+            "diagnostics": diagnostics,
+            "exit_code": run.status.code(),
+            "signal": run.status.signal(),
+            "timed_out": run.timed_out,
+            "stdout": run.stdout.text,
+            "stderr": run.stderr.text,
+            "truncated": run.stdout.truncated || run.stderr.truncated,
+        }))
+    }
+}
+
+/// A scratch directory for one run, removed when dropped.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new() -> Result<Self> {
+        let path = std::env::temp_dir().join(format!("tutor-rust-{}", Uuid::new_v4()));
+        std::fs::create_dir(&path)
+            .with_context(|| format!("could not create {}", path.display()))?;
+        Ok(Self(path))
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Limits {
+    memory: u64,
+    cpu_secs: u64,
+    /// Applied by `Sandbox::enter`, inside the new user namespace.
+    processes: u64,
+}
+
+impl Limits {
+    /// Runs in the child between fork and exec. A new session puts the
+    /// child and anything it spawns in one process group, killed together.
+    fn apply(self) -> io::Result<()> {
+        // SAFETY: plain system calls on values owned by this frame.
+        unsafe {
+            check(libc::setsid())?;
+            set_limit(libc::RLIMIT_AS, self.memory)?;
+            set_limit(libc::RLIMIT_CPU, self.cpu_secs + 1)?;
+            set_limit(libc::RLIMIT_FSIZE, 16 * 1024 * 1024)?;
+            set_limit(libc::RLIMIT_CORE, 0)?;
+        }
+        Ok(())
+    }
+}
+
+unsafe fn set_limit(resource: libc::__rlimit_resource_t, value: u64) -> io::Result<()> {
+    let limit = libc::rlimit {
+        rlim_cur: value,
+        rlim_max: value,
+    };
+    // SAFETY: `limit` outlives the call.
+    check(unsafe { libc::setrlimit(resource, &limit) })
+}
+
+fn check(result: libc::c_int) -> io::Result<()> {
+    if result == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Everything the child needs to isolate itself, allocated before the fork
+/// because allocating after it is not safe.
+struct Sandbox {
+    root: CString,
+    /// Become `SANDBOX_ID` on the host before entering.
+    switch_user: bool,
+    setgroups: CString,
+    uid_map_path: CString,
+    gid_map_path: CString,
+    uid_map: Vec<u8>,
+    gid_map: Vec<u8>,
+    binds: Vec<Bind>,
+}
+
+/// A host path bound read-only at the same path under the sandbox root.
+struct Bind {
+    source: CString,
+    target: CString,
+    /// Flags the source is mounted with. An unprivileged remount has to keep
+    /// them.
+    flags: libc::c_ulong,
+}
+
+impl Sandbox {
+    /// Creates the mount points for `binds` under `root`. Paths the host
+    /// does not have are skipped.
+    fn new(root: &Path, binds: &[PathBuf]) -> Result<Self> {
+        let cstring = |bytes: &[u8]| CString::new(bytes).context("path contains a NUL byte");
+        // SAFETY: these calls cannot fail.
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        let mut mounts = Vec::new();
+        for source in binds {
+            let Ok(metadata) = std::fs::symlink_metadata(source) else {
+                continue;
+            };
+            let target = root.join(source.strip_prefix("/").unwrap_or(source));
+            if let Some(parent) = target.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            if metadata.is_symlink() {
+                std::os::unix::fs::symlink(std::fs::read_link(source)?, &target)?;
+                continue;
+            }
+            if metadata.is_dir() {
+                std::fs::create_dir_all(&target)?;
+            } else {
+                std::fs::File::create(&target)?;
+            }
+            let source = cstring(source.as_os_str().as_bytes())?;
+            mounts.push(Bind {
+                flags: mount_flags(&source)
+                    .with_context(|| format!("could not stat {}", source.to_string_lossy()))?,
+                source,
+                target: cstring(target.as_os_str().as_bytes())?,
+            });
+        }
+        Ok(Self {
+            root: cstring(root.as_os_str().as_bytes())?,
+            switch_user: false,
+            setgroups: cstring(b"/proc/self/setgroups")?,
+            uid_map_path: cstring(b"/proc/self/uid_map")?,
+            gid_map_path: cstring(b"/proc/self/gid_map")?,
+            uid_map: format!("{} {} 1", SANDBOX_ID, uid).into_bytes(),
+            gid_map: format!("{} {} 1", SANDBOX_ID, gid).into_bytes(),
+            binds: mounts,
+        })
+    }
+
+    /// Also leave root on the host when the server runs as root, because
+    /// the kernel does not hold root to `RLIMIT_NPROC`. Only for the
+    /// program: the compiler has to reach a toolchain that may be in root's
+    /// home directory.
+    fn without_root(mut self) -> Self {
+        // SAFETY: this call cannot fail.
+        if unsafe { libc::geteuid() } == 0 {
+            self.switch_user = true;
+            self.uid_map = format!("{SANDBOX_ID} {SANDBOX_ID} 1").into_bytes();
+            self.gid_map = self.uid_map.clone();
+        }
+        self
+    }
+
+    /// A new user namespace gives the child the right to mount and chroot,
+    /// a new mount namespace keeps its mounts from the host, and a new
+    /// network namespace has no interfaces. Inside it the child is not
+    /// root, so it loses those rights again at exec.
+    ///
+    /// The program itself runs as PID 1 of a new PID namespace, so that
+    /// when it ends the kernel kills everything it started, including
+    /// processes that left its process group. The child that called
+    /// `spawn` stays outside, waits for it and exits the same way.
+    ///
+    /// `RLIMIT_NPROC` counts the processes of the namespace's user, so it is
+    /// set only inside: set before, it would count every process the
+    /// server's user has.
+    fn enter(&self, processes: u64) -> io::Result<()> {
+        let null = std::ptr::null();
+        // SAFETY: async-signal-safe system calls on memory owned by `self`.
+        unsafe {
+            if self.switch_user {
+                check(libc::setgroups(0, std::ptr::null()))?;
+                check(libc::setgid(SANDBOX_ID))?;
+                check(libc::setuid(SANDBOX_ID))?;
+                // Changing user made /proc/self root's; the maps below are
+                // written through it.
+                check(libc::prctl(libc::PR_SET_DUMPABLE, 1, 0, 0, 0))?;
+            }
+            check(libc::unshare(
+                libc::CLONE_NEWUSER | libc::CLONE_NEWNS | libc::CLONE_NEWNET | libc::CLONE_NEWPID,
+            ))?;
+            write_file(&self.setgroups, b"deny")?;
+            write_file(&self.uid_map_path, &self.uid_map)?;
+            write_file(&self.gid_map_path, &self.gid_map)?;
+            set_limit(libc::RLIMIT_NPROC, processes)?;
+            check(libc::mount(
+                null,
+                c"/".as_ptr(),
+                null,
+                libc::MS_REC | libc::MS_PRIVATE,
+                null.cast(),
+            ))?;
+            for bind in &self.binds {
+                check(libc::mount(
+                    bind.source.as_ptr(),
+                    bind.target.as_ptr(),
+                    null,
+                    libc::MS_BIND | libc::MS_REC,
+                    null.cast(),
+                ))?;
+                check(libc::mount(
+                    null,
+                    bind.target.as_ptr(),
+                    null,
+                    libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY | bind.flags,
+                    null.cast(),
+                ))?;
+            }
+            check(libc::chroot(self.root.as_ptr()))?;
+            check(libc::chdir(c"/".as_ptr()))?;
+
+            let pid = libc::fork();
+            check(pid)?;
+            if pid > 0 {
+                wait_and_mirror(pid);
+            }
+            // Killing the child, as the supervisor does at the deadline,
+            // takes the program with it even if it left the process group.
+            check(libc::prctl(
+                libc::PR_SET_PDEATHSIG,
+                libc::SIGKILL as libc::c_ulong,
+                0,
+                0,
+                0,
+            ))?;
+            check(libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0))?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs in the child that stayed outside the PID namespace: wait for the
+/// program and end with its exit code or signal, which the supervisor then
+/// reports as the program's.
+///
+/// # Safety
+///
+/// Only to be called between fork and exec; it never returns.
+unsafe fn wait_and_mirror(pid: libc::pid_t) -> ! {
+    let mut status = 0;
+    // SAFETY: async-signal-safe system calls; `status` outlives them.
+    unsafe {
+        // `spawn` returns once every copy of its exec-status pipe is closed.
+        // Only the program's copy should count, and it closes on exec.
+        libc::close_range(3, libc::c_uint::MAX, 0);
+        while libc::waitpid(pid, &mut status, 0) == -1 {
+            if io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+                libc::_exit(127);
+            }
+        }
+        if libc::WIFSIGNALED(status) {
+            let signal = libc::WTERMSIG(status);
+            libc::signal(signal, libc::SIG_DFL);
+            libc::kill(libc::getpid(), signal);
+        }
+        libc::_exit(libc::WEXITSTATUS(status))
+    }
+}
+
+fn mount_flags(path: &CString) -> io::Result<libc::c_ulong> {
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `path` is NUL-terminated and `stat` is filled on success.
+    let stat = unsafe {
+        check(libc::statvfs(path.as_ptr(), stat.as_mut_ptr()))?;
+        stat.assume_init()
+    };
+    let kept = [
+        (libc::ST_NOSUID, libc::MS_NOSUID),
+        (libc::ST_NODEV, libc::MS_NODEV),
+        (libc::ST_NOEXEC, libc::MS_NOEXEC),
+        (libc::ST_NOATIME, libc::MS_NOATIME),
+        (libc::ST_NODIRATIME, libc::MS_NODIRATIME),
+        (libc::ST_RELATIME, libc::MS_RELATIME),
+    ];
+    Ok(kept
+        .into_iter()
+        .filter(|(st, _)| stat.f_flag & st != 0)
+        .fold(0, |flags, (_, ms)| flags | ms))
+}
+
+unsafe fn write_file(path: &CString, contents: &[u8]) -> io::Result<()> {
+    // SAFETY: `path` is NUL-terminated and `contents` outlives the write.
+    unsafe {
+        let fd = libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+        check(fd)?;
+        let written = libc::write(fd, contents.as_ptr().cast(), contents.len());
+        libc::close(fd);
+        if written != contents.len() as isize {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+struct Captured {
+    text: String,
+    truncated: bool,
+}
+
+struct Supervised {
+    status: ExitStatus,
+    timed_out: bool,
+    stdout: Captured,
+    stderr: Captured,
+}
+
+/// How long output that is already in the pipes may take to be read after
+/// a program was killed at the deadline.
+const DRAIN_GRACE: Duration = Duration::from_millis(250);
+
+/// Run `command` to completion or until `timeout` or `cancelled`, when its
+/// whole process group is killed. Output past `max_output` bytes is read and dropped so
+/// the child never blocks on a full pipe. Reading stops at the deadline
+/// too, in case something outlived the child with the pipes open; the
+/// output is then marked truncated.
+fn supervise(
+    mut command: Command,
+    timeout: Duration,
+    max_output: usize,
+    cancelled: &AtomicBool,
+) -> io::Result<Supervised> {
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let stdout = capture(child.stdout.take(), max_output);
+    let stderr = capture(child.stderr.take(), max_output);
+
+    let deadline = Instant::now() + timeout;
+    let (status, timed_out) = loop {
+        if let Some(status) = child.try_wait()? {
+            break (status, false);
+        }
+        if Instant::now() >= deadline {
+            break (kill_group(&mut child)?, true);
+        }
+        if cancelled.load(Ordering::Relaxed) {
+            break (kill_group(&mut child)?, false);
+        }
+        thread::sleep(Duration::from_millis(20));
+    };
+    // Anything the program left running still holds the pipes open.
+    if !timed_out {
+        // SAFETY: signalling a process group we created.
+        unsafe {
+            libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+        }
+    }
+    let until = deadline.max(Instant::now() + DRAIN_GRACE);
+    Ok(Supervised {
+        status,
+        timed_out,
+        stdout: stdout.finish(until),
+        stderr: stderr.finish(until),
+    })
+}
+
+fn kill_group(child: &mut Child) -> io::Result<ExitStatus> {
+    // SAFETY: the child called `setsid`, so its pid is its process group.
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+    child.wait()
+}
+
+/// Output being read from one pipe on a thread of its own.
+struct Capture {
+    /// Bytes kept so far, and whether any were dropped.
+    kept: Arc<Mutex<(Vec<u8>, bool)>>,
+    /// Disconnects when the pipe is closed.
+    done: mpsc::Receiver<()>,
+}
+
+impl Capture {
+    /// What was read by the time the pipe closed or `until`, whichever
+    /// comes first. A reader still waiting at `until` is left to finish
+    /// on its own.
+    fn finish(self, until: Instant) -> Captured {
+        let timeout = until.saturating_duration_since(Instant::now());
+        let closed = matches!(
+            self.done.recv_timeout(timeout),
+            Err(mpsc::RecvTimeoutError::Disconnected)
+        );
+        let kept = self.kept.lock().unwrap_or_else(|err| err.into_inner());
+        Captured {
+            text: String::from_utf8_lossy(&kept.0).into_owned(),
+            truncated: kept.1 || !closed,
+        }
+    }
+}
+
+fn capture(pipe: Option<impl Read + Send + 'static>, max_output: usize) -> Capture {
+    let kept = Arc::new(Mutex::new((Vec::new(), false)));
+    let (closed, done) = mpsc::channel();
+    let output = Arc::clone(&kept);
+    thread::spawn(move || {
+        let _closed = closed;
+        let Some(mut pipe) = pipe else {
+            return;
+        };
+        let mut buffer = [0; 8192];
+        while let Ok(read) = pipe.read(&mut buffer) {
+            if read == 0 {
+                break;
+            }
+            let mut output = output.lock().unwrap_or_else(|err| err.into_inner());
+            let room = max_output - output.0.len();
+            output.0.extend_from_slice(&buffer[..read.min(room)]);
+            output.1 |= read > room;
+        }
+    });
+    Capture { kept, done }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use std::net::TcpListener;
+
+    fn runner(adjust: impl FnOnce(&mut RustRunnerConfig)) -> RustRunner {
+        let mut config = Config::for_tests().tools.rust;
+        config.run_timeout = Duration::from_secs(2);
+        adjust(&mut config);
+        RustRunner::new(&config).expect("rustc runs")
+    }
+
+    fn run(runner: &RustRunner, code: &str) -> Value {
+        runner
+            .call(json!({ "code": code }), &AtomicBool::new(false))
+            .unwrap()
+    }
+
+    #[test]
+    fn runs_a_program() {
+        let out = run(&runner(|_| {}), r#"fn main() { println!("{}", 6 * 7); }"#);
+        assert_eq!(out["compiled"], true, "{out}");
+        assert_eq!(out["exit_code"], 0, "{out}");
+        assert_eq!(out["stdout"], "42\n");
+    }
+
+    #[test]
+    fn compiler_cannot_read_host_files() {
+        let runner = runner(|_| {});
+        for (code, error) in [
+            (
+                r#"macro_rules! m { ($i:ident) => { $i!("/etc/passwd") } }
+                   fn main() { print!("{}", m!(include_str)); }"#,
+                "couldn't read",
+            ),
+            (
+                r#"#[cfg_attr(all(), path = "/proc/self/environ")] mod x;
+                   fn main() {}"#,
+                "couldn't read `/proc/self/environ`",
+            ),
+            (
+                r#"fn main() { print!("{}", env!("HOME")); }"#,
+                "environment variable `HOME` not defined",
+            ),
+        ] {
+            let out = run(&runner, code);
+            assert_eq!(out["compiled"], false, "{out}");
+            let diagnostics = out["diagnostics"].as_str().unwrap();
+            assert!(diagnostics.contains(error), "{diagnostics}");
+        }
+    }
+
+    #[test]
+    fn kills_programs_at_the_deadline() {
+        let out = run(&runner(|_| {}), "fn main() { loop {} }");
+        assert_eq!(out["timed_out"], true, "{out}");
+        assert_eq!(out["signal"], libc::SIGKILL, "{out}");
+    }
+
+    #[test]
+    fn limits_memory() {
+        let runner = runner(|config| config.run_memory_bytes = 64 * 1024 * 1024);
+        let out = run(
+            &runner,
+            "fn main() { let v = vec![1u8; 512 << 20]; println!(\"{}\", v[v.len() - 1]); }",
+        );
+        assert_eq!(out["compiled"], true, "{out}");
+        assert_ne!(out["exit_code"], 0, "{out}");
+        assert_eq!(out["stdout"], "", "{out}");
+        assert!(
+            out["stderr"]
+                .as_str()
+                .unwrap()
+                .contains("memory allocation"),
+            "{out}"
+        );
+    }
+
+    #[test]
+    fn ends_everything_the_program_started() {
+        // The program restarts itself in a process group of its own, which
+        // killing the supervised group would miss, and returns at once.
+        let code = r#"
+            use std::os::unix::process::CommandExt;
+            fn main() {
+                if std::env::args().nth(1).is_some() {
+                    loop {}
+                }
+                std::process::Command::new("/main")
+                    .arg("child")
+                    .process_group(0)
+                    .spawn()
+                    .unwrap();
+                println!("pid {}", std::process::id());
+            }"#;
+        let runner = runner(|config| config.run_timeout = Duration::from_secs(30));
+        let started = Instant::now();
+        let out = run(&runner, code);
+        assert_eq!(out["exit_code"], 0, "{out}");
+        assert_eq!(out["stdout"], "pid 1\n", "{out}");
+        // The pipes closed when the program ended, not at the deadline.
+        assert!(started.elapsed() < Duration::from_secs(20), "{out}");
+    }
+
+    #[test]
+    fn limits_processes() {
+        let code = r#"
+            fn main() {
+                let mut started = Vec::new();
+                while started.len() < 1000 {
+                    let sleeper = std::thread::Builder::new()
+                        .stack_size(64 * 1024)
+                        .spawn(|| std::thread::sleep(std::time::Duration::from_secs(5)));
+                    match sleeper {
+                        Ok(sleeper) => started.push(sleeper),
+                        Err(_) => break,
+                    }
+                }
+                println!("{}", started.len());
+            }"#;
+        let out = run(&runner(|_| {}), code);
+        assert_eq!(out["exit_code"], 0, "{out}");
+        let started: u64 = out["stdout"].as_str().unwrap().trim().parse().unwrap();
+        assert!(started < RUN_PROCESSES, "{out}");
+    }
+
+    #[test]
+    fn stops_reading_output_at_the_deadline() {
+        // `sleep` leaves the process group and keeps stdout open.
+        let mut command = Command::new("sh");
+        command
+            .args(["-c", "setsid sleep 10 & echo started"])
+            .process_group(0);
+        let started = Instant::now();
+        let run = supervise(
+            command,
+            Duration::from_secs(1),
+            1024,
+            &AtomicBool::new(false),
+        )
+        .unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(run.status.success());
+        assert_eq!(run.stdout.text, "started\n");
+        assert!(run.stdout.truncated);
+    }
+
+    #[test]
+    fn stops_when_the_turn_is_cancelled() {
+        let runner = runner(|config| config.run_timeout = Duration::from_secs(30));
+        let cancelled = AtomicBool::new(false);
+        let started = Instant::now();
+        let result = thread::scope(|scope| {
+            let call =
+                scope.spawn(|| runner.call(json!({ "code": "fn main() { loop {} }" }), &cancelled));
+            thread::sleep(Duration::from_millis(500));
+            cancelled.store(true, Ordering::Relaxed);
+            call.join().unwrap()
+        });
+        let err = result.unwrap_err();
+        assert!(err.to_string().contains("cancelled"), "{err:#}");
+        assert!(started.elapsed() < Duration::from_secs(20));
+    }
+
+    #[test]
+    fn has_no_network() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let code = format!(
+            r#"fn main() {{
+                match std::net::TcpStream::connect("{}") {{
+                    Ok(_) => println!("connected"),
+                    Err(_) => println!("blocked"),
+                }}
+            }}"#,
+            listener.local_addr().unwrap()
+        );
+        let out = run(&runner(|_| {}), &code);
+        assert_eq!(out["stdout"], "blocked\n", "{out}");
+    }
+
+    #[test]
+    fn truncates_output() {
+        let runner = runner(|config| config.max_output_bytes = 1024);
+        let out = run(
+            &runner,
+            r#"fn main() { print!("{}", "x".repeat(100_000)); }"#,
+        );
+        assert_eq!(out["exit_code"], 0, "{out}");
+        assert_eq!(out["truncated"], true);
+        assert_eq!(out["stdout"].as_str().unwrap().len(), 1024);
+    }
+}
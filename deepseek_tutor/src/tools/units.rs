@@ -0,0 +1,279 @@
+use anyhow::{Result, bail};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::sync::atomic::AtomicBool;
+
+use super::Tool;
+use super::calculator::{self, Rational};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Dimension {
+    Length,
+    Mass,
+    Time,
+    Area,
+    Volume,
+    Speed,
+    Energy,
+    Force,
+    Pressure,
+    Temperature,
+}
+
+/// A unit as an exact multiple of its dimension's SI unit. Temperatures
+/// also carry an offset, applied after scaling: `kelvin = value * factor +
+/// offset`.
+struct Unit {
+    names: &'static [&'static str],
+    dimension: Dimension,
+    factor: (i128, i128),
+    offset: (i128, i128),
+}
+
+const fn unit(names: &'static [&'static str], dimension: Dimension, num: i128, den: i128) -> Unit {
+    Unit {
+        names,
+        dimension,
+        factor: (num, den),
+        offset: (0, 1),
+    }
+}
+
+// Imperial and US units use their exact legal definitions.
+#[rustfmt::skip]
+const UNITS: &[Unit] = &[
+    unit(&["m", "meter", "metre", "meters", "metres"], Dimension::Length, 1, 1),
+    unit(&["km", "kilometer", "kilometre", "kilometers", "kilometres"], Dimension::Length, 1000, 1),
+    unit(&["cm", "centimeter", "centimetre", "centimeters", "centimetres"], Dimension::Length, 1, 100),
+    unit(&["mm", "millimeter", "millimetre", "millimeters", "millimetres"], Dimension::Length, 1, 1000),
+    unit(&["um", "µm", "micrometer", "micrometre", "micron"], Dimension::Length, 1, 1_000_000),
+    unit(&["nm", "nanometer", "nanometre"], Dimension::Length, 1, 1_000_000_000),
+    unit(&["in", "inch", "inches"], Dimension::Length, 127, 5000),
+    unit(&["ft", "foot", "feet"], Dimension::Length, 381, 1250),
+    unit(&["yd", "yard", "yards"], Dimension::Length, 1143, 1250),
+    unit(&["mi", "mile", "miles"], Dimension::Length, 201_168, 125),
+    unit(&["nmi", "nautical mile", "nautical miles"], Dimension::Length, 1852, 1),
+    unit(&["kg", "kilogram", "kilograms"], Dimension::Mass, 1, 1),
+    unit(&["g", "gram", "grams"], Dimension::Mass, 1, 1000),
+    unit(&["mg", "milligram", "milligrams"], Dimension::Mass, 1, 1_000_000),
+    unit(&["t", "tonne", "tonnes", "metric ton"], Dimension::Mass, 1000, 1),
+    unit(&["lb", "lbs", "pound", "pounds"], Dimension::Mass, 45_359_237, 100_000_000),
+    unit(&["oz", "ounce", "ounces"], Dimension::Mass, 45_359_237, 1_600_000_000),
+    unit(&["st", "stone"], Dimension::Mass, 635_029_318, 100_000_000),
+    unit(&["s", "sec", "second", "seconds"], Dimension::Time, 1, 1),
+    unit(&["ms", "millisecond", "milliseconds"], Dimension::Time, 1, 1000),
+    unit(&["min", "minute", "minutes"], Dimension::Time, 60, 1),
+    unit(&["h", "hr", "hour", "hours"], Dimension::Time, 3600, 1),
+    unit(&["d", "day", "days"], Dimension::Time, 86_400, 1),
+    unit(&["week", "weeks"], Dimension::Time, 604_800, 1),
+    unit(&["m2", "m^2", "square meter", "square metre"], Dimension::Area, 1, 1),
+    unit(&["cm2", "cm^2", "square centimeter", "square centimetre"], Dimension::Area, 1, 10_000),
+    unit(&["km2", "km^2", "square kilometer", "square kilometre"], Dimension::Area, 1_000_000, 1),
+    unit(&["ha", "hectare", "hectares"], Dimension::Area, 10_000, 1),
+    unit(&["ft2", "ft^2", "square foot", "square feet"], Dimension::Area, 145_161, 1_562_500),
+    unit(&["acre", "acres"], Dimension::Area, 316_160_658, 78_125),
+    unit(&["m3", "m^3", "cubic meter", "cubic metre"], Dimension::Volume, 1, 1),
+    unit(&["l", "L", "liter", "litre", "liters", "litres"], Dimension::Volume, 1, 1000),
+    unit(&["ml", "mL", "milliliter", "millilitre", "cm3", "cm^3"], Dimension::Volume, 1, 1_000_000),
+    unit(&["gal", "gallon", "gallons", "us gallon"], Dimension::Volume, 473_176_473, 125_000_000_000),
+    unit(&["qt", "quart", "quarts"], Dimension::Volume, 473_176_473, 500_000_000_000),
+    unit(&["m/s", "meters per second", "metres per second"], Dimension::Speed, 1, 1),
+    unit(&["km/h", "kph", "kmh", "kilometers per hour", "kilometres per hour"], Dimension::Speed, 5, 18),
+    unit(&["mph", "miles per hour"], Dimension::Speed, 1397, 3125),
+    unit(&["kn", "knot", "knots"], Dimension::Speed, 463, 900),
+    unit(&["J", "joule", "joules"], Dimension::Energy, 1, 1),
+    unit(&["kJ", "kilojoule", "kilojoules"], Dimension::Energy, 1000, 1),
+    unit(&["cal", "calorie", "calories"], Dimension::Energy, 523, 125),
+    unit(&["kcal", "kilocalorie", "kilocalories"], Dimension::Energy, 4184, 1),
+    unit(&["Wh", "watt hour", "watt hours"], Dimension::Energy, 3600, 1),
+    unit(&["kWh", "kilowatt hour", "kilowatt hours"], Dimension::Energy, 3_600_000, 1),
+    unit(&["eV", "electronvolt", "electronvolts"], Dimension::Energy, 801_088_317, 5_000_000_000_000_000_000_000_000_000),
+    unit(&["N", "newton", "newtons"], Dimension::Force, 1, 1),
+    unit(&["kN", "kilonewton", "kilonewtons"], Dimension::Force, 1000, 1),
+    unit(&["lbf", "pound-force", "pounds-force"], Dimension::Force, 8_896_443_230_521, 2_000_000_000_000),
+    unit(&["Pa", "pascal", "pascals"], Dimension::Pressure, 1, 1),
+    unit(&["kPa", "kilopascal", "kilopascals"], Dimension::Pressure, 1000, 1),
+    unit(&["bar"], Dimension::Pressure, 100_000, 1),
+    unit(&["atm", "atmosphere", "atmospheres"], Dimension::Pressure, 101_325, 1),
+    unit(&["mmHg"], Dimension::Pressure, 26_664_477_483, 200_000_000),
+    unit(&["psi"], Dimension::Pressure, 8_896_443_230_521, 1_290_320_000),
+    Unit {
+        names: &["K", "kelvin"],
+        dimension: Dimension::Temperature,
+        factor: (1, 1),
+        offset: (0, 1),
+    },
+    Unit {
+        names: &["C", "°C", "celsius"],
+        dimension: Dimension::Temperature,
+        factor: (1, 1),
+        offset: (5463, 20),
+    },
+    Unit {
+        names: &["F", "°F", "fahrenheit"],
+        dimension: Dimension::Temperature,
+        factor: (5, 9),
+        offset: (45_967, 180),
+    },
+];
+
+/// Exact match first so that `mm` and `Mm`-style pairs stay apart, then
+/// case-insensitively.
+fn lookup(name: &str) -> Result<&'static Unit> {
+    let name = name.trim();
+    UNITS
+        .iter()
+        .find(|unit| unit.names.contains(&name))
+        .or_else(|| {
+            UNITS
+                .iter()
+                .find(|unit| unit.names.iter().any(|n| n.eq_ignore_ascii_case(name)))
+        })
+        .ok_or_else(|| anyhow::anyhow!("unknown unit `{}`", name))
+}
+
+pub fn convert(value: Rational, from: &str, to: &str) -> Result<Rational> {
+    let (from, to) = (lookup(from)?, lookup(to)?);
+    if from.dimension != to.dimension {
+        bail!("cannot convert {:?} to {:?}", from.dimension, to.dimension);
+    }
+    let ratio = |(num, den)| Rational::new(num, den);
+    let base = value
+        .checked_mul(ratio(from.factor)?)?
+        .checked_add(ratio(from.offset)?)?;
+    base.checked_sub(ratio(to.offset)?)?
+        .checked_div(ratio(to.factor)?)
+}
+
+#[derive(Deserialize)]
+struct ConvertArgs {
+    /// A number or an expression such as `3/4`.
+    value: Value,
+    from: String,
+    to: String,
+}
+
+/// Exact conversions between common SI, imperial and US units.
+pub struct UnitConverter;
+
+impl Tool for UnitConverter {
+    fn name(&self) -> &'static str {
+        "convert_units"
+    }
+
+    fn description(&self) -> &'static str {
+        "Convert a quantity between units of length, mass, time, area, volume, speed, \
+         energy, force, pressure or temperature, exactly. Units are symbols or names such \
+         as km, ft, lb, kg, mph, km/h, kWh, atm, psi, C, F, K."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "value": {
+                    "type": ["number", "string"],
+                    "description": "The quantity, as a number or an expression such as \"3/4\""
+                },
+                "from": { "type": "string", "description": "Unit of the value" },
+                "to": { "type": "string", "description": "Unit to convert to" }
+            },
+            "required": ["value", "from", "to"],
+            "additionalProperties": false
+        })
+    }
+
+    fn call(&self, arguments: Value, _cancelled: &AtomicBool) -> Result<Value> {
+        let args: ConvertArgs = serde_json::from_value(arguments)?;
+        let value = match &args.value {
+            Value::String(text) => calculator::evaluate(text)?,
+            Value::Number(number) => calculator::evaluate(&number.to_string())?,
+            _ => bail!("value must be a number or a string"),
+        };
+        let result = convert(value, &args.from, &args.to)?;
+        let (decimal, exact) = result.to_decimal();
+        Ok(json!({
+            "value": args.value,
+            "from": args.from,
+            "to": args.to,
+            "fraction": result.to_string(),
+            "decimal": decimal,
+            "decimal_is_exact": exact,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conv(value: &str, from: &str, to: &str) -> String {
+        convert(calculator::evaluate(value).unwrap(), from, to)
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn converts_exactly() {
+        assert_eq!(conv("1", "mi", "km"), "25146/15625");
+        assert_eq!(conv("1", "ft", "in"), "12");
+        assert_eq!(conv("1", "lb", "kg"), "45359237/100000000");
+        assert_eq!(conv("16", "oz", "lb"), "1");
+        assert_eq!(conv("90", "km/h", "m/s"), "25");
+        assert_eq!(conv("1", "kWh", "kJ"), "3600");
+    }
+
+    #[test]
+    fn offsets_temperatures() {
+        assert_eq!(conv("100", "C", "F"), "212");
+        assert_eq!(conv("-40", "celsius", "fahrenheit"), "-40");
+        assert_eq!(conv("0", "K", "C"), "-5463/20");
+        assert_eq!(conv("32", "°F", "K"), "5463/20");
+    }
+
+    #[test]
+    fn looks_units_up_by_name_and_case() {
+        assert_eq!(conv("1", "Kilometres", "METER"), "1000");
+        assert_eq!(conv("1000", "ml", "L"), "1");
+        // An exact match wins over a case-insensitive one.
+        assert_eq!(conv("1", "m", "mm"), "1000");
+    }
+
+    #[test]
+    fn reports_errors() {
+        let one = Rational::integer(1);
+        assert!(convert(one, "furlong", "m").is_err());
+        let err = convert(one, "kg", "m").unwrap_err();
+        assert_eq!(err.to_string(), "cannot convert Mass to Length");
+        assert!(
+            UnitConverter
+                .call(
+                    json!({"value": true, "from": "m", "to": "ft"}),
+                    &AtomicBool::new(false)
+                )
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn tool_accepts_numbers_and_expressions() {
+        let out = UnitConverter
+            .call(
+                json!({"value": "3/4", "from": "in", "to": "mm"}),
+                &AtomicBool::new(false),
+            )
+            .unwrap();
+        assert_eq!(out["fraction"], "381/20");
+        assert_eq!(out["decimal"], "19.05");
+        assert_eq!(out["decimal_is_exact"], true);
+
+        let out = UnitConverter
+            .call(
+                json!({"value": 2.5, "from": "h", "to": "min"}),
+                &AtomicBool::new(false),
+            )
+            .unwrap();
+        assert_eq!(out["value"], 2.5);
+        assert_eq!(out["fraction"], "150");
+    }
+}
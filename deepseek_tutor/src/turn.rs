@@ -0,0 +1,368 @@
+use anyhow::{Error, anyhow};
+use async_openai::{
+    Client,
+    config::OpenAIConfig,
+    types::{
+        ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestMessage,
+        ChatCompletionRequestToolMessageArgs, ChatCompletionToolChoiceOption,
+        CreateChatCompletionRequest,
+    },
+};
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+
+use crate::citation::Citation;
+use crate::integrity::{self, FlagSource, IntegrityFlag};
+use crate::moderation::{Moderator, Review};
+use crate::pii::Redactor;
+use crate::retrieval::Passage;
+use crate::session::{NodeId, TokenUsage};
+use crate::tools::{ToolRegistry, ToolUse};
+
+/// What started a turn; decides where the session's head goes if the turn
+/// does not produce a reply.
+#[derive(Debug, Clone, Copy)]
+pub enum TurnKind {
+    /// A new or edited question; the question stays in history either way.
+    Query,
+    /// A second answer to an earlier question; on failure or cancellation
+    /// the student is returned to the reply they were reading.
+    Regenerate { previous_head: Option<NodeId> },
+}
+
+/// Identifies a turn when its result is recorded.
+#[derive(Debug)]
+pub struct TurnTicket {
+    pub turn_id: String,
+    pub student_id: String,
+    pub session_id: String,
+    pub kind: TurnKind,
+    /// The question being answered, kept for the integrity log.
+    pub question: String,
+    /// Set when the turn runs in hint-only mode.
+    pub integrity: Option<IntegrityFlag>,
+    /// Course material sent with the question, numbered from 1 as the
+    /// reply cites it.
+    pub passages: Vec<Passage>,
+}
+
+pub enum TurnOutcome {
+    Replied {
+        /// The tutor's answer after moderation.
+        reply: Review,
+        /// Summed over every upstream call in the turn, the integrity
+        /// classifier and the moderation of the reply included.
+        usage: Option<TokenUsage>,
+        /// Tool calls and their results, in order, to store before the
+        /// answer.
+        exchange: Vec<ChatCompletionRequestMessage>,
+        tools: Vec<ToolUse>,
+    },
+    Cancelled {
+        /// Tokens spent on the upstream calls that finished before the
+        /// cancel.
+        usage: Option<TokenUsage>,
+    },
+    Failed {
+        error: Error,
+        /// Tokens spent before the turn failed.
+        usage: Option<TokenUsage>,
+    },
+}
+
+/// An upstream request that has been prepared under the controller lock and
+/// is run without it.
+pub struct PendingTurn {
+    pub ticket: TurnTicket,
+    pub client: Client<OpenAIConfig>,
+    pub request: CreateChatCompletionRequest,
+    /// Integrity check to run through the model before the turn, when the
+    /// rules did not already flag it.
+    pub classifier: Option<CreateChatCompletionRequest>,
+    /// Reviews the reply before it is recorded; `None` when moderation is off.
+    pub moderator: Option<Arc<Moderator>>,
+    /// Placeholders used in `request`, to be swapped back in the reply.
+    pub redactor: Option<Redactor>,
+    /// Tools offered in `request`; `None` when tools are disabled.
+    pub tools: Option<Arc<ToolRegistry>>,
+    pub cancelled: oneshot::Receiver<()>,
+}
+
+impl PendingTurn {
+    /// Wait for the model or for a cancel, whichever comes first. Cancelling
+    /// drops the in-flight HTTP request.
+    pub async fn run(self) -> (TurnTicket, TurnOutcome) {
+        let PendingTurn {
+            mut ticket,
+            client,
+            mut request,
+            classifier,
+            moderator,
+            redactor,
+            tools,
+            cancelled,
+        } = self;
+
+        let chat = client.chat();
+        // Kept outside `work` so that a cancelled turn still reports what it
+        // spent.
+        let usage: Mutex<Option<TokenUsage>> = Mutex::new(None);
+        let count = |spent: TokenUsage| {
+            let mut usage = usage.lock().unwrap_or_else(|err| err.into_inner());
+            *usage.get_or_insert_default() += spent;
+        };
+        let work = async {
+            let mut flag = None;
+            if let Some(classifier) = classifier {
+                match chat.create(classifier).await {
+                    Ok(response) => {
+                        if let Some(classifier_usage) = &response.usage {
+                            count(TokenUsage::from(classifier_usage));
+                        }
+                        let verdict = response
+                            .choices
+                            .first()
+                            .and_then(|choice| choice.message.content.as_deref())
+                            .unwrap_or_default();
+                        if integrity::classifier_flagged(verdict) {
+                            integrity::restrict_to_hints(&mut request.messages);
+                            flag = Some(IntegrityFlag {
+                                source: FlagSource::Model,
+                                reasons: Vec::new(),
+                            });
+                        }
+                    }
+                    // The rules already passed the question; answer normally.
+                    Err(err) => eprintln!("Integrity classifier failed: {}", err),
+                }
+            }
+            let mut exchange = Vec::new();
+            let mut used = Vec::new();
+            let mut rounds = 0;
+            let message = loop {
+                let response = match chat.create(request.clone()).await {
+                    Ok(response) => response,
+                    Err(err) => return (flag, Err(Error::from(err))),
+                };
+                if let Some(turn_usage) = &response.usage {
+                    count(TokenUsage::from(turn_usage));
+                }
+                let Some(choice) = response.choices.into_iter().next() else {
+                    break None;
+                };
+                let calls = choice.message.tool_calls.clone().unwrap_or_default();
+                let Some(registry) = tools.as_ref().filter(|_| !calls.is_empty()) else {
+                    break Some(choice.message);
+                };
+                // Still calling tools after being told not to: keep any answer
+                // it gave alongside the calls, but run no more of them.
+                if rounds >= registry.max_iterations {
+                    if choice
+                        .message
+                        .content
+                        .as_deref()
+                        .is_some_and(|c| !c.trim().is_empty())
+                    {
+                        break Some(choice.message);
+                    }
+                    let err = anyhow!("model kept calling tools after {} rounds", rounds);
+                    return (flag, Err(err));
+                }
+
+                let mut assistant = ChatCompletionRequestAssistantMessageArgs::default();
+                assistant.tool_calls(calls.clone());
+                if let Some(content) = choice.message.content.filter(|c| !c.trim().is_empty()) {
+                    assistant.content(content);
+                }
+                let assistant = match assistant.build() {
+                    Ok(message) => ChatCompletionRequestMessage::from(message),
+                    Err(err) => return (flag, Err(Error::from(err))),
+                };
+                request.messages.push(assistant.clone());
+                exchange.push(assistant);
+
+                for call in calls {
+                    let output = registry
+                        .call(&call.function.name, &call.function.arguments)
+                        .await;
+                    let result = match ChatCompletionRequestToolMessageArgs::default()
+                        .tool_call_id(call.id)
+                        .content(output.as_str())
+                        .build()
+                    {
+                        Ok(message) => ChatCompletionRequestMessage::from(message),
+                        Err(err) => return (flag, Err(Error::from(err))),
+                    };
+                    request.messages.push(result.clone());
+                    exchange.push(result);
+                    used.push(ToolUse {
+                        name: call.function.name,
+                        arguments: call.function.arguments,
+                        output,
+                    });
+                }
+
+                // Out of rounds: the next completion has to be an answer.
+                rounds += 1;
+                if rounds >= registry.max_iterations {
+                    request.tool_choice = Some(ChatCompletionToolChoiceOption::None);
+                }
+            };
+
+            let text = message
+                .as_ref()
+                .and_then(|message| message.content.as_deref())
+                .unwrap_or_default()
+                .trim();
+            let text = match &redactor {
+                Some(redactor) => {
+                    for message in &mut exchange {
+                        redactor.restore_message(message);
+                    }
+                    redactor.restore(text)
+                }
+                None => text.to_string(),
+            };
+            let reply = match &moderator {
+                Some(moderator) => moderator.review(text).await,
+                None => Review::unchanged(text),
+            };
+            if reply.usage.total_tokens > 0 {
+                count(reply.usage);
+            }
+            (flag, Ok((reply, exchange, used)))
+        };
+
+        let finished = tokio::select! {
+            _ = cancelled => None,
+            finished = work => Some(finished),
+        };
+        let usage = usage.into_inner().unwrap_or_else(|err| err.into_inner());
+        let outcome = match finished {
+            None => TurnOutcome::Cancelled { usage },
+            Some((flag, response)) => {
+                if flag.is_some() {
+                    ticket.integrity = flag;
+                }
+                match response {
+                    Ok((reply, exchange, tools)) => TurnOutcome::Replied {
+                        reply,
+                        usage,
+                        exchange,
+                        tools,
+                    },
+                    Err(error) => TurnOutcome::Failed { error, usage },
+                }
+            }
+        };
+        (ticket, outcome)
+    }
+}
+
+/// Result of a finished turn.
+#[derive(Debug)]
+pub struct TurnReply {
+    pub turn_id: String,
+    /// The tutor's answer; empty when cancelled.
+    pub message: String,
+    pub cancelled: bool,
+    /// The question was flagged as graded work and answered with hints only.
+    pub hint_only: bool,
+    /// Tools the model called while answering.
+    pub tools: Vec<ToolUse>,
+    /// Passages the answer cites, then the tools it used.
+    pub citations: Vec<Citation>,
+    /// Persona that answered; `None` for the general tutor.
+    pub persona: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::testing::{FakeUpstream, completion};
+    use async_openai::types::ChatCompletionRequestUserMessageArgs;
+    use serde_json::Value;
+    use std::time::Duration;
+
+    /// A reply that calls the calculator.
+    fn tool_call(round: usize) -> Value {
+        let message = serde_json::json!({"role": "assistant", "content": null,
+            "tool_calls": [{"id": format!("call{round}"), "type": "function",
+                "function": {"name": "calculator",
+                    "arguments": "{\"expression\": \"1 + 1\"}"}}]});
+        completion("tool_calls", message)
+    }
+
+    fn pending(upstream: &FakeUpstream, cancelled: oneshot::Receiver<()>) -> PendingTurn {
+        let config = Config::for_tests().tools;
+        let question = ChatCompletionRequestUserMessageArgs::default()
+            .content("What is 1 + 1?")
+            .build()
+            .unwrap();
+        PendingTurn {
+            ticket: TurnTicket {
+                turn_id: "t1".to_string(),
+                student_id: "ana".to_string(),
+                session_id: "s1".to_string(),
+                kind: TurnKind::Query,
+                question: "What is 1 + 1?".to_string(),
+                integrity: None,
+                passages: Vec::new(),
+            },
+            client: upstream.client(),
+            request: CreateChatCompletionRequest {
+                model: "m".to_string(),
+                messages: vec![question.into()],
+                ..Default::default()
+            },
+            classifier: None,
+            moderator: None,
+            redactor: None,
+            tools: Some(Arc::new(ToolRegistry::new(&config))),
+            cancelled,
+        }
+    }
+
+    #[tokio::test]
+    async fn stops_a_model_that_keeps_calling_tools() {
+        let upstream = FakeUpstream::start(|round| (200, tool_call(round)));
+        let config = Config::for_tests().tools;
+        let (_cancel, cancelled) = oneshot::channel();
+
+        let (_, outcome) = pending(&upstream, cancelled).run().await;
+        let TurnOutcome::Failed { error, usage } = outcome else {
+            panic!("turn should fail");
+        };
+        assert!(error.to_string().contains("kept calling tools"), "{error}");
+        // Every completion, the refused one included, is counted.
+        let rounds = config.max_iterations as u32 + 1;
+        assert_eq!(usage.unwrap().total_tokens, 6 * rounds);
+        // One completion per allowed round, then the one that was refused.
+        assert_eq!(upstream.requests().len(), config.max_iterations + 1);
+    }
+
+    #[tokio::test]
+    async fn a_cancelled_turn_reports_the_tokens_it_spent() {
+        // The second completion is still being written when the turn is
+        // cancelled.
+        let upstream = FakeUpstream::start(|round| {
+            if round > 0 {
+                std::thread::sleep(Duration::from_secs(1));
+            }
+            (200, tool_call(round))
+        });
+        let (cancel, cancelled) = oneshot::channel();
+        let turn = tokio::spawn(pending(&upstream, cancelled).run());
+        while upstream.requests().len() < 2 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        cancel.send(()).unwrap();
+
+        let (_, outcome) = turn.await.unwrap();
+        let TurnOutcome::Cancelled { usage } = outcome else {
+            panic!("turn should be cancelled");
+        };
+        assert_eq!(usage.unwrap().total_tokens, 6);
+    }
+}
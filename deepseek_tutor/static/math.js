@@ -0,0 +1,85 @@
+// TeX math in tutor replies and flashcards, rendered with the bundled KaTeX
+// as MathML so that no fonts or stylesheet are needed.
+//
+// $$...$$ and \[...\] are display math, $...$ and \(...\) inline. A single
+// $ only opens math when a non-space follows it and only closes it when a
+// non-space precedes it and no digit follows, so "$5 and $10" stays text.
+/* global katex */
+const MATH_PATTERN =
+    /\$\$([\s\S]+?)\$\$|\\\[([\s\S]+?)\\\]|\\\(([\s\S]+?)\\\)|\$(?=[^\s$])((?:\\.|[^$\\\n])+?)(?<=[^\s\\])\$(?!\d)/g;
+
+// KaTeX before 0.16.10 does not count these towards maxExpand, so a formula
+// could keep the page busy forever (CVE-2024-28243, CVE-2024-28244):
+// \edef and \xdef, and Unicode sub- and superscripts. Tutor math never
+// needs them; such formulas are shown as source.
+const UNBOUNDED_EXPANSION =
+    /\\[ex]def(?![A-Za-z])|[\u00B2\u00B3\u00B9\u02B0-\u02E4\u1D2C-\u1DBF\u2070-\u209C\u2C7C\u2C7D]/;
+
+// Skipped by renderMathIn: code stays as written.
+const RAW_TAGS = new Set(['CODE', 'PRE', 'SCRIPT', 'STYLE', 'TEXTAREA']);
+
+function escapeHtml(text) {
+    return text
+        .replace(/&/g, '&amp;')
+        .replace(/</g, '&lt;')
+        .replace(/>/g, '&gt;')
+        .replace(/"/g, '&quot;');
+}
+
+// MathML for one formula; the source itself if KaTeX did not load or the
+// formula could expand without limit.
+function renderTex(tex, display) {
+    if (typeof katex === 'undefined' || UNBOUNDED_EXPANSION.test(tex)) {
+        return escapeHtml(display ? `$$${tex}$$` : `$${tex}$`);
+    }
+    return katex.renderToString(tex, {
+        displayMode: display,
+        output: 'mathml',
+        throwOnError: false,
+        maxExpand: 1000,
+        trust: false
+    });
+}
+
+function renderMatch(match, display, bracketDisplay, bracketInline, inline) {
+    if (display !== undefined) return renderTex(display, true);
+    if (bracketDisplay !== undefined) return renderTex(bracketDisplay, true);
+    return renderTex(bracketInline !== undefined ? bracketInline : inline, false);
+}
+
+// Render the math spans in HTML the server made from Markdown: their text
+// is the TeX source.
+function renderMathSpans(root) {
+    for (const span of root.querySelectorAll('span.math')) {
+        span.innerHTML = renderTex(span.textContent, span.classList.contains('math-display'));
+    }
+}
+
+// Render the math in the text under `root`, e.g. a flashcard whose
+// sides are plain text.
+function renderMathIn(root) {
+    const walker = document.createTreeWalker(root, NodeFilter.SHOW_TEXT, {
+        acceptNode(node) {
+            for (let parent = node.parentElement; parent; parent = parent.parentElement) {
+                if (RAW_TAGS.has(parent.tagName)) return NodeFilter.FILTER_REJECT;
+            }
+            MATH_PATTERN.lastIndex = 0;
+            return MATH_PATTERN.test(node.data) ? NodeFilter.FILTER_ACCEPT : NodeFilter.FILTER_REJECT;
+        }
+    });
+    const nodes = [];
+    while (walker.nextNode()) nodes.push(walker.currentNode);
+    for (const node of nodes) {
+        const span = document.createElement('span');
+        span.innerHTML = escapeHtml(node.data).replace(MATH_PATTERN, (...groups) => {
+            // Formulas were escaped with the text; KaTeX wants them as typed.
+            const unescaped = groups.slice(1, 5).map((group) => group === undefined ? undefined : group
+                .replace(/&quot;/g, '"')
+                .replace(/&gt;/g, '>')
+                .replace(/&lt;/g, '<')
+                .replace(/&amp;/g, '&'));
+            return renderMatch(groups[0], ...unescaped);
+        });
+        node.replaceWith(...span.childNodes);
+    }
+}
@@ -0,0 +1,82 @@
+/* global renderMathIn */
+// The tutor page keeps its student id in localStorage; ?student= overrides it.
+const studentId = new URLSearchParams(window.location.search).get('student')
+    || localStorage.getItem('tutorStudentId');
+const status = document.getElementById('review-status');
+let queue = [];
+
+document.addEventListener('DOMContentLoaded', () => {
+    document.getElementById('show-btn').addEventListener('click', showBack);
+    document.querySelectorAll('.grade-btn').forEach((button) => {
+        button.addEventListener('click', () => grade(Number(button.dataset.grade)));
+    });
+    loadDue();
+});
+
+function loadDue() {
+    if (!studentId) {
+        status.textContent = 'Ask the tutor something first, or open this page with ?student=<id>.';
+        return;
+    }
+    fetch(`/api/v1/students/${encodeURIComponent(studentId)}/cards/due?limit=50`)
+        .then(response => response.json())
+        .then(body => {
+            if (body.status === 'success') {
+                queue = body.data;
+                showNext();
+            } else {
+                status.textContent = 'Error loading cards: ' + (body.error?.message || 'Unknown error');
+            }
+        })
+        .catch((error) => {
+            console.error('Error loading cards:', error);
+            status.textContent = 'Error loading cards. Check console for details.';
+        });
+}
+
+function showNext() {
+    const card = queue[0];
+    document.getElementById('review-card').hidden = !card;
+    if (!card) {
+        status.textContent = 'No cards are due. Come back later.';
+        return;
+    }
+    status.textContent = `${queue.length} due`;
+    document.getElementById('card-front').textContent = card.front;
+    document.getElementById('card-back').textContent = card.back;
+    renderMathIn(document.getElementById('card-front'));
+    renderMathIn(document.getElementById('card-back'));
+    document.getElementById('card-back').hidden = true;
+    document.getElementById('show-btn').hidden = false;
+    document.getElementById('grade-buttons').hidden = true;
+}
+
+function showBack() {
+    document.getElementById('card-back').hidden = false;
+    document.getElementById('show-btn').hidden = true;
+    document.getElementById('grade-buttons').hidden = false;
+}
+
+function grade(value) {
+    const card = queue[0];
+    fetch(`/api/v1/cards/${encodeURIComponent(card.card_id)}/reviews`, {
+        method: 'POST',
+        headers: {
+            'Content-Type': 'application/json'
+        },
+        body: JSON.stringify({ student_id: studentId, grade: value })
+    })
+        .then(response => response.json())
+        .then(body => {
+            if (body.status === 'success') {
+                queue.shift();
+                showNext();
+            } else {
+                alert('Error recording review: ' + (body.error?.message || 'Unknown error'));
+            }
+        })
+        .catch((error) => {
+            console.error('Error recording review:', error);
+            alert('Error recording review. Check console for details.');
+        });
+}
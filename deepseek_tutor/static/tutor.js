@@ -0,0 +1,416 @@
+const messagesContainer = document.getElementById('messages');
+const messageInput = document.getElementById('message-input');
+const personaSelect = document.getElementById('persona-select');
+
+let currentSessionId = null;
+let currentTurnId = null;
+// Kept so the review page and later visits find the same flashcards.
+let currentStudentId = localStorage.getItem('tutorStudentId');
+if (!currentStudentId) {
+    currentStudentId = "student-" + Math.random().toString(36).substring(2, 15); // Example student ID
+    localStorage.setItem('tutorStudentId', currentStudentId);
+}
+// Course materials to search, from ?course=...&organization=... in the page URL.
+const pageParams = new URLSearchParams(window.location.search);
+const courseId = pageParams.get('course');
+const organizationId = pageParams.get('organization');
+
+document.addEventListener('DOMContentLoaded', () => {
+    document.querySelectorAll('.suggestion-btn').forEach((button) => {
+        button.addEventListener('click', () => usePrompt(button.dataset.prompt, button.dataset.persona));
+    });
+    personaSelect.addEventListener('change', choosePersona);
+    document.getElementById('send-btn').addEventListener('click', sendQuery);
+    document.getElementById('stop-btn').addEventListener('click', stopTurn);
+    document.getElementById('cards-btn').addEventListener('click', draftCards);
+    document.getElementById('new-session-btn').addEventListener('click', startNewSession);
+    loadPersonas();
+    startNewSession();
+});
+
+function loadPersonas() {
+    fetch('/api/v1/personas')
+        .then(response => response.json())
+        .then(body => {
+            if (body.status !== 'success') return;
+            for (const persona of body.data) {
+                const option = document.createElement('option');
+                option.value = persona.id;
+                option.textContent = persona.name;
+                personaSelect.appendChild(option);
+            }
+        })
+        .catch((error) => console.error('Error loading personas:', error));
+}
+
+// Switch the current session to the selected persona; "Automatic"
+// lets the first question pick one.
+function choosePersona() {
+    if (!currentSessionId) return Promise.resolve();
+    return fetch(`/api/v1/sessions/${encodeURIComponent(currentSessionId)}/persona`, {
+        method: 'PUT',
+        headers: { 'Content-Type': 'application/json' },
+        body: JSON.stringify({
+            student_id: currentStudentId,
+            persona: personaSelect.value || null
+        })
+    }).catch((error) => console.error('Error choosing persona:', error));
+}
+
+function startNewSession() {
+    // Let the tutor remember what this session showed about the
+    // student before the next one starts; failures are not fatal.
+    const previous = currentSessionId;
+    const noted = previous
+        ? fetch(`/api/v1/sessions/${encodeURIComponent(previous)}/profile`, {
+            method: 'POST',
+            headers: { 'Content-Type': 'application/json' },
+            body: JSON.stringify({ student_id: currentStudentId })
+        }).catch((error) => console.error('Error updating student profile:', error))
+        : Promise.resolve();
+    noted.then(createSession);
+}
+
+function createSession() {
+    fetch('/api/v1/sessions', {
+        method: 'POST',
+        headers: {
+            'Content-Type': 'application/json'
+        },
+        body: JSON.stringify({
+            student_id: currentStudentId,
+            course_id: courseId,
+            organization_id: organizationId,
+            persona: personaSelect.value || null
+        })
+    })
+        .then(response => response.json())
+        .then(body => {
+            if (body.status === 'success') {
+                currentSessionId = body.data.session_id;
+                messagesContainer.innerHTML = ''; // Clear messages for new session
+                appendMessage('assistant', body.data.message || 'New session started.');
+            } else {
+                alert('Error creating tutoring session: ' + (body.error?.message || 'Unknown error'));
+            }
+        })
+        .catch((error) => {
+            console.error('Error creating tutoring session:', error);
+            alert('Error creating tutoring session. Check console for details.');
+        });
+
+}
+
+function sendQuery() {
+    const query = messageInput.value.trim();
+    if (!query) return;
+    if (!currentSessionId) {
+        alert('No active session. Please start a new session.');
+        return;
+    }
+
+    appendMessage('user', query);
+    messageInput.value = '';
+
+    // Chosen here so the turn can be stopped before the reply arrives.
+    currentTurnId = 'turn-' + Math.random().toString(36).substring(2, 15);
+    document.getElementById('stop-btn').disabled = false;
+
+    fetch(`/api/v1/sessions/${encodeURIComponent(currentSessionId)}/queries`, {
+        method: 'POST',
+        headers: {
+            'Content-Type': 'application/json'
+        },
+        body: JSON.stringify({
+            student_id: currentStudentId,
+            query: query,
+            turn_id: currentTurnId
+        })
+    })
+        .then(response => response.json())
+        .then(body => {
+            if (body.status === 'success') {
+                // Show the persona the first question was routed to.
+                personaSelect.value = body.data.persona || '';
+                appendToolUses(body.data.tools || []);
+                if (body.data.cancelled) {
+                    appendMessage('assistant', 'Stopped.');
+                } else {
+                    const reply = appendMessage('assistant', body.data.message, body.data.html);
+                    if (body.data.hint_only) {
+                        const note = document.createElement('em');
+                        note.textContent = 'This looks like graded work, so here is a hint rather than the answer.';
+                        reply.prepend(note);
+                    }
+                    appendCitations(reply, body.data.turn_id, body.data.citations || []);
+                }
+            } else if (body.error?.code === 410) {
+                // Idle sessions are removed by the server.
+                alert('This session expired. Starting a new one.');
+                startNewSession();
+            } else {
+                alert('Error sending query: ' + (body.error?.message || 'Unknown error'));
+            }
+        })
+        .catch((error) => {
+            console.error('Error sending query:', error);
+            alert('Error sending query. Check console for details.');
+        })
+        .finally(() => {
+            currentTurnId = null;
+            document.getElementById('stop-btn').disabled = true;
+        });
+}
+
+function stopTurn() {
+    if (!currentTurnId) return;
+    fetch(`/api/v1/turns/${encodeURIComponent(currentTurnId)}/cancel`, {
+        method: 'POST',
+        headers: {
+            'Content-Type': 'application/json'
+        },
+        body: JSON.stringify({ student_id: currentStudentId })
+    }).catch((error) => console.error('Error stopping turn:', error));
+}
+
+function usePrompt(prompt, persona) {
+    messageInput.value = prompt;
+    // Ensure a session exists before sending a query via prompt
+    if (!currentSessionId) {
+        startNewSession();
+        setTimeout(() => {
+            if (currentSessionId) sendQuery();
+            else alert('Please wait for session to start, then try again.');
+        }, 500);
+        return;
+    }
+    // Answer as the button's persona when the server has one by that id.
+    const known = [...personaSelect.options].some(option => option.value === persona);
+    if (known && personaSelect.value !== persona) {
+        personaSelect.value = persona;
+        choosePersona().then(sendQuery);
+    } else {
+        sendQuery();
+    }
+}
+
+/* global renderMathSpans */
+// Tutor replies come with HTML the server rendered from Markdown and
+// sanitized; anything else, the student's own words included, is shown as
+// plain text.
+function appendMessage(role, content, html) {
+    const messageDiv = document.createElement('div');
+    messageDiv.className = `message ${role}`;
+    if (html) {
+        messageDiv.innerHTML = html;
+        renderMathSpans(messageDiv);
+    } else {
+        messageDiv.textContent = content;
+    }
+    messagesContainer.appendChild(messageDiv);
+    messagesContainer.scrollTop = messagesContainer.scrollHeight;
+    return messageDiv;
+}
+
+// Footnotes under a reply, with its [n] markers linked to them.
+function appendCitations(messageDiv, turnId, citations) {
+    if (citations.length === 0) return;
+    const anchor = (marker) => `cite-${turnId}-${marker}`;
+    const cited = new Set(citations.map((c) => c.marker).filter((m) => m != null));
+    linkMarkers(messageDiv, cited, anchor);
+
+    const list = document.createElement('ol');
+    list.className = 'citations';
+    for (const citation of citations) {
+        const item = document.createElement('li');
+        const label = document.createElement('span');
+        label.className = 'citation-label';
+        if (citation.kind === 'document') {
+            item.id = anchor(citation.marker);
+            const where = [];
+            if (citation.position?.page != null) where.push(`page ${citation.position.page}`);
+            if (citation.position?.section) where.push(`“${citation.position.section}”`);
+            label.textContent = `[${citation.marker}] ${citation.title}`
+                + (where.length ? `, ${where.join(', ')}` : '');
+        } else {
+            label.textContent = `Tool: ${citation.title}`;
+        }
+        const snippet = document.createElement('div');
+        snippet.className = 'citation-snippet';
+        snippet.textContent = citation.snippet;
+        item.append(label, snippet);
+        list.appendChild(item);
+    }
+    messageDiv.appendChild(list);
+}
+
+// Turn [1] and [2, 3] in the reply's text, outside code, into links.
+function linkMarkers(root, cited, anchor) {
+    const walker = document.createTreeWalker(root, NodeFilter.SHOW_TEXT, {
+        acceptNode: (node) => node.parentElement.closest('code, pre')
+            ? NodeFilter.FILTER_REJECT : NodeFilter.FILTER_ACCEPT
+    });
+    const nodes = [];
+    while (walker.nextNode()) nodes.push(walker.currentNode);
+    for (const node of nodes) {
+        const parts = node.textContent.split(/(\[\d+(?:\s*,\s*\d+)*\])/);
+        if (parts.length === 1) continue;
+        const fragment = document.createDocumentFragment();
+        for (const part of parts) {
+            const numbers = /^\[[\d,\s]+\]$/.test(part)
+                ? part.slice(1, -1).split(',').map((n) => Number(n.trim()))
+                : [];
+            if (numbers.length === 0 || !numbers.every((n) => cited.has(n))) {
+                fragment.append(part);
+                continue;
+            }
+            const sup = document.createElement('sup');
+            sup.className = 'citation-marker';
+            numbers.forEach((n) => {
+                const link = document.createElement('a');
+                link.href = '#' + anchor(n);
+                link.textContent = `[${n}]`;
+                sup.append(link);
+            });
+            fragment.append(sup);
+        }
+        node.replaceWith(fragment);
+    }
+}
+
+// Draft cards from the latest reply; each can be edited, then kept or skipped.
+function draftCards() {
+    if (!currentSessionId) return;
+    const button = document.getElementById('cards-btn');
+    button.disabled = true;
+    fetch(`/api/v1/sessions/${encodeURIComponent(currentSessionId)}/cards/candidates`, {
+        method: 'POST',
+        headers: {
+            'Content-Type': 'application/json'
+        },
+        body: JSON.stringify({ student_id: currentStudentId })
+    })
+        .then(response => response.json())
+        .then(body => {
+            if (body.status === 'success') {
+                appendCardDrafts(body.data);
+            } else {
+                alert('Error making flashcards: ' + (body.error?.message || 'Unknown error'));
+            }
+        })
+        .catch((error) => {
+            console.error('Error making flashcards:', error);
+            alert('Error making flashcards. Check console for details.');
+        })
+        .finally(() => {
+            button.disabled = false;
+        });
+}
+
+function appendCardDrafts(candidates) {
+    const container = document.createElement('div');
+    container.className = 'card-drafts';
+    for (const draft of candidates.cards) {
+        const item = document.createElement('div');
+        item.className = 'card-draft';
+        const front = document.createElement('textarea');
+        front.value = draft.front;
+        const back = document.createElement('textarea');
+        back.value = draft.back;
+        const keep = document.createElement('button');
+        keep.textContent = 'Keep';
+        const skip = document.createElement('button');
+        skip.textContent = 'Skip';
+        skip.className = 'skip-btn';
+        skip.onclick = () => item.remove();
+        keep.onclick = () => {
+            keep.disabled = true;
+            fetch('/api/v1/cards', {
+                method: 'POST',
+                headers: {
+                    'Content-Type': 'application/json'
+                },
+                body: JSON.stringify({
+                    student_id: currentStudentId,
+                    front: front.value,
+                    back: back.value,
+                    session_id: candidates.session_id,
+                    message_id: candidates.message_id
+                })
+            })
+                .then(response => response.json())
+                .then(body => {
+                    if (body.status === 'success') {
+                        item.replaceChildren(`Saved: ${body.data.front}`);
+                    } else {
+                        keep.disabled = false;
+                        alert('Error saving flashcard: ' + (body.error?.message || 'Unknown error'));
+                    }
+                })
+                .catch((error) => {
+                    keep.disabled = false;
+                    console.error('Error saving flashcard:', error);
+                    alert('Error saving flashcard. Check console for details.');
+                });
+        };
+        item.append(front, back, keep, skip);
+        container.appendChild(item);
+    }
+    messagesContainer.appendChild(container);
+    messagesContainer.scrollTop = messagesContainer.scrollHeight;
+}
+
+// Collapsed by default: the calls are for checking the tutor's working.
+function appendToolUses(tools) {
+    for (const tool of tools) {
+        const details = document.createElement('details');
+        details.className = 'tool-use';
+        const summary = document.createElement('summary');
+        summary.textContent = `Used ${tool.name}`;
+        if (tool.name === 'run_rust') {
+            details.append(summary, ...rustRun(tool));
+        } else {
+            const body = document.createElement('pre');
+            body.textContent = `${tool.arguments}\n→ ${tool.output}`;
+            details.append(summary, body);
+        }
+        messagesContainer.appendChild(details);
+    }
+}
+
+// The program, then what the compiler and the program printed.
+function rustRun(tool) {
+    let code = tool.arguments;
+    let result = {};
+    try {
+        code = JSON.parse(tool.arguments).code;
+        result = JSON.parse(tool.output);
+    } catch (e) {
+        // Show the raw strings.
+    }
+    const sections = [['Code', code]];
+    if (result.error) sections.push(['Error', result.error]);
+    if (result.diagnostics) sections.push(['Compiler', result.diagnostics]);
+    if (result.compiled) {
+        let status = result.timed_out ? 'timed out'
+            : result.signal != null ? `killed by signal ${result.signal}`
+            : `exit code ${result.exit_code}`;
+        if (result.truncated) status += ', output truncated';
+        sections.push([`Output (${status})`, result.stdout + result.stderr]);
+    }
+    return sections.flatMap(([title, text]) => {
+        const label = document.createElement('div');
+        label.className = 'tool-label';
+        label.textContent = title;
+        const body = document.createElement('pre');
+        body.textContent = text;
+        return [label, body];
+    });
+}
+
+messageInput.addEventListener('keypress', function (e) {
+    if (e.key === 'Enter' && !e.shiftKey) {
+        e.preventDefault();
+        sendQuery();
+    }
+});
@@ -0,0 +1,108 @@
+use clap::Parser;
+use serde::Deserialize;
+use std::path::PathBuf;
+use tutor_config::{Common, CommonArgs, CommonFile, FileModel, FileServer, FileUpstream};
+
+pub use tutor_config::{ConfigError, ModelConfig, ServerConfig, UpstreamConfig};
+
+/// Command line flags. Every flag can also be set through the environment
+/// variable named next to it; flags win over the environment, which wins
+/// over the config file.
+#[derive(Debug, Default, Parser)]
+#[command(name = "axum-api", about = "Tutor JSON API server")]
+pub struct Cli {
+    #[command(flatten)]
+    pub common: CommonArgs,
+}
+
+/// Raw config file contents. Every field is optional so that a partial file
+/// only overrides what it mentions.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    server: FileServer,
+    paths: FilePaths,
+    upstream: FileUpstream,
+    model: FileModel,
+    limits: FileLimits,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FilePaths {
+    data_dir: Option<PathBuf>,
+    system_prompt: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileLimits {
+    max_query_chars: Option<usize>,
+}
+
+/// Validated application configuration.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub paths: PathsConfig,
+    pub upstream: UpstreamConfig,
+    pub model: ModelConfig,
+    pub limits: LimitsConfig,
+}
+
+#[derive(Debug, Clone)]
+pub struct PathsConfig {
+    #[allow(dead_code)]
+    pub data_dir: PathBuf,
+    pub system_prompt: PathBuf,
+}
+
+#[derive(Debug, Clone)]
+pub struct LimitsConfig {
+    pub max_query_chars: usize,
+}
+
+impl Config {
+    /// Load configuration from the command line, the environment and the
+    /// config file, in that order of precedence.
+    pub fn load() -> Result<Self, ConfigError> {
+        Self::from_cli(Cli::parse(), |name| std::env::var(name).ok())
+    }
+
+    /// Resolve `cli` over the config file, reading the settings that have
+    /// no flag through `env`.
+    pub fn from_cli(
+        cli: Cli,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, ConfigError> {
+        let (file, base_dir): (FileConfig, _) =
+            tutor_config::read_file(cli.common.config.as_deref())?;
+        let common = Common::resolve(
+            cli.common,
+            CommonFile {
+                server: file.server,
+                data_dir: file.paths.data_dir,
+                system_prompt: file.paths.system_prompt,
+                upstream: file.upstream,
+                model: file.model,
+                max_query_chars: file.limits.max_query_chars,
+            },
+            &base_dir,
+            "127.0.0.1:3000",
+            env,
+        )?;
+
+        Ok(Config {
+            server: common.server,
+            paths: PathsConfig {
+                data_dir: common.data_dir,
+                system_prompt: common.system_prompt,
+            },
+            upstream: common.upstream,
+            model: common.model,
+            limits: LimitsConfig {
+                max_query_chars: common.max_query_chars,
+            },
+        })
+    }
+}
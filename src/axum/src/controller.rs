@@ -1,16 +1,19 @@
+use crate::config::Config;
 use crate::models::AppError;
 use crate::service::TutorService;
 use anyhow::Result;
 
 pub struct TutorController {
     service: TutorService,
+    max_query_chars: usize,
 }
 
 impl TutorController {
-    pub fn new() -> Self {
-        Self {
-            service: TutorService::new(),
-        }
+    pub fn new(config: &Config) -> Result<Self> {
+        Ok(Self {
+            service: TutorService::new(config)?,
+            max_query_chars: config.limits.max_query_chars,
+        })
     }
 
     /// Handle tutoring session creation request.
@@ -34,26 +37,47 @@ impl TutorController {
     ) -> Result<String, AppError> {
         // Validate inputs - check for empty strings
         if student_id.trim().is_empty() {
-            return Err(AppError::BadRequest("Student ID cannot be empty".to_string()));
+            return Err(AppError::BadRequest(
+                "Student ID cannot be empty".to_string(),
+            ));
         }
-        
+
         if session_id.trim().is_empty() {
-            return Err(AppError::BadRequest("Session ID cannot be empty".to_string()));
+            return Err(AppError::BadRequest(
+                "Session ID cannot be empty".to_string(),
+            ));
         }
-        
+
         if query.trim().is_empty() {
             return Err(AppError::BadRequest("Query cannot be empty".to_string()));
         }
-        
+
+        if query.chars().count() > self.max_query_chars {
+            return Err(AppError::BadRequest(format!(
+                "Query exceeds {} characters",
+                self.max_query_chars
+            )));
+        }
+
         // Call the service to process the query and handle errors
-        match self.service.process_query(&student_id, &session_id, &query).await {
+        match self
+            .service
+            .process_query(&student_id, &session_id, &query)
+            .await
+        {
             Ok(response) => Ok(response),
             Err(e) => {
                 // Handle specific error cases
                 if e.to_string().contains("Session not found") {
-                    Err(AppError::NotFound(format!("Session not found for student {} with session {}", student_id, session_id)))
+                    Err(AppError::NotFound(format!(
+                        "Session not found for student {} with session {}",
+                        student_id, session_id
+                    )))
                 } else {
-                    Err(AppError::Internal(format!("Failed to process query: {}", e)))
+                    Err(AppError::Internal(format!(
+                        "Failed to process query: {}",
+                        e
+                    )))
                 }
             }
         }
@@ -1,21 +1,37 @@
+mod config;
 mod controller;
 mod models;
 mod routes;
 mod service;
 mod session;
 
+use anyhow::{Context, Result};
 use axum::{
     Router,
     extract::Extension,
     routing::{get, post},
 };
+use axum_server::tls_rustls::RustlsConfig;
+use config::Config;
 use controller::TutorController;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 
 #[tokio::main]
 async fn main() {
-    let controller = Arc::new(Mutex::new(TutorController::new()));
+    // Load .env first so its variables take part in configuration.
+    dotenv::dotenv().ok();
+
+    if let Err(err) = run().await {
+        eprintln!("Error: {:#}", err);
+        std::process::exit(1);
+    }
+}
+
+async fn run() -> Result<()> {
+    let config = Config::load().context("invalid configuration")?;
+
+    let controller = Arc::new(Mutex::new(TutorController::new(&config)?));
 
     let app = Router::new()
         .route("/", get(routes::root))
@@ -23,10 +39,27 @@ async fn main() {
         .route("/api/send_query", post(routes::send_query))
         .layer(Extension(controller));
 
-    let listener = tokio::net::TcpListener::bind("127.0.0.1:3000")
-        .await
-        .expect("failed to bind");
-    println!("Listening on http://127.0.0.1:3000");
+    let addr = config.server.bind;
+    match &config.server.tls {
+        Some(tls) => {
+            let _ = rustls::crypto::ring::default_provider().install_default();
+            let tls_config = RustlsConfig::from_pem_file(&tls.cert, &tls.key)
+                .await
+                .context("failed to load TLS certificate or key")?;
+            println!("Listening on https://{}", addr);
+            axum_server::bind_rustls(addr, tls_config)
+                .serve(app.into_make_service())
+                .await
+                .context("server error")?;
+        }
+        None => {
+            let listener = tokio::net::TcpListener::bind(addr)
+                .await
+                .with_context(|| format!("failed to bind {}", addr))?;
+            println!("Listening on http://{}", addr);
+            axum::serve(listener, app).await.context("server error")?;
+        }
+    }
 
-    axum::serve(listener, app).await.unwrap();
+    Ok(())
 }
@@ -1,11 +1,12 @@
+use crate::config::{Config, ModelConfig};
 use crate::session::SessionManager;
-use anyhow::{Result, anyhow};
+use anyhow::{Context, Result, anyhow};
 use async_openai::{
     Client,
     config::OpenAIConfig,
     types::{CreateChatCompletionRequestArgs, Role},
 };
-use std::{env, fs};
+use std::fs;
 use uuid::Uuid;
 
 #[allow(dead_code)]
@@ -13,32 +14,29 @@ pub struct TutorService {
     session_manager: SessionManager,
     client: Client<OpenAIConfig>,
     system_prompt: String,
+    model: ModelConfig,
 }
 
 impl TutorService {
-    pub fn new() -> Self {
-        dotenv::dotenv().ok();
-        let api_key = env::var("OPENAI_API_KEY").expect("OPENAI_API_KEY not set");
-        let base_url = format!(
-            "{}v1",
-            env::var("OPENAI_BASE_URL").expect("OPENAI_BASE_URL not set")
-        );
+    pub fn new(config: &Config) -> Result<Self> {
+        let openai_config = OpenAIConfig::new()
+            .with_api_key(config.upstream.api_key.as_str())
+            .with_api_base(config.upstream.base_url.as_str());
+        let client = Client::with_config(openai_config);
 
-        let config = OpenAIConfig::new()
-            .with_api_key(api_key)
-            .with_api_base(base_url);
-        let client = Client::with_config(config);
+        let system_prompt = fs::read_to_string(&config.paths.system_prompt).with_context(|| {
+            format!(
+                "failed to read system prompt {}",
+                config.paths.system_prompt.display()
+            )
+        })?;
 
-        let system_prompt = fs::read_to_string("data/system_prompt.txt").unwrap_or_else(|e| {
-            eprintln!("Error loading system prompt: {}", e);
-            "You are a helpful tutor.".to_string()
-        });
-
-        Self {
+        Ok(Self {
             session_manager: SessionManager::new(),
             client,
             system_prompt,
-        }
+            model: config.model.clone(),
+        })
     }
 
     pub fn create_session(&mut self, student_id: &str) -> String {
@@ -67,10 +65,10 @@ impl TutorService {
             .get_conversation(student_id, session_id)?;
 
         let request = CreateChatCompletionRequestArgs::default()
-            .model("deepseek-ai/DeepSeek-V3")
+            .model(self.model.name.as_str())
             .messages(conversation)
-            .temperature(0.6)
-            .max_tokens(500_u32)
+            .temperature(self.model.temperature)
+            .max_tokens(self.model.max_tokens)
             .build()?;
         let response = self.client.chat().create(request).await?;
 
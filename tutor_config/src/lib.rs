@@ -0,0 +1,573 @@
+//! Configuration shared by the tutor servers.
+//!
+//! Every setting is looked up in the command line flags, then the
+//! environment, then a TOML file, then a built-in default. This crate
+//! resolves the settings all servers have; each server adds its own
+//! sections on top with the same helpers.
+
+use clap::Args;
+use serde::{Deserialize, de::DeserializeOwned};
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+/// Config file loaded when neither `--config` nor `TUTOR_CONFIG` is given.
+pub const DEFAULT_CONFIG_FILE: &str = "tutor.toml";
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("failed to read config file {path}: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("failed to parse config file {path}: {source}")]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+
+    #[error("missing `{field}`: set it in the config file or via {hint}")]
+    Missing {
+        field: &'static str,
+        hint: &'static str,
+    },
+
+    #[error("invalid `{field}`: {reason}")]
+    Invalid { field: &'static str, reason: String },
+}
+
+/// Flags every server accepts, flattened into its own command line. Every
+/// flag can also be set through the environment variable named next to it;
+/// flags win over the environment, which wins over the config file.
+#[derive(Debug, Default, Args)]
+pub struct CommonArgs {
+    /// Path to the TOML config file
+    #[arg(long, env = "TUTOR_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Address to listen on, e.g. 0.0.0.0:3000
+    #[arg(long, env = "TUTOR_BIND")]
+    pub bind: Option<String>,
+
+    /// PEM certificate chain; enables HTTPS together with --tls-key
+    #[arg(long, env = "TUTOR_TLS_CERT")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key; enables HTTPS together with --tls-cert
+    #[arg(long, env = "TUTOR_TLS_KEY")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Directory holding the system prompt and other data files
+    #[arg(long, env = "TUTOR_DATA_DIR")]
+    pub data_dir: Option<PathBuf>,
+
+    /// Upstream OpenAI-compatible base URL; `/v1` is appended if missing
+    #[arg(long, env = "OPENAI_BASE_URL")]
+    pub base_url: Option<String>,
+
+    /// Chat model used for tutoring
+    #[arg(long, env = "TUTOR_MODEL")]
+    pub model: Option<String>,
+
+    /// Sampling temperature, 0.0 to 2.0
+    #[arg(long, env = "TUTOR_TEMPERATURE")]
+    pub temperature: Option<f32>,
+
+    /// Most tokens in a tutor reply
+    #[arg(long, env = "TUTOR_MAX_TOKENS")]
+    pub max_tokens: Option<u32>,
+
+    /// Longest question a student may send, in characters
+    #[arg(long, env = "TUTOR_MAX_QUERY_CHARS")]
+    pub max_query_chars: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileServer {
+    pub bind: Option<String>,
+    pub tls: Option<FileTls>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileTls {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileUpstream {
+    pub base_url: Option<String>,
+    pub api_key: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileModel {
+    pub name: Option<String>,
+    pub temperature: Option<f32>,
+    pub max_tokens: Option<u32>,
+}
+
+/// The parts of a server's config file that [`Common::resolve`] reads.
+#[derive(Debug, Default)]
+pub struct CommonFile {
+    pub server: FileServer,
+    pub data_dir: Option<PathBuf>,
+    pub system_prompt: Option<PathBuf>,
+    pub upstream: FileUpstream,
+    pub model: FileModel,
+    pub max_query_chars: Option<usize>,
+}
+
+/// The validated settings every server has.
+#[derive(Debug, Clone)]
+pub struct Common {
+    pub server: ServerConfig,
+    pub data_dir: PathBuf,
+    pub system_prompt: PathBuf,
+    pub upstream: UpstreamConfig,
+    pub model: ModelConfig,
+    pub max_query_chars: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub bind: SocketAddr,
+    pub tls: Option<TlsConfig>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+#[derive(Clone)]
+pub struct UpstreamConfig {
+    /// Normalized base URL, always ending in `/v1` without a trailing slash.
+    pub base_url: String,
+    pub api_key: String,
+}
+
+// Keep the API key out of debug output.
+impl std::fmt::Debug for UpstreamConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UpstreamConfig")
+            .field("base_url", &self.base_url)
+            .field("api_key", &"<redacted>")
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ModelConfig {
+    pub name: String,
+    pub temperature: f32,
+    pub max_tokens: u32,
+}
+
+/// Read the config file at `path`, or `tutor.toml` in the working directory
+/// when there is one. Returns the contents, all defaults without a file,
+/// and the directory relative paths in the file are resolved against.
+pub fn read_file<T: DeserializeOwned + Default>(
+    path: Option<&Path>,
+) -> Result<(T, PathBuf), ConfigError> {
+    let path = match path {
+        Some(path) => path,
+        None if Path::new(DEFAULT_CONFIG_FILE).is_file() => Path::new(DEFAULT_CONFIG_FILE),
+        None => return Ok((T::default(), PathBuf::new())),
+    };
+    let text = std::fs::read_to_string(path).map_err(|source| ConfigError::Read {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let file = toml::from_str(&text).map_err(|source| ConfigError::Parse {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    let base_dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+    Ok((file, base_dir))
+}
+
+impl Common {
+    /// Merge the flags and environment in `args` over the file's settings.
+    /// `default_bind` is used when neither sets an address. Settings that
+    /// have no flag, like the API key, are read through `env`.
+    pub fn resolve(
+        args: CommonArgs,
+        file: CommonFile,
+        base_dir: &Path,
+        default_bind: &str,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, ConfigError> {
+        // Paths from the file are relative to the file, not the working directory.
+        let from_file = |p: Option<PathBuf>| p.map(|p| base_dir.join(p));
+
+        let bind = args
+            .bind
+            .or(file.server.bind)
+            .unwrap_or_else(|| default_bind.to_string());
+        let bind = bind
+            .parse::<SocketAddr>()
+            .map_err(|e| ConfigError::Invalid {
+                field: "server.bind",
+                reason: format!("{bind:?} is not a socket address ({e})"),
+            })?;
+
+        let file_tls = file
+            .server
+            .tls
+            .map(|t| (base_dir.join(t.cert), base_dir.join(t.key)));
+        let tls = match (args.tls_cert, args.tls_key, file_tls) {
+            (Some(cert), Some(key), _) => Some(TlsConfig { cert, key }),
+            (None, None, Some((cert, key))) => Some(TlsConfig { cert, key }),
+            (None, None, None) => None,
+            _ => {
+                return Err(ConfigError::Invalid {
+                    field: "server.tls",
+                    reason: "both a certificate and a key are required".to_string(),
+                });
+            }
+        };
+        if let Some(tls) = &tls {
+            require_file("server.tls.cert", &tls.cert)?;
+            require_file("server.tls.key", &tls.key)?;
+        }
+
+        let data_dir = args
+            .data_dir
+            .or(from_file(file.data_dir))
+            .unwrap_or_else(|| PathBuf::from("data"));
+        require_dir("paths.data_dir", &data_dir)?;
+        // A relative prompt path is looked up inside the data directory.
+        let system_prompt = data_dir.join(
+            file.system_prompt
+                .unwrap_or_else(|| PathBuf::from("system_prompt.txt")),
+        );
+        require_file("paths.system_prompt", &system_prompt)?;
+
+        let base_url = args
+            .base_url
+            .or(file.upstream.base_url)
+            .ok_or(ConfigError::Missing {
+                field: "upstream.base_url",
+                hint: "--base-url or OPENAI_BASE_URL",
+            })?;
+        let base_url = normalize_base_url(&base_url)?;
+        let api_key = env("OPENAI_API_KEY")
+            .or(file.upstream.api_key)
+            .filter(|k| !k.trim().is_empty())
+            .ok_or(ConfigError::Missing {
+                field: "upstream.api_key",
+                hint: "OPENAI_API_KEY",
+            })?;
+
+        let name = args
+            .model
+            .or(file.model.name)
+            .unwrap_or_else(|| "deepseek-ai/DeepSeek-V3".to_string());
+        if name.trim().is_empty() {
+            return Err(ConfigError::Invalid {
+                field: "model.name",
+                reason: "must not be empty".to_string(),
+            });
+        }
+        let temperature = args.temperature.or(file.model.temperature).unwrap_or(0.6);
+        if !(0.0..=2.0).contains(&temperature) {
+            return Err(ConfigError::Invalid {
+                field: "model.temperature",
+                reason: format!("{temperature} is outside 0.0..=2.0"),
+            });
+        }
+        let max_tokens = positive(
+            "model.max_tokens",
+            args.max_tokens.or(file.model.max_tokens).unwrap_or(500),
+        )?;
+        let max_query_chars = positive(
+            "limits.max_query_chars",
+            args.max_query_chars
+                .or(file.max_query_chars)
+                .unwrap_or(4000),
+        )?;
+
+        Ok(Self {
+            server: ServerConfig { bind, tls },
+            data_dir,
+            system_prompt,
+            upstream: UpstreamConfig { base_url, api_key },
+            model: ModelConfig {
+                name,
+                temperature,
+                max_tokens,
+            },
+            max_query_chars,
+        })
+    }
+}
+
+pub fn positive<T: Default + PartialEq>(field: &'static str, value: T) -> Result<T, ConfigError> {
+    if value == T::default() {
+        Err(ConfigError::Invalid {
+            field,
+            reason: "must be greater than zero".to_string(),
+        })
+    } else {
+        Ok(value)
+    }
+}
+
+pub fn require_dir(field: &'static str, path: &Path) -> Result<(), ConfigError> {
+    if path.is_dir() {
+        Ok(())
+    } else {
+        Err(ConfigError::Invalid {
+            field,
+            reason: format!("{} is not a directory", path.display()),
+        })
+    }
+}
+
+pub fn require_file(field: &'static str, path: &Path) -> Result<(), ConfigError> {
+    if path.is_file() {
+        Ok(())
+    } else {
+        Err(ConfigError::Invalid {
+            field,
+            reason: format!("{} does not exist or is not a file", path.display()),
+        })
+    }
+}
+
+/// Accepts `https://host`, `https://host/` or `https://host/v1[/]` and
+/// returns `https://host/v1`.
+pub fn normalize_base_url(raw: &str) -> Result<String, ConfigError> {
+    let invalid = |reason: String| ConfigError::Invalid {
+        field: "upstream.base_url",
+        reason,
+    };
+    let trimmed = raw.trim().trim_end_matches('/');
+    let uri = trimmed
+        .parse::<http::Uri>()
+        .map_err(|e| invalid(format!("{raw:?} is not a URL ({e})")))?;
+    match uri.scheme_str() {
+        Some("http") | Some("https") => {}
+        _ => {
+            return Err(invalid(format!(
+                "{raw:?} must start with http:// or https://"
+            )));
+        }
+    }
+    if uri.host().is_none_or(str::is_empty) {
+        return Err(invalid(format!("{raw:?} has no host")));
+    }
+    if uri.query().is_some() {
+        return Err(invalid(format!("{raw:?} must not contain a query string")));
+    }
+    if trimmed.ends_with("/v1") {
+        Ok(trimmed.to_string())
+    } else {
+        Ok(format!("{trimmed}/v1"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use std::fs;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Parser)]
+    struct TestCli {
+        #[command(flatten)]
+        common: CommonArgs,
+    }
+
+    #[derive(Default, Deserialize)]
+    #[serde(default)]
+    struct TestFile {
+        server: FileServer,
+        upstream: FileUpstream,
+        model: FileModel,
+        max_query_chars: Option<usize>,
+    }
+
+    /// A config file and data directory in a fresh temporary directory,
+    /// removed again on drop.
+    struct ConfigDir(PathBuf);
+
+    impl ConfigDir {
+        fn new(name: &str, toml: &str) -> Self {
+            static NEXT: AtomicUsize = AtomicUsize::new(0);
+            let dir = std::env::temp_dir().join(format!(
+                "tutor_config_test_{name}_{}_{}",
+                std::process::id(),
+                NEXT.fetch_add(1, Ordering::Relaxed)
+            ));
+            fs::create_dir_all(dir.join("data")).unwrap();
+            fs::write(dir.join("data/system_prompt.txt"), "You are a tutor.").unwrap();
+            fs::write(dir.join("tutor.toml"), toml).unwrap();
+            Self(dir)
+        }
+
+        fn join(&self, path: &str) -> PathBuf {
+            self.0.join(path)
+        }
+    }
+
+    impl Drop for ConfigDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// The process environment is left out so that tests do not depend on
+    /// it; `env` stands in for it.
+    fn resolve_with_env(args: CommonArgs, env: &[(&str, &str)]) -> Result<Common, ConfigError> {
+        let (file, base_dir): (TestFile, _) = read_file(args.config.as_deref())?;
+        let file = CommonFile {
+            server: file.server,
+            data_dir: Some(PathBuf::from("data")),
+            system_prompt: None,
+            upstream: file.upstream,
+            model: file.model,
+            max_query_chars: file.max_query_chars,
+        };
+        Common::resolve(args, file, &base_dir, "127.0.0.1:3000", |name| {
+            env.iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value.to_string())
+        })
+    }
+
+    fn resolve(args: CommonArgs) -> Result<Common, ConfigError> {
+        resolve_with_env(args, &[])
+    }
+
+    #[test]
+    fn normalizes_base_urls() {
+        for raw in [
+            "https://api.example.com",
+            "https://api.example.com/",
+            "https://api.example.com/v1",
+            " https://api.example.com/v1/ ",
+        ] {
+            assert_eq!(
+                normalize_base_url(raw).unwrap(),
+                "https://api.example.com/v1"
+            );
+        }
+        assert_eq!(
+            normalize_base_url("http://localhost:8080/proxy").unwrap(),
+            "http://localhost:8080/proxy/v1"
+        );
+        for raw in [
+            "api.example.com",
+            "ftp://api.example.com",
+            "https://x/v1?key=1",
+            "",
+        ] {
+            assert!(normalize_base_url(raw).is_err(), "{raw:?} was accepted");
+        }
+    }
+
+    #[test]
+    fn flags_and_environment_win_over_file() {
+        let dir = ConfigDir::new(
+            "precedence",
+            r#"
+            max_query_chars = 100
+            [server]
+            bind = "127.0.0.1:4000"
+            [upstream]
+            base_url = "https://file.example.com"
+            api_key = "file-key"
+            [model]
+            name = "file-model"
+            temperature = 0.1
+            max_tokens = 111
+            "#,
+        );
+        let config = dir.join("tutor.toml");
+        let cli = TestCli::try_parse_from([
+            "tutor".as_ref(),
+            "--config".as_ref(),
+            config.as_os_str(),
+            "--model".as_ref(),
+            "flag-model".as_ref(),
+            "--max-tokens".as_ref(),
+            "333".as_ref(),
+        ])
+        .unwrap();
+        let common = resolve_with_env(cli.common, &[("OPENAI_API_KEY", "env-key")]).unwrap();
+
+        assert_eq!(common.model.name, "flag-model");
+        assert_eq!(common.model.max_tokens, 333);
+        assert_eq!(common.model.temperature, 0.1);
+        assert_eq!(common.upstream.api_key, "env-key");
+        assert_eq!(common.max_query_chars, 100);
+        assert_eq!(common.server.bind, "127.0.0.1:4000".parse().unwrap());
+        assert_eq!(common.data_dir, dir.join("data"));
+        assert_eq!(common.system_prompt, dir.join("data/system_prompt.txt"));
+    }
+
+    #[test]
+    fn defaults_fill_what_nobody_sets() {
+        let dir = ConfigDir::new(
+            "defaults",
+            "[upstream]\nbase_url = \"https://api.example.com\"\napi_key = \"k\"\n",
+        );
+        let common = resolve(CommonArgs {
+            config: Some(dir.join("tutor.toml")),
+            ..CommonArgs::default()
+        })
+        .unwrap();
+        assert_eq!(common.server.bind, "127.0.0.1:3000".parse().unwrap());
+        assert_eq!(common.upstream.base_url, "https://api.example.com/v1");
+        assert_eq!(common.model.name, "deepseek-ai/DeepSeek-V3");
+        assert_eq!(common.model.max_tokens, 500);
+        assert_eq!(common.max_query_chars, 4000);
+    }
+
+    #[test]
+    fn rejects_invalid_overrides() {
+        let dir = ConfigDir::new(
+            "invalid",
+            "[upstream]\nbase_url = \"https://api.example.com\"\napi_key = \"k\"\n",
+        );
+        let field = |args: CommonArgs| match resolve(CommonArgs {
+            config: Some(dir.join("tutor.toml")),
+            ..args
+        }) {
+            Err(ConfigError::Invalid { field, .. }) => field,
+            other => panic!("expected an invalid field, got {other:?}"),
+        };
+        assert_eq!(
+            field(CommonArgs {
+                temperature: Some(2.5),
+                ..CommonArgs::default()
+            }),
+            "model.temperature"
+        );
+        assert_eq!(
+            field(CommonArgs {
+                max_query_chars: Some(0),
+                ..CommonArgs::default()
+            }),
+            "limits.max_query_chars"
+        );
+        assert_eq!(
+            field(CommonArgs {
+                tls_cert: Some(PathBuf::from("cert.pem")),
+                ..CommonArgs::default()
+            }),
+            "server.tls"
+        );
+    }
+}